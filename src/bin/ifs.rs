@@ -21,12 +21,22 @@ extern crate fs;
 
 pub mod release;
 
+use std::cmp;
 use std::env;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
 
-use fs::client::config::{Config, Target};
-use fs::client::Client;
+use fs::client::config::{Config, Target, TlsConfig};
+use fs::client::{Client, ConnectError};
+use fs::proto::admin::client::{AdminError, AdminInterface};
+use fs::proto::auth::client::AuthError;
 use fs::proto::content::state::ContentState;
+use fs::server::credentials::{DEFAULT_ROUNDS, PasswordHash, Secret, from_hex};
+use fs::server::permissions::Permissions;
 use release::*;
 
 
@@ -72,7 +82,52 @@ USAGE:
     ifs getinfo
         Get an info about the server.
     ifs copyfrom <path>
-        Add a file from the local filesystem.
+        Add a file from the filesystem of the server, showing the progress of the copy.
+        The server reads the file itself, so it must be under one of its import_roots;
+        a relative path is taken from the current directory.
+    ifs admin connections
+        List the connections to the server.
+    ifs admin kill <connection>
+        Close a connection.
+    ifs admin tasks
+        List the tasks of all connections.
+    ifs admin cancel <connection> <task>
+        Fail a task of a connection.
+    ifs admin maintenance <cleanup|verify>
+        Remove the files of interrupted copies, or check the stored files.
+    ifs admin shutdown
+        Stop the server once the running tasks are finished.
+    ifs admin token <identity> <permissions> <ttl-seconds>
+        Mint a token for the identity, limited to the permissions, like import,read.
+        Prints the id of the token and the token, which is IFS_TOKEN.
+    ifs admin revoke <token-id>
+        Revoke a token before it expires.
+    ifs passwd <login>
+        Read a password from stdin and print a line for the server credential file.
+    ifs keygen <login>
+        Generate a key and print a line for the server credential file; the hex after key$ is IFS_KEY.
+
+ENVIRONMENT:
+    IFS_UNIXSOCKET
+        Connect to this Unix socket instead of 127.0.0.1:1313.
+    IFS_TLS_CA
+        Connect over TLS, trusting only the server certificates signed by this CA.
+    IFS_TLS_SERVER_NAME
+        The name the server certificate is issued for; localhost by default.
+    IFS_TLS_CERT, IFS_TLS_KEY
+        The client certificate and key, if the server requires them.
+    IFS_HEARTBEAT
+        Ping the server each N seconds and disconnect if it stops answering.
+    IFS_CHECKSUM
+        Protect frames with a checksum: crc32c or blake2b, if the server accepts it.
+    IFS_CAPTURE
+        Record the frames of the connection into this file; see ifscap.
+    IFS_LOGIN, IFS_PASSWORD
+        The login and the password, if the server requires them.
+    IFS_KEY
+        The key of IFS_LOGIN in hex, proven to the server instead of sending the password.
+    IFS_TOKEN
+        A token minted by the server, sent instead of the password over TLS or the Unix socket.
 ");
 }
 
@@ -97,18 +152,63 @@ fn main() {
     match command.as_str() {
         "getinfo"   => if !args.is_empty() { help(); return; },
         "copyfrom"  => if args.len() != 1 { help(); return; },
+        "admin"     => if !valid_admin_args(args) { help(); return; },
+        "passwd"    => if args.len() != 1 { help(); return; } else { return passwd(&args[0]); },
+        "keygen"    => if args.len() != 1 { help(); return; } else { return keygen(&args[0]); },
         _           => { println!("Unknown command {}", command); help(); return; },
     }
 
-    let target = Target::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1313));
+    let target = match env::var("IFS_UNIXSOCKET") {
+        Ok(unixsocket) => Target::Unix(unixsocket),
+        Err(_) => Target::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1313)),
+    };
     info!("  ::  Connecting to {:?}", target);
 
-    let config = Config::new(target);
+    let mut config = Config::new(target);
+    if let Ok(ca) = env::var("IFS_TLS_CA") {
+        let server_name = env::var("IFS_TLS_SERVER_NAME").unwrap_or_else(|_| "localhost".to_owned());
+        let mut tls = TlsConfig::new(ca, server_name);
+        if let (Ok(cert), Ok(key)) = (env::var("IFS_TLS_CERT"), env::var("IFS_TLS_KEY")) {
+            tls.client_cert = Some((cert, key));
+        }
+        config.tls = Some(tls);
+    }
+    if let Ok(interval) = env::var("IFS_HEARTBEAT") {
+        match interval.parse() {
+            Ok(interval) => config.heartbeat_interval = interval,
+            Err(_) => { println!("Invalid IFS_HEARTBEAT {}", interval); return; },
+        }
+    }
+    if let Ok(checksum) = env::var("IFS_CHECKSUM") {
+        match checksum.parse() {
+            Ok(checksum) => config.frame_checksum = Some(checksum),
+            Err(err) => { println!("Invalid IFS_CHECKSUM: {}", err); return; },
+        }
+    }
+    config.capture = env::var("IFS_CAPTURE").ok();
+    if let (Ok(login), Ok(password)) = (env::var("IFS_LOGIN"), env::var("IFS_PASSWORD")) {
+        config.login = Some((login, password));
+    }
+    if let (Ok(login), Ok(key)) = (env::var("IFS_LOGIN"), env::var("IFS_KEY")) {
+        match from_hex(&key) {
+            Some(key) => config.key = Some((login, key)),
+            None => { println!("Invalid IFS_KEY: expected hex"); return; },
+        }
+    }
+    config.token = env::var("IFS_TOKEN").ok();
     let mut client = Client::new(config);
 
+    if command == "admin" {
+        match client.connect_admin() {
+            Ok(admin) => run_admin(&admin, args),
+            Err(error) => connect_failed(error),
+        }
+        return;
+    }
+
     let ifs = match client.connect() {
         Ok(ifs) => ifs,
-        Err(error) => panic!(format!("{:?}", error)),
+        Err(error) => return connect_failed(error),
     };
 
     info!("  ::  Connected");
@@ -116,17 +216,143 @@ fn main() {
 
     match command.as_str() {
         "getinfo" => {
-            match ifs.info().unwrap().wait().unwrap() {
-                ContentState::GetInfo(ref info) => info!("Server info: {:?}", &info.response),
-                _ => unreachable!(),
+            match ifs.info().unwrap().wait() {
+                Ok(ContentState::GetInfo(ref info)) => info!("Server info: {:?}", &info.response),
+                Ok(_) => unreachable!(),
+                Err(reason) => error!("{}", reason),
             };
         },
         "copyfrom" => {
-            match ifs.copy_from(args[0].as_str()).unwrap().wait().unwrap() {
-                ContentState::CopyFrom(ref copy_from) => info!("Copy result: {:?}", &copy_from.result),
-                _ => unreachable!(),
+            // The server only accepts absolute paths, as its working directory is not the one of the client
+            let path = env::current_dir().map(|dir| dir.join(&args[0])).unwrap_or_else(|_| PathBuf::from(&args[0]));
+            let shown = Arc::new(AtomicBool::new(false));
+            let shown_ = shown.clone();
+            let task = ifs.copy_from_with_progress(&path.to_string_lossy(), move |done, total| {
+                shown_.store(true, Ordering::SeqCst);
+                show_progress(done, total);
+            }).unwrap();
+            let result = task.wait();
+            if shown.load(Ordering::SeqCst) {
+                let _ = writeln!(io::stderr(), "");
+            }
+            match result {
+                Ok(ContentState::CopyFrom(ref copy_from)) => info!("Copy result: {:?}", &copy_from.result),
+                Ok(_) => unreachable!(),
+                Err(reason) => error!("{}", reason),
             };
         },
         _ => unreachable!(),
     }
 }
+
+
+/// Draws a progress bar over the current line of stderr
+fn show_progress(done: u64, total: u64) {
+    const WIDTH: u64 = 40;
+    let (filled, percent) = match total {
+        0 => (WIDTH, 100),
+        total => (cmp::min(done, total) * WIDTH / total, cmp::min(done, total) * 100 / total),
+    };
+    let bar = (0..WIDTH).map(|i| if i < filled { '#' } else { '-' }).collect::<String>();
+    let mut stderr = io::stderr();
+    let _ = write!(stderr, "\r[{}] {:>3}%  {} / {} bytes", bar, percent, done, total);
+    let _ = stderr.flush();
+}
+
+
+fn connect_failed(error: ConnectError) {
+    match error {
+        ConnectError::AuthError(AuthError::InacceptableProtocol(version, versions)) => {
+            error!("The server does not support protocol version {}; supported versions: {}", version, versions);
+        },
+        error => panic!(format!("{:?}", error)),
+    }
+}
+
+
+fn valid_admin_args(args: &[String]) -> bool {
+    let numbers = |args: &[String]| args.iter().all(|arg| arg.parse::<u64>().is_ok());
+    match args.split_first() {
+        Some((command, rest)) => match command.as_str() {
+            "connections" | "tasks" | "shutdown" => rest.is_empty(),
+            "kill" => rest.len() == 1 && numbers(rest),
+            "cancel" => rest.len() == 2 && numbers(rest),
+            "maintenance" | "revoke" => rest.len() == 1,
+            "token" => rest.len() == 3 && rest[1].parse::<Permissions>().is_ok() && numbers(&rest[2..]),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+
+/// Runs an admin command; the arguments are checked by `valid_admin_args`
+fn run_admin(admin: &AdminInterface, args: &[String]) {
+    let number = |n: usize| args[n].parse::<u64>().unwrap();
+    let result = match args[0].as_str() {
+        "connections" => admin.connections().map(|connections| {
+            println!("{:>6}  {:<24}{:>12}{:>7}{:>14}{:>14}", "ID", "PEER", "STARTED", "TASKS", "READ", "WRITTEN");
+            for c in connections {
+                println!("{:>6}  {:<24}{:>12}{:>7}{:>14}{:>14}",
+                    c.id, c.peer, c.started, c.tasks, c.bytes_read, c.bytes_written);
+            }
+        }),
+        "tasks" => admin.tasks().map(|tasks| {
+            println!("{:>6}{:>6}{:>9}  {:<10}{}", "CONN", "TASK", "RUNNING", "STATE", "DESCRIPTION");
+            for t in tasks {
+                println!("{:>6}{:>6}{:>8}s  {:<10}{}", t.connection, t.task_id, t.running,
+                    if t.finished { "finished" } else { "running" }, t.description);
+            }
+        }),
+        "kill" => admin.kill_connection(number(1) as usize).map(|message| println!("{}", message)),
+        "cancel" => admin.cancel_task(number(1) as usize, number(2)).map(|message| println!("{}", message)),
+        "maintenance" => admin.maintenance(&args[1]).map(|message| println!("{}", message)),
+        "shutdown" => admin.shutdown().map(|message| println!("{}", message)),
+        "token" => admin.mint_token(&args[1], args[2].parse().unwrap(), number(3))
+            .map(|(id, token)| println!("{} {}", id, token)),
+        "revoke" => admin.revoke_token(&args[1]).map(|message| println!("{}", message)),
+        _ => unreachable!(),
+    };
+    match result {
+        Ok(()) => (),
+        Err(AdminError::Rejected(reason)) => error!("Rejected: {}", reason),
+        Err(AdminError::Failed(message)) => error!("Failed: {}", message),
+        Err(err) => error!("{:?}", err),
+    }
+}
+
+
+fn valid_login(login: &str) -> bool {
+    if login.is_empty() || login.contains(':') {
+        error!("The login must be non-empty and must not contain ':'");
+        return false;
+    }
+    true
+}
+
+
+fn passwd(login: &str) {
+    if !valid_login(login) {
+        return;
+    }
+    let mut password = String::new();
+    if let Err(err) = io::stdin().read_line(&mut password) {
+        error!("Reading the password: {:?}", err);
+        return;
+    }
+    match PasswordHash::new(password.trim_right_matches(|c| c == '\r' || c == '\n'), DEFAULT_ROUNDS) {
+        Ok(hash) => println!("{}:{}", login, hash),
+        Err(err) => error!("Hashing the password: {:?}", err),
+    }
+}
+
+
+fn keygen(login: &str) {
+    if !valid_login(login) {
+        return;
+    }
+    match Secret::new_key() {
+        Ok(key) => println!("{}:{}", login, key),
+        Err(err) => error!("Generating the key: {:?}", err),
+    }
+}
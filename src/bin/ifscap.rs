@@ -0,0 +1,179 @@
+#![cfg_attr(feature = "dev", allow(unstable_features))]
+
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+#![cfg_attr(feature = "trace", feature(custom_attribute, plugin))]
+#![cfg_attr(feature = "trace", plugin(trace))]
+
+#![cfg_attr(feature = "clippy", allow(items_after_statements))]
+
+#![feature(question_mark)]
+
+
+#[macro_use] extern crate log;
+extern crate log4rs;
+
+extern crate fs;
+
+pub mod release;
+
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use fs::capture::{CaptureReader, Decoder, Direction, Record, replay};
+use fs::client::config::{Config, Target};
+use fs::client::Client;
+use release::*;
+
+
+static CONFIG: &'static str = r#"
+appenders:
+    stdout:
+        kind: console
+        encoder:
+            pattern: "{l:<10}{m}{n}"
+
+root:
+    level: warn
+    appenders:
+        - stdout
+
+"#;
+
+
+fn init_logger() {
+    if log4rs::init_file("log.toml", Default::default()).is_err() {
+        init_default();
+    };
+
+    fn init_default() {
+        let creator = Default::default();
+        let config = log4rs::file::Config::parse(CONFIG, log4rs::file::Format::Yaml, &creator)
+            .expect("default config is valid")
+            .into_config();
+
+        log4rs::init_config(config).unwrap();
+    }
+}
+
+
+fn help() {
+    println!("
+
+USAGE:
+    ifscap decode <capture>
+        Print the messages recorded in a capture file.
+    ifscap replay [--timing] <capture> [<address>]
+        Send the messages recorded from the client to the server and print the answers.
+        The address is host:port or the path of a unix socket; 127.0.0.1:1313 by default.
+        With --timing the recorded pauses between the messages are kept.
+
+Captures are recorded by ifsd for each connection when `capture_dir` is set,
+and by ifs with IFS_CAPTURE=<file>.
+");
+}
+
+
+fn load(path: &str) -> Result<Vec<Record>, String> {
+    let file = File::open(path).map_err(|err| format!("Opening {}: {}", path, err))?;
+    let reader = CaptureReader::new(BufReader::new(file)).map_err(|err| format!("Reading {}: {}", path, err))?;
+    reader.collect::<Result<Vec<_>, _>>().map_err(|err| format!("Reading {}: {}", path, err))
+}
+
+
+fn target(address: Option<&String>) -> Target {
+    match address {
+        None => Target::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1313)),
+        Some(address) => match address.parse() {
+            Ok(addr) => Target::Tcp(addr),
+            Err(_) => Target::Unix(address.clone()),
+        },
+    }
+}
+
+
+/// Prints records relative to the time of the first one
+struct Printer {
+    decoder: Decoder,
+    start: Option<Duration>,
+}
+
+
+impl Printer {
+    fn new() -> Printer {
+        Printer { decoder: Decoder::new(), start: None }
+    }
+
+    fn print(&mut self, record: &Record) {
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some(record.time);
+                record.time
+            },
+        };
+        let offset = if record.time > start { record.time - start } else { Duration::new(0, 0) };
+        let subprotocol = self.decoder.subprotocol();
+        println!("{:>6}.{:06} {} {:?} {}",
+            offset.as_secs(),
+            offset.subsec_nanos() / 1000,
+            match record.direction {
+                Direction::FromClient => "C->S",
+                Direction::FromServer => "S->C",
+            },
+            subprotocol,
+            self.decoder.describe(record));
+    }
+}
+
+
+fn main() {
+    init_logger();
+
+    info!("Irbis FS capture tool v. {}; compiled with {}", env!("CARGO_PKG_VERSION"), RUSTC_VERSION);
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        help();
+        return;
+    }
+    let (command, args) = (&args[0], &args[1..]);
+
+    match command.as_str() {
+        "decode" => {
+            if args.len() != 1 { help(); return; }
+            let records = match load(&args[0]) {
+                Ok(records) => records,
+                Err(err) => { println!("{}", err); return; },
+            };
+            let mut printer = Printer::new();
+            for record in &records {
+                printer.print(record);
+            }
+        },
+        "replay" => {
+            let timing = args.first().map_or(false, |arg| arg == "--timing");
+            let args = if timing { &args[1..] } else { args };
+            if args.is_empty() || args.len() > 2 { help(); return; }
+            let records = match load(&args[0]) {
+                Ok(records) => records,
+                Err(err) => { println!("{}", err); return; },
+            };
+
+            let mut client = Client::new(Config::new(target(args.get(1))));
+            let stream = match client.open_stream() {
+                Ok(stream) => stream,
+                Err(err) => { println!("Connecting: {:?}", err); return; },
+            };
+            let mut printer = Printer::new();
+            if let Err(err) = replay(stream, &records, timing, &mut |record| printer.print(record)) {
+                println!("Replay stopped: {}", err);
+            }
+        },
+        _ => { println!("Unknown command {}", command); help(); },
+    }
+}
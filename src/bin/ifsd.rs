@@ -16,6 +16,9 @@ extern crate fs;
 
 pub mod release;
 
+use std::env;
+use std::process;
+
 use compat::getpid;
 use fs::server::config::Config;
 use fs::server::Server;
@@ -57,14 +60,43 @@ fn init_logger() {
 }
 
 
+/// Applies the settings given by the environment:
+///
+///     IFSD_DEFAULT_PERMISSIONS
+///         The permissions of anonymous clients, like `info,read`; only `info` by default.
+///     IFSD_IMPORT_ROOTS
+///         The directories the clients may import files from, separated like in PATH; none by default.
+fn config_from_env(mut config: Config) -> Result<Config, String> {
+    if let Ok(permissions) = env::var("IFSD_DEFAULT_PERMISSIONS") {
+        config.default_permissions = match permissions.parse() {
+            Ok(permissions) => permissions,
+            Err(err) => return Err(format!("IFSD_DEFAULT_PERMISSIONS: {}", err)),
+        };
+    }
+    if let Some(roots) = env::var_os("IFSD_IMPORT_ROOTS") {
+        for root in env::split_paths(&roots) {
+            if !root.is_absolute() {
+                return Err(format!("IFSD_IMPORT_ROOTS: {} is not an absolute path", root.display()));
+            }
+            config.import_roots.push(root.to_string_lossy().into_owned());
+        }
+    }
+    Ok(config)
+}
+
+
 fn main() {
     init_logger();
 
     info!("Irbis FS server v. {}; compiled with {}", env!("CARGO_PKG_VERSION"), RUSTC_VERSION);
 
-    let config = Config::new();
-
-    args().nth(1).then(|f| Config.load(f).map_err(|err| {exit(1); err} ));
+    let config = match config_from_env(Config::new()) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        },
+    };
 
     let (port, daemonize) = (config.port, config.daemonize);
     
@@ -0,0 +1,252 @@
+//! Decoding and replaying wire captures.
+
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use protocol::checksum::Negotiated;
+use protocol::heartbeat::Control;
+use protocol::message::{Reader, ReadError, ReadFlow, WriteFrame};
+use protocol::stream::Stream;
+
+use ::proto::auth::message as auth;
+use ::proto::content::message as content;
+
+pub use protocol::capture::{CaptureReader, Direction, Record};
+
+
+/// The time to wait for each answer of the server while replaying
+const REPLAY_READ_TIMEOUT: u64 = 5;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subprotocol {
+    Auth,
+    Content,
+}
+
+
+/// Describes recorded messages, following the subprotocol switch of the connection
+#[derive(Debug)]
+pub struct Decoder {
+    subprotocol: Subprotocol,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder { subprotocol: Subprotocol::Auth }
+    }
+
+    /// The subprotocol of the next messages
+    pub fn subprotocol(&self) -> Subprotocol {
+        self.subprotocol
+    }
+
+    /// Parses the recorded message with the subprotocol of its stage
+    pub fn describe(&mut self, record: &Record) -> String {
+        let message = match record.to_message() {
+            Ok(message) => message,
+            Err(err) => return format!("Invalid frame: {}", err),
+        };
+        match Control::parse(&message) {
+            Some(Ok(control)) => return format!("{:?}", control),
+            Some(Err(err)) => return format!("Invalid heartbeat message: {:?}", err),
+            None => (),
+        }
+        match (self.subprotocol, record.direction) {
+            (Subprotocol::Auth, Direction::FromClient) => format!("{:?}", auth::ClientMessage::parse(message)),
+            (Subprotocol::Auth, Direction::FromServer) => {
+                let message = auth::ServerMessage::parse(message);
+                if let Ok(auth::ServerMessage::AuthOk(_)) = message {
+                    self.subprotocol = Subprotocol::Content;
+                }
+                format!("{:?}", message)
+            },
+            (Subprotocol::Content, Direction::FromClient) => format!("{:?}", content::ClientMessage::parse(message)),
+            (Subprotocol::Content, Direction::FromServer) => format!("{:?}", content::ServerMessage::parse(message)),
+        }
+    }
+}
+
+
+/// Returns `true` for heartbeat frames, which are not replayed
+fn is_control(record: &Record) -> bool {
+    record.to_message().ok().map_or(false, |message| Control::parse(&message).is_some())
+}
+
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::new(0, 0))
+}
+
+
+/// Sends the frames recorded from the client to the server in their order,
+/// waiting for an answer of the server in place of each frame recorded from it.
+/// With `timing`, the recorded pauses between the client frames are kept.
+/// Heartbeat frames are not replayed; the pings of the server are answered instead.
+/// Each frame sent and received is passed to `report`.
+pub fn replay(mut stream: Stream, records: &[Record], timing: bool, report: &mut FnMut(&Record))
+    -> Result<(), ReadError>
+{
+    if let Err(err) = stream.set_read_timeout(Some(Duration::from_secs(REPLAY_READ_TIMEOUT))) {
+        return Err(ReadError::ConnectionError(format!("Setting read timeout: {:?}", err)));
+    }
+
+    let mut sent_at: Option<Duration> = None;
+    for record in records.iter().filter(|record| !is_control(record)) {
+        match record.direction {
+            Direction::FromClient => {
+                if let (true, Some(previous)) = (timing, sent_at) {
+                    if record.time > previous {
+                        thread::sleep(record.time - previous);
+                    }
+                }
+                sent_at = Some(record.time);
+                if let Err(err) = stream.write_all(&record.frame) {
+                    return Err(ReadError::ConnectionError(format!("Writing to server: {:?}", err)));
+                }
+                report(&Record { direction: Direction::FromClient, time: now(), frame: record.frame.clone() });
+            },
+            Direction::FromServer => {
+                let answer = read_answer(&mut stream, report)?;
+                report(&answer);
+            },
+        }
+    }
+    Ok(())
+}
+
+
+/// Reads the next frame of the server, answering its pings on the way
+fn read_answer(stream: &mut Stream, report: &mut FnMut(&Record)) -> Result<Record, ReadError> {
+    loop {
+        // The recorded client may have negotiated a checksum
+        let mut reader = Reader::new(Negotiated::Any);
+        'read_message: loop {
+            match reader.read(stream) {
+                ReadFlow::Error(err)    => return Err(err),
+                ReadFlow::Incomplete    => continue 'read_message,
+                ReadFlow::WouldBlock    => return Err(ReadError::ConnectionError("No answer from server".to_owned())),
+                ReadFlow::Complete      => break 'read_message,
+            }
+        }
+        let record = Record { direction: Direction::FromServer, time: now(), frame: reader.frame() };
+        let message = reader.to_message().map_err(|err| ReadError::Fatal(format!("Parse error: {:?}", err)))?;
+        match Control::parse(&message) {
+            Some(Ok(control)) => {
+                report(&record);
+                if let Some(pong) = control.reply() {
+                    let mut buf = Vec::new();
+                    if pong.write_frame(&mut buf).is_ok() && stream.write_all(&buf).is_ok() {
+                        report(&Record { direction: Direction::FromClient, time: now(), frame: buf });
+                    }
+                }
+            },
+            Some(Err(err)) => return Err(ReadError::Fatal(format!("Invalid heartbeat message: {:?}", err))),
+            None => return Ok(record),
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    use unix_socket::UnixStream;
+
+    use protocol::heartbeat::{Control, MT_PONG};
+    use protocol::message::WriteFrame;
+    use protocol::stream::Stream;
+
+    use ::client::connection::Connection;
+    use ::proto::PROTOCOL_VERSION;
+    use ::proto::auth::message::{CStart, SAuthOk};
+    use ::proto::content::message::{CGetInfo, ErrorCode, SError};
+
+    use super::{Decoder, Direction, Record, Subprotocol, replay};
+
+    fn record<M: WriteFrame>(direction: Direction, message: M) -> Record {
+        let mut frame = Vec::new();
+        message.write_frame(&mut frame).unwrap();
+        Record { direction: direction, time: Duration::new(0, 0), frame: frame }
+    }
+
+    fn session() -> Vec<Record> {
+        vec![
+            record(Direction::FromClient, CStart::create(PROTOCOL_VERSION, 1, None, vec![])),
+            record(Direction::FromServer, Control::Ping(1)),
+            record(Direction::FromClient, Control::Pong(1)),
+            record(Direction::FromServer, SAuthOk::create(3, None, None)),
+            record(Direction::FromClient, CGetInfo::create(1)),
+            record(Direction::FromServer, SError::create(1, ErrorCode::Internal, "Nope".to_owned())),
+        ]
+    }
+
+    #[test]
+    fn decode_session() {
+        let mut decoder = Decoder::new();
+        let descriptions = session().iter().map(|record| decoder.describe(record)).collect::<Vec<_>>();
+        assert_eq!(decoder.subprotocol(), Subprotocol::Content);
+
+        assert!(descriptions[0].contains("Start"), "{}", descriptions[0]);
+        assert_eq!(descriptions[1], "Ping(1)");
+        assert_eq!(descriptions[2], "Pong(1)");
+        assert!(descriptions[3].contains("AuthOk"), "{}", descriptions[3]);
+        assert!(descriptions[4].contains("GetInfo"), "{}", descriptions[4]);
+        assert!(descriptions[5].contains("Nope"), "{}", descriptions[5]);
+
+        let garbage = Record { direction: Direction::FromClient, time: Duration::new(0, 0), frame: vec![1, 2] };
+        assert!(decoder.describe(&garbage).starts_with("Invalid frame"));
+    }
+
+    #[test]
+    fn replay_client_frames() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let session = session();
+        let (auth_ok, error) = (session[3].frame.clone(), session[5].frame.clone());
+
+        // The server answers the start and pings the client before answering the request
+        let server = thread::spawn(move || {
+            let mut stream = Stream::Unix(server);
+            let start = Connection::_read(&mut stream).unwrap();
+            stream.write_all(&auth_ok).unwrap();
+            let mut ping = Vec::new();
+            Control::Ping(9).write_frame(&mut ping).unwrap();
+            stream.write_all(&ping).unwrap();
+            let request = Connection::_read(&mut stream).unwrap();
+            let pong = Connection::_read(&mut stream).unwrap();
+            stream.write_all(&error).unwrap();
+            (start.mtype, request.mtype, pong.mtype)
+        });
+
+        let mut replayed = Vec::new();
+        replay(Stream::Unix(client), &session, false, &mut |record| replayed.push(record.clone())).unwrap();
+        let (start, request, pong) = server.join().unwrap();
+        assert_eq!(start, session[0].to_message().unwrap().mtype);
+        assert_eq!(request, session[4].to_message().unwrap().mtype);
+        assert_eq!(pong, MT_PONG);
+
+        let mut decoder = Decoder::new();
+        let descriptions = replayed.iter()
+            .map(|record| (record.direction, decoder.describe(record)))
+            .collect::<Vec<_>>();
+        assert_eq!(descriptions.len(), 6);
+        assert_eq!(descriptions[1].0, Direction::FromServer);
+        assert!(descriptions[1].1.contains("AuthOk"));
+        assert_eq!(descriptions[2].0, Direction::FromClient);
+        assert!(descriptions[2].1.contains("GetInfo"));
+        assert_eq!(descriptions[3], (Direction::FromServer, "Ping(9)".to_owned()));
+        assert_eq!(descriptions[4], (Direction::FromClient, "Pong(9)".to_owned()));
+        assert!(descriptions[5].1.contains("Nope"));
+    }
+}
@@ -1,4 +1,15 @@
+use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use protocol::capture::Capture;
+pub use protocol::checksum::Checksum;
+use protocol::heartbeat::Heartbeat;
+use protocol::tls;
+use protocol::tls::ClientConfig;
+
+use ::proto::auth::client::AuthConfig;
 
 
 #[derive(Clone, Debug)]
@@ -8,11 +19,39 @@ pub enum Target {
 }
 
 
+/// TLS settings for TCP connections
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// The CA certificates the server certificate must be signed by
+    pub ca: String,
+    /// The name the server certificate must be issued for
+    pub server_name: String,
+    /// The certificate and the key files to authenticate the client with
+    pub client_cert: Option<(String, String)>,
+}
+
+
 pub struct Config {
     pub tcp_keepalive: u32,
     pub tcp_backlog: i32,
     pub timeout: u64,
     pub target: Target,
+    /// Use TLS for TCP connections
+    pub tls: Option<TlsConfig>,
+    /// Ping the server each N seconds, 0 to disable
+    pub heartbeat_interval: u64,
+    /// Close the connection when so many pings are left without an answer
+    pub heartbeat_misses: u32,
+    /// Request checksums of all frames after the start
+    pub frame_checksum: Option<Checksum>,
+    /// Record the frames of the connection into this capture file
+    pub capture: Option<String>,
+    /// The login and the password, if the server requires them
+    pub login: Option<(String, String)>,
+    /// The login and the key proven to the server instead of sending a password
+    pub key: Option<(String, Vec<u8>)>,
+    /// A token minted by the server, sent instead of a password
+    pub token: Option<String>,
 }
 
 //#[derive(Debug)]
@@ -27,17 +66,69 @@ pub struct Config {
 // --------------------------------------------------------------------------------------------------------------------
 
 
+impl TlsConfig {
+    pub fn new(ca: String, server_name: String) -> TlsConfig {
+        TlsConfig {
+            ca: ca,
+            server_name: server_name,
+            client_cert: None,
+        }
+    }
+
+    pub fn load(&self) -> io::Result<Arc<ClientConfig>> {
+        let client_cert = self.client_cert.as_ref().map(|&(ref cert, ref key)| (&cert[..], &key[..]));
+        tls::client_config(&self.ca, client_cert)
+    }
+}
+
+
 impl Config {
     pub fn default(target: Target) -> Config {
         Config {
             tcp_keepalive: 0,
             tcp_backlog: 511,
             timeout: 0,
-            target: target
+            target: target,
+            tls: None,
+            heartbeat_interval: 0,
+            heartbeat_misses: 3,
+            frame_checksum: None,
+            capture: None,
+            login: None,
+            key: None,
+            token: None,
         }
     }
 
     pub fn new(target: Target) -> Config {
         Self::default(target)
     }
+
+    pub fn heartbeat(&self) -> Option<Heartbeat> {
+        match self.heartbeat_interval {
+            0 => None,
+            secs => Some(Heartbeat::new(Duration::from_secs(secs), self.heartbeat_misses)),
+        }
+    }
+
+    /// Creates the capture file, if recording is enabled.
+    /// The connection is not recorded if the file can not be created.
+    pub fn capture(&self) -> Option<Arc<Capture>> {
+        self.capture.as_ref().and_then(|path| match Capture::client(path) {
+            Ok(capture) => Some(Arc::new(capture)),
+            Err(err) => {
+                warn!("Creating capture file {}: {:?}", path, err);
+                None
+            },
+        })
+    }
+
+    pub fn auth(&self) -> AuthConfig {
+        let mut config = AuthConfig::new();
+        config.checksum = self.frame_checksum;
+        config.login = self.login.clone();
+        config.key = self.key.clone();
+        config.token = self.token.clone();
+        config
+    }
 }
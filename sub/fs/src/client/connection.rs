@@ -1,15 +1,23 @@
-use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Receiver, channel};
 use std::thread;
 
+use protocol::capture::Capture;
+use protocol::checksum::{Checksum, Negotiated};
+use protocol::heartbeat::Heartbeat;
 use protocol::stream::Stream;
-use protocol::message::{Message, RawMessage, Reader, ReadError, ReadFlow};
+use protocol::message::{RawMessage, Reader, ReadError, ReadFlow};
 
 use ::connection::{StreamSender, StreamMessage};
-use ::proto::auth::client::{AuthProtocol, AuthError};
+use ::client::config::Config;
+use ::proto::admin;
+use ::proto::admin::client::AdminInterface;
+use ::proto::auth::client::{AuthConfig, AuthProtocol, AuthError};
+use ::proto::auth::message::SessionTicket;
 use ::proto::content::client::{ContentProtocol, ContentInterface};
 
+use super::eventloop::ConnectError;
+
 
 #[cfg(feature = "dev")]
 static mut depth: u32 = 0;
@@ -19,28 +27,72 @@ static mut depth: u32 = 0;
 pub struct Connection {
     pub stream: Stream,
     writer_tx: StreamSender,
+    /// Pings sent by the writer thread
+    heartbeat: Option<Arc<Mutex<Heartbeat>>>,
+    /// Records the frames of the connection
+    capture: Option<Arc<Capture>>,
+}
+
+
+/// The reading side of a connection, to be moved to a reader thread
+pub struct ConnectionReader {
+    stream: Stream,
+    writer_tx: StreamSender,
+    heartbeat: Option<Arc<Mutex<Heartbeat>>>,
+    capture: Option<Arc<Capture>>,
 }
 
 
 impl Connection  {
-    pub fn connect(stream: Stream) -> Result<ContentInterface, AuthError> {
-        #![allow(unused_must_use)]
+    /// Wraps the stream and starts the writer thread
+    pub fn new(stream: Stream) -> Connection {
+        Self::with_options(stream, None, None)
+    }
+
+    /// Wraps the stream and starts the writer thread, which also pings the server.
+    /// All frames are recorded to the capture, if any.
+    pub fn with_options(stream: Stream, heartbeat: Option<Heartbeat>, capture: Option<Arc<Capture>>) -> Connection {
         let (tx, rx) = channel::<StreamMessage>();
 
         let connection = Connection {
             stream: stream,
-            writer_tx: Arc::new(Mutex::new(tx)),
+            writer_tx: StreamSender::new(tx),
+            heartbeat: heartbeat.map(|heartbeat| Arc::new(Mutex::new(heartbeat))),
+            capture: capture,
         };
 
         connection.create_writer_thread(rx);
+        connection
+    }
 
-        let mut protocol = AuthProtocol::new(connection);
-        match protocol.auth() {
-            Ok(client_id) => {
-                Ok(ContentInterface::new(Arc::new(ContentProtocol::new(protocol.connection, client_id))))
-            },
-            Err(err) => Err(err)
-        }
+    pub fn connect(stream: Stream, config: &Config) -> Result<ContentInterface, AuthError> {
+        let (connection, client_id, ticket) = Self::authenticate(stream, config, config.auth())?;
+        Ok(ContentInterface::new(Arc::new(ContentProtocol::with_ticket(connection, client_id, ticket))))
+    }
+
+    /// Connects and takes over the tasks of the interface whose connection is lost
+    pub fn resume(stream: Stream, config: &Config, lost: &ContentInterface) -> Result<ContentInterface, ConnectError> {
+        let (connection, client_id, ticket) = Self::authenticate(stream, config, config.auth())
+            .map_err(ConnectError::AuthError)?;
+        lost.resume(connection, client_id, ticket).map_err(ConnectError::NotResumed)
+    }
+
+    /// Connects to the admin subprotocol of the server
+    pub fn connect_admin(stream: Stream, config: &Config) -> Result<AdminInterface, AuthError> {
+        let mut auth = config.auth();
+        auth.subprotocol = admin::message::SUBPROTOCOL_CODE;
+        let (connection, client_id, _) = Self::authenticate(stream, config, auth)?;
+        Ok(AdminInterface::new(connection, client_id))
+    }
+
+    fn authenticate(stream: Stream, config: &Config, auth: AuthConfig)
+        -> Result<(Connection, usize, Option<SessionTicket>), AuthError>
+    {
+        let connection = Connection::with_options(stream, config.heartbeat(), config.capture());
+        let mut protocol = AuthProtocol::with_config(connection, auth);
+        let client_id = protocol.auth()?;
+        let ticket = protocol.ticket();
+        Ok((protocol.connection, client_id, ticket))
     }
 
     pub fn sender(&self) -> StreamSender {
@@ -51,53 +103,98 @@ impl Connection  {
         ::connection::send_message(&self.writer_tx, message);
     }
 
+    /// Adds the checksum to all frames sent from now on, and requires it on the frames read
+    pub fn set_checksum(&self, checksum: Option<Checksum>) {
+        self.writer_tx.checksum().set(checksum);
+    }
+
+    /// Accepts the frames with the requested checksum until the server answers, as the answer may carry it
+    pub fn request_checksum(&self, checksum: Option<Checksum>) {
+        self.writer_tx.checksum().request(checksum);
+    }
+
     /// Creates a thread that writes into the server stream each message received
-    fn create_writer_thread(&self, rx: Receiver<Option<Message>>) {
+    fn create_writer_thread(&self, rx: Receiver<StreamMessage>) {
         let mut stream = self.stream.try_clone().unwrap();
+        let checksum = self.writer_tx.checksum().clone();
+        let traffic = self.writer_tx.traffic().clone();
+        let heartbeat = self.heartbeat.clone();
+        let capture = self.capture.clone();
         thread::spawn(move || {
-            loop {
-                match rx.recv() {
-                    Ok(m) => match m {
-                        Some(msg) => {
-//                            trace!(" * before write {:?}", msg);
-                            match stream.write(&*msg.as_bytes()) {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    warn!("Error writing to server: {:?}", e);
-                                    break;
-                                },
-                            }
-                        },
-                        None => break,
-                    },
-                    Err(_) => break,
-                };
-            }
+            ::connection::write_messages(&mut stream, rx, checksum, traffic, heartbeat, capture);
 //            stream.shutdown();
         });
     }
 
+    /// A reader of the server stream, which shares the heartbeat and the capture of the connection
+    pub fn reader(&self) -> ConnectionReader {
+        ConnectionReader {
+            stream: self.stream.try_clone().unwrap(),
+            writer_tx: self.writer_tx.clone(),
+            heartbeat: self.heartbeat.clone(),
+            capture: self.capture.clone(),
+        }
+    }
+
+    /// Reads the next message of the subprotocol, answering heartbeat messages on the way
     #[cfg_attr(feature = "dev", trace)]
     pub fn read(&self) -> Result<RawMessage, ReadError> {
-        let mut stream = self.stream.try_clone().unwrap();
-        Self::_read(&mut stream)
+        self.reader().read(&|| false)
     }
 
 
     #[cfg_attr(feature = "dev", trace)]
     pub fn _read(stream: &mut Stream) -> Result<RawMessage, ReadError> {
-        let mut reader = Reader::new();
+        Self::read_message(stream, &|| false, None, Negotiated::Any)
+    }
+
+    /// Reads a message with one of the negotiated checksums. Read timeouts are ignored while `wait` holds.
+    fn read_message(stream: &mut Stream, wait: &Fn() -> bool, capture: Option<&Capture>, negotiated: Negotiated)
+        -> Result<RawMessage, ReadError>
+    {
+        let mut reader = Reader::new(negotiated);
         'read_message: loop {
             match reader.read(stream) {
                 ReadFlow::Error(err)    => return Err(err),
                 ReadFlow::Incomplete    => continue 'read_message,
+                ReadFlow::WouldBlock if wait() => continue 'read_message,
+                ReadFlow::WouldBlock    => return Err(ReadError::ConnectionError("Read timed out".to_owned())),
                 ReadFlow::Complete      => match reader.to_message() {
-                    Ok(message) => return Ok(message),
+                    Ok(message) => {
+                        if let Some(capture) = capture {
+                            capture.received(&reader.frame());
+                        }
+                        return Ok(message)
+                    },
                     Err(err)    => {
-                        return Err(ReadError::Fatal(format!("Parse error: {:?}", err)))
+                        return Err(ReadError::NonFatal(format!("Parse error: {:?}", err)))
                     }
                 },
             };
         }
     }
 }
+
+
+impl ConnectionReader {
+    /// Reads the next message of the subprotocol, answering heartbeat messages on the way.
+    /// Read timeouts are ignored while `idle` holds, as nothing is expected from the server then.
+    pub fn read(&mut self, idle: &Fn() -> bool) -> Result<RawMessage, ReadError> {
+        let heartbeat = self.heartbeat.as_ref().map(|heartbeat| &**heartbeat);
+        let capture = self.capture.as_ref().map(|capture| &**capture);
+        // With a heartbeat, a dead server is detected by the writer, which shuts the stream down
+        let wait = || heartbeat.is_some() || idle();
+        loop {
+            let negotiated = self.writer_tx.checksum().negotiated();
+            let message = try!(Connection::read_message(&mut self.stream, &wait, capture, negotiated));
+            if let Some(message) = try!(::connection::handle_control(&self.writer_tx, heartbeat, message)) {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Closes the connection, as the reader stops
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown();
+    }
+}
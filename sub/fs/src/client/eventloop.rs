@@ -7,7 +7,9 @@ use std::net::{SocketAddr};
 use std::sync::{Arc, Mutex};
 
 use protocol::stream::Stream;
+use protocol::tls::TlsStream;
 
+use ::proto::admin::client::AdminInterface;
 use ::proto::auth::client::AuthError;
 use ::proto::content::client::ContentInterface;
 
@@ -19,9 +21,12 @@ use super::connection::{Connection};
 #[derive(Debug)]
 pub enum ConnectError {
     TCP(io::Error),
+    TLS(io::Error),
     Unixsocket(io::Error),
     Unsupported,
-    AuthError(AuthError)
+    AuthError(AuthError),
+    /// The session of a lost connection can't be resumed, for the given reason
+    NotResumed(String),
 }
 
 
@@ -50,6 +55,28 @@ impl Client {
 
     /// Starts thread communication with server.
     pub fn connect(&mut self) -> Result<ContentInterface, ConnectError> {
+        let stream = self.open_stream()?;
+        match Connection::connect(stream, &self.config) {
+            Ok(protocol) => Ok(protocol),
+            Err(error) => Err(ConnectError::AuthError(error))
+        }
+    }
+
+    /// Connects to the admin subprotocol of the server
+    pub fn connect_admin(&mut self) -> Result<AdminInterface, ConnectError> {
+        let stream = self.open_stream()?;
+        Connection::connect_admin(stream, &self.config).map_err(ConnectError::AuthError)
+    }
+
+    /// Reconnects to the server and takes over the tasks of the interface whose connection is lost.
+    /// The server keeps the session of a lost connection for a grace period only.
+    pub fn resume(&mut self, lost: &ContentInterface) -> Result<ContentInterface, ConnectError> {
+        let stream = self.open_stream()?;
+        Connection::resume(stream, &self.config, lost)
+    }
+
+    /// Opens a connection to the server without starting the protocol
+    pub fn open_stream(&mut self) -> Result<Stream, ConnectError> {
         match self.config.target.clone() {
             Target::Unix(unixsocket) => self.connect_unixsocket(unixsocket),
             Target::Tcp(address) => self.connect_tcp(address),
@@ -68,7 +95,7 @@ impl Client {
 //    }
 
     /// Connects to a socket address.
-    fn connect_tcp(&mut self, addr: SocketAddr) -> Result<ContentInterface, ConnectError> {
+    fn connect_tcp(&mut self, addr: SocketAddr) -> Result<Stream, ConnectError> {
         let (tcp_keepalive, timeout) = {
             let c = &self.config;
             (c.tcp_keepalive, c.timeout)
@@ -100,27 +127,33 @@ impl Client {
             else { None }
         ).unwrap();
 
-        match Connection::connect(Stream::Tcp(stream)) {
-            Ok(protocol) => Ok(protocol),
-            Err(error) => Err(ConnectError::AuthError(error))
+        match self.config.tls {
+            Some(ref tls) => match tls.load() {
+                Ok(tls_config) => Ok(Stream::Tls(TlsStream::client(stream, &tls_config, &tls.server_name))),
+                Err(err) => Err(ConnectError::TLS(err)),
+            },
+            None => Ok(Stream::Tcp(stream)),
         }
     }
 
     #[cfg(unix)]
-    fn connect_unixsocket(&mut self, unixsocket: String) -> Result<ContentInterface, ConnectError> {
+    fn connect_unixsocket(&mut self, unixsocket: String) -> Result<Stream, ConnectError> {
+        let timeout = self.config.timeout;
+
         let stream = match UnixStream::connect(unixsocket) {
-            Ok(s) => s,
+            Ok(s) => Stream::Unix(s),
             Err(err) => return Err(ConnectError::Unixsocket(err))
         };
 
-        match Connection::connect(Stream::Unix(stream)) {
-            Ok(protocol) => Ok(protocol),
-            Err(error) => Err(ConnectError::AuthError(error))
+        let timeout = if timeout > 0 { Some(Duration::new(timeout, 0)) } else { None };
+        if let Err(err) = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)) {
+            return Err(ConnectError::Unixsocket(err));
         }
+        Ok(stream)
     }
 
     #[cfg(not(unix))]
-    fn connect_unixsocket(&mut self, unixsocket: String) -> Result<ContentInterface, ConnectError> {
+    fn connect_unixsocket(&mut self, unixsocket: String) -> Result<Stream, ConnectError> {
         Err(ConnectError::Unsupported)
     }
 
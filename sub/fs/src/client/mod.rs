@@ -2,4 +2,4 @@ pub mod config;
 pub mod connection;
 mod eventloop;
 
-pub use self::eventloop::{Client};
+pub use self::eventloop::{Client, ConnectError};
@@ -1,16 +1,357 @@
+use std::fmt;
+use std::io::Write;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Instant;
 
-use protocol::message::Message;
+use protocol::capture::Capture;
+use protocol::checksum::{Checksum, Negotiated, seal_frame};
+use protocol::heartbeat::{Control, Heartbeat};
+use protocol::message::{EncodeError, RawMessage, ReadError, WriteFrame};
+use protocol::stream::Stream;
 
 
-pub type StreamMessage = Option<Message>;
-pub type StreamSender = Arc<Mutex<Sender<StreamMessage>>>;
+pub type StreamMessage = Option<Box<WriteFrame + Send>>;
+
+/// Wakes up the writer of a connection queue
+pub type Notify = Arc<Fn() + Send + Sync>;
+
+
+/// The sending half of the queue of outgoing messages of a connection
+#[derive(Clone)]
+pub struct StreamSender {
+    tx: Arc<Mutex<Sender<StreamMessage>>>,
+    /// Called after each message, if the writer does not block on the queue itself
+    notify: Option<Notify>,
+    checksum: FrameChecksum,
+    traffic: Traffic,
+}
+
+
+/// The checksum added to outgoing frames, shared by the senders, the writer and the reader of a connection.
+/// It is switched on once negotiated; frames carry their checksum code, so the switch
+/// needs no synchronization with the messages already queued.
+/// The reader accepts only the frames with the negotiated checksum.
+#[derive(Clone, Debug, Default)]
+pub struct FrameChecksum {
+    agreed: Arc<AtomicUsize>,
+    /// The checksum requested on start, until the answer
+    requested: Arc<AtomicUsize>,
+}
+
+
+/// Counts the bytes of the frames read and written on a connection
+#[derive(Clone, Debug, Default)]
+pub struct Traffic {
+    read: Arc<AtomicUsize>,
+    written: Arc<AtomicUsize>,
+}
+
+
+/// The initial capacity of a writer's frame buffer
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl StreamSender {
+    pub fn new(tx: Sender<StreamMessage>) -> StreamSender {
+        StreamSender {
+            tx: Arc::new(Mutex::new(tx)),
+            notify: None,
+            checksum: FrameChecksum::default(),
+            traffic: Traffic::default(),
+        }
+    }
+
+    pub fn with_notify(tx: Sender<StreamMessage>, notify: Notify) -> StreamSender {
+        StreamSender {
+            tx: Arc::new(Mutex::new(tx)),
+            notify: Some(notify),
+            checksum: FrameChecksum::default(),
+            traffic: Traffic::default(),
+        }
+    }
+
+    /// The checksum of the frames written for this queue
+    pub fn checksum(&self) -> &FrameChecksum {
+        &self.checksum
+    }
+
+    /// The traffic of the connection of this queue
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Queues the message; returns `false` if the connection is already closed
+    pub fn send(&self, message: StreamMessage) -> bool {
+        let sent = self.tx.lock().unwrap().send(message).is_ok();
+        if let Some(ref notify) = self.notify {
+            notify();
+        }
+        sent
+    }
+}
+
+
+impl fmt::Debug for StreamSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StreamSender {{ notify: {}, checksum: {:?} }}", self.notify.is_some(), self.checksum.get())
+    }
+}
+
+
+impl FrameChecksum {
+    pub fn get(&self) -> Option<Checksum> {
+        Checksum::from_code(self.agreed.load(Ordering::SeqCst) as u8)
+    }
+
+    /// Switches to the agreed checksum, ending the request if any
+    pub fn set(&self, checksum: Option<Checksum>) {
+        self.agreed.store(checksum.map_or(0, |checksum| checksum.code() as usize), Ordering::SeqCst);
+        self.requested.store(0, Ordering::SeqCst);
+    }
+
+    /// Accepts the requested checksum on read until the answer, which may carry it or not
+    pub fn request(&self, checksum: Option<Checksum>) {
+        self.requested.store(checksum.map_or(0, |checksum| checksum.code() as usize), Ordering::SeqCst);
+    }
+
+    /// The checksums accepted on the frames read now
+    pub fn negotiated(&self) -> Negotiated {
+        match Checksum::from_code(self.requested.load(Ordering::SeqCst) as u8) {
+            Some(requested) => Negotiated::Requested(requested),
+            None => Negotiated::Agreed(self.get()),
+        }
+    }
+
+    /// Frames the message at the end of the buffer, followed by the checksum if it is set
+    pub fn write_frame(&self, message: &WriteFrame, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let start = buf.len();
+        try!(message.write_frame(buf));
+        if let Some(checksum) = self.get() {
+            seal_frame(buf, start, checksum);
+        }
+        Ok(())
+    }
+}
+
+
+impl Traffic {
+    pub fn read(&self) -> usize {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub fn add_read(&self, bytes: usize) {
+        self.read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_written(&self, bytes: usize) {
+        self.written.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
 
 
 pub fn send_message(tx: &StreamSender, message: StreamMessage) {
-    let locked = tx.lock().unwrap();
-    locked.send(message).unwrap();
+    if !tx.send(message) {
+        debug!("Message is dropped: the connection is closed");
+    }
+}
+
+
+/// Writes each received message into the stream, framing all of them in the same buffer.
+/// With a heartbeat, pings are sent in between when they are due,
+/// and the stream is shut down once the peer stops answering them.
+/// With a capture, each frame is recorded before it is written.
+/// The written bytes are counted in `traffic`.
+/// Returns when the channel is closed, on `None` or on a write error.
+pub fn write_messages(stream: &mut Stream, rx: Receiver<StreamMessage>, checksum: FrameChecksum, traffic: Traffic,
+                      heartbeat: Option<Arc<Mutex<Heartbeat>>>, capture: Option<Arc<Capture>>)
+{
+    let mut buf: Vec<u8> = Vec::with_capacity(WRITE_BUFFER_SIZE);
+    loop {
+        let message: Box<WriteFrame + Send> = match heartbeat {
+            None => match rx.recv() {
+                Ok(Some(message)) => message,
+                Ok(None) | Err(_) => break,
+            },
+            Some(ref heartbeat) => {
+                let poll = heartbeat.lock().unwrap().poll(Instant::now());
+                match poll {
+                    Ok(Some(ping)) => Box::new(ping),
+                    Ok(None) => {
+                        let timeout = heartbeat.lock().unwrap().timeout(Instant::now());
+                        match rx.recv_timeout(timeout) {
+                            Ok(Some(message)) => message,
+                            Ok(None) | Err(RecvTimeoutError::Disconnected) => break,
+                            Err(RecvTimeoutError::Timeout) => continue,
+                        }
+                    },
+                    Err(missed) => {
+                        warn!("The peer has not answered {} pings, closing the connection", missed);
+                        let _ = stream.shutdown();
+                        break;
+                    },
+                }
+            },
+        };
+        buf.clear();
+        if let Err(err) = checksum.write_frame(&*message, &mut buf) {
+            warn!("Error encoding message {:?}: {:?}", message, err);
+            continue;
+        }
+        if let Some(ref capture) = capture {
+            capture.sent(&buf);
+        }
+        if let Err(err) = stream.write_all(&buf) {
+            warn!("Error writing to peer: {:?}", err);
+            break;
+        }
+        traffic.add_written(buf.len());
+    }
+}
+
+
+/// Answers a heartbeat message. Returns the message back if it belongs to the subprotocol.
+pub fn handle_control(sender: &StreamSender, heartbeat: Option<&Mutex<Heartbeat>>, message: RawMessage)
+    -> Result<Option<RawMessage>, ReadError>
+{
+    let control = match Control::parse(&message) {
+        None => return Ok(Some(message)),
+        Some(Ok(control)) => control,
+        Some(Err(err)) => return Err(ReadError::NonFatal(format!("Invalid heartbeat message: {:?}", err))),
+    };
+    trace!("  >>  {:?}", control);
+    match control {
+        Control::Ping(_) => if let Some(pong) = control.reply() {
+            send_message(sender, Some(Box::new(pong)));
+        },
+        Control::Pong(seq) => if let Some(heartbeat) = heartbeat {
+            heartbeat.lock().unwrap().on_pong(seq);
+        },
+    }
+    Ok(None)
 }
 
 
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use unix_socket::UnixStream;
+
+    use protocol::heartbeat::{Control, Heartbeat, MT_PING};
+    use protocol::message::{RawMessage, RawMessageBody, WriteFrame};
+    use protocol::stream::Stream;
+
+    use protocol::checksum::{Checksum, Negotiated, seal_frame};
+
+    use super::{FrameChecksum, StreamMessage, StreamSender, Traffic, handle_control, write_messages};
+
+    fn raw(control: Control) -> RawMessage {
+        let mut buf = Vec::new();
+        control.write_frame(&mut buf).unwrap();
+        RawMessage::new(buf[2], RawMessageBody::Binary(buf[8..].to_vec()))
+    }
+
+    #[test]
+    fn answer_ping() {
+        let (tx, rx) = channel::<StreamMessage>();
+        let sender = StreamSender::new(tx);
+        assert!(handle_control(&sender, None, raw(Control::Ping(7))).unwrap().is_none());
+
+        let mut expected = Vec::new();
+        Control::Pong(7).write_frame(&mut expected).unwrap();
+        let mut buf = Vec::new();
+        rx.try_recv().unwrap().unwrap().write_frame(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn register_pong() {
+        let (tx, _rx) = channel::<StreamMessage>();
+        let sender = StreamSender::new(tx);
+        let heartbeat = Mutex::new(Heartbeat::new(Duration::from_millis(1), 3));
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(heartbeat.lock().unwrap().poll(Instant::now()), Ok(Some(Control::Ping(1))));
+
+        assert!(handle_control(&sender, Some(&heartbeat), raw(Control::Pong(1))).unwrap().is_none());
+        assert_eq!(heartbeat.lock().unwrap().missed(), 0);
+    }
+
+    #[test]
+    fn pass_through() {
+        let (tx, _rx) = channel::<StreamMessage>();
+        let sender = StreamSender::new(tx);
+        let message = RawMessage::new(1, RawMessageBody::Binary(vec![1, 2, 3]));
+        assert_eq!(handle_control(&sender, None, message).unwrap().unwrap().mtype, 1);
+        assert!(handle_control(&sender, None, RawMessage::new(MT_PING, RawMessageBody::Binary(vec![]))).is_err());
+    }
+
+    #[test]
+    fn writer_closes_dead_peer() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let (_tx, rx) = channel::<StreamMessage>();
+        let heartbeat = Arc::new(Mutex::new(Heartbeat::new(Duration::from_millis(10), 2)));
+        let writer = thread::spawn(move || write_messages(&mut Stream::Unix(a), rx, FrameChecksum::default(),
+                                                          Traffic::default(), Some(heartbeat), None));
+
+        // Two pings are left without an answer, then the writer shuts the stream down
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+
+        let mut expected = Vec::new();
+        Control::Ping(1).write_frame(&mut expected).unwrap();
+        Control::Ping(2).write_frame(&mut expected).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn writer_seals_frames() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let (tx, rx) = channel::<StreamMessage>();
+        let sender = StreamSender::new(tx);
+        let checksum = sender.checksum().clone();
+        let traffic = Traffic::default();
+        let counted = traffic.clone();
+        let writer = thread::spawn(move || write_messages(&mut Stream::Unix(a), rx, checksum, counted, None, None));
+
+        let mut plain = Vec::new();
+        Control::Ping(1).write_frame(&mut plain).unwrap();
+        sender.send(Some(Box::new(Control::Ping(1))));
+        let mut received = vec![0u8; plain.len()];
+        b.read_exact(&mut received).unwrap();
+        assert_eq!(received, plain);
+
+        sender.checksum().set(Some(Checksum::Crc32c));
+        assert_eq!(sender.checksum().get(), Some(Checksum::Crc32c));
+        sender.send(Some(Box::new(Control::Ping(2))));
+        sender.send(None);
+        writer.join().unwrap();
+
+        let mut sealed = Vec::new();
+        Control::Ping(2).write_frame(&mut sealed).unwrap();
+        seal_frame(&mut sealed, 0, Checksum::Crc32c);
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        assert_eq!(received, sealed);
+        assert_eq!(traffic.written(), plain.len() + sealed.len());
+    }
+}
@@ -6,6 +6,7 @@
 #![cfg_attr(feature = "trace", feature(custom_attribute, plugin))]
 #![cfg_attr(feature = "trace", plugin(trace))]
 
+#![feature(proc_macro)]
 #![feature(question_mark)]
 
 #[cfg(all(feature = "bench", test))]
@@ -14,22 +15,29 @@ extern crate test;
 extern crate blake2_rfc;
 #[cfg(unix)] extern crate libc;
 #[macro_use] extern crate log;
+#[cfg(unix)] extern crate mio;
 extern crate net2;
 #[cfg(unix)] extern crate nix;
+extern crate rand;
 #[macro_use] extern crate slice_as_array;
 #[cfg(unix)] extern crate unix_socket;
 extern crate uuid;
 
 extern crate compat;
 extern crate protocol;
+#[macro_use] extern crate protocol_derive;
 
 
+pub mod capture;
 pub mod client;
 pub mod connection;
 pub mod proto;
 pub mod server;
 pub mod types;
 
+#[cfg(test)]
+pub mod testing;
+
 
 
 #[cfg(test)]
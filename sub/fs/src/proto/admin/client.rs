@@ -0,0 +1,136 @@
+use std::cell::Cell;
+
+use protocol::workflow::WorkflowError;
+
+use ::client::connection::Connection;
+use ::server::permissions::Permissions;
+use ::types::TaskId;
+
+use super::message::{ClientMessage, ServerMessage, ConnectionInfo, TaskInfo};
+use super::message::{CCancelTask, CKillConnection, CListConnections, CListTasks, CMaintenance, CShutdown};
+use super::message::{CMintToken, CRevokeToken};
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[derive(Debug)]
+pub enum AdminError {
+    /// The server has refused the request
+    Rejected(String),
+    /// The request has failed on the server
+    Failed(String),
+    WorkflowError(WorkflowError),
+}
+
+
+/// Sends admin requests one by one and waits for their answers
+#[derive(Debug)]
+pub struct AdminInterface {
+    pub connection: Connection,
+    pub client_id: usize,
+    next_id: Cell<TaskId>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl AdminInterface {
+    pub fn new(connection: Connection, client_id: usize) -> AdminInterface {
+        AdminInterface {
+            connection: connection,
+            client_id: client_id,
+            next_id: Cell::new(0),
+        }
+    }
+
+    pub fn connections(&self) -> Result<Vec<ConnectionInfo>, AdminError> {
+        match self.request(CListConnections::create)? {
+            ServerMessage::Connections(m) => Ok(m.connections),
+            m => Err(unexpected(m)),
+        }
+    }
+
+    pub fn kill_connection(&self, connection: usize) -> Result<String, AdminError> {
+        self.request_done(|task_id| CKillConnection::create(task_id, connection))
+    }
+
+    pub fn tasks(&self) -> Result<Vec<TaskInfo>, AdminError> {
+        match self.request(CListTasks::create)? {
+            ServerMessage::Tasks(m) => Ok(m.tasks),
+            m => Err(unexpected(m)),
+        }
+    }
+
+    pub fn cancel_task(&self, connection: usize, task_id: TaskId) -> Result<String, AdminError> {
+        self.request_done(|id| CCancelTask::create(id, connection, task_id))
+    }
+
+    /// Runs a maintenance job and waits until it is done
+    pub fn maintenance(&self, job: &str) -> Result<String, AdminError> {
+        self.request_done(|task_id| CMaintenance::create(task_id, job.to_owned()))
+    }
+
+    /// Asks the server to stop once the running tasks are finished
+    pub fn shutdown(&self) -> Result<String, AdminError> {
+        self.request_done(CShutdown::create)
+    }
+
+    /// Mints a token for the identity living `ttl` seconds; returns its id and the token
+    pub fn mint_token(&self, identity: &str, scopes: Permissions, ttl: u64) -> Result<(String, String), AdminError> {
+        let message = self.request(|task_id| CMintToken::create(task_id, identity.to_owned(), scopes, ttl))?;
+        match message {
+            ServerMessage::Token(m) => Ok((m.id, m.token)),
+            m => Err(unexpected(m)),
+        }
+    }
+
+    pub fn revoke_token(&self, id: &str) -> Result<String, AdminError> {
+        self.request_done(|task_id| CRevokeToken::create(task_id, id.to_owned()))
+    }
+
+    fn request_done<F: FnOnce(TaskId) -> ClientMessage>(&self, create: F) -> Result<String, AdminError> {
+        match self.request(create)? {
+            ServerMessage::Done(m) => Ok(m.message),
+            m => Err(unexpected(m)),
+        }
+    }
+
+    /// Sends the request and reads messages until its answer
+    fn request<F: FnOnce(TaskId) -> ClientMessage>(&self, create: F) -> Result<ServerMessage, AdminError> {
+        let task_id = self.next_id.get();
+        self.next_id.set(task_id + 1);
+        let message = create(task_id);
+        info!("  >>  {:?}", message);
+        self.connection.send_message(Some(Box::new(message)));
+
+        loop {
+            let raw = self.connection.read().map_err(|_| AdminError::WorkflowError(WorkflowError::ConnectionError))?;
+            let message = ServerMessage::parse(raw)
+                .map_err(|err| AdminError::WorkflowError(WorkflowError::Exception(format!("{:?}", err))))?;
+            info!("  <<  {:?}", message);
+            if message.get_task_id() != task_id {
+                warn!("Ignored an answer to another request: {:?}", message);
+                continue;
+            }
+            return match message {
+                ServerMessage::Reject(m) => Err(AdminError::Rejected(m.reason)),
+                ServerMessage::Error(m) => Err(AdminError::Failed(m.message)),
+                m => Ok(m),
+            };
+        }
+    }
+}
+
+
+fn unexpected(message: ServerMessage) -> AdminError {
+    AdminError::WorkflowError(WorkflowError::ProtocolError(format!("Unexpected answer {:?}", message)))
+}
+
+
+impl Drop for AdminInterface {
+    fn drop(&mut self) {
+        let _ = self.connection.stream.shutdown();
+    }
+}
@@ -0,0 +1,379 @@
+use std::fmt;
+use std::str::Utf8Error;
+
+use protocol::serde::ParserError;
+
+use ::server::permissions::Permissions;
+use ::types::TaskId;
+
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// Message with unknown code
+    UnknownCode,
+    ParseError(String),
+    BadProtocol,
+}
+
+
+#[derive(Debug, Dispatch)]
+pub enum ClientMessage {
+    #[code = "MC_LIST_CONNECTIONS"] ListConnections(CListConnections),
+    #[code = "MC_KILL_CONNECTION"]  KillConnection(CKillConnection),
+    #[code = "MC_LIST_TASKS"]       ListTasks(CListTasks),
+    #[code = "MC_CANCEL_TASK"]      CancelTask(CCancelTask),
+    #[code = "MC_MAINTENANCE"]      Maintenance(CMaintenance),
+    #[code = "MC_SHUTDOWN"]         Shutdown(CShutdown),
+    #[code = "MC_MINT_TOKEN"]       MintToken(CMintToken),
+    #[code = "MC_REVOKE_TOKEN"]     RevokeToken(CRevokeToken),
+}
+
+
+#[derive(Debug, Dispatch)]
+pub enum ServerMessage {
+    #[code = "MS_CONNECTIONS"]      Connections(SConnections),
+    #[code = "MS_TASKS"]            Tasks(STasks),
+    #[code = "MS_DONE"]             Done(SDone),
+    #[code = "MS_TOKEN"]            Token(SToken),
+    #[code = "MS_REJECT"]           Reject(SReject),
+    #[code = "MS_ERROR"]            Error(SError),
+}
+
+// --------------------------------------------------------------------------------------------------------------------
+
+pub const SUBPROTOCOL_CODE: u8 = 2;
+
+pub const MC_LIST_CONNECTIONS: u8 = 1;
+pub const MC_KILL_CONNECTION: u8 = 2;
+pub const MC_LIST_TASKS: u8 = 3;
+pub const MC_CANCEL_TASK: u8 = 4;
+pub const MC_MAINTENANCE: u8 = 5;
+pub const MC_SHUTDOWN: u8 = 6;
+pub const MC_MINT_TOKEN: u8 = 7;
+pub const MC_REVOKE_TOKEN: u8 = 8;
+
+#[derive(Debug, Encode, Parse)]
+pub struct CListConnections {
+    pub task_id: TaskId,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct CKillConnection {
+    pub task_id: TaskId,
+    pub connection: usize,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct CListTasks {
+    pub task_id: TaskId,
+}
+
+/// Fails a task of another connection
+#[derive(Debug, Encode, Parse)]
+pub struct CCancelTask {
+    pub task_id: TaskId,
+    pub connection: usize,
+    pub target: TaskId,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct CMaintenance {
+    pub task_id: TaskId,
+    /// The name of the job: `cleanup` or `verify`
+    pub job: String,
+}
+
+/// Stops accepting connections and shuts the server down once the running tasks are finished
+#[derive(Debug, Encode, Parse)]
+pub struct CShutdown {
+    pub task_id: TaskId,
+}
+
+/// Mints a token for the identity, granting it some of its permissions
+#[derive(Debug, Encode, Parse)]
+pub struct CMintToken {
+    pub task_id: TaskId,
+    pub identity: String,
+    pub scopes: Permissions,
+    /// The lifetime of the token, in seconds
+    pub ttl: u64,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct CRevokeToken {
+    pub task_id: TaskId,
+    /// The id of the token, as given when it was minted
+    pub id: String,
+}
+
+
+pub const MS_CONNECTIONS: u8 = 1;
+pub const MS_TASKS: u8 = 2;
+pub const MS_DONE: u8 = 3;
+pub const MS_TOKEN: u8 = 4;
+
+pub const MS_REJECT: u8 = 254;
+pub const MS_ERROR: u8 = 255;
+
+#[derive(Debug, Clone, PartialEq, Encode, Parse)]
+pub struct ConnectionInfo {
+    pub id: usize,
+    pub peer: String,
+    /// The time of the connection since the UNIX epoch, in seconds
+    pub started: u64,
+    /// The number of tasks in progress
+    pub tasks: u32,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Parse)]
+pub struct TaskInfo {
+    pub connection: usize,
+    pub task_id: TaskId,
+    pub description: String,
+    /// The time since the task has started, in seconds
+    pub running: u64,
+    pub finished: bool,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct SConnections {
+    pub task_id: TaskId,
+    pub connections: Vec<ConnectionInfo>,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct STasks {
+    pub task_id: TaskId,
+    pub tasks: Vec<TaskInfo>,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct SDone {
+    pub task_id: TaskId,
+    pub message: String,
+}
+
+#[derive(Encode, Parse)]
+pub struct SToken {
+    pub task_id: TaskId,
+    pub id: String,
+    pub token: String,
+    /// The time since the UNIX epoch, in seconds
+    pub expires: u64,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct SReject {
+    pub task_id: TaskId,
+    pub reason: String,
+}
+
+#[derive(Debug, Encode, Parse)]
+pub struct SError {
+    pub task_id: TaskId,
+    pub message: String,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl From<Utf8Error> for ParseError {
+    fn from(err: Utf8Error) -> Self {
+        ParseError::ParseError(format!("{:?}", err))
+    }
+}
+
+
+impl From<ParserError> for ParseError {
+    fn from(err: ParserError) -> Self {
+        ParseError::ParseError(format!("{:?}", err))
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl ClientMessage {
+    pub fn get_task_id(&self) -> TaskId {
+        match *self {
+            ClientMessage::ListConnections(ref m)   => m.task_id,
+            ClientMessage::KillConnection(ref m)    => m.task_id,
+            ClientMessage::ListTasks(ref m)         => m.task_id,
+            ClientMessage::CancelTask(ref m)        => m.task_id,
+            ClientMessage::Maintenance(ref m)       => m.task_id,
+            ClientMessage::Shutdown(ref m)          => m.task_id,
+            ClientMessage::MintToken(ref m)         => m.task_id,
+            ClientMessage::RevokeToken(ref m)       => m.task_id,
+        }
+    }
+}
+
+
+impl ServerMessage {
+    pub fn get_task_id(&self) -> TaskId {
+        match *self {
+            ServerMessage::Connections(ref m)   => m.task_id,
+            ServerMessage::Tasks(ref m)         => m.task_id,
+            ServerMessage::Done(ref m)          => m.task_id,
+            ServerMessage::Token(ref m)         => m.task_id,
+            ServerMessage::Reject(ref m)        => m.task_id,
+            ServerMessage::Error(ref m)         => m.task_id,
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl CListConnections {
+    pub fn create(task_id: TaskId) -> ClientMessage {
+        ClientMessage::ListConnections(CListConnections { task_id: task_id })
+    }
+}
+
+
+impl CKillConnection {
+    pub fn create(task_id: TaskId, connection: usize) -> ClientMessage {
+        ClientMessage::KillConnection(CKillConnection { task_id: task_id, connection: connection })
+    }
+}
+
+
+impl CListTasks {
+    pub fn create(task_id: TaskId) -> ClientMessage {
+        ClientMessage::ListTasks(CListTasks { task_id: task_id })
+    }
+}
+
+
+impl CCancelTask {
+    pub fn create(task_id: TaskId, connection: usize, target: TaskId) -> ClientMessage {
+        ClientMessage::CancelTask(CCancelTask { task_id: task_id, connection: connection, target: target })
+    }
+}
+
+
+impl CMaintenance {
+    pub fn create(task_id: TaskId, job: String) -> ClientMessage {
+        ClientMessage::Maintenance(CMaintenance { task_id: task_id, job: job })
+    }
+}
+
+
+impl CShutdown {
+    pub fn create(task_id: TaskId) -> ClientMessage {
+        ClientMessage::Shutdown(CShutdown { task_id: task_id })
+    }
+}
+
+
+impl CMintToken {
+    pub fn create(task_id: TaskId, identity: String, scopes: Permissions, ttl: u64) -> ClientMessage {
+        ClientMessage::MintToken(CMintToken { task_id: task_id, identity: identity, scopes: scopes, ttl: ttl })
+    }
+}
+
+
+impl CRevokeToken {
+    pub fn create(task_id: TaskId, id: String) -> ClientMessage {
+        ClientMessage::RevokeToken(CRevokeToken { task_id: task_id, id: id })
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl SConnections {
+    pub fn create(task_id: TaskId, connections: Vec<ConnectionInfo>) -> ServerMessage {
+        ServerMessage::Connections(SConnections { task_id: task_id, connections: connections })
+    }
+}
+
+
+impl STasks {
+    pub fn create(task_id: TaskId, tasks: Vec<TaskInfo>) -> ServerMessage {
+        ServerMessage::Tasks(STasks { task_id: task_id, tasks: tasks })
+    }
+}
+
+
+impl SDone {
+    pub fn create(task_id: TaskId, message: String) -> ServerMessage {
+        ServerMessage::Done(SDone { task_id: task_id, message: message })
+    }
+}
+
+
+impl SToken {
+    pub fn create(task_id: TaskId, id: String, token: String, expires: u64) -> ServerMessage {
+        ServerMessage::Token(SToken { task_id: task_id, id: id, token: token, expires: expires })
+    }
+}
+
+
+/// The token is a secret, so it is not logged
+impl fmt::Debug for SToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SToken {{ task_id: {}, id: {:?}, token: \"***\", expires: {} }}",
+            self.task_id, self.id, self.expires)
+    }
+}
+
+
+impl SReject {
+    pub fn create(task_id: TaskId, reason: String) -> ServerMessage {
+        ServerMessage::Reject(SReject { task_id: task_id, reason: reason })
+    }
+}
+
+
+impl SError {
+    pub fn create(task_id: TaskId, message: String) -> ServerMessage {
+        ServerMessage::Error(SError { task_id: task_id, message: message })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use super::*;
+
+    #[test]
+    fn connection_list() {
+        let info = ConnectionInfo {
+            id: 3,
+            peer: "127.0.0.1:40000".to_owned(),
+            started: 1500000000,
+            tasks: 2,
+            bytes_read: 100,
+            bytes_written: 2000,
+        };
+        let raw = SConnections::create(7, vec![info.clone(), info.clone()]).encode();
+        assert_eq!(raw.mtype, MS_CONNECTIONS);
+        match ServerMessage::parse(raw).unwrap() {
+            ServerMessage::Connections(m) => {
+                assert_eq!(m.task_id, 7);
+                assert_eq!(m.connections, vec![info.clone(), info]);
+            },
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn cancel_task() {
+        let raw = CCancelTask::create(1, 4, 9).encode();
+        assert_eq!(raw.mtype, MC_CANCEL_TASK);
+        match ClientMessage::parse(raw).unwrap() {
+            ClientMessage::CancelTask(CCancelTask { task_id: 1, connection: 4, target: 9 }) => (),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+}
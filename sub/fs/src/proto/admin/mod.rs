@@ -0,0 +1,169 @@
+
+pub mod client;
+pub mod message;
+pub mod server;
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::sync::Arc;
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+
+    use unix_socket::UnixStream;
+
+    use protocol::stream::Stream;
+    use protocol::workflow::{Protocol, Workflow};
+
+    use ::client::connection::Connection;
+    use ::connection::{StreamMessage, Traffic};
+    use ::server::config::Config;
+    use ::server::control::{Peer, ServerControl};
+    use ::server::credentials::Authenticator;
+    use ::server::permissions::{Permission, Permissions};
+    use ::server::tokens::{TokenAuthority, TokenError};
+    use ::testing;
+
+    use super::client::{AdminError, AdminInterface};
+    use super::message::{CKillConnection, CListConnections, CMaintenance, CMintToken, CRevokeToken, ServerMessage};
+    use super::server::AdminProtocol;
+
+
+    fn protocol(peer: Peer, control: Arc<ServerControl>) -> (AdminProtocol, Receiver<StreamMessage>) {
+        protocol_with(peer, control, Permissions::all())
+    }
+
+    fn protocol_with(peer: Peer, control: Arc<ServerControl>, permissions: Permissions)
+        -> (AdminProtocol, Receiver<StreamMessage>)
+    {
+        let (mut context, rx) = testing::context(0, testing::holder(Config::new()));
+        context.peer = peer;
+        context.control = control;
+        context.permissions = permissions;
+        (AdminProtocol::new(context), rx)
+    }
+
+    fn answer(rx: &Receiver<StreamMessage>) -> ServerMessage {
+        ServerMessage::parse(testing::answer(rx)).unwrap()
+    }
+
+    fn control() -> Arc<ServerControl> {
+        let control = Arc::new(ServerControl::new());
+        control.add_connection(0, Peer::Unix(None), Traffic::default(), None);
+        control.add_connection(5, Peer::Tcp("10.0.0.2:5000".parse().unwrap()), Traffic::default(), None);
+        control
+    }
+
+    #[test]
+    fn list_connections() {
+        let (protocol, rx) = protocol(Peer::Unix(None), control());
+        assert!(match protocol.flow(CListConnections::create(3).encode()) { Workflow::Continue => true, _ => false });
+        match answer(&rx) {
+            ServerMessage::Connections(m) => {
+                assert_eq!(m.task_id, 3);
+                assert_eq!(m.connections.iter().map(|c| (c.id, &c.peer[..])).collect::<Vec<_>>(),
+                    vec![(0, "unix"), (5, "10.0.0.2:5000")]);
+            },
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn unprivileged_peer() {
+        let (protocol, rx) = protocol(Peer::Tcp("10.0.0.1:4000".parse().unwrap()), control());
+        protocol.flow(CListConnections::create(1).encode());
+        match answer(&rx) {
+            ServerMessage::Reject(m) => assert_eq!(m.task_id, 1),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn no_admin_permission() {
+        let permissions = Permissions::of(&[Permission::Info, Permission::Import]);
+        let (protocol, rx) = protocol_with(Peer::Unix(None), control(), permissions);
+        protocol.flow(CListConnections::create(1).encode());
+        match answer(&rx) {
+            ServerMessage::Reject(m) => assert!(m.reason.contains("Permission denied"), "{}", m.reason),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let (protocol, rx) = protocol(Peer::Unix(None), control());
+        protocol.flow(CKillConnection::create(1, 0).encode());
+        assert!(match answer(&rx) { ServerMessage::Reject(_) => true, _ => false });
+        protocol.flow(CKillConnection::create(2, 9).encode());
+        assert!(match answer(&rx) { ServerMessage::Error(_) => true, _ => false });
+        protocol.flow(CMaintenance::create(3, "defrag".to_owned()).encode());
+        assert!(match answer(&rx) { ServerMessage::Reject(_) => true, _ => false });
+    }
+
+    #[test]
+    fn client_requests() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let control = control();
+        let (killed, mut killed_peer) = UnixStream::pair().unwrap();
+        control.add_connection(7, Peer::Unix(None), Traffic::default(), Some(Stream::Unix(killed)));
+
+        let (protocol, rx) = protocol(Peer::Unix(None), control);
+        let server = thread::spawn(move || {
+            let mut stream = Stream::Unix(server_stream);
+            for _ in 0..3 {
+                protocol.flow(Connection::_read(&mut stream).unwrap());
+                let mut buf = Vec::new();
+                rx.try_recv().unwrap().unwrap().write_frame(&mut buf).unwrap();
+                stream.write(&buf).unwrap();
+            }
+        });
+
+        let admin = AdminInterface::new(Connection::new(Stream::Unix(client_stream)), 0);
+        assert_eq!(admin.connections().unwrap().len(), 3);
+        assert!(admin.kill_connection(7).unwrap().contains("#7"));
+        match admin.cancel_task(5, 1) {
+            Err(AdminError::Failed(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        server.join().unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(::std::io::Read::read_to_end(&mut killed_peer, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn mint_and_revoke_tokens() {
+        let authenticator = Authenticator::new(None).with_tokens(TokenAuthority::new(vec![5; 32]));
+        let control = Arc::new(ServerControl::with_authenticator(authenticator));
+        let (protocol, rx) = protocol(Peer::Unix(None), control.clone());
+        let scopes = Permissions::of(&[Permission::Read]);
+        let mint = |task_id, ttl| CMintToken::create(task_id, "ci".to_owned(), scopes, ttl);
+
+        protocol.flow(mint(1, 60).encode());
+        let (id, token) = match answer(&rx) {
+            ServerMessage::Token(m) => (m.id, m.token),
+            m => panic!("Unexpected message {:?}", m),
+        };
+        let claims = control.authenticator().authenticate_token(&token).unwrap();
+        assert_eq!((&claims.id[..], &claims.identity[..], claims.scopes), (&id[..], "ci", scopes));
+
+        protocol.flow(CRevokeToken::create(2, id).encode());
+        assert!(match answer(&rx) { ServerMessage::Done(_) => true, _ => false });
+        assert_eq!(control.authenticator().authenticate_token(&token), Err(TokenError::Revoked));
+
+        protocol.flow(mint(3, 0).encode());
+        assert!(match answer(&rx) { ServerMessage::Reject(_) => true, _ => false });
+    }
+
+    #[test]
+    fn tokens_not_enabled() {
+        let (protocol, rx) = protocol(Peer::Unix(None), control());
+        protocol.flow(CMintToken::create(1, "ci".to_owned(), Permissions::all(), 60).encode());
+        match answer(&rx) {
+            ServerMessage::Error(m) => assert!(m.message.contains("token_key"), "{}", m.message),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+}
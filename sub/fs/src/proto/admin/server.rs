@@ -0,0 +1,237 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use protocol::message::RawMessage;
+use protocol::workflow::{Protocol, Workflow, WorkflowError};
+
+use ::connection::StreamSender;
+use ::server::database::Database;
+use ::server::permissions::Permission;
+use ::server::pool::Executor;
+use ::server::registry::{ConnectionContext, ServerProtocol};
+use ::server::tokens::Claims;
+use ::types::TaskId;
+
+use super::message::{ClientMessage, ServerMessage, CMintToken, SConnections, SDone, SError, SReject, STasks, SToken};
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+pub fn send_message(sender: &StreamSender, message: ServerMessage) -> Result<(), ()> {
+    info!("  <<  {:?}", message);
+    ::connection::send_message(sender, Some(Box::new(message)));
+    Ok(())
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+/// Maintenance jobs run on request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaintenanceJob {
+    /// Removes the temporary files of interrupted copies
+    Cleanup,
+    /// Checks the stored files against their content ids
+    Verify,
+}
+
+
+#[derive(Debug)]
+pub struct AdminProtocol {
+    context: ConnectionContext,
+    /// Why requests are not served, unless the client is privileged
+    denied: Option<String>,
+    /// The number of maintenance jobs in progress
+    jobs: Arc<AtomicUsize>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl FromStr for MaintenanceJob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MaintenanceJob, String> {
+        match s {
+            "cleanup"   => Ok(MaintenanceJob::Cleanup),
+            "verify"    => Ok(MaintenanceJob::Verify),
+            _           => Err(format!("Unknown maintenance job {}; expected cleanup or verify", s)),
+        }
+    }
+}
+
+
+impl AdminProtocol {
+    pub fn new(context: ConnectionContext) -> AdminProtocol {
+        let denied = if !context.db.lock().unwrap().config.is_admin_peer(&context.peer) {
+            Some(format!("Admin requests are not allowed from {}", context.peer))
+        } else if !context.permissions.contains(Permission::Admin) {
+            Some(format!("Permission denied: {} is not allowed", Permission::Admin))
+        } else {
+            None
+        };
+        if let Some(ref reason) = denied {
+            warn!("  ::  Connection #{} from {} can't use the admin subprotocol: {}", context.id, context.peer, reason);
+        }
+        AdminProtocol {
+            context: context,
+            denied: denied,
+            jobs: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn send_message(&self, message: ServerMessage) -> Result<(), ()> {
+        send_message(&self.context.sender, message)
+    }
+
+    fn kill_connection(&self, task_id: TaskId, id: usize) -> ServerMessage {
+        if id == self.context.id {
+            return SReject::create(task_id, "Can't kill the own connection".to_owned());
+        }
+        match self.context.control.kill_connection(id) {
+            true    => SDone::create(task_id, format!("Connection #{} is killed", id)),
+            false   => SError::create(task_id, format!("No connection #{}", id)),
+        }
+    }
+
+    fn cancel_task(&self, task_id: TaskId, id: usize, target: TaskId) -> ServerMessage {
+        match self.context.control.cancel_task(id, target, "Cancelled by the administrator") {
+            true    => SDone::create(task_id, format!("Task #{} of connection #{} is cancelled", target, id)),
+            false   => SError::create(task_id, format!("No running task #{} of connection #{}", target, id)),
+        }
+    }
+
+    fn mint_token(&self, task_id: TaskId, m: CMintToken) -> ServerMessage {
+        let authenticator = self.context.control.authenticator();
+        let tokens = match authenticator.tokens() {
+            Some(tokens) => tokens,
+            None => return SError::create(task_id, "Tokens are not enabled: token_key is not set".to_owned()),
+        };
+        if m.ttl == 0 {
+            return SReject::create(task_id, "The lifetime of a token must be positive".to_owned());
+        }
+        let claims = match Claims::new(m.identity, m.scopes, Duration::from_secs(m.ttl)) {
+            Ok(claims) => claims,
+            Err(err) => return SError::create(task_id, format!("Creating token id: {:?}", err)),
+        };
+        info!("  ::  Connection #{} minted token {} for {} (scopes: {}, expires: {})",
+            self.context.id, claims.id, claims.identity, claims.scopes, claims.expires);
+        SToken::create(task_id, claims.id.clone(), tokens.mint(&claims), claims.expires)
+    }
+
+    fn revoke_token(&self, task_id: TaskId, id: String) -> ServerMessage {
+        let authenticator = self.context.control.authenticator();
+        let tokens = match authenticator.tokens() {
+            Some(tokens) => tokens,
+            None => return SError::create(task_id, "Tokens are not enabled: token_key is not set".to_owned()),
+        };
+        match tokens.revoke(&id) {
+            Ok(true) => {
+                info!("  ::  Connection #{} revoked token {}", self.context.id, id);
+                SDone::create(task_id, format!("Token {} is revoked", id))
+            },
+            Ok(false) => SDone::create(task_id, format!("Token {} has been revoked already", id)),
+            Err(err) => SError::create(task_id, format!("Saving the revocation: {:?}", err)),
+        }
+    }
+
+    /// Runs the job in the executor and answers once it is done
+    fn start_maintenance(&self, task_id: TaskId, job: MaintenanceJob) {
+        let db = self.context.db.clone();
+        let sender = self.context.sender.clone();
+        let jobs = self.jobs.clone();
+        jobs.fetch_add(1, Ordering::SeqCst);
+        info!("  ::  Starting maintenance job {:?}", job);
+        let started = self.context.executor.spawn(move || {
+            let message = match job {
+                MaintenanceJob::Cleanup => Database::cleanup(db)
+                    .map(|removed| format!("Removed {} temporary files", removed)),
+                MaintenanceJob::Verify => Database::verify(db)
+                    .map(|(checked, damaged)| match damaged.len() {
+                        0 => format!("Checked {} files, all of them are intact", checked),
+                        _ => format!("Checked {} files, damaged: {}", checked,
+                            damaged.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")),
+                    }),
+            };
+            let _ = send_message(&sender, match message {
+                Ok(message) => SDone::create(task_id, message),
+                Err(err) => SError::create(task_id, format!("{:?}", err)),
+            });
+            jobs.fetch_sub(1, Ordering::SeqCst);
+        });
+        if started.is_err() {
+            self.jobs.fetch_sub(1, Ordering::SeqCst);
+            let _ = self.send_message(SError::create(task_id, "The server is busy, try again later".to_owned()));
+        }
+    }
+
+    /// Stops the listeners from the executor, since the listening addresses are connected to
+    fn start_shutdown(&self, task_id: TaskId) {
+        let (addresses, unixsocket) = {
+            let db = self.context.db.lock().unwrap();
+            (db.config.addresses(), db.config.unixsocket.clone())
+        };
+        let control = self.context.control.clone();
+        if control.is_stopping() {
+            let _ = self.send_message(SError::create(task_id, "The server is already stopping".to_owned()));
+            return;
+        }
+        warn!("  ::  Shutdown is requested by connection #{} from {}", self.context.id, self.context.peer);
+        let _ = self.send_message(SDone::create(task_id, "The server is stopping".to_owned()));
+        // Not queued to the workers, which may be too busy to take it
+        let _ = Executor::Thread.spawn(move || {
+            control.shutdown(addresses, unixsocket);
+        });
+    }
+}
+
+
+impl Protocol for AdminProtocol {
+    fn flow(&self, raw_message: RawMessage) -> Workflow {
+        let message = match ClientMessage::parse(raw_message) {
+            Err(err) => return Workflow::Terminate(WorkflowError::Exception(format!("{:?}", err))),
+            Ok(message) => message,
+        };
+        info!("  >>  {:?}", message);
+        let task_id = message.get_task_id();
+        if let Some(ref reason) = self.denied {
+            let _ = self.send_message(SReject::create(task_id, reason.clone()));
+            return Workflow::Continue;
+        }
+
+        let control = &self.context.control;
+        let answer = match message {
+            ClientMessage::ListConnections(_) => SConnections::create(task_id, control.connections()),
+            ClientMessage::KillConnection(m) => self.kill_connection(task_id, m.connection),
+            ClientMessage::ListTasks(_) => STasks::create(task_id, control.tasks()),
+            ClientMessage::CancelTask(m) => self.cancel_task(task_id, m.connection, m.target),
+            ClientMessage::Maintenance(m) => match m.job.parse() {
+                Ok(job) => {
+                    self.start_maintenance(task_id, job);
+                    return Workflow::Continue;
+                },
+                Err(err) => SReject::create(task_id, err),
+            },
+            ClientMessage::Shutdown(_) => {
+                self.start_shutdown(task_id);
+                return Workflow::Continue;
+            },
+            ClientMessage::MintToken(m) => self.mint_token(task_id, m),
+            ClientMessage::RevokeToken(m) => self.revoke_token(task_id, m.id),
+        };
+        let _ = self.send_message(answer);
+        Workflow::Continue
+    }
+}
+
+
+impl ServerProtocol for AdminProtocol {
+    fn active_tasks(&self) -> usize {
+        self.jobs.load(Ordering::SeqCst)
+    }
+}
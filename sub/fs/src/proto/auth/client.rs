@@ -1,9 +1,17 @@
-use protocol::message::{Message, RawMessage, EncodeError, ReadError};
-use protocol::workflow::{Protocol, Workflow, WorkflowError};
+use std::cell::{Cell, RefCell};
+
+use protocol::checksum::Checksum;
+use protocol::message::{RawMessage, EncodeError, ReadError};
+use protocol::stream::Stream;
+use protocol::workflow::{Protocol, Workflow, WorkflowError, ProtocolVersion, ProtocolVersionRange};
 
 use ::client::connection::{Connection};
+use ::proto::content::message as content;
 
-use super::message::{ClientMessage, ServerMessage, CStart};
+use super::mac;
+use super::mac::Session;
+use super::message::{ClientMessage, ServerMessage, CAuthHash, CAuthPlain, CAuthSCM, CAuthToken, CStart};
+use super::message::{SAuthProof, SRequestAuthHash, SRequestAuthSCM, SessionTicket};
 use super::super::PROTOCOL_VERSION;
 
 
@@ -18,13 +26,25 @@ pub enum AuthError {
     EncodeError(EncodeError),
     ReadError(ReadError),
     WorkflowError(WorkflowError),
-    InacceptableProtocol,
+    /// The server does not accept the client protocol version; carries the versions accepted by the server
+    InacceptableProtocol(ProtocolVersion, ProtocolVersionRange),
 }
 
 
 #[derive(Debug)]
 pub struct AuthConfig {
-    password: Option<String>,
+    /// The login and the password sent if the server asks for them
+    pub login: Option<(String, String)>,
+    /// The login and the key proven if the server challenges the client; preferred to the password
+    pub key: Option<(String, Vec<u8>)>,
+    /// The token sent if the server challenges the client; preferred to the password, but not to the key
+    pub token: Option<String>,
+    /// The protocol version requested on start
+    pub version: ProtocolVersion,
+    /// The frame checksum requested on start
+    pub checksum: Option<Checksum>,
+    /// The subprotocol requested on start
+    pub subprotocol: u8,
 }
 
 
@@ -39,6 +59,14 @@ pub enum AuthProtocolStage {
 #[derive(Debug)]
 pub struct AuthProtocol {
     config: AuthConfig,
+    /// The versions accepted by the server, if it has rejected the client version
+    rejected: Cell<Option<ProtocolVersionRange>>,
+    /// The proof expected from the server after proving the key
+    server_proof: RefCell<Option<Vec<u8>>>,
+    /// The server has proven the key
+    server_proven: Cell<bool>,
+    /// Given by servers since 0.1.8 on accepting the client
+    ticket: RefCell<Option<SessionTicket>>,
     pub connection: Connection,
 }
 
@@ -61,25 +89,50 @@ impl From<ReadError> for AuthError {
 // --------------------------------------------------------------------------------------------------------------------
 
 
+impl AuthConfig {
+    pub fn new() -> AuthConfig {
+        AuthConfig {
+            login: None,
+            key: None,
+            token: None,
+            version: PROTOCOL_VERSION,
+            checksum: None,
+            subprotocol: content::SUBPROTOCOL_CODE,
+        }
+    }
+}
+
+
 impl AuthProtocol {
     pub fn new(connection: Connection) -> AuthProtocol {
+        Self::with_config(connection, AuthConfig::new())
+    }
+
+    pub fn with_config(connection: Connection, config: AuthConfig) -> AuthProtocol {
         AuthProtocol{
-            config: AuthConfig{
-                password: None,
-//                password: Some("123".to_owned()),
-            },
+            config: config,
+            rejected: Cell::new(None),
+            server_proof: RefCell::new(None),
+            server_proven: Cell::new(false),
+            ticket: RefCell::new(None),
             connection: connection,
         }
     }
 
     fn send_message(&mut self, client_message: ClientMessage) -> Result<(), EncodeError> {
         info!("  >>  {:?}", client_message);
-        let message = try!(Message::from_raw(client_message.encode()));
-        Ok(self.connection.send_message(Some(message)))
+        Ok(self.connection.send_message(Some(Box::new(client_message))))
+    }
+
+    /// The ticket of the session, once accepted by a server giving it
+    pub fn ticket(&self) -> Option<SessionTicket> {
+        self.ticket.borrow().clone()
     }
 
     pub fn auth(&mut self) -> Result<usize, AuthError> {
-        try!(self.send_message(CStart::create(PROTOCOL_VERSION, 1, [].to_vec())));
+        let (version, subprotocol) = (self.config.version, self.config.subprotocol);
+        self.connection.request_checksum(self.config.checksum);
+        try!(self.send_message(CStart::create(version, subprotocol, self.config.checksum, [].to_vec())));
         'iter_messages: loop {
             let message = try!(self.connection.read());
             match self.flow(message) {
@@ -87,6 +140,9 @@ impl AuthProtocol {
                 Workflow::SwitchProtocol(client_id) => return Ok(client_id),
                 Workflow::Terminate(err)            => {
                     info!("Terminated: {}", err);
+                    if let Some(versions) = self.rejected.get() {
+                        return Err(AuthError::InacceptableProtocol(version, versions));
+                    }
                     return Err(AuthError::WorkflowError(err));
                 },
             }
@@ -95,6 +151,83 @@ impl AuthProtocol {
 }
 
 
+impl AuthProtocol {
+    /// Whether secrets may be sent in clear: over TLS or the Unix socket, whatever the server says
+    fn secure_transport(&self) -> bool {
+        match self.connection.stream {
+            Stream::Tcp(_) => false,
+            Stream::Tls(_) | Stream::Unix(_) => true,
+        }
+    }
+
+    fn on_request_auth_hash(&self, m: SRequestAuthHash) -> Workflow {
+        let message = match (&self.config.key, &self.config.token, &self.config.login) {
+            (&Some((ref login, ref key)), _, _) => {
+                let nonce = match mac::nonce() {
+                    Ok(nonce) => nonce,
+                    Err(err) =>
+                        return Workflow::Terminate(WorkflowError::Exception(format!("Creating nonce: {:?}", err))),
+                };
+                let session = Session {
+                    version: self.config.version,
+                    subprotocol: self.config.subprotocol,
+                    checksum: self.config.checksum,
+                };
+                let proof = mac::client_proof(key, &session, login, &m.nonce.0, &nonce);
+                *self.server_proof.borrow_mut() = Some(mac::server_proof(key, &session, login, &m.nonce.0, &nonce));
+                CAuthHash::create(login.clone(), nonce, proof)
+            },
+            (&None, &Some(ref token), _) if m.plain && self.secure_transport() => CAuthToken::create(token.clone()),
+            (&None, &Some(_), _) if m.plain => return Workflow::Terminate(WorkflowError::ProtocolError(
+                "Refusing to send the token in clear over TCP without TLS; a key is required".to_owned())),
+            (&None, &Some(_), _) => return Workflow::Terminate(WorkflowError::ProtocolError(
+                "Server does not accept a token over this connection; a key is required".to_owned())),
+            (&None, &None, &Some((ref login, ref password))) if m.plain && self.secure_transport() =>
+                CAuthPlain::create(login.clone(), password.clone()),
+            (&None, &None, &Some(_)) if m.plain => return Workflow::Terminate(WorkflowError::ProtocolError(
+                "Refusing to send the password in clear over TCP without TLS; a key is required".to_owned())),
+            (&None, &None, &Some(_)) => return Workflow::Terminate(WorkflowError::ProtocolError(
+                "Server does not accept a password over this connection; a key is required".to_owned())),
+            (&None, &None, &None) => return Workflow::Terminate(WorkflowError::ProtocolError(
+                "Server requires a login and a key, a token or a password".to_owned())),
+        };
+        info!("  >>  {:?}", message);
+        self.connection.send_message(Some(Box::new(message)));
+        Workflow::Continue
+    }
+
+    /// The credentials of the process only mean something to a local server, which can't prove a key this way
+    fn on_request_auth_scm(&self, m: SRequestAuthSCM) -> Workflow {
+        match self.connection.stream {
+            Stream::Unix(_) => (),
+            Stream::Tcp(_) | Stream::Tls(_) => return Workflow::Terminate(WorkflowError::ProtocolError(
+                "Server asked for the credentials of the process over a network connection".to_owned())),
+        }
+        if self.config.key.is_some() {
+            return Workflow::Terminate(WorkflowError::ProtocolError(
+                "Server identifies the client by its process instead of proving the key".to_owned()));
+        }
+        info!("Identified by the server as {}", m.identity);
+        let message = CAuthSCM::create();
+        info!("  >>  {:?}", message);
+        self.connection.send_message(Some(Box::new(message)));
+        Workflow::Continue
+    }
+
+    fn on_auth_proof(&self, m: SAuthProof) -> Workflow {
+        let expected = self.server_proof.borrow_mut().take();
+        match expected {
+            Some(ref expected) if mac::verify(expected, &m.mac.0) => {
+                self.server_proven.set(true);
+                Workflow::Continue
+            },
+            Some(_) => Workflow::Terminate(WorkflowError::ProtocolError("Server failed to prove the key".to_owned())),
+            None => Workflow::Terminate(WorkflowError::ProtocolError("Unexpected server proof".to_owned())),
+        }
+    }
+}
+
+
 impl <'a> Protocol for AuthProtocol {
     #[cfg_attr(feature = "dev", trace)]
     fn flow(&self, raw_message: RawMessage) -> Workflow {
@@ -108,10 +241,37 @@ impl <'a> Protocol for AuthProtocol {
                             format!("Server error with message: {}", m.message)))
                     },
                     ServerMessage::Reject(m) => {
+                        self.rejected.set(m.versions);
                         Workflow::Terminate(WorkflowError::ProtocolError(
                             format!("Server rejected connection with message: {}", m.reason)))
                     },
+                    ServerMessage::RequestAuthPlain(_) => match self.config.login {
+                        Some(_) if !self.secure_transport() => Workflow::Terminate(WorkflowError::ProtocolError(
+                            "Refusing to send the password in clear over TCP without TLS".to_owned())),
+                        Some((ref login, ref password)) => {
+                            let message = CAuthPlain::create(login.clone(), password.clone());
+                            info!("  >>  {:?}", message);
+                            self.connection.send_message(Some(Box::new(message)));
+                            Workflow::Continue
+                        },
+                        None => Workflow::Terminate(WorkflowError::ProtocolError(
+                            "Server requires a login and a password".to_owned())),
+                    },
+                    ServerMessage::RequestAuthHash(m) => self.on_request_auth_hash(m),
+                    ServerMessage::RequestAuthSCM(m) => self.on_request_auth_scm(m),
+                    ServerMessage::AuthProof(m) => self.on_auth_proof(m),
                     ServerMessage::AuthOk(m) => {
+                        // A client with a key only trusts the server proving it, which happens right before accepting
+                        if self.config.key.is_some() && !self.server_proven.get() {
+                            return Workflow::Terminate(WorkflowError::ProtocolError(
+                                "Server did not prove the key".to_owned()));
+                        }
+                        if m.checksum.is_some() && m.checksum != self.config.checksum {
+                            return Workflow::Terminate(WorkflowError::ProtocolError(
+                                format!("Server chose a frame checksum not requested: {:?}", m.checksum)));
+                        }
+                        self.connection.set_checksum(m.checksum);
+                        *self.ticket.borrow_mut() = m.ticket;
                         Workflow::SwitchProtocol(m.id)
                     },
                 }
@@ -0,0 +1,111 @@
+//! Challenge-response proofs of the `AuthHash` method.
+//!
+//! Each side sends a fresh nonce and proves the shared key with a keyed BLAKE2b MAC over both nonces,
+//! the start of the session and the login. The proofs of the sides are domain-separated, so that
+//! one can not be reflected as the other, and a proof is only valid for the nonces it was made for.
+
+use std::io;
+
+use blake2_rfc::blake2b::Blake2b;
+use rand::{OsRng, Rng};
+
+use protocol::checksum::Checksum;
+use protocol::serde::EncodeTo;
+use protocol::workflow::ProtocolVersion;
+
+
+pub const NONCE_SIZE: usize = 32;
+pub const MAC_SIZE: usize = 64;
+
+const CLIENT_LABEL: &'static [u8] = b"irbis-fs auth-hash client";
+const SERVER_LABEL: &'static [u8] = b"irbis-fs auth-hash server";
+
+
+/// What both sides agree on when the client starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Session {
+    pub version: ProtocolVersion,
+    pub subprotocol: u8,
+    /// The requested frame checksum
+    pub checksum: Option<Checksum>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+pub fn nonce() -> io::Result<Vec<u8>> {
+    let mut nonce = vec![0u8; NONCE_SIZE];
+    OsRng::new()?.fill_bytes(&mut nonce);
+    Ok(nonce)
+}
+
+
+/// Proves the key of the client; `nonce` is the one of the server
+pub fn client_proof(key: &[u8], session: &Session, login: &str, server_nonce: &[u8], client_nonce: &[u8]) -> Vec<u8> {
+    mac(key, CLIENT_LABEL, session, login, server_nonce, client_nonce)
+}
+
+
+/// Proves the key of the server in answer to a valid client proof
+pub fn server_proof(key: &[u8], session: &Session, login: &str, server_nonce: &[u8], client_nonce: &[u8]) -> Vec<u8> {
+    mac(key, SERVER_LABEL, session, login, server_nonce, client_nonce)
+}
+
+
+/// Compares in a time independent of the position of the first difference
+pub fn verify(expected: &[u8], mac: &[u8]) -> bool {
+    expected.len() == mac.len() && expected.iter().zip(mac.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+
+fn mac(key: &[u8], label: &[u8], session: &Session, login: &str, server_nonce: &[u8], client_nonce: &[u8])
+    -> Vec<u8>
+{
+    // All parts but the label are length-prefixed, so that their boundaries can't be shifted
+    let mut data = label.to_vec();
+    session.version.encode_to(&mut data).unwrap();
+    session.subprotocol.encode_to(&mut data).unwrap();
+    session.checksum.map_or(0, |checksum| checksum.code()).encode_to(&mut data).unwrap();
+    login.encode_to(&mut data).unwrap();
+    (server_nonce.len() as u32).encode_to(&mut data).unwrap();
+    data.extend_from_slice(server_nonce);
+    (client_nonce.len() as u32).encode_to(&mut data).unwrap();
+    data.extend_from_slice(client_nonce);
+
+    let mut state = Blake2b::with_key(MAC_SIZE, key);
+    state.update(&data);
+    state.finalize().as_bytes().to_vec()
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use protocol::workflow::ProtocolVersion;
+
+    use super::{Session, client_proof, nonce, server_proof, verify};
+
+    const SESSION: Session = Session { version: ProtocolVersion(0, 1, 5), subprotocol: 1, checksum: None };
+
+    #[test]
+    fn proofs_are_bound() {
+        let (key, server_nonce, client_nonce) = (b"0123456789abcdef", nonce().unwrap(), nonce().unwrap());
+        assert!(server_nonce != client_nonce);
+        let proof = client_proof(key, &SESSION, "alice", &server_nonce, &client_nonce);
+        assert!(verify(&proof, &client_proof(key, &SESSION, "alice", &server_nonce, &client_nonce)));
+
+        // Another nonce, login, session, key or direction gives another proof
+        assert!(!verify(&proof, &client_proof(key, &SESSION, "alice", &nonce().unwrap(), &client_nonce)));
+        assert!(!verify(&proof, &client_proof(key, &SESSION, "bob", &server_nonce, &client_nonce)));
+        let session = Session { subprotocol: 2, ..SESSION };
+        assert!(!verify(&proof, &client_proof(key, &session, "alice", &server_nonce, &client_nonce)));
+        assert!(!verify(&proof, &client_proof(b"fedcba9876543210", &SESSION, "alice", &server_nonce, &client_nonce)));
+        assert!(!verify(&proof, &server_proof(key, &SESSION, "alice", &server_nonce, &client_nonce)));
+        assert!(!verify(&proof, &proof[1..]));
+    }
+}
@@ -1,9 +1,12 @@
 
+use std::fmt;
+use std::io;
+use std::io::Write;
 use std::str::Utf8Error;
 
-use protocol::message::{RawMessage, RawMessageBody};
-use protocol::serde::{Encoder, Encode, Parse, Parser, ParserError};
-use protocol::workflow::ProtocolVersion;
+use protocol::checksum::Checksum;
+use protocol::serde::{Bytes, EncodeTo, Parse, Parser, ParserError};
+use protocol::workflow::{ProtocolVersion, ProtocolVersionRange};
 
 
 #[derive(Debug, PartialEq)]
@@ -15,23 +18,25 @@ pub enum ParseError {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Dispatch)]
 pub enum ClientMessage {
-    Start(CStart),
-//    AuthPlain(CAuthPlain),
-//    AuthHash(CAuthHash),
-//    AuthSCM(CAuthSCM),
+    #[code = "MC_START"]        Start(CStart),
+    #[code = "MC_AUTH_PLAIN"]   AuthPlain(CAuthPlain),
+    #[code = "MC_AUTH_HASH"]    AuthHash(CAuthHash),
+    #[code = "MC_AUTH_SCM"]     AuthSCM(CAuthSCM),
+    #[code = "MC_AUTH_TOKEN"]   AuthToken(CAuthToken),
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Dispatch)]
 pub enum ServerMessage {
-    AuthOk(SAuthOk),
-    Reject(SReject),
-    Error(SError),
-//    RequestAuthPlain(SRequestAuthPlain),
-//    RequestAuthHash(SRequestAuthHash),
-//    RequestAuthSCM(SRequestAuthSCM),
+    #[code = "MS_AUTH_OK"]      AuthOk(SAuthOk),
+    #[code = "MS_REJECT"]       Reject(SReject),
+    #[code = "MS_ERROR"]        Error(SError),
+    #[code = "MS_REQUEST_AUTH_PLAIN"] RequestAuthPlain(SRequestAuthPlain),
+    #[code = "MS_REQUEST_AUTH_HASH"] RequestAuthHash(SRequestAuthHash),
+    #[code = "MS_AUTH_PROOF"]   AuthProof(SAuthProof),
+    #[code = "MS_REQUEST_AUTH_SCM"] RequestAuthSCM(SRequestAuthSCM),
 }
 
 // --------------------------------------------------------------------------------------------------------------------
@@ -39,34 +44,112 @@ pub enum ServerMessage {
 pub const SUBPROTOCOL_CODE: u8 = 0;
 
 pub const MC_START: u8 = 0;
+pub const MC_AUTH_PLAIN: u8 = 1;
+pub const MC_AUTH_HASH: u8 = 2;
+pub const MC_AUTH_SCM: u8 = 3;
+pub const MC_AUTH_TOKEN: u8 = 4;
 
 #[derive(Debug)]
 pub struct CStart {
     pub version: ProtocolVersion,
     pub subprotocol: u8,
+    /// The frame checksum requested by the client. Servers prior to 0.1.4 ignore it
+    pub checksum: Option<Checksum>,
     pub args: Vec<u8>,
 }
 
+/// The answer to `SRequestAuthPlain`
+#[derive(Encode, Parse)]
+pub struct CAuthPlain {
+    pub login: String,
+    pub password: String,
+}
+
+/// The answer to `SRequestAuthHash`: a proof of the key of the login, see `mac::client_proof`
+#[derive(Debug, Encode, Parse)]
+pub struct CAuthHash {
+    pub login: String,
+    pub nonce: Bytes,
+    pub mac: Bytes,
+}
+
+/// The answer to `SRequestAuthSCM`: the client accepts the identity of its process
+#[derive(Debug)]
+pub struct CAuthSCM;
+
+/// An answer to `SRequestAuthHash` allowing plain secrets, since 0.1.7: a token minted by the server
+#[derive(Encode, Parse)]
+pub struct CAuthToken {
+    pub token: String,
+}
+
 
 pub const MS_AUTH_OK: u8 = 1;
+pub const MS_REQUEST_AUTH_PLAIN: u8 = 2;
+pub const MS_REQUEST_AUTH_HASH: u8 = 3;
+pub const MS_AUTH_PROOF: u8 = 4;
+pub const MS_REQUEST_AUTH_SCM: u8 = 5;
 pub const MS_REJECT: u8 = 254;
 pub const MS_ERROR: u8 = 255;
 
 #[derive(Debug)]
 pub struct SAuthOk{
+    /// The id of the connection on the server
     pub id: usize,
+    /// The frame checksum used by both sides from now on, if the server accepts the requested one.
+    /// Absent if no checksum is used
+    pub checksum: Option<Checksum>,
+    /// Given to clients since 0.1.8
+    pub ticket: Option<SessionTicket>,
+}
+
+/// Names the run of the server and lets the client resume the session of the connection on another one
+#[derive(Clone, PartialEq, Encode, Parse)]
+pub struct SessionTicket {
+    /// Changes when the server restarts, and the sessions are lost with it
+    pub run_id: String,
+    /// Proves the session is resumed by its client; empty if the server does not keep sessions
+    pub secret: Bytes,
 }
 
 #[derive(Debug)]
 pub struct SReject {
     pub reason: String,
+    /// The protocol versions accepted by the server, if the client's one is not.
+    /// Absent in rejects of servers prior to 0.1.2
+    pub versions: Option<ProtocolVersionRange>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Encode, Parse)]
 pub struct SError {
     pub message: String,
 }
 
+/// Asks the client for a login and a password
+#[derive(Debug)]
+pub struct SRequestAuthPlain;
+
+/// Challenges the client to prove its key. Sent to clients since 0.1.5 instead of `SRequestAuthPlain`
+#[derive(Debug, Encode, Parse)]
+pub struct SRequestAuthHash {
+    pub nonce: Bytes,
+    /// The client may answer with `CAuthPlain` instead, the connection being encrypted or local
+    pub plain: bool,
+}
+
+/// Proves the key of the server, see `mac::server_proof`. Sent right before `SAuthOk`
+#[derive(Debug, Encode, Parse)]
+pub struct SAuthProof {
+    pub mac: Bytes,
+}
+
+/// Offers the identity the server has given to the process of the client by its credentials on the Unix socket.
+/// Sent to clients since 0.1.6 instead of the other requests
+#[derive(Debug, Encode, Parse)]
+pub struct SRequestAuthSCM {
+    pub identity: String,
+}
+
 // --------------------------------------------------------------------------------------------------------------------
 
 impl From<Utf8Error> for ParseError {
@@ -86,96 +169,101 @@ impl From<ParserError> for ParseError {
 // --------------------------------------------------------------------------------------------------------------------
 
 
-//impl RawCodec for ClientMessage {
-impl ClientMessage {
-    pub fn encode(self) -> RawMessage {
-        match self {
-            ClientMessage::Start(m)    => RawMessage::new(MC_START, m.encode()),
-        }
+impl CStart {
+    pub fn create(version: ProtocolVersion, subprotocol: u8, checksum: Option<Checksum>, sub_args: Vec<u8>)
+        -> ClientMessage
+    {
+        ClientMessage::Start(CStart{
+            version: version,
+            subprotocol: subprotocol,
+            checksum: checksum,
+            args: sub_args,
+        })
     }
+}
 
-    pub fn parse(raw_message: RawMessage) -> Result<ClientMessage, ParseError> {
-        match raw_message.mtype {
-            MC_START    => Ok(CStart::parse(raw_message.body)?),
-            _           => Err(ParseError::UnknownCode)
-        }
+
+impl EncodeTo for CStart {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.version.encode_to(w)?;
+        self.subprotocol.encode_to(w)?;
+        self.checksum.map_or(0, |checksum| checksum.code()).encode_to(w)?;
+        w.write_all(&[0u8, 0u8])?;
+        // TODO self.args
+        Ok(())
     }
 }
 
 
-//impl RawCodec for ServerMessage {
-impl ServerMessage {
-    pub fn encode(self) -> RawMessage {
-        match self {
-            ServerMessage::AuthOk(m)   => RawMessage::new(MS_AUTH_OK, m.encode()),
-            ServerMessage::Reject(m)   => RawMessage::new(MS_REJECT, m.encode()),
-            ServerMessage::Error(m)    => RawMessage::new(MS_ERROR, m.encode()),
-        }
-    }
+impl Parse for CStart {
+    fn parse_from(input: &mut Parser) -> Result<CStart, ParserError> {
+        let version     = ProtocolVersion::parse_from(input)?;
+        let subprotocol = u8::parse_from(input)?;
+        // Unknown checksums are not requested
+        let checksum    = Checksum::from_code(u8::parse_from(input)?);
 
-    pub fn parse(raw_message: RawMessage) -> Result<ServerMessage, ParseError> {
-        Ok(match raw_message.mtype {
-            MS_AUTH_OK  => SAuthOk::parse(raw_message.body)?,
-            MS_REJECT   => SReject::parse(raw_message.body)?,
-            MS_ERROR    => SError::parse(raw_message.body)?,
-            _           => return Err(ParseError::UnknownCode)
-        })
+        u16::parse_from(input)?;
+
+        let sub_args: Vec<u8> = Vec::new();
+
+        Ok(CStart { version: version, subprotocol: subprotocol, checksum: checksum, args: sub_args })
     }
 }
 
 
-// --------------------------------------------------------------------------------------------------------------------
+impl CAuthPlain {
+    pub fn create(login: String, password: String) -> ClientMessage {
+        ClientMessage::AuthPlain(CAuthPlain{ login: login, password: password })
+    }
+}
 
 
-//impl RawCodec for CStart {
-impl CStart {
-    pub fn create(version: ProtocolVersion, subprotocol: u8, sub_args: Vec<u8>) -> ClientMessage {
-        ClientMessage::Start(CStart{
-            version: version,
-            subprotocol: subprotocol,
-            args: sub_args,
-        })
+impl CAuthHash {
+    pub fn create(login: String, nonce: Vec<u8>, mac: Vec<u8>) -> ClientMessage {
+        ClientMessage::AuthHash(CAuthHash{ login: login, nonce: Bytes(nonce), mac: Bytes(mac) })
     }
+}
 
-    pub fn encode(self) -> RawMessageBody {
-        let mut encode = Encoder::new();
 
-        encode += self.version.0;
-        encode += self.version.1;
-        encode += self.version.2;
-        encode += self.subprotocol;
-        encode += 0u8;
-        encode += 0u8;
-        encode += 0u8;
-        // TODO self.args
+impl CAuthSCM {
+    pub fn create() -> ClientMessage {
+        ClientMessage::AuthSCM(CAuthSCM)
+    }
+}
 
-        RawMessageBody::Binary(encode.complete())
+
+impl EncodeTo for CAuthSCM {
+    fn encode_to<W: Write>(&self, _: &mut W) -> io::Result<()> {
+        Ok(())
     }
+}
 
-    pub fn parse(body: RawMessageBody) -> Result<ClientMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
 
-                let mut input = Parser::new(v);
+impl Parse for CAuthSCM {
+    fn parse_from(_: &mut Parser) -> Result<CAuthSCM, ParserError> {
+        Ok(CAuthSCM)
+    }
+}
 
-                let version     = ProtocolVersion::new(
-                    u8::parse_from(&mut input)?,
-                    u8::parse_from(&mut input)?,
-                    u16::parse_from(&mut input)?
-                );
-                let subprotocol = u8::parse_from(&mut input)?;
 
-                u8::parse_from(&mut input)?;
-                u16::parse_from(&mut input)?;
+/// Messages are logged, so the password is left out
+impl fmt::Debug for CAuthPlain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CAuthPlain {{ login: {:?}, password: \"***\" }}", self.login)
+    }
+}
 
-                input.complete()?;
 
-                let sub_args: Vec<u8> = Vec::new();
+impl CAuthToken {
+    pub fn create(token: String) -> ClientMessage {
+        ClientMessage::AuthToken(CAuthToken{ token: token })
+    }
+}
 
-                Ok(CStart::create(version, subprotocol, sub_args))
-            },
-            _ => Err(ParseError::BadProtocol)
-        }
+
+impl fmt::Debug for CAuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CAuthToken {{ token: \"***\" }}")
     }
 }
 
@@ -184,57 +272,97 @@ impl CStart {
 
 
 impl SAuthOk {
-    pub fn create(id: usize) -> ServerMessage {
-        ServerMessage::AuthOk(SAuthOk{id: id})
+    pub fn create(id: usize, checksum: Option<Checksum>, ticket: Option<SessionTicket>) -> ServerMessage {
+        ServerMessage::AuthOk(SAuthOk{id: id, checksum: checksum, ticket: ticket})
     }
+}
 
-    pub fn encode(self) -> RawMessageBody {
 
-        let mut encode = Encoder::new();
-        encode += self.id;
-        RawMessageBody::Binary(encode.complete())
+/// The checksum and the ticket are only appended when present, as clients prior to 0.1.8 expect.
+/// The code of the checksum is 0 before a ticket if no checksum is used
+impl EncodeTo for SAuthOk {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.id.encode_to(w)?;
+        if self.checksum.is_some() || self.ticket.is_some() {
+            self.checksum.map_or(0, |checksum| checksum.code()).encode_to(w)?;
+        }
+        if let Some(ref ticket) = self.ticket {
+            ticket.encode_to(w)?;
+        }
+        Ok(())
     }
+}
 
-    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
-
-                let mut input = Parser::new(v);
-                let id          = usize::parse_from(&mut input)?;
-                input.complete()?;
 
-                Ok(SAuthOk::create(id))
+impl Parse for SAuthOk {
+    fn parse_from(input: &mut Parser) -> Result<SAuthOk, ParserError> {
+        let id          = usize::parse_from(input)?;
+        let checksum    = match input.is_complete() {
+            true    => None,
+            false   => match u8::parse_from(input)? {
+                0       => None,
+                code    => Some(Checksum::from_code(code).ok_or(ParserError::InvalidValue)?),
             },
-            _ => Err(ParseError::BadProtocol)
-        }
+        };
+        let ticket      = match input.is_complete() {
+            true    => None,
+            false   => Some(SessionTicket::parse_from(input)?),
+        };
+        Ok(SAuthOk{ id: id, checksum: checksum, ticket: ticket })
     }
 }
 
 
-impl SReject {
-    pub fn create(reason: String) -> ServerMessage {
-        ServerMessage::Reject(SReject{ reason: reason })
+impl SessionTicket {
+    pub fn new(run_id: String, secret: Vec<u8>) -> SessionTicket {
+        SessionTicket { run_id: run_id, secret: Bytes(secret) }
     }
 
-    pub fn encode(self) -> RawMessageBody {
+    /// The server keeps the session for a while if the connection is lost
+    pub fn is_resumable(&self) -> bool {
+        !self.secret.0.is_empty()
+    }
+}
+
 
-        let mut encode = Encoder::new();
-        encode += self.reason;
-        RawMessageBody::Binary(encode.complete())
+/// The secret is left out of the logs
+impl fmt::Debug for SessionTicket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SessionTicket {{ run_id: {:?}, resumable: {} }}", self.run_id, self.is_resumable())
     }
+}
 
-    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
 
-                let mut input = Parser::new(v);
-                let reason      = String::parse_from(&mut input)?;
-                input.complete()?;
+impl SReject {
+    pub fn create(reason: String) -> ServerMessage {
+        ServerMessage::Reject(SReject{ reason: reason, versions: None })
+    }
 
-                Ok(SReject::create(reason))
-            },
-            _ => Err(ParseError::BadProtocol),
+    pub fn create_version(reason: String, versions: ProtocolVersionRange) -> ServerMessage {
+        ServerMessage::Reject(SReject{ reason: reason, versions: Some(versions) })
+    }
+}
+
+
+impl EncodeTo for SReject {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.reason.encode_to(w)?;
+        if let Some(ref versions) = self.versions {
+            versions.encode_to(w)?;
         }
+        Ok(())
+    }
+}
+
+
+impl Parse for SReject {
+    fn parse_from(input: &mut Parser) -> Result<SReject, ParserError> {
+        let reason      = String::parse_from(input)?;
+        let versions    = match input.is_complete() {
+            true    => None,
+            false   => Some(ProtocolVersionRange::parse_from(input)?),
+        };
+        Ok(SReject{ reason: reason, versions: versions })
     }
 }
 
@@ -243,25 +371,46 @@ impl SError {
     pub fn create(message: String) -> ServerMessage {
         ServerMessage::Error(SError{ message: message })
     }
+}
 
-    pub fn encode(self) -> RawMessageBody {
 
-        let mut encode = Encoder::new();
-        encode += self.message;
-        RawMessageBody::Binary(encode.complete())
+impl SRequestAuthPlain {
+    pub fn create() -> ServerMessage {
+        ServerMessage::RequestAuthPlain(SRequestAuthPlain)
     }
+}
+
 
-    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
+impl EncodeTo for SRequestAuthPlain {
+    fn encode_to<W: Write>(&self, _: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-                let mut input = Parser::new(v);
-                let message     = String::parse_from(&mut input)?;
-                input.complete()?;
 
-                Ok(SError::create(message))
-            },
-            _ => Err(ParseError::BadProtocol),
-        }
+impl Parse for SRequestAuthPlain {
+    fn parse_from(_: &mut Parser) -> Result<SRequestAuthPlain, ParserError> {
+        Ok(SRequestAuthPlain)
+    }
+}
+
+
+impl SRequestAuthHash {
+    pub fn create(nonce: Vec<u8>, plain: bool) -> ServerMessage {
+        ServerMessage::RequestAuthHash(SRequestAuthHash{ nonce: Bytes(nonce), plain: plain })
+    }
+}
+
+
+impl SAuthProof {
+    pub fn create(mac: Vec<u8>) -> ServerMessage {
+        ServerMessage::AuthProof(SAuthProof{ mac: Bytes(mac) })
+    }
+}
+
+
+impl SRequestAuthSCM {
+    pub fn create(identity: String) -> ServerMessage {
+        ServerMessage::RequestAuthSCM(SRequestAuthSCM{ identity: identity })
     }
 }
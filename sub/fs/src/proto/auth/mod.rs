@@ -1,5 +1,796 @@
 
 pub mod client;
+pub mod mac;
 pub mod message;
 pub mod server;
 
+
+#[cfg(all(test, unix))]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use unix_socket::UnixStream;
+
+    use protocol::checksum::Checksum;
+    use protocol::message::{Message, RawMessage, RawMessageBody, WriteFrame};
+    use protocol::serde::{EncodeTo, Parse, Parser};
+    use protocol::stream::Stream;
+    use protocol::tls;
+    use protocol::tls::TlsStream;
+    use protocol::workflow::{Protocol, Workflow, WorkflowError, ProtocolVersion, ProtocolVersionRange};
+
+    use ::client::connection::Connection;
+    use ::connection::{StreamSender, Traffic};
+    use ::server::admission::Admission;
+    use ::server::config::{Config, UnixIdentity};
+    use ::server::control::{Peer, ServerControl};
+    use ::server::credentials::{Authenticator, Credentials, PasswordHash, Secret};
+    use ::server::permissions::{Permission, Permissions};
+    use ::server::registry::Registry;
+    use ::server::tokens::{Claims, TokenAuthority};
+    use ::testing;
+    use super::client::{AuthConfig as ClientConfig, AuthProtocol as ClientProtocol, AuthError};
+    use super::server::{AuthConfig as ServerConfig, AuthProtocol as ServerProtocol, refuse_unadmitted};
+    use super::mac;
+    use super::mac::Session;
+    use super::message::{CAuthHash, CAuthPlain, CAuthSCM, CAuthToken, CStart, ClientMessage, SAuthOk, SReject};
+    use super::message::{MS_REJECT, SRequestAuthHash, SRequestAuthPlain, SRequestAuthSCM, ServerMessage, SessionTicket};
+    use super::super::{AUTH_HASH_VERSION, AUTH_TOKEN_VERSION, CHECKSUM_VERSION, PROTOCOL_VERSION, SUPPORTED_VERSIONS};
+    use super::super::VERSION_RANGE_VERSION;
+
+
+    fn client_config(version: ProtocolVersion) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.version = version;
+        config
+    }
+
+    fn server_config(min: ProtocolVersion, max: ProtocolVersion) -> ServerConfig {
+        let mut config = ServerConfig::new();
+        config.versions = ProtocolVersionRange::new(min, max);
+        config
+    }
+
+    /// Runs the client side of the auth stage against a server side over a socket pair
+    fn handshake(client: ClientConfig, server: ServerConfig) -> (Result<usize, AuthError>, Workflow) {
+        let (result, workflow, _) = handshake_sender(client, server);
+        (result, workflow)
+    }
+
+    /// Returns the sender of the server side along with the handshake result
+    fn handshake_sender(client: ClientConfig, server: ServerConfig)
+        -> (Result<usize, AuthError>, Workflow, StreamSender)
+    {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let connection = Connection::new(Stream::Unix(client_stream));
+            ClientProtocol::with_config(connection, client).auth()
+        });
+
+        let (workflow, sender) = serve(Stream::Unix(server_stream), server);
+        (client.join().unwrap(), workflow, sender)
+    }
+
+    /// Runs the server side of the auth stage on the stream until it ends
+    fn serve(mut stream: Stream, server: ServerConfig) -> (Workflow, StreamSender) {
+        let (tx, rx) = channel();
+        let sender = StreamSender::new(tx);
+        let protocol = ServerProtocol::with_config(sender.clone(), 1, server);
+        let mut workflow = Workflow::Continue;
+        while let Workflow::Continue = workflow {
+            // The client may hang up instead of answering a request
+            workflow = match Connection::_read(&mut stream) {
+                Ok(message) => protocol.flow(message),
+                Err(_) => Workflow::Terminate(WorkflowError::ConnectionError),
+            };
+            while let Ok(Some(message)) = rx.try_recv() {
+                let mut buf = Vec::new();
+                message.write_frame(&mut buf).unwrap();
+                stream.write(&buf).unwrap();
+            }
+        }
+        (workflow, sender)
+    }
+
+    /// Runs the client over TCP against a fake server, which gives the answers once the client has started.
+    /// Returns what the client has sent after the start.
+    fn fake_tcp_server(client: ClientConfig, answers: Vec<ServerMessage>)
+        -> (Result<usize, AuthError>, Vec<RawMessage>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let connection = Connection::new(Stream::Tcp(TcpStream::connect(addr).unwrap()));
+            ClientProtocol::with_config(connection, client).auth()
+        });
+
+        let mut stream = Stream::Tcp(listener.accept().unwrap().0);
+        Connection::_read(&mut stream).unwrap();
+        for message in answers {
+            // The client may have hung up already
+            let _ = stream.write(Message::from_raw(message.encode()).unwrap().as_bytes());
+        }
+        let result = client.join().unwrap();
+        let mut sent = Vec::new();
+        while let Ok(message) = Connection::_read(&mut stream) {
+            sent.push(message);
+        }
+        (result, sent)
+    }
+
+    fn password_config(secure_transport: bool) -> ServerConfig {
+        let mut credentials = Credentials::default();
+        credentials.add("alice".to_owned(), Secret::Password(PasswordHash::with_salt("secret", 2, b"salt".to_vec())));
+        credentials.add("robot".to_owned(), Secret::Key(vec![7; 32]));
+        let mut config = ServerConfig::new();
+        config.authenticator = Some(Arc::new(Authenticator::new(Some(credentials))));
+        config.secure_transport = secure_transport;
+        config
+    }
+
+    /// The server only accepts the tokens of the authority
+    fn token_config(secure_transport: bool, tokens: TokenAuthority) -> ServerConfig {
+        let mut config = ServerConfig::new();
+        config.authenticator = Some(Arc::new(Authenticator::new(None).with_tokens(tokens)));
+        config.secure_transport = secure_transport;
+        config
+    }
+
+    fn upload_claims() -> Claims {
+        Claims::new("ci".to_owned(), Permissions::of(&[Permission::Import]), Duration::from_secs(60)).unwrap()
+    }
+
+    fn login(login: &str, password: &str) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.login = Some((login.to_owned(), password.to_owned()));
+        config
+    }
+
+    fn with_key(login: &str, key: Vec<u8>) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.key = Some((login.to_owned(), key));
+        config
+    }
+
+    /// Passes the message to the server side and returns its answers
+    fn exchange(protocol: &ServerProtocol, rx: &::std::sync::mpsc::Receiver<::connection::StreamMessage>,
+                message: ClientMessage) -> (Workflow, Vec<ServerMessage>)
+    {
+        let workflow = protocol.flow(message.encode());
+        let mut answers = Vec::new();
+        while let Ok(message @ Some(_)) = rx.try_recv() {
+            answers.push(ServerMessage::parse(testing::decode(message)).unwrap());
+        }
+        (workflow, answers)
+    }
+
+    #[test]
+    fn current_client_current_server() {
+        match handshake(ClientConfig::new(), ServerConfig::new()) {
+            (Ok(_), Workflow::SwitchProtocol(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn old_supported_client_current_server() {
+        match handshake(client_config(SUPPORTED_VERSIONS.min), ServerConfig::new()) {
+            (Ok(_), Workflow::SwitchProtocol(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn old_client_current_server() {
+        // The client is told the accepted versions if it is new enough to parse them
+        let version = ProtocolVersion(0, 1, 0);
+        match handshake(client_config(version), ServerConfig::new()) {
+            (Err(AuthError::WorkflowError(_)), Workflow::Terminate(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+        match handshake(client_config(VERSION_RANGE_VERSION), server_config(PROTOCOL_VERSION, PROTOCOL_VERSION)) {
+            (Err(AuthError::InacceptableProtocol(v, versions)), Workflow::Terminate(_)) => {
+                assert_eq!(v, VERSION_RANGE_VERSION);
+                assert_eq!(versions, ProtocolVersionRange::new(PROTOCOL_VERSION, PROTOCOL_VERSION));
+            },
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn old_client_parses_rejection() {
+        let (tx, rx) = channel();
+        let protocol = ServerProtocol::with_config(StreamSender::new(tx), 1, ServerConfig::new());
+        let start = CStart::create(ProtocolVersion(0, 1, 0), 1, None, Vec::new());
+        assert!(match protocol.flow(start.encode()) { Workflow::Terminate(_) => true, _ => false });
+
+        // As parsed by clients prior to 0.1.2: the reason alone, with nothing left over
+        let raw = testing::decode(rx.try_recv().unwrap());
+        assert_eq!(raw.mtype, MS_REJECT);
+        let mut input = match raw.body {
+            RawMessageBody::Binary(body) => Parser::new(body),
+            body => panic!("Unexpected body {:?}", body),
+        };
+        assert!(String::parse_from(&mut input).unwrap().contains("Unsupported protocol version"));
+        input.complete().unwrap();
+    }
+
+    #[test]
+    fn newer_client_current_server() {
+        let version = ProtocolVersion(PROTOCOL_VERSION.0, PROTOCOL_VERSION.1 + 1, 0);
+        match handshake(client_config(version), ServerConfig::new()) {
+            (Err(AuthError::InacceptableProtocol(_, versions)), Workflow::Terminate(_)) => {
+                assert_eq!(versions, SUPPORTED_VERSIONS);
+            },
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn current_client_old_server() {
+        let old = ProtocolVersion(0, 1, 1);
+        match handshake(ClientConfig::new(), server_config(old, old)) {
+            (Err(AuthError::InacceptableProtocol(v, versions)), Workflow::Terminate(_)) => {
+                assert_eq!(v, PROTOCOL_VERSION);
+                assert_eq!(versions, ProtocolVersionRange::new(old, old));
+            },
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn reject_without_versions() {
+        // Servers prior to 0.1.2 reject without a list of accepted versions
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            ClientProtocol::new(Connection::new(Stream::Unix(client_stream))).auth()
+        });
+
+        let mut stream = Stream::Unix(server_stream);
+        Connection::_read(&mut stream).unwrap();
+        let reject = Message::from_raw(SReject::create("Go away".to_owned()).encode()).unwrap();
+        stream.write(reject.as_bytes()).unwrap();
+
+        match client.join().unwrap() {
+            Err(AuthError::WorkflowError(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn requested_subprotocol() {
+        let mut client = ClientConfig::new();
+        client.subprotocol = 9;
+        let mut server = ServerConfig::new();
+        server.subprotocols = vec![1, 9];
+        match handshake(client, server) {
+            (Ok(_), Workflow::SwitchProtocol(9)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn unknown_subprotocol() {
+        let mut client = ClientConfig::new();
+        client.subprotocol = 9;
+        match handshake(client, ServerConfig::new()) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                assert!(reason.contains("Unsupported subprotocol 9"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn negotiate_checksum() {
+        let mut client = ClientConfig::new();
+        client.checksum = Some(Checksum::Blake2b);
+        match handshake_sender(client, ServerConfig::new()) {
+            (Ok(_), Workflow::SwitchProtocol(_), sender) =>
+                assert_eq!(sender.checksum().get(), Some(Checksum::Blake2b)),
+            (r, w, _) => panic!("Unexpected handshake result {:?}", (r, w)),
+        }
+    }
+
+    #[test]
+    fn checksum_declined() {
+        let mut client = ClientConfig::new();
+        client.checksum = Some(Checksum::Crc32c);
+        let mut server = ServerConfig::new();
+        server.checksum = false;
+        match handshake_sender(client, server) {
+            (Ok(_), Workflow::SwitchProtocol(_), sender) => assert_eq!(sender.checksum().get(), None),
+            (r, w, _) => panic!("Unexpected handshake result {:?}", (r, w)),
+        }
+    }
+
+    #[test]
+    fn checksum_not_requested() {
+        match handshake_sender(ClientConfig::new(), ServerConfig::new()) {
+            (Ok(_), Workflow::SwitchProtocol(_), sender) => assert_eq!(sender.checksum().get(), None),
+            (r, w, _) => panic!("Unexpected handshake result {:?}", (r, w)),
+        }
+    }
+
+    #[test]
+    fn checksum_wire_compatibility() {
+        // Older peers send zero in place of the checksum and no trailing byte in SAuthOk
+        let mut buf = Vec::new();
+        match CStart::create(PROTOCOL_VERSION, 1, None, vec![]) {
+            ClientMessage::Start(start) => start.encode_to(&mut buf).unwrap(),
+            _ => unreachable!(),
+        }
+        let mut with_checksum = Vec::new();
+        match CStart::create(PROTOCOL_VERSION, 1, Some(Checksum::Crc32c), vec![]) {
+            ClientMessage::Start(start) => start.encode_to(&mut with_checksum).unwrap(),
+            _ => unreachable!(),
+        }
+        assert_eq!(buf.len(), with_checksum.len());
+        assert_eq!(CStart::parse_from(&mut Parser::new(buf.clone())).unwrap().checksum, None);
+        assert_eq!(CStart::parse_from(&mut Parser::new(with_checksum)).unwrap().checksum, Some(Checksum::Crc32c));
+
+        let mut buf = Vec::new();
+        match SAuthOk::create(5, None, None) {
+            ServerMessage::AuthOk(ok) => ok.encode_to(&mut buf).unwrap(),
+            _ => unreachable!(),
+        }
+        assert_eq!(buf.len(), 8);
+        let ok = SAuthOk::parse_from(&mut Parser::new(buf.clone())).unwrap();
+        assert_eq!((ok.id, ok.checksum), (5, None));
+        buf.push(Checksum::Blake2b.code());
+        assert_eq!(SAuthOk::parse_from(&mut Parser::new(buf.clone())).unwrap().checksum, Some(Checksum::Blake2b));
+    }
+
+    #[test]
+    fn session_ticket_wire() {
+        let ticket = SessionTicket::new("run".to_owned(), vec![1, 2]);
+        for checksum in vec![None, Some(Checksum::Crc32c)] {
+            let mut buf = Vec::new();
+            match SAuthOk::create(5, checksum, Some(ticket.clone())) {
+                ServerMessage::AuthOk(ok) => ok.encode_to(&mut buf).unwrap(),
+                _ => unreachable!(),
+            }
+            let ok = SAuthOk::parse_from(&mut Parser::new(buf)).unwrap();
+            assert_eq!((ok.id, ok.checksum, ok.ticket), (5, checksum, Some(ticket.clone())));
+        }
+    }
+
+    #[test]
+    fn session_ticket() {
+        let config = |resumable| {
+            let mut config = ServerConfig::new();
+            config.run_id = Some("run".to_owned());
+            config.resumable = resumable;
+            config
+        };
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 7, config(true));
+        let (workflow, answers) = exchange(&server, &rx, CStart::create(PROTOCOL_VERSION, 1, None, vec![]));
+        assert!(match workflow { Workflow::SwitchProtocol(1) => true, _ => false });
+        match answers.first() {
+            Some(&ServerMessage::AuthOk(SAuthOk { id, ticket: Some(ref ticket), .. })) => {
+                assert_eq!((id, &ticket.run_id[..]), (7, "run"));
+                assert!(ticket.is_resumable());
+                assert_eq!(server.ticket().as_ref(), Some(ticket));
+            },
+            _ => panic!("Unexpected answers {:?}", answers),
+        }
+
+        // A server not keeping sessions names its run only, and older clients get no ticket
+        for (version, resumable, expected) in vec![(PROTOCOL_VERSION, false, Some(false)),
+                                                   (AUTH_TOKEN_VERSION, true, None)] {
+            let (tx, rx) = channel();
+            let server = ServerProtocol::with_config(StreamSender::new(tx), 7, config(resumable));
+            let (_, answers) = exchange(&server, &rx, CStart::create(version, 1, None, vec![]));
+            match answers.first() {
+                Some(&ServerMessage::AuthOk(ref ok)) => {
+                    assert_eq!(ok.ticket.as_ref().map(|ticket| ticket.is_resumable()), expected)
+                },
+                _ => panic!("Unexpected answers {:?}", answers),
+            }
+        }
+
+        // The client is given the id of its connection
+        match handshake(ClientConfig::new(), config(true)) {
+            (Ok(1), Workflow::SwitchProtocol(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn password_accepted() {
+        match handshake(login("alice", "secret"), password_config(true)) {
+            (Ok(_), Workflow::SwitchProtocol(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn password_rejected() {
+        let config = password_config(true);
+        let authenticator = config.authenticator.clone().unwrap();
+        match handshake(login("alice", "wrong"), config) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                assert!(reason.contains("Invalid login or password"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+        // The next attempt is not checked until the backoff delay passes
+        let mut config = password_config(true);
+        config.authenticator = Some(authenticator);
+        match handshake(login("alice", "secret"), config) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                assert!(reason.contains("Too many failed attempts"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn password_required() {
+        match handshake(ClientConfig::new(), password_config(true)) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), _) =>
+                assert!(reason.contains("requires a login"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn password_over_plain_tcp() {
+        // Clients prior to 0.1.5 are only asked for a password, which is refused to be sent
+        let mut client = login("alice", "secret");
+        client.version = CHECKSUM_VERSION;
+        match handshake(client, password_config(false)) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                assert!(reason.contains("requires TLS or a Unix socket"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+        // Newer clients don't send it
+        match handshake(login("alice", "secret"), password_config(false)) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), _) =>
+                assert!(reason.contains("a key is required"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn key_accepted() {
+        // No secret is sent, so a plain connection will do
+        match handshake(with_key("robot", vec![7; 32]), password_config(false)) {
+            (Ok(_), Workflow::SwitchProtocol(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn key_rejected() {
+        match handshake(with_key("robot", vec![8; 32]), password_config(false)) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                assert!(reason.contains("Invalid login or key"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+        match handshake(with_key("alice", b"secret".to_vec()), password_config(true)) {
+            (Err(AuthError::WorkflowError(_)), Workflow::Terminate(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn key_proof_replayed() {
+        let start = || CStart::create(PROTOCOL_VERSION, 1, None, vec![]);
+        let session = Session { version: PROTOCOL_VERSION, subprotocol: 1, checksum: None };
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 1, password_config(false));
+        let (workflow, answers) = exchange(&server, &rx, start());
+        assert!(match workflow { Workflow::Continue => true, _ => false });
+        let nonce = match answers.first() {
+            Some(&ServerMessage::RequestAuthHash(SRequestAuthHash { ref nonce, plain: false })) => nonce.0.clone(),
+            _ => panic!("Unexpected answers {:?}", answers),
+        };
+        let client_nonce = mac::nonce().unwrap();
+        let proof = mac::client_proof(&[7; 32], &session, "robot", &nonce, &client_nonce);
+        let auth = || CAuthHash::create("robot".to_owned(), client_nonce.clone(), proof.clone());
+        let (workflow, answers) = exchange(&server, &rx, auth());
+        assert!(match workflow { Workflow::SwitchProtocol(1) => true, _ => false });
+        match (answers.get(0), answers.get(1)) {
+            (Some(&ServerMessage::AuthProof(ref m)), Some(&ServerMessage::AuthOk(_))) => assert!(mac::verify(
+                &mac::server_proof(&[7; 32], &session, "robot", &nonce, &client_nonce), &m.mac.0)),
+            _ => panic!("Unexpected answers {:?}", answers),
+        }
+        assert_eq!(server.identity(), Some("robot".to_owned()));
+
+        // Another connection challenges with another nonce
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 2, password_config(false));
+        exchange(&server, &rx, start());
+        let (workflow, answers) = exchange(&server, &rx, auth());
+        assert!(match workflow { Workflow::Terminate(_) => true, _ => false });
+        assert!(match answers.first() { Some(&ServerMessage::Reject(_)) => true, _ => false }, "{:?}", answers);
+    }
+
+    #[test]
+    fn deferred_check() {
+        let mut config = password_config(true);
+        config.deferred = true;
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 1, config);
+        exchange(&server, &rx, CStart::create(PROTOCOL_VERSION, 1, None, vec![]));
+        let (workflow, answers) = exchange(&server, &rx, CAuthPlain::create("alice".to_owned(), "secret".to_owned()));
+        // Nothing is answered until the caller has checked the password
+        assert!(match workflow { Workflow::Continue => true, _ => false });
+        assert!(answers.is_empty(), "{:?}", answers);
+        let check = server.take_check().unwrap();
+        assert!(server.take_check().is_none());
+        let checked = thread::spawn(move || check.run()).join().unwrap();
+        assert!(match server.complete(checked) { Workflow::SwitchProtocol(1) => true, _ => false });
+        assert!(match rx.try_recv() { Ok(Some(_)) => true, _ => false });
+        assert_eq!(server.identity(), Some("alice".to_owned()));
+
+        let mut config = password_config(true);
+        config.deferred = true;
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 2, config);
+        exchange(&server, &rx, CStart::create(PROTOCOL_VERSION, 1, None, vec![]));
+        exchange(&server, &rx, CAuthPlain::create("alice".to_owned(), "wrong".to_owned()));
+        let checked = server.take_check().unwrap().run();
+        assert!(match server.complete(checked) { Workflow::Terminate(_) => true, _ => false });
+        match rx.try_recv() {
+            Ok(message @ Some(_)) => match ServerMessage::parse(testing::decode(message)).unwrap() {
+                ServerMessage::Reject(ref m) => assert!(m.reason.contains("Invalid login or password"), "{:?}", m),
+                m => panic!("Unexpected answer {:?}", m),
+            },
+            r => panic!("Unexpected answer {:?}", r),
+        }
+        assert_eq!(server.identity(), None);
+    }
+
+    #[test]
+    fn password_not_sent_over_tcp() {
+        // Whatever the server says, the password is only sent over TLS or the Unix socket
+        for request in vec![SRequestAuthPlain::create(), SRequestAuthHash::create(vec![1; 32], true)] {
+            let (result, sent) = fake_tcp_server(login("alice", "secret"), vec![request]);
+            assert!(sent.is_empty(), "{:?}", sent);
+            match result {
+                Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))) =>
+                    assert!(reason.contains("Refusing to send the password"), "{}", reason),
+                r => panic!("Unexpected handshake result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn token_not_sent_over_tcp() {
+        let mut client = ClientConfig::new();
+        client.token = Some(TokenAuthority::new(vec![3; 32]).mint(&upload_claims()));
+        let (result, sent) = fake_tcp_server(client, vec![SRequestAuthHash::create(vec![1; 32], true)]);
+        assert!(sent.is_empty(), "{:?}", sent);
+        match result {
+            Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))) =>
+                assert!(reason.contains("Refusing to send the token"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn server_must_prove_key() {
+        // A server skipping the proof is not trusted
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            ClientProtocol::with_config(Connection::new(Stream::Unix(client_stream)), with_key("robot", vec![7; 32]))
+                .auth()
+        });
+        let mut stream = Stream::Unix(server_stream);
+        Connection::_read(&mut stream).unwrap();
+        for message in vec![SRequestAuthHash::create(vec![1; 32], false), SAuthOk::create(0, None, None)] {
+            stream.write(Message::from_raw(message.encode()).unwrap().as_bytes()).unwrap();
+        }
+        match client.join().unwrap() {
+            Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))) =>
+                assert!(reason.contains("did not prove the key"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+    #[test]
+    fn peer_identity_from_config() {
+        let (_client, server) = UnixStream::pair().unwrap();
+        let stream = Stream::Unix(server);
+        let (registry, control) = (Registry::new(), ServerControl::new());
+        let uid = unsafe { ::libc::getuid() };
+        let mut db = testing::database(Config::new());
+        db.config.unix_identities = vec![
+            UnixIdentity { uid: Some(uid.wrapping_add(1)), gid: None, identity: "other".to_owned() },
+            UnixIdentity { uid: Some(uid), gid: None, identity: "local".to_owned() },
+            UnixIdentity { uid: None, gid: None, identity: "anyone".to_owned() },
+        ];
+        let auth = ServerConfig::from_config(&db, &registry, &control, 1, &stream);
+        assert_eq!(auth.peer_identity, Some("local".to_owned()));
+        assert_eq!(auth.run_id, Some(db.run_id.to_string()));
+
+        db.config.unix_identities.truncate(1);
+        let auth = ServerConfig::from_config(&db, &registry, &control, 1, &stream);
+        assert_eq!(auth.peer_identity, None);
+    }
+
+    #[test]
+    fn connection_not_admitted() {
+        let (_client, server) = UnixStream::pair().unwrap();
+        let stream = Stream::Unix(server);
+        let mut db = testing::database(Config::new());
+        db.config.max_connections = 1;
+        let control = ServerControl::new().with_admission(Admission::from_config(&db.config).unwrap());
+        let registry = Registry::new();
+        assert_eq!(ServerConfig::from_config(&db, &registry, &control, 1, &stream).rejection, None);
+
+        // The connection itself is not counted
+        control.add_connection(1, Peer::Unix(None), Traffic::default(), None);
+        assert_eq!(ServerConfig::from_config(&db, &registry, &control, 1, &stream).rejection, None);
+
+        let config = ServerConfig::from_config(&db, &registry, &control, 2, &stream);
+        match handshake(ClientConfig::new(), config) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                assert!(reason.contains("Too many connections"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn tls_connection_not_admitted_on_start() {
+        let mut db = testing::database(Config::new());
+        db.config.deny_hosts = vec!["127.0.0.1".to_owned()];
+        let control = ServerControl::new().with_admission(Admission::from_config(&db.config).unwrap());
+        let server_tls = tls::server_config(&testing::tls_data("server.pem"), &testing::tls_data("server.key"), None)
+            .unwrap();
+        let client_tls = tls::client_config(&testing::tls_data("ca.pem"), None).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TlsStream::client(TcpStream::connect(addr).unwrap(), &client_tls, "localhost");
+            ClientProtocol::with_config(Connection::new(Stream::Tls(stream)), ClientConfig::new()).auth()
+        });
+
+        let mut stream = Stream::Tls(TlsStream::server(listener.accept().unwrap().0, &server_tls));
+        // Nothing reaches a TLS client before the handshake, so the rejection waits for its start
+        assert!(!refuse_unadmitted(&control, 1, &mut stream));
+        let config = ServerConfig::from_config(&db, &Registry::new(), &control, 1, &stream);
+        let (workflow, _) = serve(stream, config);
+        assert!(match workflow { Workflow::Terminate(_) => true, _ => false });
+        match client.join().unwrap() {
+            Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))) =>
+                assert!(reason.contains("not admitted") && reason.contains("not allowed"), "{}", reason),
+            r => panic!("Unexpected auth result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peer_identity_accepted() {
+        let mut config = password_config(false);
+        config.peer_identity = Some("local".to_owned());
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 1, config);
+        let (workflow, answers) = exchange(&server, &rx, CStart::create(PROTOCOL_VERSION, 1, None, vec![]));
+        assert!(match workflow { Workflow::Continue => true, _ => false });
+        match answers.first() {
+            Some(&ServerMessage::RequestAuthSCM(SRequestAuthSCM { ref identity })) => assert_eq!(identity, "local"),
+            _ => panic!("Unexpected answers {:?}", answers),
+        }
+        let (workflow, answers) = exchange(&server, &rx, CAuthSCM::create());
+        assert!(match workflow { Workflow::SwitchProtocol(1) => true, _ => false });
+        assert!(match answers.first() { Some(&ServerMessage::AuthOk(_)) => true, _ => false }, "{:?}", answers);
+        assert_eq!(server.identity(), Some("local".to_owned()));
+
+        // A client without credentials is identified all the same
+        let mut config = password_config(false);
+        config.peer_identity = Some("local".to_owned());
+        match handshake(ClientConfig::new(), config) {
+            (Ok(_), Workflow::SwitchProtocol(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+
+        // A client with a key requires the server to prove it
+        let mut config = password_config(false);
+        config.peer_identity = Some("local".to_owned());
+        match handshake(with_key("robot", vec![7; 32]), config) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                assert!(reason.contains("instead of proving the key"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peer_identity_not_over_tcp() {
+        // Peer credentials mean nothing over the network, so a fake server can't skip the auth with them
+        for client in vec![ClientConfig::new(), with_key("robot", vec![7; 32])] {
+            let answers = vec![SRequestAuthSCM::create("local".to_owned()), SAuthOk::create(0, None, None)];
+            let (result, sent) = fake_tcp_server(client, answers);
+            assert!(sent.is_empty(), "{:?}", sent);
+            match result {
+                Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))) =>
+                    assert!(reason.contains("over a network connection"), "{}", reason),
+                r => panic!("Unexpected handshake result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn peer_identity_old_client() {
+        // Clients prior to 0.1.6 authenticate as before
+        let mut config = password_config(false);
+        config.peer_identity = Some("local".to_owned());
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 1, config);
+        let (_, answers) = exchange(&server, &rx, CStart::create(AUTH_HASH_VERSION, 1, None, vec![]));
+        assert!(match answers.first() { Some(&ServerMessage::RequestAuthHash(_)) => true, _ => false });
+        let (workflow, _) = exchange(&server, &rx, CAuthSCM::create());
+        assert!(match workflow { Workflow::Terminate(_) => true, _ => false });
+        assert_eq!(server.identity(), None);
+    }
+
+    #[test]
+    fn token_accepted() {
+        let tokens = TokenAuthority::new(vec![3; 32]);
+        let claims = upload_claims();
+        let token = tokens.mint(&claims);
+        let mut client = ClientConfig::new();
+        client.token = Some(token.clone());
+        match handshake(client, token_config(true, TokenAuthority::new(vec![3; 32]))) {
+            (Ok(_), Workflow::SwitchProtocol(_)) => (),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 1, token_config(true, tokens));
+        exchange(&server, &rx, CStart::create(PROTOCOL_VERSION, 1, None, vec![]));
+        let (workflow, answers) = exchange(&server, &rx, CAuthToken::create(token));
+        assert!(match workflow { Workflow::SwitchProtocol(1) => true, _ => false });
+        assert!(match answers.first() { Some(&ServerMessage::AuthOk(_)) => true, _ => false }, "{:?}", answers);
+        assert_eq!(server.identity(), Some("ci".to_owned()));
+        assert_eq!(server.token(), Some(claims));
+    }
+
+    #[test]
+    fn token_rejected() {
+        let revoked = upload_claims();
+        let cases = vec![
+            (TokenAuthority::new(vec![4; 32]).mint(&upload_claims()), "Invalid token signature"),
+            (TokenAuthority::new(vec![3; 32]).mint(&revoked), "Token has been revoked"),
+            ("garbage".to_owned(), "Malformed token"),
+        ];
+        for (token, expected) in cases {
+            let tokens = TokenAuthority::new(vec![3; 32]);
+            tokens.revoke(&revoked.id).unwrap();
+            let mut client = ClientConfig::new();
+            client.token = Some(token);
+            match handshake(client, token_config(true, tokens)) {
+                (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), Workflow::Terminate(_)) =>
+                    assert!(reason.contains(expected), "{}", reason),
+                r => panic!("Unexpected handshake result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn token_over_plain_tcp() {
+        let tokens = TokenAuthority::new(vec![3; 32]);
+        let token = tokens.mint(&upload_claims());
+        let mut client = ClientConfig::new();
+        client.token = Some(token.clone());
+        match handshake(client, token_config(false, TokenAuthority::new(vec![3; 32]))) {
+            (Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))), _) =>
+                assert!(reason.contains("does not accept a token"), "{}", reason),
+            r => panic!("Unexpected handshake result {:?}", r),
+        }
+
+        // A token sent anyway is not accepted
+        let (tx, rx) = channel();
+        let server = ServerProtocol::with_config(StreamSender::new(tx), 1, token_config(false, tokens));
+        exchange(&server, &rx, CStart::create(PROTOCOL_VERSION, 1, None, vec![]));
+        let (workflow, answers) = exchange(&server, &rx, CAuthToken::create(token));
+        assert!(match workflow { Workflow::Terminate(_) => true, _ => false });
+        assert!(match answers.first() { Some(&ServerMessage::Reject(_)) => true, _ => false }, "{:?}", answers);
+        assert_eq!(server.identity(), None);
+    }
+}
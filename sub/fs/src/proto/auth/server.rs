@@ -1,18 +1,62 @@
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
 
-use protocol::message::{Message, RawMessage};
-use protocol::workflow::{Protocol, Workflow, WorkflowError};
+use protocol::message::{RawMessage, WriteFrame};
+use protocol::stream::Stream;
+use protocol::workflow::{Protocol, Workflow, WorkflowError, ProtocolVersion, ProtocolVersionRange};
 
 use ::connection::{StreamSender};
-use super::message::{ClientMessage, ServerMessage, SAuthOk};
+use ::proto::content::message as content;
+use ::server::control::{Peer, ServerControl};
+use ::server::credentials::{AuthFailure, Authenticator};
+use ::server::database::Database;
+use ::server::registry::Registry;
+use ::server::tokens::Claims;
+use super::mac;
+use super::mac::Session;
+use super::message::{ClientMessage, ServerMessage, CAuthHash, CAuthPlain, CAuthSCM, CAuthToken, CStart};
+use super::message::{SAuthOk, SAuthProof, SReject, SRequestAuthHash, SRequestAuthPlain, SRequestAuthSCM};
+use super::message::SessionTicket;
+use super::super::{AUTH_HASH_VERSION, AUTH_SCM_VERSION, AUTH_TOKEN_VERSION, SESSION_VERSION, SUPPORTED_VERSIONS};
+use super::super::VERSION_RANGE_VERSION;
+
+/// How long the rejection of a refused connection may take to write
+const REFUSE_TIMEOUT_MS: u64 = 1000;
+
 
 // --------------------------------------------------------------------------------------------------------------------
 
 
 #[derive(Debug)]
 pub struct AuthConfig {
-    need_password: bool
+    /// Checks the credentials of clients, if they must authenticate
+    pub authenticator: Option<Arc<Authenticator>>,
+    /// The connection is encrypted or local, so that passwords may be sent over it
+    pub secure_transport: bool,
+    /// The identity of a Unix socket peer, given by its credentials; no password or key is asked then
+    pub peer_identity: Option<String>,
+    /// The failed logins of the peer are delayed, not the ones of the other peers
+    pub peer: Peer,
+    /// The protocol versions accepted from clients
+    pub versions: ProtocolVersionRange,
+    /// Accept frame checksums requested by clients
+    pub checksum: bool,
+    /// The codes of the subprotocols served after auth
+    pub subprotocols: Vec<u8>,
+    /// The run of the server, named to clients since 0.1.8
+    pub run_id: Option<String>,
+    /// The sessions of lost connections are kept for their clients to resume
+    pub resumable: bool,
+    /// The connection is not admitted for this reason, given to the client on start.
+    /// Other than TLS ones, such connections are mostly refused on accept already, see `refuse_unadmitted`.
+    pub rejection: Option<String>,
+    /// The credentials are not checked by the protocol itself: they are taken with `take_check`,
+    /// so that the caller checks them apart and completes the auth with `complete`
+    pub deferred: bool,
 }
 
 
@@ -20,14 +64,55 @@ pub struct AuthConfig {
 pub enum AuthProtocolStage {
     BeforeStart,
     NeedAuth,
+    /// The credentials given by the client are being checked
+    Checking,
     Ok
 }
 
 
+/// The credentials given by a client, checked with the password hashing, which takes a while
+#[derive(Debug)]
+pub struct CredentialCheck {
+    authenticator: Arc<Authenticator>,
+    peer: Peer,
+    login: String,
+    proof: Proof,
+}
+
+
+/// The outcome of a `CredentialCheck`, with the key of the login if a key is proven
+#[derive(Debug)]
+pub struct CheckedCredentials {
+    login: String,
+    proof: Proof,
+    result: Result<Option<Vec<u8>>, AuthFailure>,
+}
+
+
+enum Proof {
+    Password(String),
+    Key { session: Session, nonce: Vec<u8>, client_nonce: Vec<u8>, mac: Vec<u8> },
+}
+
+
 #[derive(Debug)]
 pub struct AuthProtocol {
     config: AuthConfig,
     stage: Cell<AuthProtocolStage>,
+    /// The protocol version of the client, once it is accepted
+    client_version: Cell<Option<ProtocolVersion>>,
+    /// The accepted start, completed once the client is authenticated
+    start: Cell<Option<Session>>,
+    /// The challenge sent to the client, used once
+    nonce: RefCell<Option<Vec<u8>>>,
+    /// The login of the authenticated client
+    identity: RefCell<Option<String>>,
+    /// The claims of the token the client has authenticated with
+    token: RefCell<Option<Claims>>,
+    /// The ticket given to the client on accepting it
+    ticket: RefCell<Option<SessionTicket>>,
+    /// The credentials to be checked by the caller, if the checks are deferred
+    check: RefCell<Option<CredentialCheck>>,
     pub id: usize,
     pub sender: StreamSender,
 }
@@ -38,26 +123,102 @@ pub struct AuthProtocol {
 
 pub fn send_message(sender: &StreamSender, message: ServerMessage) -> Result<(), ()> {
     info!("  <<  {:?}", message);
-    let message = match Message::from_raw(message.encode()) {
-        Err(_)  => return Err(()),
-        Ok(r)   => r
-    };
-    trace!("  <<<<  {:?}", message);
-    ::connection::send_message(sender, Some(message));
+    ::connection::send_message(sender, Some(Box::new(message)));
     Ok(())
 }
 
 
+/// Refuses a new connection which is not admitted, before reading anything of it:
+/// the rejection is written to the stream at once and the stream is shut down.
+/// It fits in the socket buffer of a new connection; a blocking stream waits for a short while only.
+/// A TLS client gets nothing before the handshake, so it is left to be rejected on start.
+/// Returns whether the connection is refused.
+pub fn refuse_unadmitted(control: &ServerControl, id: usize, stream: &mut Stream) -> bool {
+    if let Stream::Tls(_) = *stream {
+        return false;
+    }
+    let reason = match control.admit(id, &Peer::of(stream)) {
+        Ok(()) => return false,
+        Err(reason) => format!("Connection #{} is not admitted: {}", id, reason),
+    };
+    warn!("{}", reason);
+    let mut buf = Vec::new();
+    // The frame checksum is not negotiated yet
+    if let Err(err) = SReject::create(reason).write_frame(&mut buf) {
+        error!("Error encoding the rejection of connection #{}: {:?}", id, err);
+    } else {
+        let _ = stream.set_write_timeout(Some(Duration::from_millis(REFUSE_TIMEOUT_MS)));
+        if let Err(err) = stream.write_all(&buf) {
+            debug!("  ::  The rejection of connection #{} is not written: {:?}", id, err);
+        }
+    }
+    let _ = stream.shutdown();
+    true
+}
+
+
 // --------------------------------------------------------------------------------------------------------------------
 
 
+impl AuthConfig {
+    pub fn new() -> AuthConfig {
+        AuthConfig {
+            authenticator: None,
+            secure_transport: false,
+            peer_identity: None,
+            peer: Peer::Unknown,
+            versions: SUPPORTED_VERSIONS,
+            checksum: true,
+            subprotocols: vec![content::SUBPROTOCOL_CODE],
+            run_id: None,
+            resumable: false,
+            rejection: None,
+            deferred: false,
+        }
+    }
+
+    pub fn from_config(db: &Database, registry: &Registry, control: &ServerControl, id: usize, stream: &Stream)
+        -> AuthConfig
+    {
+        let config = &db.config;
+        let peer = Peer::of(stream);
+        let mut auth = AuthConfig::new();
+        auth.rejection = control.admit(id, &peer).err();
+        auth.run_id = Some(db.run_id.to_string());
+        auth.resumable = config.session_grace > 0;
+        auth.checksum = config.frame_checksum;
+        auth.subprotocols = registry.codes();
+        let authenticator = control.authenticator();
+        if authenticator.need_auth() {
+            auth.authenticator = Some(authenticator);
+        }
+        auth.secure_transport = match *stream {
+            Stream::Tcp(_) => false,
+            Stream::Tls(_) | Stream::Unix(_) => true,
+        };
+        auth.peer_identity = config.peer_identity(&peer);
+        auth.peer = peer;
+        auth
+    }
+}
+
+
 impl AuthProtocol {
     pub fn new(sender: StreamSender, id: usize) -> AuthProtocol {
+        Self::with_config(sender, id, AuthConfig::new())
+    }
+
+    pub fn with_config(sender: StreamSender, id: usize, config: AuthConfig) -> AuthProtocol {
         AuthProtocol{
-            config: AuthConfig{
-                need_password: false,
-            },
+            config: config,
             stage: Cell::new(AuthProtocolStage::BeforeStart),
+            client_version: Cell::new(None),
+            start: Cell::new(None),
+            nonce: RefCell::new(None),
+            identity: RefCell::new(None),
+            token: RefCell::new(None),
+            ticket: RefCell::new(None),
+            check: RefCell::new(None),
             id: id,
             sender: sender,
         }
@@ -66,6 +227,262 @@ impl AuthProtocol {
     pub fn send_message(&self, message: ServerMessage) -> Result<(), ()> {
         send_message(&self.sender, message)
     }
+
+    pub fn client_version(&self) -> Option<ProtocolVersion> {
+        self.client_version.get()
+    }
+
+    /// The login of the client, if it has authenticated
+    pub fn identity(&self) -> Option<String> {
+        self.identity.borrow().clone()
+    }
+
+    /// What the token of the client grants, if it has authenticated with one
+    pub fn token(&self) -> Option<Claims> {
+        self.token.borrow().clone()
+    }
+
+    /// The ticket given to the client, if it has been accepted with one
+    pub fn ticket(&self) -> Option<SessionTicket> {
+        self.ticket.borrow().clone()
+    }
+
+    /// The credentials given by the client, to be checked by the caller if the checks are deferred
+    pub fn take_check(&self) -> Option<CredentialCheck> {
+        self.check.borrow_mut().take()
+    }
+
+    /// Completes the auth with the credentials checked by the caller
+    pub fn complete(&self, checked: CheckedCredentials) -> Workflow {
+        match self.stage.get() {
+            AuthProtocolStage::Checking => self.on_checked(checked),
+            _ => Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned())),
+        }
+    }
+
+    /// Sends the rejection to the client, terminating the protocol
+    pub fn reject(&self, error: String) -> Workflow {
+        warn!("{}", error);
+        let _ = self.send_message(SReject::create(error.clone()));
+        Workflow::Terminate(WorkflowError::ProtocolError(error))
+    }
+
+    fn on_start(&self, c: CStart) -> Workflow {
+        if let Some(ref reason) = self.config.rejection {
+            return self.reject(format!("Connection #{} is not admitted: {}", self.id, reason));
+        }
+
+        if !self.config.versions.contains(&c.version) {
+            let error = format!("Unsupported protocol version {}; accepted: {}", c.version, self.config.versions);
+            // Older clients can't parse a rejection with the accepted versions
+            if c.version < VERSION_RANGE_VERSION {
+                return self.reject(error);
+            }
+            warn!("{}", error);
+            let _ = self.send_message(SReject::create_version(error.clone(), self.config.versions));
+            return Workflow::Terminate(WorkflowError::ProtocolError(error))
+        }
+
+        if !self.config.subprotocols.contains(&c.subprotocol) {
+            return self.reject(format!("Unsupported subprotocol {}", c.subprotocol));
+        }
+
+        self.start.set(Some(Session { version: c.version, subprotocol: c.subprotocol, checksum: c.checksum }));
+        if let (Some(identity), true) = (self.config.peer_identity.clone(), c.version >= AUTH_SCM_VERSION) {
+            self.stage.set(AuthProtocolStage::NeedAuth);
+            return match self.send_message(SRequestAuthSCM::create(identity)) {
+                Ok(_)   => Workflow::Continue,
+                Err(_)  => Workflow::Terminate(WorkflowError::ConnectionError)
+            };
+        }
+        if self.config.authenticator.is_none() {
+            return self.accept();
+        }
+        if c.version >= AUTH_HASH_VERSION {
+            let nonce = match mac::nonce() {
+                Ok(nonce) => nonce,
+                Err(err) => return Workflow::Terminate(WorkflowError::Exception(format!("Creating nonce: {:?}", err))),
+            };
+            *self.nonce.borrow_mut() = Some(nonce.clone());
+            self.stage.set(AuthProtocolStage::NeedAuth);
+            return match self.send_message(SRequestAuthHash::create(nonce, self.config.secure_transport)) {
+                Ok(_)   => Workflow::Continue,
+                Err(_)  => Workflow::Terminate(WorkflowError::ConnectionError)
+            };
+        }
+        if !self.config.secure_transport {
+            return self.reject("Password authentication requires TLS or a Unix socket".to_owned());
+        }
+        self.stage.set(AuthProtocolStage::NeedAuth);
+        match self.send_message(SRequestAuthPlain::create()) {
+            Ok(_)   => Workflow::Continue,
+            Err(_)  => Workflow::Terminate(WorkflowError::ConnectionError)
+        }
+    }
+
+    fn on_auth_plain(&self, c: CAuthPlain) -> Workflow {
+        let authenticator = match self.config.authenticator {
+            Some(ref authenticator) => authenticator,
+            None => return Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned())),
+        };
+        if !self.config.secure_transport {
+            return self.reject("Password authentication requires TLS or a Unix socket".to_owned());
+        }
+        self.check(CredentialCheck {
+            authenticator: authenticator.clone(),
+            peer: self.config.peer.clone(),
+            login: c.login,
+            proof: Proof::Password(c.password),
+        })
+    }
+
+    fn on_auth_hash(&self, c: CAuthHash) -> Workflow {
+        let nonce = self.nonce.borrow_mut().take();
+        let (authenticator, session, nonce) = match (&self.config.authenticator, self.start.get(), nonce) {
+            (&Some(ref authenticator), Some(session), Some(nonce)) => (authenticator, session, nonce),
+            _ => return Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned())),
+        };
+        if c.nonce.0.len() != mac::NONCE_SIZE {
+            return self.reject(format!("Invalid nonce size {}", c.nonce.0.len()));
+        }
+        self.check(CredentialCheck {
+            authenticator: authenticator.clone(),
+            peer: self.config.peer.clone(),
+            login: c.login,
+            proof: Proof::Key { session: session, nonce: nonce, client_nonce: c.nonce.0, mac: c.mac.0 },
+        })
+    }
+
+    /// Checks the credentials, or leaves them to the caller if the checks are deferred
+    fn check(&self, check: CredentialCheck) -> Workflow {
+        self.stage.set(AuthProtocolStage::Checking);
+        if self.config.deferred {
+            *self.check.borrow_mut() = Some(check);
+            return Workflow::Continue;
+        }
+        self.on_checked(check.run())
+    }
+
+    fn on_checked(&self, checked: CheckedCredentials) -> Workflow {
+        let CheckedCredentials { login, proof, result } = checked;
+        match (proof, result) {
+            (Proof::Password(_), Ok(_)) => {
+                info!("  ::  Connection #{} authenticated as {}", self.id, login);
+            },
+            (Proof::Key { session, nonce, client_nonce, .. }, Ok(Some(key))) => {
+                info!("  ::  Connection #{} authenticated as {} with a key", self.id, login);
+                let proof = mac::server_proof(&key, &session, &login, &nonce, &client_nonce);
+                if self.send_message(SAuthProof::create(proof)).is_err() {
+                    return Workflow::Terminate(WorkflowError::ConnectionError);
+                }
+            },
+            (Proof::Key { .. }, Ok(None)) => return self.reject_failure(&login, "key", AuthFailure::BadCredentials),
+            (Proof::Password(_), Err(failure)) => return self.reject_failure(&login, "password", failure),
+            (Proof::Key { .. }, Err(failure)) => return self.reject_failure(&login, "key", failure),
+        }
+        *self.identity.borrow_mut() = Some(login);
+        self.accept()
+    }
+
+    fn on_auth_token(&self, c: CAuthToken) -> Workflow {
+        let (authenticator, session) = match (&self.config.authenticator, self.start.get()) {
+            (&Some(ref authenticator), Some(session)) if session.version >= AUTH_TOKEN_VERSION =>
+                (authenticator, session),
+            _ => return Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned())),
+        };
+        if !self.config.secure_transport {
+            return self.reject("Token authentication requires TLS or a Unix socket".to_owned());
+        }
+        match authenticator.authenticate_token(&c.token) {
+            Ok(claims) => {
+                info!("  ::  Connection #{} authenticated as {} with token {} (scopes: {}), subprotocol {}",
+                    self.id, claims.identity, claims.id, claims.scopes, session.subprotocol);
+                *self.identity.borrow_mut() = Some(claims.identity.clone());
+                *self.token.borrow_mut() = Some(claims);
+                self.accept()
+            },
+            Err(err) => self.reject(format!("{}", err)),
+        }
+    }
+
+    fn on_auth_scm(&self, _: CAuthSCM) -> Workflow {
+        // Only offered to the clients since 0.1.6
+        let identity = match (&self.config.peer_identity, self.start.get()) {
+            (&Some(ref identity), Some(session)) if session.version >= AUTH_SCM_VERSION => identity.clone(),
+            _ => return Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned())),
+        };
+        info!("  ::  Connection #{} authenticated as {} by its peer credentials", self.id, identity);
+        *self.identity.borrow_mut() = Some(identity);
+        self.accept()
+    }
+
+    fn reject_failure(&self, login: &str, secret: &str, failure: AuthFailure) -> Workflow {
+        match failure {
+            AuthFailure::BadCredentials => self.reject(format!("Invalid login or {} for {}", secret, login)),
+            AuthFailure::Backoff(delay) => self.reject(format!("Too many failed attempts for {}; retry in {} s",
+                login, delay.as_secs() + if delay.subsec_nanos() > 0 { 1 } else { 0 })),
+        }
+    }
+
+    /// Completes the start, switching to the requested subprotocol
+    fn accept(&self) -> Workflow {
+        let Session { version, subprotocol, checksum } = match self.start.get() {
+            Some(start) => start,
+            None => return Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned())),
+        };
+        self.stage.set(AuthProtocolStage::Ok);
+        self.client_version.set(Some(version));
+        let checksum = if self.config.checksum { checksum } else { None };
+        let ticket = match self.config.run_id {
+            Some(ref run_id) if version >= SESSION_VERSION => {
+                let secret = match self.config.resumable {
+                    true => match mac::nonce() {
+                        Ok(secret) => secret,
+                        Err(err) => return Workflow::Terminate(
+                            WorkflowError::Exception(format!("Creating session secret: {:?}", err))),
+                    },
+                    false => vec![],
+                };
+                Some(SessionTicket::new(run_id.clone(), secret))
+            },
+            _ => None,
+        };
+        *self.ticket.borrow_mut() = ticket.clone();
+        match self.send_message(SAuthOk::create(self.id, checksum, ticket)) {
+            Ok(_)   => {
+                // The client verifies frames whether or not it has seen the answer yet
+                self.sender.checksum().set(checksum);
+                Workflow::SwitchProtocol(subprotocol as usize)
+            },
+            Err(_)  => Workflow::Terminate(WorkflowError::ConnectionError)
+        }
+    }
+}
+
+
+impl CredentialCheck {
+    /// Checks the credentials; the failures of the login from the peer delay its next attempts
+    pub fn run(self) -> CheckedCredentials {
+        let CredentialCheck { authenticator, peer, login, proof } = self;
+        let result = match proof {
+            Proof::Password(ref password) => authenticator.authenticate(&peer, &login, password).map(|()| None),
+            Proof::Key { ref session, ref nonce, ref client_nonce, mac: ref client_mac } =>
+                authenticator.authenticate_key(&peer, &login, |key| {
+                    mac::verify(&mac::client_proof(key, session, &login, nonce, client_nonce), client_mac)
+                }).map(Some),
+        };
+        CheckedCredentials { login: login, proof: proof, result: result }
+    }
+}
+
+
+impl fmt::Debug for Proof {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Proof::Password(_) => write!(f, "Password(\"***\")"),
+            Proof::Key { ref session, .. } => write!(f, "Key {{ session: {:?} }}", session),
+        }
+    }
 }
 
 
@@ -75,29 +492,13 @@ impl <'a> Protocol for AuthProtocol {
             Err(err) => Workflow::Terminate(WorkflowError::Exception(format!("{:?}", err))),
             Ok(v) => {
                 info!("  >>  {:?}", v);
-                match v {
-                    ClientMessage::Start(c) => match self.stage.get() {
-                        AuthProtocolStage::BeforeStart => {
-                            if c.subprotocol != 1 {
-                                let error = format!("Wrong requested protocol: {:?}", c.subprotocol);
-                                error!("Wrong requested protocol: {:?}", c.subprotocol);
-                                return Workflow::Terminate(WorkflowError::Exception(error))
-                            }
-
-                            // TODO аутентификация клиента
-                            // self.stage = NeedAuth;
-                            //Workflow::Continue
-
-                            self.stage.set(AuthProtocolStage::Ok);
-                            match self.send_message(SAuthOk::create(0)) {
-                                Ok(_)   => {
-                                    Workflow::SwitchProtocol(0)
-                                },
-                                Err(_)  => Workflow::Terminate(WorkflowError::ConnectionError)
-                            }
-                        },
-                        _ => Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned()))
-                    },
+                match (v, self.stage.get()) {
+                    (ClientMessage::Start(c), AuthProtocolStage::BeforeStart) => self.on_start(c),
+                    (ClientMessage::AuthPlain(c), AuthProtocolStage::NeedAuth) => self.on_auth_plain(c),
+                    (ClientMessage::AuthHash(c), AuthProtocolStage::NeedAuth) => self.on_auth_hash(c),
+                    (ClientMessage::AuthSCM(c), AuthProtocolStage::NeedAuth) => self.on_auth_scm(c),
+                    (ClientMessage::AuthToken(c), AuthProtocolStage::NeedAuth) => self.on_auth_token(c),
+                    _ => Workflow::Terminate(WorkflowError::Exception("Wrong message order".to_owned()))
                 }
             }
         }
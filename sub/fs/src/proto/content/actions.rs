@@ -1,18 +1,24 @@
 
+use std::cell::Cell;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Receiver;
-use std::thread;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
 
 use compat::{getpid, getos};
 use ::server::database::{Database, DatabaseHolder};
+use ::server::permissions::Permission;
+use ::server::pool::{Executor, JobHandle, PoolBusy};
 
 use super::message::*;
-use super::task::{TaskHandle};
+use super::task::{TaskHandle, TaskState};
 use super::server::{send_message};
 
 #[cfg(all(target_pointer_width = "32"))] const BITS: u16 = 32;
 #[cfg(all(target_pointer_width = "64"))] const BITS: u16 = 64;
 
+/// The least interval between the progress updates of a task
+const PROGRESS_INTERVAL_MS: u64 = 250;
+
 
 #[derive(Debug)]
 pub enum ContentAction {
@@ -28,6 +34,8 @@ pub struct GetInfo;
 pub struct CopyFrom {
     pub uri: String,
     pub db: DatabaseHolder,
+    /// Whether the client takes progress updates
+    pub progress: bool,
 }
 
 
@@ -44,10 +52,29 @@ pub struct TaskContainer {
 
 
 impl ContentAction {
-    pub fn start(&self, task: TaskHolder, rx: Receiver<ClientMessage>) -> thread::JoinHandle<()> {
+    /// A short description for task listings
+    pub fn describe(&self) -> String {
+        match *self {
+            ContentAction::GetInfo => "GetInfo".to_owned(),
+            ContentAction::CopyFrom(ref a) => format!("CopyFrom {}", a.uri),
+        }
+    }
+
+    /// What the client must be allowed to start the action
+    pub fn permission(&self) -> Permission {
+        match *self {
+            ContentAction::GetInfo => Permission::Info,
+            ContentAction::CopyFrom(_) => Permission::Import,
+        }
+    }
+
+    /// Starts the job of the task; refused when the workers are too busy
+    pub fn start(&self, task: TaskHolder, rx: Receiver<ClientMessage>, executor: &Executor)
+        -> Result<JobHandle, PoolBusy>
+    {
         match *self {
-            ContentAction::GetInfo => get_info(task, rx),
-            ContentAction::CopyFrom(ref a) => copy_from(task, rx),
+            ContentAction::GetInfo => get_info(task, rx, executor),
+            ContentAction::CopyFrom(ref a) => copy_from(task, rx, executor),
         }
     }
 }
@@ -64,45 +91,78 @@ impl TaskContainer {
     }
 }
 
-fn get_info(task: TaskHolder, rx: Receiver<ClientMessage>) -> thread::JoinHandle<()> {
+fn get_info(task: TaskHolder, rx: Receiver<ClientMessage>, executor: &Executor) -> Result<JobHandle, PoolBusy> {
     use super::message::{SInfo};
 
-    thread::spawn(move || {
+    executor.spawn(move || {
         let os = getos();
-        let task = task.lock().unwrap();
+        let mut task = task.lock().unwrap();
+        if task.handle.is_finished() {
+            // Expired already
+            return;
+        }
         let info = SInfo::create(
             task.handle.task_id,
             getpid(), // FIXME pid сервера
             BITS,
             format!("{} {} {}", os.0, os.1, os.2),
         );
-        send_message(&task.handle.stream_tx, info);
-        task.handle.finished.set(true);
+        task.handle.complete(TaskState::Done, info);
     })
 }
 
 
-fn copy_from(task: TaskHolder, rx: Receiver<ClientMessage>) -> thread::JoinHandle<()> {
-    use super::message::{SError, SCopyFrom, SCopyFromState};
+fn copy_from(task: TaskHolder, rx: Receiver<ClientMessage>, executor: &Executor) -> Result<JobHandle, PoolBusy> {
+    use super::message::{ErrorCode, SCancelled, SError, SCopyFrom, SCopyFromState};
+    use ::server::import::ImportError;
 
-    thread::spawn(move || {
-        let (db, uri) = match task.lock().unwrap().action {
-            ContentAction::CopyFrom(ref action) => (action.db.clone(), action.uri.clone()),
+    executor.spawn(move || {
+        let (db, uri, updates) = match task.lock().unwrap().action {
+            ContentAction::CopyFrom(ref action) => (action.db.clone(), action.uri.clone(), action.progress),
             _ => unreachable!(),
         };
-        let result = Database::copy_from(db, &uri);
+        // The copy also stops once the connection is gone, as nobody waits for the result then
+        let cancelled = || match rx.try_recv() {
+            Ok(ClientMessage::Cancel(_)) | Err(TryRecvError::Disconnected) => true,
+            Ok(_) | Err(TryRecvError::Empty) => false,
+        };
+        let interval = Duration::from_millis(PROGRESS_INTERVAL_MS);
+        let reported = Cell::new(None::<Instant>);
+        let progress = |done: u64, total: u64| {
+            let now = Instant::now();
+            if !updates || reported.get().map_or(false, |reported| now.duration_since(reported) < interval) {
+                return;
+            }
+            reported.set(Some(now));
+            let task = task.lock().unwrap();
+            if !task.handle.is_finished() {
+                let state = SCopyFromState::Progress { done: done, total: total };
+                send_message(&task.handle.stream_tx, SCopyFrom::create(task.handle.task_id, state));
+            }
+        };
+        let result = Database::copy_from(db, &uri, &cancelled, &progress);
         {
             let mut task = task.lock().unwrap();
-            match result {
-                Ok(result) => {
-                    let result = SCopyFromState::Complete(result);
-                    send_message(&task.handle.stream_tx, SCopyFrom::create(task.handle.task_id, result));
+            if task.handle.is_finished() {
+                // Expired or cancelled already
+                return;
+            }
+            let task_id = task.handle.task_id;
+            let (state, message) = match result {
+                Ok(result) => (TaskState::Done, SCopyFrom::create(task_id, SCopyFromState::Complete(result))),
+                Err(ImportError::Cancelled) => (TaskState::Cancelled, SCancelled::create(task_id)),
+                Err(ref err) if err.is_denied() => {
+                    warn!("  ::  Import of {} is denied: {}", uri, err);
+                    let reason = format!("Import of {} is denied: {}", uri, err);
+                    (TaskState::Failed, SError::create(task_id, ErrorCode::ImportDenied, reason))
                 },
                 Err(err) => {
-                    send_message(&task.handle.stream_tx, SError::create(task.handle.task_id, format!("{:?}", err)));
+                    warn!("  ::  Import of {} has failed: {}", uri, err);
+                    let message = format!("Import of {} has failed: {}", uri, err);
+                    (TaskState::Failed, SError::create(task_id, ErrorCode::Io, message))
                 },
             };
-            task.handle.finished.set(true);
+            task.handle.complete(state, message);
         }
     })
 }
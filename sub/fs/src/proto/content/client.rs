@@ -1,19 +1,20 @@
 
 use std::collections::hash_map::{HashMap, Entry};
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time;
 
-use protocol::message::{Message, RawMessage};
-use protocol::workflow::{Protocol, Workflow, WorkflowError};
+use protocol::message::RawMessage;
+use protocol::workflow::{Protocol, Workflow};
 
 use ::connection::StreamSender;
-use ::client::connection::{Connection};
+use ::client::connection::{Connection, ConnectionReader};
+use ::proto::auth::message::SessionTicket;
 use ::types::{TaskId};
 
-use super::message::{ClientMessage, ServerMessage};
+use super::message::{ClientMessage, ServerMessage, CCancel, raw_task_id};
 use super::state;
-use super::task::{StateHandle, StateHolder, State};
+use super::task::{CompletionCallback, ProgressCallback, SimpleState, StateHandle, StateHolder, State, TaskError};
 
 
 // --------------------------------------------------------------------------------------------------------------------
@@ -21,12 +22,7 @@ use super::task::{StateHandle, StateHolder, State};
 
 pub fn send_message(sender: &StreamSender, message: ClientMessage) -> Result<(), ()> {
     info!("  >>  {:?}", message);
-    let message = match Message::from_raw(message.encode()) {
-        Err(_)  => return Err(()),
-        Ok(r)   => r
-    };
-    trace!("  >>>>  {:?}", message);
-    ::connection::send_message(sender, Some(message));
+    ::connection::send_message(sender, Some(Box::new(message)));
     Ok(())
 }
 
@@ -46,12 +42,18 @@ type TaskMap<S> = Arc<Mutex<HashMap<TaskId, StateHolder<S>>>>;
 type ContentTasks = TaskMap<state::ContentState>;
 
 
-#[derive(Debug)]
+/// The messages of the server are dispatched to the tasks by a reader thread
 pub struct ContentProtocol {
     pub connection: Connection,
     pub client_id: usize,
+    /// Given by the server on auth, to resume the session on another connection
+    ticket: Option<SessionTicket>,
     _next_id: Arc<Mutex<TaskId>>,
     tasks: ContentTasks,
+    /// Notified when tasks are finished or the connection is closed; waited for with the lock of `tasks`
+    changed: Arc<Condvar>,
+    /// Why the reader thread has stopped
+    closed: Arc<Mutex<Option<String>>>,
 }
 
 
@@ -74,18 +76,72 @@ impl TaskInterface {
         TaskInterface { protocol: protocol, task_id: task_id }
     }
 
-    pub fn wait(self) -> Result<state::ContentState, String> {
-        self.protocol.wait(self.task_id);
+    /// Waits for the result of the task. If the connection is lost, the task is kept to be resumed.
+    pub fn wait(self) -> Result<state::ContentState, TaskError> {
+        if let Err(err) = self.protocol.wait_any(&[self.task_id]) {
+            return Err(TaskError::NotFinished(err));
+        }
         self.finish()
     }
 
-    fn finish(self) -> Result<state::ContentState, String> {
-        let state_holder: StateHolder<state::ContentState> = try!(self.protocol.finish_task(self.task_id));
-        let state_handle = match Arc::try_unwrap(state_holder) {
-            Ok(mutex) => mutex.into_inner().unwrap(),
-            Err(_) => panic!("Can't unwrap Arc!"),
+    /// Waits until one of the tasks of a connection is finished and takes it out of `tasks`;
+    /// gives its id and result. If the connection is lost, the tasks are kept to be resumed.
+    pub fn wait_any(tasks: &mut Vec<TaskInterface>)
+        -> Result<(TaskId, Result<state::ContentState, TaskError>), String>
+    {
+        let protocol = match tasks.first() {
+            Some(task) => task.protocol.clone(),
+            None => return Err("No tasks to wait for".to_owned()),
         };
-        Ok(state_handle.task.into_inner())
+        if tasks.iter().any(|task| !protocol.shares_tasks(&task.protocol)) {
+            return Err("The tasks are not of one connection".to_owned());
+        }
+        let task_ids = tasks.iter().map(|task| task.task_id).collect::<Vec<_>>();
+        let index = protocol.wait_any(&task_ids).map_err(|err| format!("No task is finished: {}", err))?;
+        let task = tasks.remove(index);
+        Ok((task.task_id, task.finish()))
+    }
+
+    /// Waits for all the tasks; gives their results in order
+    pub fn wait_all(tasks: Vec<TaskInterface>) -> Vec<Result<state::ContentState, TaskError>> {
+        tasks.into_iter().map(TaskInterface::wait).collect()
+    }
+
+    /// Gives the result of the task to the callback once the task is finished, or at once if it is finished already.
+    /// The callback is called on the reader thread, so it must not wait for the tasks of the connection.
+    /// If the connection is lost, the callback is kept with the task to be resumed.
+    pub fn on_complete<F: FnMut(Result<state::ContentState, TaskError>) + Send + 'static>(self, mut callback: F) {
+        {
+            let tasks = self.protocol.tasks.lock().unwrap();
+            if let Some(state_holder) = tasks.get(&self.task_id) {
+                let state_lock = state_holder.lock().unwrap();
+                if state_lock.state.borrow().is_waiting() {
+                    *state_lock.on_complete.borrow_mut() = Some(CompletionCallback(Box::new(callback)));
+                    return;
+                }
+            }
+        }
+        callback(self.finish())
+    }
+
+    /// Sets the callback given the progress updates of the task from now on: the bytes done and the total.
+    /// The callback is called on the reader thread with the task locked, so it must not call the task.
+    pub fn on_progress<F: FnMut(u64, u64) + Send + 'static>(&self, callback: F) {
+        if let Some(state_holder) = self.protocol.tasks.lock().unwrap().get(&self.task_id) {
+            *state_holder.lock().unwrap().on_progress.borrow_mut() = Some(ProgressCallback(Box::new(callback)));
+        }
+    }
+
+    /// Asks the server to cancel the task. Wait for the task then to see whether it has been cancelled
+    /// or finished before.
+    pub fn cancel(&self) -> Result<(), String> {
+        self.protocol.send_message(CCancel::create(self.task_id))
+            .map_err(|_| format!("Failed to cancel task #{}", self.task_id))
+    }
+
+    fn finish(self) -> Result<state::ContentState, TaskError> {
+        let state_holder = self.protocol.finish_task(self.task_id).map_err(|_| TaskError::Absent)?;
+        task_result(state_holder)
     }
 }
 
@@ -108,6 +164,61 @@ impl ContentInterface {
             Err(err) => Err(err),
         }
     }
+
+    /// Like `copy_from`, with the callback set before the request is sent, so that it is given all the updates.
+    /// See `TaskInterface::on_progress`
+    pub fn copy_from_with_progress<F: FnMut(u64, u64) + Send + 'static>(&self, path: &str, callback: F)
+        -> Result<TaskInterface, RequestError>
+    {
+        let progress = ProgressCallback(Box::new(callback));
+        match self.protocol.start_task_with(state::CopyFrom::create(path.to_owned()), Some(progress)) {
+            Ok(task_id) => Ok(TaskInterface::new(self.protocol.clone(), task_id)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// A task started on this connection or on the one it has resumed, if it is not finished yet
+    pub fn task(&self, task_id: TaskId) -> Option<TaskInterface> {
+        match self.protocol.tasks.lock().unwrap().contains_key(&task_id) {
+            true => Some(TaskInterface::new(self.protocol.clone(), task_id)),
+            false => None,
+        }
+    }
+
+    /// The ids of the tasks not finished yet, in order
+    pub fn pending_tasks(&self) -> Vec<TaskId> {
+        let mut tasks = self.protocol.tasks.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        tasks.sort();
+        tasks
+    }
+
+    /// The ticket given by the server to resume the session
+    pub fn ticket(&self) -> Option<&SessionTicket> {
+        self.protocol.ticket.as_ref()
+    }
+
+    /// Takes over the tasks of this interface, whose connection is lost, on a new connection to the same server.
+    /// The tasks the server has not kept are failed.
+    pub fn resume(&self, connection: Connection, client_id: usize, ticket: Option<SessionTicket>)
+        -> Result<ContentInterface, String>
+    {
+        let lost = match self.protocol.ticket {
+            Some(ref lost) if lost.is_resumable() => lost,
+            _ => return Err("The server has given no ticket to resume the session".to_owned()),
+        };
+        if ticket.as_ref().map(|ticket| &ticket.run_id) != Some(&lost.run_id) {
+            return Err("The server has restarted since the connection was lost".to_owned());
+        }
+        let protocol = Arc::new(ContentProtocol::resumed(connection, client_id, ticket, &self.protocol));
+        let resume = state::Resume::create(self.protocol.client_id, lost.secret.0.clone());
+        let task_id = protocol.start_task(resume).map_err(|err| format!("{:?}", err))?;
+        let kept = match TaskInterface::new(protocol.clone(), task_id).wait().map_err(|err| err.to_string())? {
+            state::ContentState::Resume(resume) => resume.tasks,
+            _ => unreachable!(),
+        };
+        protocol.fail_lost_tasks(&kept);
+        Ok(ContentInterface::new(protocol))
+    }
 }
 
 
@@ -116,41 +227,84 @@ impl ContentInterface {
 
 impl ContentProtocol {
     pub fn new(connection: Connection, client_id: usize) -> ContentProtocol {
-        ContentProtocol {
+        Self::with_ticket(connection, client_id, None)
+    }
+
+    pub fn with_ticket(connection: Connection, client_id: usize, ticket: Option<SessionTicket>) -> ContentProtocol {
+        let protocol = ContentProtocol {
             connection: connection,
             client_id: client_id,
+            ticket: ticket,
             _next_id: Arc::new(Mutex::new(0)),
             tasks: Arc::new(Mutex::new(HashMap::new())),
-        }
+            changed: Arc::new(Condvar::new()),
+            closed: Arc::new(Mutex::new(None)),
+        };
+        protocol.create_reader_thread();
+        protocol
+    }
+
+    /// Shares the tasks and task ids with the protocol of the lost connection
+    fn resumed(connection: Connection, client_id: usize, ticket: Option<SessionTicket>, lost: &ContentProtocol)
+        -> ContentProtocol
+    {
+        let protocol = ContentProtocol {
+            connection: connection,
+            client_id: client_id,
+            ticket: ticket,
+            _next_id: lost._next_id.clone(),
+            tasks: lost.tasks.clone(),
+            changed: lost.changed.clone(),
+            closed: Arc::new(Mutex::new(None)),
+        };
+        protocol.create_reader_thread();
+        protocol
     }
 
+    /// Whether the tasks are shared with the other protocol, which is the same or resumes the same session
+    fn shares_tasks(&self, other: &ContentProtocol) -> bool {
+        &*self.tasks as *const _ == &*other.tasks as *const _
+    }
 
-//    /// Creates a thread that writes into the server stream each message received
-//    fn create_reader_thread(&self) {
-//
-//        let mut stream = self.connection.stream.try_clone().unwrap();
-//        let tasks = self.tasks.clone();
-//
-//        thread::spawn(move || {
-//            'read: loop {
-//                let message = match Connection::_read(&mut stream) {
-//                    Ok(message) => message,
-//                    Err(_) => break 'read,
-//                };
-//                match Self::_flow(tasks.clone(), message) {
-//                    Workflow::SwitchProtocol(client_id) => {
-//                        warn!("Irnored unexpected protocol switching");
-//                    },
-//                    Workflow::Continue                  => (),
-//                    Workflow::Terminate(err)            => {
-//                        info!("Terminated: {}", err);
-//                        break 'read;
-//                    }
-//                }
-//            }
-//        });
-//    }
+    /// Creates a thread that dispatches the messages of the server to the tasks until the connection is closed
+    fn create_reader_thread(&self) {
+        let mut reader = self.connection.reader();
+        let tasks = self.tasks.clone();
+        let changed = self.changed.clone();
+        let closed = self.closed.clone();
+        thread::spawn(move || {
+            let reason = Self::dispatch_messages(&mut reader, &tasks, &changed);
+            reader.shutdown();
+            let _tasks = tasks.lock().unwrap();
+            *closed.lock().unwrap() = Some(reason);
+            changed.notify_all();
+        });
+    }
 
+    /// Reads the messages of the server for the tasks; gives the reason the connection is closed
+    fn dispatch_messages(reader: &mut ConnectionReader, tasks: &ContentTasks, changed: &Condvar) -> String {
+        let idle = || !tasks.lock().unwrap().values().any(|task| task.lock().unwrap().state.borrow().is_waiting());
+        loop {
+            let message = match reader.read(&idle) {
+                Ok(message) => message,
+                Err(ref err) if !err.is_fatal() => {
+                    warn!("Dropped an invalid message: {:?}", err);
+                    continue;
+                },
+                Err(err) => return format!("{:?}", err),
+            };
+            let workflow = Self::_flow(tasks.clone(), message);
+            complete_tasks(tasks, changed);
+            match workflow {
+                Workflow::Continue          => (),
+                Workflow::SwitchProtocol(_) => return "Unexpected protocol switching".to_owned(),
+                Workflow::Terminate(err)    => {
+                    info!("Terminated: {}", err);
+                    return format!("{}", err);
+                }
+            }
+        }
+    }
 
     pub fn send_message(&self, message: ClientMessage) -> Result<(), ()> {
         send_message(&self.connection.sender(), message)
@@ -163,8 +317,16 @@ impl ContentProtocol {
     }
 
     fn start_task(&self, task: state::ContentState) -> Result<TaskId, RequestError> {
+        self.start_task_with(task, None)
+    }
+
+    /// Starts the task with the progress callback set already
+    fn start_task_with(&self, task: state::ContentState, on_progress: Option<ProgressCallback>)
+        -> Result<TaskId, RequestError>
+    {
         let task_id = self.next_id();
         let state_handle = StateHandle::new(task_id, self.connection.sender(), task);
+        *state_handle.on_progress.borrow_mut() = on_progress;
         let state_holder = Arc::new(Mutex::new(state_handle));
         {
             let mut tasks = self.tasks.lock().unwrap();
@@ -191,88 +353,139 @@ impl ContentProtocol {
         }
     }
 
-    pub fn wait(&self, task_id: TaskId) -> Result<(), WorkflowError> {
-        let state_holder = {
-            match self.tasks.lock()?.entry(task_id) {
-                Entry::Vacant(_) => return Err(WorkflowError::Exception(format!("Absent task id {}", task_id))),
-                Entry::Occupied(entry) => entry.get().clone(),
-            }
-        };
-
+    /// Blocks until one of the tasks is not waiting anymore; gives its index
+    fn wait_any(&self, task_ids: &[TaskId]) -> Result<usize, String> {
+        let mut tasks = self.tasks.lock().unwrap();
         loop {
-            try!(self.read_one());
-            {
+            for (index, task_id) in task_ids.iter().enumerate() {
+                let state_holder = match tasks.get(task_id) {
+                    Some(state_holder) => state_holder,
+                    None => return Err(format!("Absent task id {}", task_id)),
+                };
                 let state_lock = state_holder.lock().unwrap();
                 let state = state_lock.state.borrow();
-                if !state.is_waiting() { break; };
+                if !state.is_waiting() {
+                    return Ok(index);
+                }
             }
-            thread::sleep(time::Duration::from_millis(10));
+            if let Some(ref reason) = *self.closed.lock().unwrap() {
+                return Err(reason.clone());
+            }
+            tasks = self.changed.wait(tasks).unwrap();
         }
-        Ok(())
     }
 
-    pub fn read_one(&self) -> Result<(), WorkflowError> {
-        let message = match self.connection.read() {
-            Ok(message) => message,
-            Err(_) => return Err(WorkflowError::ConnectionError)
-        };
-        match self.flow(message) {
-            Workflow::Continue          => Ok(()),
-            Workflow::SwitchProtocol(_) => {
-                Err(WorkflowError::ProtocolError("Unexpected protocol switching".to_owned()))
-            },
-            Workflow::Terminate(err)    => {
-                info!("Terminated: {}", err);
-                Err(err)
+    /// Fails the tasks still waiting for results which the server has not kept
+    fn fail_lost_tasks(&self, kept: &[TaskId]) {
+        {
+            let tasks = self.tasks.lock().unwrap();
+            for (task_id, state_holder) in tasks.iter() {
+                let state_lock = state_holder.lock().unwrap();
+                if !kept.contains(task_id) {
+                    state_lock.fail(TaskError::Lost);
+                }
             }
         }
+        complete_tasks(&self.tasks, &self.changed);
+    }
+
+    /// Fails the task alone if it is still waiting; the connection goes on
+    fn fail_task(tasks: &ContentTasks, task_id: TaskId, error: TaskError) {
+        match tasks.lock().unwrap().get(&task_id) {
+            Some(state_holder) => state_holder.lock().unwrap().fail(error),
+            None => warn!("Dropped an error for an absent task #{}: {}", task_id, error),
+        }
     }
 
+    /// A message the tasks can't take fails the task it is given for, or is dropped;
+    /// the connection goes on anyway
     fn _flow(tasks: ContentTasks, raw_message: RawMessage) -> Workflow {
-        match ServerMessage::parse(raw_message) {
-            Err(err) => Workflow::Terminate(WorkflowError::Exception(format!("{:?}", err))),
-            Ok(message) => {
-                info!("  <<  {:?}", message);
-                let task_id = message.get_task_id();
-                let r: Result<ServerMessage, Workflow> = match message {
-                    ServerMessage::Error(m) => {
-                        Err(Workflow::Terminate(WorkflowError::ProtocolError(
-                            format!("Server error with message: {}", m.message))))
-                    },
-                    ServerMessage::Reject(m) => {
-                        Err(Workflow::Terminate(WorkflowError::ProtocolError(
-                            format!("Server rejected connection with message: {}", m.reason))))
-                    },
-                    ServerMessage::Info(m) => Ok(ServerMessage::Info(m)),
-                    ServerMessage::CopyFrom(m) => Ok(ServerMessage::CopyFrom(m)),
-                };
-                match r {
-                    Err(m) => m,
-                    Ok(m) => {
-                        let tasks = tasks.lock().unwrap();
-
-                        let state_lock = match tasks.get(&task_id) {
-                            Some(state_holder)  => state_holder.lock().unwrap(),
-                            None                =>
-                                // TODO ошибку в журнал и продолжить
-                                return Workflow::Terminate(WorkflowError::ProtocolError(
-                                    format!("Message for an absent task #{}: {:?}", task_id, m))),
-                        };
-                        let mut task = state_lock.task.borrow_mut();
-                        match task.handle_message(&*state_lock, m) {
-                            Ok(_)       => Workflow::Continue,
-                            // TODO ошибку в журнал, очистить состояние и продолжить
-                            Err(err)    => Workflow::Terminate(WorkflowError::ProtocolError(
-                                format!("Client error while processing message: {}", err)))
-                        }
-                    }
+        let raw_task_id = raw_task_id(&raw_message);
+        let message = match ServerMessage::parse(raw_message) {
+            Ok(message) => message,
+            Err(err) => {
+                match raw_task_id {
+                    Some(task_id) => Self::fail_task(&tasks, task_id, TaskError::InvalidAnswer(format!("{:?}", err))),
+                    None => warn!("Dropped an invalid message: {:?}", err),
                 }
+                return Workflow::Continue;
             }
+        };
+        info!("  <<  {:?}", message);
+        let task_id = message.get_task_id();
+        let tasks = tasks.lock().unwrap();
+        let state_lock = match tasks.get(&task_id) {
+            Some(state_holder) => state_holder.lock().unwrap(),
+            None => {
+                warn!("Dropped a message for an absent task #{}: {:?}", task_id, message);
+                return Workflow::Continue;
+            }
+        };
+        if !state_lock.state.borrow().is_waiting() {
+            warn!("Dropped a message for the finished task #{}: {:?}", task_id, message);
+            return Workflow::Continue;
+        }
+        match message {
+            ServerMessage::Error(m) => state_lock.fail(TaskError::Failed(m.code, m.message)),
+            ServerMessage::Reject(m) => state_lock.fail(TaskError::Rejected(m.reason)),
+            ServerMessage::Cancelled(m) => {
+                *state_lock.state.borrow_mut() = SimpleState::Cancelled;
+                *state_lock.task.borrow_mut() = state::Cancelled::create(m.task_id);
+            },
+            m => {
+                let result = state_lock.task.borrow_mut().handle_message(&*state_lock, m);
+                if let Err(err) = result {
+                    state_lock.fail(TaskError::InvalidAnswer(err));
+                }
+            },
+        }
+        Workflow::Continue
+    }
+}
+
+
+/// Wakes the waiters up and gives the finished tasks with completion callbacks to the callbacks,
+/// once the lock of the tasks is released
+fn complete_tasks(tasks: &ContentTasks, changed: &Condvar) {
+    let completed = {
+        let mut tasks = tasks.lock().unwrap();
+        let task_ids = tasks.iter()
+            .filter(|&(_, state_holder)| {
+                let state_lock = state_holder.lock().unwrap();
+                let finished = !state_lock.state.borrow().is_waiting() && state_lock.on_complete.borrow().is_some();
+                finished
+            })
+            .map(|(task_id, _)| *task_id)
+            .collect::<Vec<_>>();
+        task_ids.iter().filter_map(|task_id| tasks.remove(task_id)).collect::<Vec<_>>()
+    };
+    changed.notify_all();
+    for state_holder in completed {
+        let callback = {
+            let state_lock = state_holder.lock().unwrap();
+            let callback = state_lock.on_complete.borrow_mut().take();
+            callback
+        };
+        if let Some(CompletionCallback(mut callback)) = callback {
+            callback(task_result(state_holder));
         }
     }
 }
 
 
+/// The result of a finished task taken out of the tasks
+fn task_result(state_holder: StateHolder<state::ContentState>) -> Result<state::ContentState, TaskError> {
+    let state_handle = match Arc::try_unwrap(state_holder) {
+        Ok(mutex) => mutex.into_inner().unwrap(),
+        Err(_) => panic!("Can't unwrap Arc!"),
+    };
+    if let Some(error) = state_handle.error.into_inner() {
+        return Err(error);
+    }
+    Ok(state_handle.task.into_inner())
+}
+
+
 impl Protocol for ContentProtocol {
     fn flow(&self, raw_message: RawMessage) -> Workflow {
         let tasks = self.tasks.clone();
@@ -281,6 +494,14 @@ impl Protocol for ContentProtocol {
 }
 
 
+impl fmt::Debug for ContentProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ContentProtocol {{ connection: {:?}, client_id: {}, tasks: {:?}, closed: {:?} }}",
+            self.connection, self.client_id, self.tasks, self.closed)
+    }
+}
+
+
 impl Drop for ContentProtocol {
     fn drop(&mut self) {
         self.connection.stream.shutdown();
@@ -1,9 +1,12 @@
 
+use std::fmt;
 use std::str::Utf8Error;
 
 use protocol::message::{RawMessage, RawMessageBody};
-use protocol::serde::{Encoder, Encode, Parse, Parser, ParserError};
+use protocol::serde::{Bytes, ParserError, decode_u64};
+use protocol::workflow::ProtocolVersion;
 
+use ::proto::TASK_ERROR_VERSION;
 use ::types::{ContentId, TaskId};
 
 
@@ -16,45 +19,70 @@ pub enum ParseError {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Dispatch)]
 pub enum ClientMessage {
-    GetInfo(CGetInfo),
-    CopyFrom(CCopyFrom),
+    #[code = "MC_GET_INFO"]     GetInfo(CGetInfo),
+    #[code = "MC_COPY_FROM"]    CopyFrom(CCopyFrom),
+    #[code = "MC_RESUME"]       Resume(CResume),
+    #[code = "MC_CANCEL"]       Cancel(CCancel),
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Dispatch)]
 pub enum ServerMessage {
-    Info(SInfo),
-    CopyFrom(SCopyFrom),
-    Reject(SReject),
-    Error(SError),
+    #[code = "MS_INFO"]         Info(SInfo),
+    #[code = "MS_COPY_FROM"]    CopyFrom(SCopyFrom),
+    #[code = "MS_RESUMED"]      Resumed(SResumed),
+    #[code = "MS_CANCELLED"]    Cancelled(SCancelled),
+    #[code = "MS_REJECT"]       Reject(SReject),
+    #[code = "MS_ERROR"]        Error(SError),
 }
 
 // --------------------------------------------------------------------------------------------------------------------
 
+pub const SUBPROTOCOL_CODE: u8 = 1;
+
 pub const MC_GET_INFO: u8 = 1;
 pub const MC_COPY_FROM: u8 = 2;
+pub const MC_RESUME: u8 = 3;
+pub const MC_CANCEL: u8 = 4;
 
-#[derive(Debug)]
+#[derive(Debug, Encode, Parse)]
 pub struct CGetInfo {
     pub task_id: TaskId,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Encode, Parse)]
 pub struct CCopyFrom {
     pub task_id: TaskId,
     pub uri: String,
 }
 
+/// Takes over the tasks of a lost connection with the ticket it was given on auth
+#[derive(Encode, Parse)]
+pub struct CResume {
+    pub task_id: TaskId,
+    /// The id of the lost connection
+    pub id: usize,
+    pub secret: Bytes,
+}
+
+/// Stops the task; its result is not sent anymore
+#[derive(Debug, Encode, Parse)]
+pub struct CCancel {
+    pub task_id: TaskId,
+}
+
 
 pub const MS_INFO: u8 = 1;
 pub const MS_COPY_FROM: u8 = 2;
+pub const MS_RESUMED: u8 = 3;
+pub const MS_CANCELLED: u8 = 4;
 
 pub const MS_REJECT: u8 = 254;
 pub const MS_ERROR: u8 = 255;
 
-#[derive(Debug)]
+#[derive(Debug, Encode, Parse)]
 pub struct SInfo {
     pub task_id: TaskId,
     pub pid: u32,
@@ -62,26 +90,78 @@ pub struct SInfo {
     pub os: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Encode, Parse)]
 pub enum SCopyFromState {
-    Progress(u8),
-    Complete(ContentId),
+    /// The bytes copied so far and the size of the file
+    #[tag = "1"]    Progress { done: u64, total: u64 },
+    #[tag = "0"]    Complete(ContentId),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Encode, Parse)]
 pub struct SCopyFrom {
     pub task_id: TaskId,
     pub state: SCopyFromState,
 }
 
-#[derive(Debug)]
+/// The session is resumed with the tasks kept for it; the results of the finished ones are sent again
+#[derive(Debug, Encode, Parse)]
+pub struct SResumed {
+    pub task_id: TaskId,
+    pub tasks: Vec<TaskId>,
+}
+
+/// The task is cancelled before it has finished
+#[derive(Debug, Encode, Parse)]
+pub struct SCancelled {
+    pub task_id: TaskId,
+}
+
+/// The task is refused before it has started. Sent to clients prior to 0.1.10 only;
+/// newer ones are given an `SError` with the code of the refusal.
+#[derive(Debug, Encode, Parse)]
 pub struct SReject {
     pub task_id: TaskId,
     pub reason: String,
 }
 
-#[derive(Debug)]
+/// Why a task has failed on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Parse)]
+pub enum ErrorCode {
+    /// The server has failed to do the task
+    #[tag = "0"]    Internal,
+    /// The request of the task can't be parsed
+    #[tag = "1"]    InvalidRequest,
+    /// The task id is taken by another task of the connection
+    #[tag = "2"]    DuplicateTask,
+    /// The task has run past its deadline
+    #[tag = "3"]    DeadlineExceeded,
+    /// The task is cancelled by the server administrator
+    #[tag = "4"]    Aborted,
+    /// Reading or writing the files has failed
+    #[tag = "5"]    Io,
+    /// The server has too many jobs queued to start the task; it may be retried later
+    #[tag = "6"]    Busy,
+    /// The file is not allowed to be imported: it is outside of the import roots, or not a regular file
+    #[tag = "7"]    ImportDenied,
+    /// The client has no permission for the task
+    #[tag = "8"]    PermissionDenied,
+    /// The client has sent more requests than its rate allows; it may retry later
+    #[tag = "9"]    RateLimited,
+    /// There is no suspended session to resume, or it belongs to another client
+    #[tag = "10"]   ResumeFailed,
+}
+
+/// The task has failed; the other tasks of the connection go on
+#[derive(Debug, Encode, Parse)]
 pub struct SError {
+    pub task_id: TaskId,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// The `SError` of clients prior to 0.1.10, which know no error codes
+#[derive(Debug, Encode, Parse)]
+pub struct SUncodedError {
     pub task_id: TaskId,
     pub message: String,
 }
@@ -104,90 +184,65 @@ impl From<ParserError> for ParseError {
 }
 
 
-// --------------------------------------------------------------------------------------------------------------------
-
-
-//impl RawCodec for ClientMessage {
-impl ClientMessage {
-    pub fn encode(self) -> RawMessage {
-        match self {
-            ClientMessage::GetInfo(m)   => RawMessage::new(MC_GET_INFO, m.encode()),
-            ClientMessage::CopyFrom(m)  => RawMessage::new(MC_COPY_FROM, m.encode()),
-        }
-    }
-
-    pub fn parse(raw_message: RawMessage) -> Result<ClientMessage, ParseError> {
-        match raw_message.mtype {
-            MC_GET_INFO   => Ok(try!(CGetInfo::parse(raw_message.body))),
-            MC_COPY_FROM  => Ok(try!(CCopyFrom::parse(raw_message.body))),
-            _           => Err(ParseError::UnknownCode)
-        }
+impl fmt::Debug for CResume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CResume {{ task_id: {}, id: {} }}", self.task_id, self.id)
     }
 }
 
 
-//impl RawCodec for ServerMessage {
-impl ServerMessage {
-    pub fn encode(self) -> RawMessage {
-        match self {
-            ServerMessage::Info(m)      => RawMessage::new(MS_INFO, m.encode()),
-            ServerMessage::CopyFrom(m)  => RawMessage::new(MS_COPY_FROM, m.encode()),
-            ServerMessage::Reject(m)    => RawMessage::new(MS_REJECT, m.encode()),
-            ServerMessage::Error(m)     => RawMessage::new(MS_ERROR, m.encode()),
-        }
-    }
+// --------------------------------------------------------------------------------------------------------------------
 
-    pub fn parse(raw_message: RawMessage) -> Result<ServerMessage, ParseError> {
-        match raw_message.mtype {
-            MS_INFO      => Ok(try!(SInfo::parse(raw_message.body))),
-            MS_COPY_FROM => Ok(try!(SCopyFrom::parse(raw_message.body))),
-            MS_REJECT    => Ok(try!(SReject::parse(raw_message.body))),
-            MS_ERROR     => Ok(try!(SError::parse(raw_message.body))),
-            _            => Err(ParseError::UnknownCode)
-        }
-    }
 
+impl ServerMessage {
     pub fn get_task_id(&self) -> TaskId {
         match *self {
             ServerMessage::Info(ref m)      => m.task_id,
             ServerMessage::CopyFrom(ref m)  => m.task_id,
+            ServerMessage::Resumed(ref m)   => m.task_id,
+            ServerMessage::Cancelled(ref m) => m.task_id,
             ServerMessage::Reject(ref m)    => m.task_id,
             ServerMessage::Error(ref m)     => m.task_id,
         }
     }
-}
-
-
-// --------------------------------------------------------------------------------------------------------------------
-
 
-impl CGetInfo {
-    pub fn create(task_id: TaskId) -> ClientMessage {
-        ClientMessage::GetInfo(CGetInfo{task_id: task_id})
+    /// Encodes the message in the layout known to a client of the version.
+    /// Clients prior to 0.1.10 are given no error codes: a refused task is rejected,
+    /// and a failed one gets the error without its code.
+    pub fn encode_for(self, version: ProtocolVersion) -> RawMessage {
+        match self {
+            ServerMessage::Error(m) if version < TASK_ERROR_VERSION => if m.code.is_refusal() {
+                SReject::create(m.task_id, m.message).encode()
+            } else {
+                let m = SUncodedError { task_id: m.task_id, message: m.message };
+                RawMessage::new(MS_ERROR, RawMessageBody::Binary(::protocol::serde::Encode::encode(m)))
+            },
+            m => m.encode(),
+        }
     }
+}
 
-    pub fn encode(&self) -> RawMessageBody {
-        let mut encode = Encoder::new();
-
-        encode += self.task_id;
 
-        RawMessageBody::Binary(encode.complete())
+impl ErrorCode {
+    /// Whether the task is refused before its job is started, rather than failed.
+    /// Clients prior to 0.1.10 are given a rejection for it.
+    pub fn is_refusal(&self) -> bool {
+        match *self {
+            ErrorCode::Busy | ErrorCode::ImportDenied | ErrorCode::PermissionDenied | ErrorCode::RateLimited |
+            ErrorCode::ResumeFailed => true,
+            ErrorCode::Internal | ErrorCode::InvalidRequest | ErrorCode::DuplicateTask |
+            ErrorCode::DeadlineExceeded | ErrorCode::Aborted | ErrorCode::Io => false,
+        }
     }
+}
 
-    pub fn parse(body: RawMessageBody) -> Result<ClientMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
-
-                let mut input = Parser::new(v);
 
-                let task_id     = u64::parse_from(&mut input)?;
+// --------------------------------------------------------------------------------------------------------------------
 
-                input.complete()?;
 
-                Ok(CGetInfo::create(task_id))
-            },
-            _ => Err(ParseError::BadProtocol)
-        }
+impl CGetInfo {
+    pub fn create(task_id: TaskId) -> ClientMessage {
+        ClientMessage::GetInfo(CGetInfo{task_id: task_id})
     }
 }
 
@@ -196,30 +251,19 @@ impl CCopyFrom {
     pub fn create(task_id: TaskId, uri: String) -> ClientMessage {
         ClientMessage::CopyFrom(CCopyFrom{ task_id: task_id, uri: uri })
     }
+}
 
-    pub fn encode(self) -> RawMessageBody {
-        let mut encode = Encoder::new();
-
-        encode += self.task_id;
-        encode += self.uri;
 
-        RawMessageBody::Binary(encode.complete())
+impl CResume {
+    pub fn create(task_id: TaskId, id: usize, secret: Vec<u8>) -> ClientMessage {
+        ClientMessage::Resume(CResume{ task_id: task_id, id: id, secret: Bytes(secret) })
     }
+}
 
-    pub fn parse(body: RawMessageBody) -> Result<ClientMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
-                let mut input = Parser::new(v);
-
-                let task_id     = u64::parse_from(&mut input)?;
-                let uri         = String::parse_from(&mut input)?;
-
-                input.complete()?;
 
-                Ok(CCopyFrom::create(task_id, uri))
-            },
-            _ => Err(ParseError::BadProtocol)
-        }
+impl CCancel {
+    pub fn create(task_id: TaskId) -> ClientMessage {
+        ClientMessage::Cancel(CCancel{ task_id: task_id })
     }
 }
 
@@ -236,70 +280,6 @@ impl SInfo {
             os: os,
         })
     }
-
-    pub fn encode(self) -> RawMessageBody {
-
-        let mut encode = Encoder::new();
-
-        encode += self.task_id;
-        encode += self.pid;
-        encode += self.arch_bits;
-        encode += self.os;
-
-        RawMessageBody::Binary(encode.complete())
-    }
-
-    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
-
-                let mut input = Parser::new(v);
-
-                let task_id     = u64::parse_from(&mut input)?;
-                let pid         = u32::parse_from(&mut input)?;
-                let arch_bits   = u16::parse_from(&mut input)?;
-                let os          = String::parse_from(&mut input)?;
-
-                input.complete()?;
-
-                Ok(SInfo::create(task_id, pid, arch_bits, os))
-            },
-            _ => Err(ParseError::BadProtocol)
-        }
-    }
-}
-
-
-// --------------------------------------------------------------------------------------------------------------------
-
-
-impl Encode for SCopyFromState {
-    fn encode(self) -> Vec<u8> {
-        let mut encoder = Encoder::new();
-
-        match self {
-            SCopyFromState::Complete(content_id) => {
-                encoder += 0u8;
-                encoder += content_id;
-            },
-            SCopyFromState::Progress(progress) => {
-                encoder += 1u8;
-                encoder += progress;
-            },
-        }
-
-        encoder.complete()
-    }
-}
-
-impl Parse for SCopyFromState {
-    fn parse_from(input: &mut Parser) -> Result<SCopyFromState, ParserError> {
-        Ok(match u8::parse_from(input)? {
-            0 => SCopyFromState::Complete(ContentId::parse_from(input)?),
-            1 => SCopyFromState::Progress(u8::parse_from(input)?),
-            _ => unreachable!()
-        })
-    }
 }
 
 
@@ -310,32 +290,19 @@ impl SCopyFrom {
             state: state,
         })
     }
+}
 
-    pub fn encode(self) -> RawMessageBody {
-
-        let mut encode = Encoder::new();
-
-        encode += self.task_id;
-        encode += self.state;
 
-        RawMessageBody::Binary(encode.complete())
+impl SResumed {
+    pub fn create(task_id: TaskId, tasks: Vec<TaskId>) -> ServerMessage {
+        ServerMessage::Resumed(SResumed{ task_id: task_id, tasks: tasks })
     }
+}
 
-    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
-
-                let mut input = Parser::new(v);
-
-                let task_id     = u64::parse_from(&mut input)?;
-                let state       = SCopyFromState::parse_from(&mut input)?;
-
-                input.complete()?;
 
-                Ok(SCopyFrom::create(task_id, state))
-            },
-            _ => Err(ParseError::BadProtocol)
-        }
+impl SCancelled {
+    pub fn create(task_id: TaskId) -> ServerMessage {
+        ServerMessage::Cancelled(SCancelled{ task_id: task_id })
     }
 }
 
@@ -344,112 +311,152 @@ impl SReject {
     pub fn create(task_id: TaskId, reason: String) -> ServerMessage {
         ServerMessage::Reject(SReject{ task_id: task_id, reason: reason })
     }
+}
 
-    pub fn encode(self) -> RawMessageBody {
 
-        let mut encode = Encoder::new();
+impl SError {
+    pub fn create(task_id: TaskId, code: ErrorCode, message: String) -> ServerMessage {
+        ServerMessage::Error(SError{ task_id: task_id, code: code, message: message })
+    }
+}
 
-        encode += self.task_id;
-        encode += self.reason;
 
-        RawMessageBody::Binary(encode.complete())
+
+/// The task id of a message which can't be parsed, as every message of the subprotocol starts with it
+pub fn raw_task_id(raw_message: &RawMessage) -> Option<TaskId> {
+    match raw_message.body {
+        RawMessageBody::Binary(ref body) if body.len() >= 8 => Some(decode_u64(&body[..8])),
+        _ => None,
     }
+}
 
-    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
 
-                let mut input = Parser::new(v);
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
 
-                let task_id     = u64::parse_from(&mut input)?;
-                let reason      = String::parse_from(&mut input)?;
+    use protocol::message::{RawMessage, RawMessageBody};
+    use protocol::workflow::ProtocolVersion;
 
-                input.complete()?;
+    use ::proto::TASK_ERROR_VERSION;
+    use ::types::ContentId;
+    use super::*;
 
-                Ok(SReject::create(task_id, reason))
-            },
-            _ => Err(ParseError::BadProtocol)
-        }
-    }
-}
 
-impl SError {
-    pub fn create(task_id: TaskId, message: String) -> ServerMessage {
-        ServerMessage::Error(SError{ task_id: task_id, message: message })
+    fn body(raw_message: &RawMessage) -> Vec<u8> {
+        match raw_message.body {
+            RawMessageBody::Binary(ref v) => v.clone(),
+            _ => panic!("Binary body expected"),
+        }
     }
 
-    pub fn encode(self) -> RawMessageBody {
+    #[test]
+    fn client_message_layout() {
+        let raw = CCopyFrom::create(0x0102, "/a".to_owned()).encode();
+        assert_eq!(raw.mtype, MC_COPY_FROM);
+        assert_eq!(body(&raw), vec![0, 0, 0, 0, 0, 0, 1, 2, 2, b'/', b'a']);
 
-        let mut encode = Encoder::new();
-
-        encode += self.task_id;
-        encode += self.message;
-
-        RawMessageBody::Binary(encode.complete())
+        match ClientMessage::parse(raw).unwrap() {
+            ClientMessage::CopyFrom(m) => {
+                assert_eq!(m.task_id, 0x0102);
+                assert_eq!(m.uri, "/a");
+            },
+            m => panic!("Unexpected message {:?}", m),
+        }
     }
 
-    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-        match body {
-            RawMessageBody::Binary(v) => {
+    #[test]
+    fn server_message_tagged_enum() {
+        let raw = SCopyFrom::create(3, SCopyFromState::Complete(ContentId::from_slice(&[7; 64]))).encode();
+        assert_eq!(body(&raw)[8], 0);
+        match ServerMessage::parse(raw).unwrap() {
+            ServerMessage::CopyFrom(SCopyFrom { task_id: 3, state: SCopyFromState::Complete(id) }) => {
+                assert_eq!(id.to_string(), ContentId::from_slice(&[7; 64]).to_string());
+            },
+            m => panic!("Unexpected message {:?}", m),
+        }
 
-                let mut input = Parser::new(v);
+        let raw = SCopyFrom::create(3, SCopyFromState::Progress { done: 42, total: 0x0100 }).encode();
+        assert_eq!(&body(&raw)[8..], &[1, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 1, 0]);
+        match ServerMessage::parse(raw).unwrap() {
+            ServerMessage::CopyFrom(SCopyFrom { state: SCopyFromState::Progress { done: 42, total: 256 }, .. }) => (),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
 
-                let task_id     = u64::parse_from(&mut input)?;
-                let message     = String::parse_from(&mut input)?;
+    #[test]
+    fn resume_messages() {
+        match ClientMessage::parse(CResume::create(5, 3, vec![1, 2]).encode()).unwrap() {
+            ClientMessage::Resume(m) => assert_eq!((m.task_id, m.id, m.secret.0), (5, 3, vec![1, 2])),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        match ServerMessage::parse(SResumed::create(5, vec![1, 4]).encode()).unwrap() {
+            ServerMessage::Resumed(m) => assert_eq!((m.task_id, m.tasks), (5, vec![1, 4])),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
 
-                input.complete()?;
+    #[test]
+    fn cancel_messages() {
+        let raw = CCancel::create(6).encode();
+        assert_eq!(raw.mtype, MC_CANCEL);
+        match ClientMessage::parse(raw).unwrap() {
+            ClientMessage::Cancel(m) => assert_eq!(m.task_id, 6),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        assert_eq!(ServerMessage::parse(SCancelled::create(6).encode()).unwrap().get_task_id(), 6);
+    }
 
-                Ok(SError::create(task_id, message))
+    #[test]
+    fn error_codes() {
+        let raw = SError::create(3, ErrorCode::DeadlineExceeded, "Late".to_owned()).encode();
+        assert_eq!(raw_task_id(&raw), Some(3));
+        assert_eq!(body(&raw)[8], 3);
+        match ServerMessage::parse(raw).unwrap() {
+            ServerMessage::Error(m) => {
+                assert_eq!((m.task_id, m.code, &m.message[..]), (3, ErrorCode::DeadlineExceeded, "Late"));
             },
-            _ => Err(ParseError::BadProtocol)
+            m => panic!("Unexpected message {:?}", m),
         }
+
+        // A truncated request is an error, which still tells the task
+        let mut raw = CCopyFrom::create(5, "/a".to_owned()).encode();
+        raw.body = RawMessageBody::Binary(body(&raw)[..9].to_vec());
+        assert_eq!(raw_task_id(&raw), Some(5));
+        assert!(ClientMessage::parse(raw).is_err());
+        assert_eq!(raw_task_id(&RawMessage::new(MC_CANCEL, RawMessageBody::Binary(vec![0; 7]))), None);
     }
-}
 
+    #[test]
+    fn errors_of_old_clients() {
+        let old = ProtocolVersion(0, 1, 9);
+        let raw = SError::create(3, ErrorCode::DeadlineExceeded, "Late".to_owned()).encode_for(old);
+        assert_eq!(raw.mtype, MS_ERROR);
+        assert_eq!(body(&raw), vec![0, 0, 0, 0, 0, 0, 0, 3, 4, b'L', b'a', b't', b'e']);
+
+        // A refusal is a rejection for them
+        let raw = SError::create(4, ErrorCode::PermissionDenied, "Denied".to_owned()).encode_for(old);
+        match ServerMessage::parse(raw).unwrap() {
+            ServerMessage::Reject(m) => assert_eq!((m.task_id, &m.reason[..]), (4, "Denied")),
+            m => panic!("Unexpected message {:?}", m),
+        }
+
+        let raw = SError::create(4, ErrorCode::PermissionDenied, "Denied".to_owned()).encode_for(TASK_ERROR_VERSION);
+        assert_eq!((raw.mtype, body(&raw)[8]), (MS_ERROR, 8));
+        assert_eq!(body(&SInfo::create(5, 1, 64, "os".to_owned()).encode_for(old)),
+            body(&SInfo::create(5, 1, 64, "os".to_owned()).encode()));
+    }
 
+    #[test]
+    fn unknown_code() {
+        let raw = RawMessage::new(100, RawMessageBody::Binary(vec![]));
+        assert_eq!(ServerMessage::parse(raw).unwrap_err(), ParseError::UnknownCode);
+    }
 
-//impl SInfo {
-//    pub fn create(task_id: TaskId, pid: u32, arch_bits: u16, os: String) -> ServerMessage {
-//        ServerMessage::Info(SInfo {
-//            task_id: task_id,
-//            pid: pid,
-//            arch_bits: arch_bits,
-//            os: os,
-//        })
-//    }
-//
-//    pub fn encode(&self) -> RawMessageBody {
-//        let os_len = self.os.len() as u16;
-//        let data: Vec<u8> = [
-//            &(unsafe { transmute::<TaskId, [u8; 8]>(self.task_id.to_be()) })[..],
-//            &(unsafe { transmute::<u32, [u8; 4]>(self.pid.to_be()) })[..],
-//            &(unsafe { transmute::<u16, [u8; 2]>(self.arch_bits.to_be()) })[..],
-//            &(unsafe { transmute::<u16, [u8; 2]>(os_len.to_be()) })[..],
-//            self.os.as_bytes()
-//        ].concat();
-//        RawMessageBody::Binary(data)
-//    }
-//
-//    pub fn parse(body: RawMessageBody) -> Result<ServerMessage, ParseError> {
-//        match body {
-//            RawMessageBody::Binary(v) => {
-//
-//                let mut task_id = [0; 8];
-//                task_id.copy_from_slice(&v[0..8]);
-//                let task_id = TaskId::from_be(unsafe { transmute::<[u8; 8], TaskId>(task_id) });
-//
-//                let mut pid = [0; 4];
-//                pid.copy_from_slice(&v[8..8+4]);
-//                let pid = u32::from_be(unsafe { transmute::<[u8; 4], u32>(pid) });
-//
-//                let arch_bits: u16 = (v[12] as u16) * 256 + (v[13] as u16);
-//
-//                let os_len: usize = (v[14] as usize) * 256 + (v[15] as usize);
-//                let os = try!(from_utf8(&v[16 .. 16 + os_len])).to_owned();
-//
-//                Ok(SInfo::create(task_id, pid, arch_bits, os))
-//            },
-//            _ => Err(ParseError::BadProtocol)
-//        }
-//    }
-//}
+    #[test]
+    fn trailing_data() {
+        let mut raw = CGetInfo::create(1).encode();
+        raw.body = RawMessageBody::Binary([&body(&raw)[..], &[0][..]].concat());
+        assert!(ClientMessage::parse(raw).is_err());
+    }
+}
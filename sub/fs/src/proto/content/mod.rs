@@ -2,7 +2,573 @@
 pub mod actions;
 pub mod client;
 pub mod message;
+pub mod registry;
 pub mod server;
 pub mod state;
 pub mod task;
 
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{Receiver, channel};
+    use std::thread;
+    use std::time::Duration;
+
+    use unix_socket::UnixStream;
+
+    use protocol::message::{Message, RawMessageBody};
+    use protocol::serde::{Parse, Parser};
+    use protocol::stream::Stream;
+    use protocol::workflow::{Protocol, ProtocolVersion, Workflow};
+
+    use ::client::connection::Connection;
+    use ::connection::StreamMessage;
+    use ::proto::auth::message::SessionTicket;
+    use ::server::admission::Admission;
+    use ::server::config::Config;
+    use ::server::control::ServerControl;
+    use ::server::database::DatabaseHolder;
+    use ::server::permissions::{Permission, Permissions};
+    use ::server::registry::ServerProtocol;
+    use ::server::tokens::Claims;
+    use ::testing;
+    use ::types::ContentId;
+
+    use super::client::{ContentInterface, ContentProtocol as ClientProtocol, TaskInterface};
+    use super::message::{CCancel, CCopyFrom, CGetInfo, CResume, SCancelled, SCopyFrom, SCopyFromState, SInfo, SReject};
+    use super::message::{ErrorCode, MS_ERROR, SError, SUncodedError, ServerMessage};
+    use super::server::ContentProtocol;
+    use super::state::ContentState;
+    use super::task::TaskError;
+
+
+    fn database() -> DatabaseHolder {
+        testing::holder(Config::new())
+    }
+
+    fn protocol(id: usize, db: DatabaseHolder, control: Arc<ServerControl>, permissions: Permissions,
+                ticket: Option<SessionTicket>) -> (ContentProtocol, Receiver<StreamMessage>)
+    {
+        let (mut context, rx) = testing::context(id, db);
+        context.control = control;
+        context.ticket = ticket;
+        context.permissions = permissions;
+        (ContentProtocol::new(context), rx)
+    }
+
+    fn answer(rx: &Receiver<StreamMessage>) -> ServerMessage {
+        ServerMessage::parse(testing::answer(rx)).unwrap()
+    }
+
+    #[test]
+    fn denied_task_is_rejected() {
+        let permissions = Permissions::of(&[Permission::Info]);
+        let (protocol, rx) = protocol(1, database(), Arc::new(ServerControl::new()), permissions, None);
+
+        let workflow = protocol.flow(CCopyFrom::create(3, "/etc/passwd".to_owned()).encode());
+        assert!(match workflow { Workflow::Continue => true, _ => false });
+        match answer(&rx) {
+            ServerMessage::Error(m) => {
+                assert_eq!((m.task_id, m.code), (3, ErrorCode::PermissionDenied));
+                assert!(m.message.contains("import"), "{}", m.message);
+            },
+            m => panic!("Unexpected message {:?}", m),
+        }
+        assert_eq!(protocol.active_tasks(), 0);
+
+        // The connection goes on serving the allowed requests
+        assert!(match protocol.flow(CGetInfo::create(4).encode()) { Workflow::Continue => true, _ => false });
+        match answer(&rx) {
+            ServerMessage::Info(m) => assert_eq!(m.task_id, 4),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn token_is_refused_outside_its_scopes() {
+        // The identity may do anything, but its token only grants the info
+        let mut config = Config::new();
+        config.permissions.insert("ci".to_owned(), Permissions::all());
+        let claims = Claims::new("ci".to_owned(), Permissions::of(&[Permission::Info]), Duration::from_secs(60))
+            .unwrap();
+        let permissions = config.granted(Some("ci"), Some(&claims));
+        let (protocol, rx) = protocol(1, testing::holder(config), Arc::new(ServerControl::new()), permissions, None);
+
+        protocol.flow(CCopyFrom::create(1, "/etc/passwd".to_owned()).encode());
+        match answer(&rx) {
+            ServerMessage::Error(m) => assert_eq!(m.code, ErrorCode::PermissionDenied),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        protocol.flow(CGetInfo::create(2).encode());
+        assert!(match answer(&rx) { ServerMessage::Info(_) => true, _ => false });
+    }
+
+    #[test]
+    fn rate_limited_task_is_rejected() {
+        let mut config = Config::new();
+        config.rate_limit = 1;
+        let control = ServerControl::new().with_admission(Admission::from_config(&config).unwrap());
+        let (protocol, rx) = protocol(1, database(), Arc::new(control), Permissions::all(), None);
+
+        protocol.flow(CGetInfo::create(1).encode());
+        assert_eq!(answer(&rx).get_task_id(), 1);
+        protocol.flow(CGetInfo::create(2).encode());
+        match answer(&rx) {
+            ServerMessage::Error(m) => assert_eq!((m.task_id, m.code), (2, ErrorCode::RateLimited)),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn invalid_request_fails_task_alone() {
+        let (protocol, rx) = protocol(1, database(), Arc::new(ServerControl::new()), Permissions::all(), None);
+
+        let mut raw_message = CCopyFrom::create(3, "relative/path".to_owned()).encode();
+        if let RawMessageBody::Binary(ref mut body) = raw_message.body {
+            body.truncate(12);
+        }
+        assert!(match protocol.flow(raw_message) { Workflow::Continue => true, _ => false });
+        match answer(&rx) {
+            ServerMessage::Error(m) => assert_eq!((m.task_id, m.code), (3, ErrorCode::InvalidRequest)),
+            m => panic!("Unexpected message {:?}", m),
+        }
+
+        protocol.flow(CGetInfo::create(4).encode());
+        assert_eq!(answer(&rx).get_task_id(), 4);
+    }
+
+    #[test]
+    fn old_client_gets_errors_without_codes() {
+        let (mut context, rx) = testing::context(1, database());
+        context.client_version = ProtocolVersion(0, 1, 9);
+        context.permissions = Permissions::of(&[Permission::Info]);
+        let protocol = ContentProtocol::new(context);
+
+        // A refused task is rejected, as before the error codes
+        protocol.flow(CCopyFrom::create(3, "/etc/passwd".to_owned()).encode());
+        match answer(&rx) {
+            ServerMessage::Reject(m) => assert_eq!(m.task_id, 3),
+            m => panic!("Unexpected message {:?}", m),
+        }
+
+        let mut raw_message = CGetInfo::create(4).encode();
+        if let RawMessageBody::Binary(ref mut body) = raw_message.body {
+            body.push(0);
+        }
+        protocol.flow(raw_message);
+        let raw = testing::answer(&rx);
+        assert_eq!(raw.mtype, MS_ERROR);
+        let body = match raw.body {
+            RawMessageBody::Binary(body) => body,
+            _ => panic!("Binary body expected"),
+        };
+        let mut parser = Parser::new(body);
+        let error = SUncodedError::parse_from(&mut parser).unwrap();
+        parser.complete().unwrap();
+        assert_eq!(error.task_id, 4);
+        assert!(error.message.starts_with("Invalid request"), "{}", error.message);
+    }
+
+    #[test]
+    fn duplicate_task_fails_alone() {
+        let db = database();
+        let (protocol, rx) = protocol(1, db.clone(), Arc::new(ServerControl::new()), Permissions::all(), None);
+
+        // The job waits for the database, so the task is still running
+        let guard = db.lock().unwrap();
+        protocol.flow(CCopyFrom::create(3, "relative/path".to_owned()).encode());
+        assert!(match protocol.flow(CGetInfo::create(3).encode()) { Workflow::Continue => true, _ => false });
+        match answer(&rx) {
+            ServerMessage::Error(m) => assert_eq!((m.task_id, m.code), (3, ErrorCode::DuplicateTask)),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        drop(guard);
+        assert_eq!(answer(&rx).get_task_id(), 3);
+    }
+
+    #[test]
+    fn finished_tasks_are_reaped() {
+        let (protocol, rx) = protocol(1, database(), Arc::new(ServerControl::new()), Permissions::all(), None);
+
+        protocol.flow(CGetInfo::create(1).encode());
+        assert_eq!(answer(&rx).get_task_id(), 1);
+        while !protocol.list_tasks().is_empty() {
+            protocol.on_tick();
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The id of a reaped task may be used again
+        protocol.flow(CGetInfo::create(1).encode());
+        assert_eq!(answer(&rx).get_task_id(), 1);
+    }
+
+    #[test]
+    fn import_outside_roots_is_denied() {
+        let (protocol, rx) = protocol(1, database(), Arc::new(ServerControl::new()), Permissions::all(), None);
+        for (task_id, uri) in vec![(1, "/etc/passwd"), (2, "relative/path"), (3, "/tmp/../etc/passwd")] {
+            protocol.flow(CCopyFrom::create(task_id, uri.to_owned()).encode());
+            match answer(&rx) {
+                ServerMessage::Error(m) => assert_eq!((m.task_id, m.code), (task_id, ErrorCode::ImportDenied)),
+                m => panic!("Unexpected message {:?}", m),
+            }
+        }
+    }
+
+    #[test]
+    fn expired_task_is_failed_and_reaped() {
+        let mut config = Config::new();
+        config.task_timeout = 1;
+        let db = testing::holder(config);
+        let (protocol, rx) = protocol(1, db.clone(), Arc::new(ServerControl::new()), Permissions::all(), None);
+
+        // The job waits for the database past the deadline
+        let guard = db.lock().unwrap();
+        protocol.flow(CCopyFrom::create(3, "relative/path".to_owned()).encode());
+        while protocol.active_tasks() > 0 {
+            protocol.on_tick();
+            thread::sleep(Duration::from_millis(10));
+        }
+        match answer(&rx) {
+            ServerMessage::Error(m) => assert_eq!((m.task_id, m.code), (3, ErrorCode::DeadlineExceeded)),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        drop(guard);
+        while !protocol.list_tasks().is_empty() {
+            protocol.on_tick();
+            thread::sleep(Duration::from_millis(1));
+        }
+        // What the stopped job has come to is not sent
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn tasks_are_stopped_with_connection() {
+        let db = database();
+        let (protocol, rx) = protocol(1, db.clone(), Arc::new(ServerControl::new()), Permissions::all(), None);
+        let tasks = protocol.tasks().unwrap();
+
+        // The job waits for the database until the connection is gone
+        let guard = db.lock().unwrap();
+        protocol.flow(CCopyFrom::create(3, "relative/path".to_owned()).encode());
+        let listed = protocol.list_tasks().iter().map(|info| (info.task_id, info.finished)).collect::<Vec<_>>();
+        assert_eq!(listed, vec![(3, false)]);
+        drop(protocol);
+        assert_eq!(tasks.active(), 0);
+        assert!(tasks.list().is_empty());
+        drop(guard);
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn resume_suspended_session() {
+        let db = database();
+        let control = Arc::new(ServerControl::new());
+        let ticket = SessionTicket::new("run".to_owned(), vec![5; 16]);
+        let (lost, lost_rx) = protocol(1, db.clone(), control.clone(), Permissions::all(), Some(ticket));
+
+        // The job waits for the database until the connection is lost
+        let guard = db.lock().unwrap();
+        lost.flow(CCopyFrom::create(3, "relative/path".to_owned()).encode());
+        lost.suspend();
+        drop(guard);
+        assert_eq!(control.suspended_sessions(), 1);
+        while lost.active_tasks() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(lost_rx.try_recv().is_err());
+
+        let (resumed, rx) = protocol(2, db, control.clone(), Permissions::all(), None);
+        resumed.flow(CResume::create(7, 1, vec![6; 16]).encode());
+        match answer(&rx) {
+            ServerMessage::Error(m) => assert_eq!((m.task_id, m.code), (7, ErrorCode::ResumeFailed)),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        assert_eq!(control.suspended_sessions(), 1);
+
+        resumed.flow(CResume::create(8, 1, vec![5; 16]).encode());
+        match answer(&rx) {
+            ServerMessage::Resumed(m) => assert_eq!((m.task_id, m.tasks), (8, vec![3])),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        // The result of the job is sent on the new connection
+        assert_eq!(answer(&rx).get_task_id(), 3);
+        assert_eq!(control.suspended_sessions(), 0);
+    }
+
+    #[test]
+    fn resumed_session_gets_undelivered_results() {
+        let db = database();
+        let control = Arc::new(ServerControl::new());
+        let ticket = SessionTicket::new("run".to_owned(), vec![5; 16]);
+        let (lost, lost_rx) = protocol(1, db.clone(), control.clone(), Permissions::all(), Some(ticket));
+
+        // The result is queued for the lost connection, but the task is not reaped yet
+        lost.flow(CGetInfo::create(4).encode());
+        assert_eq!(answer(&lost_rx).get_task_id(), 4);
+        lost.suspend();
+        assert_eq!(control.suspended_sessions(), 1);
+
+        let (resumed, rx) = protocol(2, db, control.clone(), Permissions::all(), None);
+        resumed.flow(CResume::create(8, 1, vec![5; 16]).encode());
+        match answer(&rx) {
+            ServerMessage::Resumed(m) => assert_eq!((m.task_id, m.tasks), (8, vec![4])),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        match answer(&rx) {
+            ServerMessage::Info(m) => assert_eq!(m.task_id, 4),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn session_without_secret_is_not_kept() {
+        let control = Arc::new(ServerControl::new());
+        let ticket = SessionTicket::new("run".to_owned(), vec![]);
+        let db = database();
+        let (protocol, _rx) = protocol(1, db.clone(), control.clone(), Permissions::all(), Some(ticket));
+        let guard = db.lock().unwrap();
+        protocol.flow(CCopyFrom::create(3, "relative/path".to_owned()).encode());
+        protocol.suspend();
+        drop(guard);
+        assert_eq!(control.suspended_sessions(), 0);
+    }
+
+    #[test]
+    fn cancel_task() {
+        let db = database();
+        let (protocol, rx) = protocol(1, db.clone(), Arc::new(ServerControl::new()), Permissions::all(), None);
+
+        // The job waits for the database until the task is cancelled
+        let guard = db.lock().unwrap();
+        protocol.flow(CCopyFrom::create(3, "relative/path".to_owned()).encode());
+        assert_eq!(protocol.active_tasks(), 1);
+        assert!(match protocol.flow(CCancel::create(3).encode()) { Workflow::Continue => true, _ => false });
+        match answer(&rx) {
+            ServerMessage::Cancelled(m) => assert_eq!(m.task_id, 3),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        assert_eq!(protocol.active_tasks(), 0);
+        drop(guard);
+
+        // Neither the job nor a repeated cancel sends anything else
+        assert!(match protocol.flow(CCancel::create(3).encode()) { Workflow::Continue => true, _ => false });
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn client_task_cancelled() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let connection = Connection::new(Stream::Unix(client_stream));
+            let content = ContentInterface::new(Arc::new(ClientProtocol::new(connection, 0)));
+            let task = content.copy_from("/a").unwrap();
+            task.cancel().unwrap();
+            task.wait()
+        });
+
+        let mut stream = Stream::Unix(server_stream);
+        Connection::_read(&mut stream).unwrap();
+        Connection::_read(&mut stream).unwrap();
+        stream.write(Message::from_raw(SCancelled::create(0).encode()).unwrap().as_bytes()).unwrap();
+
+        match client.join().unwrap() {
+            Ok(ContentState::Cancelled(ref cancelled)) => assert_eq!(cancelled.task_id, 0),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn client_copy_progress() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let updates_ = updates.clone();
+        let (ready_tx, ready_rx) = channel();
+        let client = thread::spawn(move || {
+            let connection = Connection::new(Stream::Unix(client_stream));
+            let content = ContentInterface::new(Arc::new(ClientProtocol::new(connection, 0)));
+            let task = content.copy_from("/a").unwrap();
+            task.on_progress(move |done, total| updates_.lock().unwrap().push((done, total)));
+            ready_tx.send(()).unwrap();
+            task.wait()
+        });
+
+        let mut stream = Stream::Unix(server_stream);
+        Connection::_read(&mut stream).unwrap();
+        ready_rx.recv().unwrap();
+        let answers = vec![
+            SCopyFrom::create(0, SCopyFromState::Progress { done: 4096, total: 8000 }),
+            SCopyFrom::create(0, SCopyFromState::Progress { done: 8000, total: 8000 }),
+            SCopyFrom::create(0, SCopyFromState::Complete(ContentId::from_slice(&[7; 64]))),
+        ];
+        for answer in answers {
+            stream.write(Message::from_raw(answer.encode()).unwrap().as_bytes()).unwrap();
+        }
+
+        match client.join().unwrap() {
+            Ok(ContentState::CopyFrom(ref copy_from)) => {
+                assert_eq!(copy_from.progress, Some((8000, 8000)));
+                assert!(copy_from.result.is_some());
+            },
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(*updates.lock().unwrap(), vec![(4096, 8000), (8000, 8000)]);
+    }
+
+    #[test]
+    fn client_copy_progress_from_start() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let updates_ = updates.clone();
+        let client = thread::spawn(move || {
+            let connection = Connection::new(Stream::Unix(client_stream));
+            let content = ContentInterface::new(Arc::new(ClientProtocol::new(connection, 0)));
+            let task = content.copy_from_with_progress("/a", move |done, total| {
+                updates_.lock().unwrap().push((done, total))
+            }).unwrap();
+            task.wait()
+        });
+
+        // The server answers at once, the first update may come before the client gets the task
+        let mut stream = Stream::Unix(server_stream);
+        Connection::_read(&mut stream).unwrap();
+        let answers = vec![
+            SCopyFrom::create(0, SCopyFromState::Progress { done: 8000, total: 8000 }),
+            SCopyFrom::create(0, SCopyFromState::Complete(ContentId::from_slice(&[7; 64]))),
+        ];
+        for answer in answers {
+            stream.write(Message::from_raw(answer.encode()).unwrap().as_bytes()).unwrap();
+        }
+
+        assert!(match client.join().unwrap() { Ok(ContentState::CopyFrom(_)) => true, _ => false });
+        assert_eq!(*updates.lock().unwrap(), vec![(8000, 8000)]);
+    }
+
+    #[test]
+    fn client_tasks_complete_in_any_order() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Stream::Unix(client_stream));
+        let content = ContentInterface::new(Arc::new(ClientProtocol::new(connection, 0)));
+        let mut tasks = vec![content.info().unwrap(), content.info().unwrap()];
+        let (tx, rx) = channel();
+        content.info().unwrap().on_complete(move |result| tx.send(result).unwrap());
+
+        let mut stream = Stream::Unix(server_stream);
+        for _ in 0..3 {
+            Connection::_read(&mut stream).unwrap();
+        }
+        let mut answer = |task_id| {
+            let message = SInfo::create(task_id, 1, 64, "test".to_owned());
+            stream.write(Message::from_raw(message.encode()).unwrap().as_bytes()).unwrap();
+        };
+
+        // The callback is called by the reader thread, while nobody waits
+        answer(2);
+        match rx.recv().unwrap() {
+            Ok(ContentState::GetInfo(ref info)) => assert_eq!(info.response.as_ref().map(|m| m.task_id), Some(2)),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        answer(1);
+        match TaskInterface::wait_any(&mut tasks) {
+            Ok((1, Ok(ContentState::GetInfo(_)))) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        answer(0);
+        let results = TaskInterface::wait_all(tasks);
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_ok());
+        assert!(content.pending_tasks().is_empty());
+    }
+
+    #[test]
+    fn client_connection_lost() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Stream::Unix(client_stream));
+        let content = ContentInterface::new(Arc::new(ClientProtocol::new(connection, 0)));
+        let task = content.info().unwrap();
+
+        let mut stream = Stream::Unix(server_stream);
+        Connection::_read(&mut stream).unwrap();
+        drop(stream);
+
+        // The task is kept to be resumed
+        assert!(task.wait().is_err());
+        assert_eq!(content.pending_tasks(), vec![0]);
+    }
+
+    #[test]
+    fn client_task_rejected() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let connection = Connection::new(Stream::Unix(client_stream));
+            let content = ContentInterface::new(Arc::new(ClientProtocol::new(connection, 0)));
+            let rejected = content.copy_from("/etc/passwd").unwrap().wait();
+            let info = content.info().unwrap().wait();
+            (rejected, info)
+        });
+
+        let mut stream = Stream::Unix(server_stream);
+        let answers = vec![
+            SReject::create(0, "Permission denied: import is not allowed".to_owned()),
+            SInfo::create(1, 1, 64, "test".to_owned()),
+        ];
+        for answer in answers {
+            Connection::_read(&mut stream).unwrap();
+            stream.write(Message::from_raw(answer.encode()).unwrap().as_bytes()).unwrap();
+        }
+
+        match client.join().unwrap() {
+            (Err(TaskError::Rejected(ref reason)), Ok(ContentState::GetInfo(ref info))) => {
+                assert!(reason.contains("Permission denied"), "{}", reason);
+                assert_eq!(info.response.as_ref().map(|m| m.task_id), Some(1));
+            },
+            r => panic!("Unexpected results {:?}", r),
+        }
+    }
+
+    #[test]
+    fn client_task_failed_alone() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Stream::Unix(client_stream));
+        let content = ContentInterface::new(Arc::new(ClientProtocol::new(connection, 0)));
+        let tasks = vec![content.copy_from("/a").unwrap(), content.info().unwrap(), content.info().unwrap()];
+
+        let mut stream = Stream::Unix(server_stream);
+        for _ in 0..3 {
+            Connection::_read(&mut stream).unwrap();
+        }
+        let mut invalid = SInfo::create(1, 1, 64, "test".to_owned()).encode();
+        if let RawMessageBody::Binary(ref mut body) = invalid.body {
+            body.truncate(12);
+        }
+        let answers = vec![
+            SError::create(0, ErrorCode::Io, "Import of /a has failed".to_owned()).encode(),
+            SInfo::create(9, 1, 64, "test".to_owned()).encode(),
+            invalid,
+            SInfo::create(2, 1, 64, "test".to_owned()).encode(),
+        ];
+        for answer in answers {
+            stream.write(Message::from_raw(answer).unwrap().as_bytes()).unwrap();
+        }
+
+        let mut results = TaskInterface::wait_all(tasks).into_iter();
+        match results.next() {
+            Some(Err(TaskError::Failed(ErrorCode::Io, _))) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match results.next() {
+            Some(Err(TaskError::InvalidAnswer(_))) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match results.next() {
+            Some(Ok(ContentState::GetInfo(ref info))) => assert_eq!(info.response.as_ref().map(|m| m.task_id), Some(2)),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! The tasks of a connection on the server side.
+//!
+//! A task is registered when it is started, with the channel to its job and the handle of the job.
+//! It is reaped once its job is done. The tasks left in the registry when it is stopped or dropped,
+//! with the connection or with an expired session, are cancelled and their jobs are asked to stop.
+//!
+//! The registry is locked before the tasks, and the jobs lock only their own tasks.
+
+use std::collections::hash_map::{HashMap, Entry};
+use std::mem;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::mpsc::Sender;
+
+use ::proto::admin::message::TaskInfo;
+use ::server::pool::JobHandle;
+use ::types::TaskId;
+
+use super::actions::{TaskContainer, TaskHolder};
+use super::message::{CCancel, ClientMessage, ErrorCode};
+use super::server::fail_task;
+use super::task::TaskState;
+
+
+/// A task with the channel to its job
+#[derive(Debug)]
+struct TaskEntry {
+    task: TaskHolder,
+    tx: Sender<ClientMessage>,
+    /// Set once the job is started
+    job: Option<JobHandle>,
+}
+
+
+/// The tasks of a connection by their ids
+#[derive(Debug)]
+pub struct TaskRegistry {
+    tasks: Mutex<HashMap<TaskId, TaskEntry>>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl TaskRegistry {
+    pub fn new() -> TaskRegistry {
+        TaskRegistry { tasks: Mutex::new(HashMap::new()) }
+    }
+
+    /// Registers a task before its job is started
+    pub fn insert(&self, task_id: TaskId, task: TaskHolder, tx: Sender<ClientMessage>) -> Result<(), String> {
+        match self.tasks.lock().unwrap().entry(task_id) {
+            Entry::Occupied(_) => Err(format!("Duplicate task id {}", task_id)),
+            Entry::Vacant(entry) => {
+                entry.insert(TaskEntry { task: task, tx: tx, job: None });
+                Ok(())
+            },
+        }
+    }
+
+    /// Keeps the handle of the started job, so that the task is reaped once the job is done
+    pub fn set_job(&self, task_id: TaskId, job: JobHandle) {
+        if let Some(entry) = self.tasks.lock().unwrap().get_mut(&task_id) {
+            entry.job = Some(job);
+        }
+    }
+
+    /// Forgets a task whose job could not be started
+    pub fn remove(&self, task_id: TaskId) {
+        self.tasks.lock().unwrap().remove(&task_id);
+    }
+
+    pub fn get(&self, task_id: TaskId) -> Option<TaskHolder> {
+        self.tasks.lock().unwrap().get(&task_id).map(|entry| entry.task.clone())
+    }
+
+    /// All the tasks, to be locked once the registry is released
+    pub fn holders(&self) -> Vec<TaskHolder> {
+        self.tasks.lock().unwrap().values().map(|entry| entry.task.clone()).collect()
+    }
+
+    /// Asks the job of the task to stop at its next cancellation point.
+    /// The task lock must not be held, as the registry is locked before the tasks.
+    pub fn stop_job(&self, task_id: TaskId) {
+        if let Some(entry) = self.tasks.lock().unwrap().get(&task_id) {
+            let _ = entry.tx.send(CCancel::create(task_id));
+        }
+    }
+
+    /// Counts the tasks which are not finished yet
+    pub fn active(&self) -> usize {
+        let tasks = self.tasks.lock().unwrap();
+        tasks.values().filter(|entry| !lock(&entry.task).handle.is_finished()).count()
+    }
+
+    /// The tasks in order of their ids, as listed by the admin subprotocol
+    pub fn list(&self, connection: usize) -> Vec<TaskInfo> {
+        let tasks = self.tasks.lock().unwrap();
+        let mut list = tasks.values()
+            .map(|entry| {
+                let task = lock(&entry.task);
+                TaskInfo {
+                    connection: connection,
+                    task_id: task.handle.task_id,
+                    description: task.action.describe(),
+                    running: task.handle.started.elapsed().as_secs(),
+                    finished: task.handle.is_finished(),
+                }
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|info| info.task_id);
+        list
+    }
+
+    /// Removes the tasks whose jobs are done; gives their number.
+    /// A task left unfinished by its job, which has panicked, is failed on the way.
+    pub fn reap(&self) -> usize {
+        let mut tasks = self.tasks.lock().unwrap();
+        let done = tasks.iter_mut()
+            .filter_map(|(task_id, entry)| {
+                entry.job.as_mut().and_then(|job| job.try_join()).map(|ok| (*task_id, ok))
+            })
+            .collect::<Vec<_>>();
+        for &(task_id, ok) in &done {
+            let entry = tasks.remove(&task_id).unwrap();
+            let mut task = lock(&entry.task);
+            if !task.handle.is_finished() {
+                let failure = if ok { "has given no result" } else { "has panicked" };
+                error!("  ::  The job of task #{} {}", task_id, failure);
+                fail_task(&mut task, ErrorCode::Internal, "The job of the task has failed");
+            }
+        }
+        done.len()
+    }
+
+    /// Takes all the tasks out of the registry, to keep them for a suspended session: those in progress,
+    /// and the finished ones, whose results the lost connection may not have delivered.
+    /// Gives their ids in order, and the registry of the session.
+    pub fn take_all(&self) -> (Vec<TaskId>, TaskRegistry) {
+        let taken = mem::replace(&mut *self.tasks.lock().unwrap(), HashMap::new());
+        let mut task_ids = taken.keys().cloned().collect::<Vec<_>>();
+        task_ids.sort();
+        (task_ids, TaskRegistry { tasks: Mutex::new(taken) })
+    }
+
+    /// Takes over all the tasks of a suspended session. Before they are registered,
+    /// `switch` is called with the tasks locked, so that their jobs send nothing meanwhile.
+    /// Fails with a task id registered in both, taking nothing then.
+    pub fn take_over<F>(&self, other: TaskRegistry, switch: F) -> Result<(), TaskId>
+        where F: FnOnce(&mut [MutexGuard<TaskContainer>])
+    {
+        let mut tasks = self.tasks.lock().unwrap();
+        let taken = mem::replace(&mut *other.tasks.lock().unwrap(), HashMap::new());
+        let busy = taken.keys().cloned().find(|task_id| tasks.contains_key(task_id));
+        if let Some(task_id) = busy {
+            // The tasks of the session are stopped with it
+            *other.tasks.lock().unwrap() = taken;
+            return Err(task_id);
+        }
+        {
+            let holders = taken.values().map(|entry| entry.task.clone()).collect::<Vec<_>>();
+            let mut locked = holders.iter().map(lock).collect::<Vec<_>>();
+            switch(&mut locked[..]);
+        }
+        tasks.extend(taken);
+        Ok(())
+    }
+
+    /// Cancels the tasks not finished yet, with nothing sent to the client, and asks all the jobs to stop.
+    /// The registry is left empty; the jobs are not waited for.
+    pub fn stop_all(&self) {
+        let tasks = match self.tasks.lock() {
+            Ok(mut tasks) => mem::replace(&mut *tasks, HashMap::new()),
+            Err(_) => return,
+        };
+        for (task_id, entry) in tasks {
+            {
+                let mut task = lock(&entry.task);
+                if !task.handle.is_finished() {
+                    debug!("  ::  Task #{} is stopped", task_id);
+                    task.handle.finish(TaskState::Cancelled);
+                }
+            }
+            let _ = entry.tx.send(CCancel::create(task_id));
+        }
+    }
+}
+
+
+/// Locks the task even if its job has panicked with the lock held
+fn lock(task: &TaskHolder) -> MutexGuard<TaskContainer> {
+    task.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+
+impl Drop for TaskRegistry {
+    fn drop(&mut self) {
+        self.stop_all();
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    use ::connection::{StreamMessage, StreamSender};
+    use ::proto::PROTOCOL_VERSION;
+    use ::proto::content::actions::{ContentAction, TaskContainer, TaskHolder};
+    use ::proto::content::message::ClientMessage;
+    use ::proto::content::task::{TaskHandle, TaskState};
+    use ::server::pool::Executor;
+    use ::types::TaskId;
+
+    use super::TaskRegistry;
+
+    fn task(task_id: TaskId) -> (TaskHolder, Receiver<StreamMessage>) {
+        let (tx, rx) = channel();
+        let handle = TaskHandle::new(task_id, StreamSender::new(tx), PROTOCOL_VERSION, None);
+        (Arc::new(Mutex::new(TaskContainer::new(handle, ContentAction::GetInfo))), rx)
+    }
+
+    fn wait_reaped(registry: &TaskRegistry, count: usize) {
+        let mut reaped = 0;
+        while reaped < count {
+            reaped += registry.reap();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn finished_tasks_are_reaped() {
+        let registry = TaskRegistry::new();
+        let (done, _done_rx) = task(1);
+        let (failed, failed_rx) = task(2);
+        for (task_id, holder) in vec![(1, done.clone()), (2, failed.clone())] {
+            let (tx, _) = channel::<ClientMessage>();
+            registry.insert(task_id, holder, tx).unwrap();
+        }
+        assert!(registry.insert(1, done.clone(), channel().0).is_err());
+        assert_eq!(registry.active(), 2);
+
+        let job = Executor::Thread.spawn(move || done.lock().unwrap().handle.finish(TaskState::Done)).unwrap();
+        registry.set_job(1, job);
+        registry.set_job(2, Executor::Thread.spawn(|| panic!("Expected panic")).unwrap());
+        wait_reaped(&registry, 2);
+
+        assert!(registry.list(0).is_empty());
+        assert_eq!(failed.lock().unwrap().handle.state, TaskState::Failed);
+        assert!(failed_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn dropped_registry_stops_tasks() {
+        let registry = TaskRegistry::new();
+        let (holder, rx) = task(1);
+        let (tx, job_rx) = channel::<ClientMessage>();
+        registry.insert(1, holder.clone(), tx).unwrap();
+        let listed = registry.list(7).iter().map(|info| (info.connection, info.task_id)).collect::<Vec<_>>();
+        assert_eq!(listed, vec![(7, 1)]);
+
+        drop(registry);
+        assert_eq!(holder.lock().unwrap().handle.state, TaskState::Cancelled);
+        assert!(match job_rx.try_recv() { Ok(ClientMessage::Cancel(_)) => true, _ => false });
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,19 +1,28 @@
 
-use std::collections::hash_map::{HashMap, Entry};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
 
 use protocol::message::{Message, RawMessage};
-use protocol::workflow::{Protocol, Workflow, WorkflowError};
+use protocol::workflow::{Protocol, ProtocolVersion, Workflow, WorkflowError};
 
-use ::connection::StreamSender;
+use ::connection::{StreamMessage, StreamSender};
 use ::server::database::DatabaseHolder;
+use ::proto::PROGRESS_VERSION;
+use ::proto::admin::message::TaskInfo;
+use ::proto::auth::message::SessionTicket;
+use ::server::control::{Peer, ServerControl, TaskSet};
+use ::server::permissions::Permissions;
+use ::server::pool::{Executor, PoolBusy};
+use ::server::registry::{ConnectionContext, ServerProtocol};
 use ::types::{TaskId};
 
 use super::actions;
-use super::actions::{ContentAction, TaskHolder, TaskContainer};
-use super::message::{ClientMessage, ServerMessage};
-use super::task::{TaskHandle};
+use super::actions::{ContentAction, TaskContainer};
+use super::message::{ClientMessage, ServerMessage, CResume, SCancelled, SError, SResumed};
+use super::message::{ErrorCode, raw_task_id};
+use super::registry::TaskRegistry;
+use super::task::{TaskHandle, TaskState};
 
 
 // --------------------------------------------------------------------------------------------------------------------
@@ -21,16 +30,20 @@ use super::task::{TaskHandle};
 
 pub fn send_message(sender: &StreamSender, message: ServerMessage) -> Result<(), ()> {
     info!("  <<  {:?}", message);
-    let message = match Message::from_raw(message.encode()) {
-        Err(_)  => return Err(()),
-        Ok(r)   => r
-    };
-    trace!("  <<<<  {:?}", message);
-    ::connection::send_message(sender, Some(message));
+    ::connection::send_message(sender, Some(Box::new(message)));
     Ok(())
 }
 
 
+/// Sends a message encoded already, for the version of the client
+pub fn send_raw(sender: &StreamSender, raw_message: RawMessage) {
+    match Message::from_raw(raw_message) {
+        Ok(message) => ::connection::send_message(sender, Some(Box::new(message))),
+        Err(err) => error!("  ::  Error encoding message: {:?}", err),
+    }
+}
+
+
 // --------------------------------------------------------------------------------------------------------------------
 
 
@@ -40,7 +53,22 @@ pub struct ContentConfig {
 }
 
 
-type TaskMap<T> = Arc<Mutex<HashMap<TaskId, T>>>;
+/// The tasks of a connection, as listed and cancelled by the admin subprotocol
+#[derive(Debug)]
+struct ContentTasks {
+    id: usize,
+    tasks: Arc<TaskRegistry>,
+}
+
+
+/// The tasks of a lost connection, with the messages they have sent since
+struct ContentSession {
+    /// The tasks not reaped when the connection was lost, in progress or with their results to send again
+    kept: Vec<TaskId>,
+    /// Stops the tasks if the session is not resumed in time
+    tasks: TaskRegistry,
+    parked: Receiver<StreamMessage>,
+}
 
 
 #[derive(Debug)]
@@ -48,8 +76,18 @@ pub struct ContentProtocol {
     pub db: DatabaseHolder,
     pub id: usize,
     pub sender: StreamSender,
-    tasks_h: TaskMap<TaskHolder>,
-    tasks_tx: TaskMap<Sender<ClientMessage>>,
+    executor: Executor,
+    client_version: ProtocolVersion,
+    /// What the client is allowed to do
+    permissions: Permissions,
+    control: Arc<ServerControl>,
+    identity: Option<String>,
+    peer: Peer,
+    /// Lets the client resume the session if the connection is lost
+    ticket: Option<SessionTicket>,
+    task_timeout: Option<Duration>,
+    session_grace: Option<Duration>,
+    tasks: Arc<TaskRegistry>,
 }
 
 
@@ -58,76 +96,298 @@ pub struct ContentProtocol {
 
 
 impl ContentProtocol {
-    pub fn new(sender: StreamSender, id: usize, db: DatabaseHolder) -> ContentProtocol {
+    pub fn new(context: ConnectionContext) -> ContentProtocol {
+        let (task_timeout, session_grace) = {
+            let config = &context.db.lock().unwrap().config;
+            (seconds(config.task_timeout), seconds(config.session_grace))
+        };
         ContentProtocol {
-            db: db,
-            id: id,
-            sender: sender,
-            tasks_h: Arc::new(Mutex::new(HashMap::new())),
-            tasks_tx: Arc::new(Mutex::new(HashMap::new())),
+            task_timeout: task_timeout,
+            session_grace: session_grace,
+            db: context.db,
+            id: context.id,
+            sender: context.sender,
+            executor: context.executor,
+            client_version: context.client_version,
+            permissions: context.permissions,
+            control: context.control,
+            identity: context.identity,
+            peer: context.peer,
+            ticket: context.ticket,
+            tasks: Arc::new(TaskRegistry::new()),
         }
     }
 
-    pub fn start_task(&self, task_id: TaskId, action: actions::ContentAction) -> Result<(), String> {
+    pub fn start_task(&self, task_id: TaskId, action: actions::ContentAction) -> Result<(), (ErrorCode, String)> {
+        // The tasks done meanwhile are reaped, so that the registry does not grow even without timers
+        self.tasks.reap();
         let (tx, rx) = channel::<ClientMessage>();
-        let handle = TaskHandle::new(task_id, self.sender.clone());
+        let deadline = self.task_timeout.map(|timeout| Instant::now() + timeout);
+        let handle = TaskHandle::new(task_id, self.sender.clone(), self.client_version, deadline);
         let container = TaskContainer::new(handle, action);
         let task_holder = Arc::new(Mutex::new(container));
+        self.tasks.insert(task_id, task_holder.clone(), tx).map_err(|err| (ErrorCode::DuplicateTask, err))?;
+        let job = {
+            let container_ = task_holder.lock().unwrap();
+            container_.action.start(task_holder.clone(), rx, &self.executor)
+        };
+        match job {
+            Ok(job) => {
+                self.tasks.set_job(task_id, job);
+                Ok(())
+            },
+            Err(PoolBusy) => {
+                self.tasks.remove(task_id);
+                Err((ErrorCode::Busy, "The server is busy, try again later".to_owned()))
+            },
+        }
+    }
+
+    /// Fails the task before its job is started, in the layout known to the client
+    fn send_error(&self, task_id: TaskId, code: ErrorCode, message: String) {
+        let message = SError::create(task_id, code, message);
+        info!("  <<  {:?}", message);
+        send_raw(&self.sender, message.encode_for(self.client_version));
+    }
+
+    /// Counts the tasks which are not finished yet
+    pub fn active_tasks(&self) -> usize {
+        self.tasks.active()
+    }
+
+    /// The tasks not reaped yet, for diagnostics
+    pub fn list_tasks(&self) -> Vec<TaskInfo> {
+        self.tasks.list(self.id)
+    }
+
+    /// Fails the tasks which have run past their deadline.
+    /// Their jobs may still be running, but the results are not sent anymore.
+    pub fn expire_tasks(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for holder in self.tasks.holders() {
+            let mut task = holder.lock().unwrap();
+            if !task.handle.is_finished() && task.handle.is_expired(now) {
+                warn!("  ::  Task #{} of connection #{} has exceeded the deadline", task.handle.task_id, self.id);
+                fail_task(&mut task, ErrorCode::DeadlineExceeded, "Task deadline exceeded");
+                expired.push(task.handle.task_id);
+            }
+        }
+        for task_id in expired {
+            self.tasks.stop_job(task_id);
+        }
+    }
+
+    /// Cancels the task at once; its job stops at the next cancellation point
+    fn cancel_task(&self, task_id: TaskId) -> Workflow {
+        let task = match self.tasks.get(task_id) {
+            Some(task) => task,
+            None => {
+                warn!("  ::  Connection #{} cancels an absent task #{}", self.id, task_id);
+                return Workflow::Continue;
+            },
+        };
         {
-            let mut tasks_h = self.tasks_h.lock().unwrap();
-            let mut tasks_tx = self.tasks_tx.lock().unwrap();
-            match tasks_h.entry(task_id) {
-                Entry::Occupied(_) => return Err(format!("Duplicate task id {}", task_id)),
-                Entry::Vacant(entry) => {
-                    tasks_tx.insert(task_id, tx);
-                    entry.insert(task_holder.clone())
-                },
-            };
+            let mut task = task.lock().unwrap();
+            if task.handle.is_finished() {
+                debug!("  ::  Task #{} of connection #{} has finished before cancelling", task_id, self.id);
+                return Workflow::Continue;
+            }
+            info!("  ::  Task #{} of connection #{} is cancelled by the client", task_id, self.id);
+            task.handle.complete(TaskState::Cancelled, SCancelled::create(task_id));
         }
-        let container_ = task_holder.lock().unwrap();
-        container_.action.start(task_holder.clone(), rx);
-        Ok(())
-    }
-
-    pub fn finish_task(&self, task_id: TaskId) -> Result<(), String> {
-        let mut tasks_h = self.tasks_h.lock().unwrap();
-        let mut tasks_tx = self.tasks_tx.lock().unwrap();
-        match tasks_h.entry(task_id) {
-            Entry::Vacant(_) => return Err(format!("Absent task id {}", task_id)),
-            Entry::Occupied(entry) => {
-                tasks_tx.remove(&task_id);
-                entry.remove()
+        self.tasks.stop_job(task_id);
+        Workflow::Continue
+    }
+
+    /// Parks the tasks of the lost connection for the grace period.
+    /// Their jobs go on, and what they send is kept for the client resuming the session,
+    /// after the results of the finished tasks, which the lost connection may not have delivered.
+    pub fn suspend(&self) {
+        let (grace, secret) = match (self.session_grace, self.ticket.as_ref()) {
+            (Some(grace), Some(ticket)) if ticket.is_resumable() => (grace, ticket.secret.0.clone()),
+            _ => return,
+        };
+        let (kept, tasks) = self.tasks.take_all();
+        if kept.is_empty() {
+            return;
+        }
+        let (tx, rx) = channel();
+        let parked = StreamSender::new(tx);
+        for holder in tasks.holders() {
+            let mut task = holder.lock().unwrap();
+            if let Some(result) = task.handle.result.clone() {
+                send_raw(&parked, result);
+            }
+            task.handle.stream_tx = parked.clone();
+        }
+        info!("  ::  Connection #{} is suspended with tasks {:?}", self.id, kept);
+        let session = ContentSession {
+            kept: kept,
+            tasks: tasks,
+            parked: rx,
+        };
+        self.control.suspend_session(self.id, self.identity.clone(), secret, grace, Box::new(session));
+    }
+
+    /// Takes over the tasks of a suspended session and sends what they have sent while it was suspended
+    fn resume(&self, m: CResume) -> Workflow {
+        let session = self.control.resume_session(m.id, &m.secret.0, self.identity.as_ref().map(|s| &s[..]))
+            .and_then(|state| state.downcast::<ContentSession>().ok());
+        let session = match session {
+            Some(session) => *session,
+            None => {
+                warn!("  ::  Connection #{} failed to resume connection #{}", self.id, m.id);
+                let reason = format!("No session of connection #{} to resume", m.id);
+                self.send_error(m.task_id, ErrorCode::ResumeFailed, reason);
+                return Workflow::Continue;
             },
         };
-        Ok(())
+        let ContentSession { kept, tasks, parked } = session;
+        // The jobs send under the locks of their tasks, so nothing is sent until the tasks are switched over
+        let taken = self.tasks.take_over(tasks, |tasks| {
+            info!("  ::  Connection #{} resumes connection #{} with tasks {:?}", self.id, m.id, kept);
+            let _ = send_message(&self.sender, SResumed::create(m.task_id, kept.clone()));
+            while let Ok(message) = parked.try_recv() {
+                ::connection::send_message(&self.sender, message);
+            }
+            for task in tasks.iter_mut() {
+                task.handle.stream_tx = self.sender.clone();
+            }
+        });
+        match taken {
+            Ok(()) => Workflow::Continue,
+            Err(task_id) => {
+                Workflow::Terminate(WorkflowError::ProtocolError(format!("Duplicate task id {}", task_id)))
+            },
+        }
+    }
+}
+
+
+fn seconds(secs: u64) -> Option<Duration> {
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
     }
 }
 
 
-//impl <'a> Drop for ContentProtocol<'a> {
-//    fn drop(mut self) {
-//
-//    }
-//}
+/// Fails the task; the results of its job are not sent anymore
+pub fn fail_task(task: &mut TaskContainer, code: ErrorCode, message: &str) {
+    let task_id = task.handle.task_id;
+    task.handle.complete(TaskState::Failed, SError::create(task_id, code, message.to_owned()));
+}
+
+
+impl TaskSet for ContentTasks {
+    fn list(&self) -> Vec<TaskInfo> {
+        self.tasks.list(self.id)
+    }
+
+    fn active(&self) -> usize {
+        self.tasks.active()
+    }
+
+    fn cancel(&self, task_id: TaskId, reason: &str) -> bool {
+        let task = match self.tasks.get(task_id) {
+            Some(task) => task,
+            None => return false,
+        };
+        {
+            let mut task = task.lock().unwrap();
+            if task.handle.is_finished() {
+                return false;
+            }
+            info!("  ::  Task #{} of connection #{} is cancelled: {}", task_id, self.id, reason);
+            fail_task(&mut task, ErrorCode::Aborted, reason);
+        }
+        self.tasks.stop_job(task_id);
+        true
+    }
+}
+
+
+/// The tasks still running are stopped with the connection, unless they are kept for a suspended session
+impl Drop for ContentProtocol {
+    fn drop(&mut self) {
+        self.tasks.stop_all();
+    }
+}
 
 
 impl Protocol for ContentProtocol {
     fn flow(&self, raw_message: RawMessage) -> Workflow {
+        let raw_task_id = raw_task_id(&raw_message);
         match ClientMessage::parse(raw_message) {
-            Err(err) => Workflow::Terminate(WorkflowError::Exception(format!("{:?}", err))),
+            Err(err) => {
+                // The frame is read in full, so the connection goes on; only the task is failed
+                match raw_task_id {
+                    Some(task_id) => {
+                        warn!("  ::  Task #{} of connection #{} has failed: invalid request {:?}",
+                            task_id, self.id, err);
+                        self.send_error(task_id, ErrorCode::InvalidRequest, format!("Invalid request: {:?}", err));
+                    },
+                    None => warn!("  ::  Dropped an invalid message of connection #{}: {:?}", self.id, err),
+                }
+                Workflow::Continue
+            },
             Ok(v) => {
                 info!("  >>  {:?}", v);
                 let (task_id, action) = match v {
                     ClientMessage::GetInfo(m) => (m.task_id, ContentAction::GetInfo),
                     ClientMessage::CopyFrom(m) => (m.task_id,
-                        ContentAction::CopyFrom(actions::CopyFrom{uri: m.uri, db: self.db.clone()})
+                        ContentAction::CopyFrom(actions::CopyFrom {
+                            uri: m.uri,
+                            db: self.db.clone(),
+                            progress: self.client_version >= PROGRESS_VERSION,
+                        })
                     ),
+                    ClientMessage::Resume(m) => return self.resume(m),
+                    ClientMessage::Cancel(m) => return self.cancel_task(m.task_id),
                 };
-                match self.start_task(task_id, action) {
-                    Ok(_)       => Workflow::Continue,
-                    Err(err)    => Workflow::Terminate(WorkflowError::ProtocolError(err))
+                let permission = action.permission();
+                if !self.permissions.contains(permission) {
+                    warn!("  ::  Task #{} of connection #{} is rejected: no {} permission",
+                        task_id, self.id, permission);
+                    let reason = format!("Permission denied: {} is not allowed", permission);
+                    self.send_error(task_id, ErrorCode::PermissionDenied, reason);
+                    return Workflow::Continue;
+                }
+                if !self.control.take_request(self.identity.as_ref().map(|s| &s[..]), &self.peer) {
+                    warn!("  ::  Task #{} of connection #{} is rejected: rate limit exceeded", task_id, self.id);
+                    self.send_error(task_id, ErrorCode::RateLimited, "Rate limit exceeded".to_owned());
+                    return Workflow::Continue;
                 }
+                if let Err((code, err)) = self.start_task(task_id, action) {
+                    warn!("  ::  Task #{} of connection #{} has failed: {}", task_id, self.id, err);
+                    self.send_error(task_id, code, err);
+                }
+                Workflow::Continue
             }
         }
     }
 }
+
+
+impl ServerProtocol for ContentProtocol {
+    fn active_tasks(&self) -> usize {
+        ContentProtocol::active_tasks(self)
+    }
+
+    fn on_tick(&self) {
+        self.tasks.reap();
+        self.expire_tasks()
+    }
+
+    fn tasks(&self) -> Option<Arc<TaskSet>> {
+        Some(Arc::new(ContentTasks {
+            id: self.id,
+            tasks: self.tasks.clone(),
+        }))
+    }
+
+    fn suspend(&self) {
+        ContentProtocol::suspend(self)
+    }
+}
@@ -1,6 +1,10 @@
 
+use std::fmt;
+
+use ::types::TaskId;
+
 use super::message;
-use super::message::ServerMessage;
+use super::message::{ServerMessage, SCopyFromState};
 use super::task::{StateHandle, State, SimpleState};
 
 
@@ -8,6 +12,8 @@ use super::task::{StateHandle, State, SimpleState};
 pub enum ContentState {
     GetInfo(GetInfo),
     CopyFrom(CopyFrom),
+    Resume(Resume),
+    Cancelled(Cancelled),
 }
 
 
@@ -20,10 +26,28 @@ pub struct GetInfo {
 #[derive(Debug)]
 pub struct CopyFrom {
     uri: String,
+    /// The last progress update: the bytes copied and the size of the file
+    pub progress: Option<(u64, u64)>,
     pub result: Option<message::SCopyFrom>,
 }
 
 
+/// The task cancelled by the client, as confirmed by the server
+#[derive(Debug)]
+pub struct Cancelled {
+    pub task_id: TaskId,
+}
+
+
+/// Takes over the tasks of a lost connection
+pub struct Resume {
+    id: usize,
+    secret: Vec<u8>,
+    /// The tasks the server has kept: in progress, or with their results sent again
+    pub tasks: Vec<TaskId>,
+}
+
+
 // --------------------------------------------------------------------------------------------------------------------
 
 
@@ -32,12 +56,16 @@ impl State for ContentState {
         match *self {
             ContentState::GetInfo(ref mut s) => s.start(task_state),
             ContentState::CopyFrom(ref mut s) => s.start(task_state),
+            ContentState::Resume(ref mut s) => s.start(task_state),
+            ContentState::Cancelled(ref mut s) => s.start(task_state),
         }
     }
     fn handle_message<T: State>(&mut self, task_state: &StateHandle<T>, message: ServerMessage) -> Result<(), String> {
         match *self {
             ContentState::GetInfo(ref mut s) => s.handle_message(task_state, message),
             ContentState::CopyFrom(ref mut s) => s.handle_message(task_state, message),
+            ContentState::Resume(ref mut s) => s.handle_message(task_state, message),
+            ContentState::Cancelled(ref mut s) => s.handle_message(task_state, message),
         }
     }
 }
@@ -75,7 +103,7 @@ impl State for GetInfo {
 
 impl <'a> CopyFrom {
     pub fn create(uri: String) -> ContentState {
-        ContentState::CopyFrom(CopyFrom { uri: uri, result: None })
+        ContentState::CopyFrom(CopyFrom { uri: uri, progress: None, result: None })
     }
 }
 
@@ -87,6 +115,11 @@ impl State for CopyFrom {
 
     fn handle_message<T: State>(&mut self, task_state: &StateHandle<T>, server_message: ServerMessage) -> Result<(), String> {
         match server_message {
+            ServerMessage::CopyFrom(message::SCopyFrom { state: SCopyFromState::Progress { done, total }, .. }) => {
+                self.progress = Some((done, total));
+                task_state.report_progress(done, total);
+                Ok(())
+            },
             ServerMessage::CopyFrom(copy_from) => {
                 self.result = Some(copy_from);
                 *task_state.state.borrow_mut() = SimpleState::Ready;
@@ -99,3 +132,57 @@ impl State for CopyFrom {
         }
     }
 }
+
+
+impl Resume {
+    pub fn create(id: usize, secret: Vec<u8>) -> ContentState {
+        ContentState::Resume(Resume { id: id, secret: secret, tasks: Vec::new() })
+    }
+}
+
+
+impl fmt::Debug for Resume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Resume {{ id: {}, tasks: {:?} }}", self.id, self.tasks)
+    }
+}
+
+
+impl State for Resume {
+    fn start<T: State>(&mut self, task_state: &StateHandle<T>) {
+        let message = message::CResume::create(task_state.task_id, self.id, self.secret.clone());
+        super::client::send_message(&task_state.stream_tx, message);
+    }
+
+    fn handle_message<T: State>(&mut self, task_state: &StateHandle<T>, server_message: ServerMessage) -> Result<(), String> {
+        match server_message {
+            ServerMessage::Resumed(resumed) => {
+                self.tasks = resumed.tasks;
+                *task_state.state.borrow_mut() = SimpleState::Ready;
+                Ok(())
+            },
+            _ => {
+                *task_state.state.borrow_mut() = SimpleState::Error;
+                Err(format!("Unexpected message {:?} for task {:?}", server_message, self))
+            }
+        }
+    }
+}
+
+
+impl Cancelled {
+    pub fn create(task_id: TaskId) -> ContentState {
+        ContentState::Cancelled(Cancelled { task_id: task_id })
+    }
+}
+
+
+impl State for Cancelled {
+    fn start<T: State>(&mut self, _task_state: &StateHandle<T>) {}
+
+    /// The job may have sent something before it is stopped; that is of no use anymore
+    fn handle_message<T: State>(&mut self, _task_state: &StateHandle<T>, server_message: ServerMessage) -> Result<(), String> {
+        debug!("Ignored message {:?} for cancelled task #{}", server_message, self.task_id);
+        Ok(())
+    }
+}
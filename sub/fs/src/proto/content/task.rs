@@ -1,25 +1,51 @@
 
 
-use std::cell::{Cell, RefCell};
+use std::cell::RefCell;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Receiver};
 use std::thread;
+use std::time::Instant;
 
 
+use protocol::message::RawMessage;
+use protocol::workflow::ProtocolVersion;
+
 use ::connection::{StreamSender};
 use ::types::{TaskId};
 
-use super::message::{ClientMessage, ServerMessage};
+use super::message::{ClientMessage, ErrorCode, ServerMessage};
+use super::server::send_raw;
 
 
 // --------------------------------------------------------------------------------------------------------------------
 
 
+/// Where a task is in its life on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    /// The result is sent
+    Done,
+    /// The task is rejected or failed, by its job or by the server
+    Failed,
+    Cancelled,
+}
+
+
 #[derive(Debug)]
 pub struct TaskHandle {
     pub task_id: TaskId,
     pub stream_tx: StreamSender,
-    pub finished: Cell<bool>,
+    /// The messages of the task are encoded for the client of this version
+    pub client_version: ProtocolVersion,
+    /// Nothing is sent for the task once it is finished
+    pub state: TaskState,
+    pub started: Instant,
+    /// The task is failed if not finished by this time
+    pub deadline: Option<Instant>,
+    /// The last message sent for the finished task, sent again to a resumed session
+    pub result: Option<RawMessage>,
 }
 
 
@@ -31,17 +57,48 @@ pub enum SimpleState{
     Waiting,
     Error,
     Ready,
+    Cancelled,
 }
 
 
 pub type StateHolder<T> = Arc<Mutex<StateHandle<T>>>;
 
 
+/// Why a task has given no result
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskError {
+    /// The server has refused to start the task; newer servers fail it with the code of the refusal instead
+    Rejected(String),
+    /// The task has failed on the server
+    Failed(ErrorCode, String),
+    /// The server has answered with a message the task can't take
+    InvalidAnswer(String),
+    /// The connection is lost before the task is finished
+    NotFinished(String),
+    /// The server has not kept the task for a resumed session
+    Lost,
+    /// The task is not started, or its result is taken already
+    Absent,
+}
+
+
+/// Takes the progress updates of a task: the bytes done and the total
+pub struct ProgressCallback(pub Box<FnMut(u64, u64) + Send>);
+
+
+/// Takes the result of a task once it is finished
+pub struct CompletionCallback<S>(pub Box<FnMut(Result<S, TaskError>) + Send>);
+
+
 #[derive(Debug)]
 pub struct StateHandle<S: State> {
     pub task_id: TaskId,
     pub stream_tx: StreamSender,
     pub state: RefCell<SimpleState>,
+    /// Why the task has failed
+    pub error: RefCell<Option<TaskError>>,
+    pub on_progress: RefCell<Option<ProgressCallback>>,
+    pub on_complete: RefCell<Option<CompletionCallback<S>>>,
     pub task: RefCell<S>,
 }
 
@@ -56,13 +113,41 @@ pub trait State {
 
 
 impl TaskHandle {
-    pub fn new(task_id: TaskId, stream_tx: StreamSender) -> Self {
+    pub fn new(task_id: TaskId, stream_tx: StreamSender, client_version: ProtocolVersion, deadline: Option<Instant>)
+        -> Self
+    {
         TaskHandle {
             task_id: task_id,
             stream_tx: stream_tx,
-            finished: Cell::new(false),
+            client_version: client_version,
+            state: TaskState::Running,
+            started: Instant::now(),
+            deadline: deadline,
+            result: None,
         }
     }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.map_or(false, |deadline| deadline <= now)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state != TaskState::Running
+    }
+
+    pub fn finish(&mut self, state: TaskState) {
+        self.state = state;
+    }
+
+    /// Sends the last message of the task and finishes it.
+    /// The message is kept, as the connection may be lost before it is delivered.
+    pub fn complete(&mut self, state: TaskState, message: ServerMessage) {
+        info!("  <<  {:?}", message);
+        let raw = message.encode_for(self.client_version);
+        send_raw(&self.stream_tx, raw.clone());
+        self.result = Some(raw);
+        self.finish(state);
+    }
 }
 
 
@@ -75,9 +160,56 @@ impl <S: State> StateHandle<S> {
             task_id: task_id,
             stream_tx: stream_tx,
             state: RefCell::new(SimpleState::Waiting),
+            error: RefCell::new(None),
+            on_progress: RefCell::new(None),
+            on_complete: RefCell::new(None),
             task: RefCell::new(task),
         }
     }
+
+    /// Fails the task unless it is finished already
+    pub fn fail(&self, error: TaskError) {
+        if self.state.borrow().is_waiting() {
+            *self.state.borrow_mut() = SimpleState::Error;
+        }
+        if self.state.borrow().is_error() && self.error.borrow().is_none() {
+            *self.error.borrow_mut() = Some(error);
+        }
+    }
+
+    pub fn report_progress(&self, done: u64, total: u64) {
+        if let Some(ref mut callback) = *self.on_progress.borrow_mut() {
+            (callback.0)(done, total);
+        }
+    }
+}
+
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TaskError::Rejected(ref reason) => write!(f, "The task is rejected: {}", reason),
+            TaskError::Failed(code, ref message) => write!(f, "The task has failed ({:?}): {}", code, message),
+            TaskError::InvalidAnswer(ref error) => write!(f, "Invalid answer of the server: {}", error),
+            TaskError::NotFinished(ref reason) => write!(f, "The task is not finished: {}", reason),
+            TaskError::Lost => write!(f, "The task is lost with the session"),
+            TaskError::Absent => write!(f, "The task is absent"),
+        }
+    }
+}
+
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressCallback")
+    }
+}
+
+
+impl <S> fmt::Debug for CompletionCallback<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CompletionCallback")
+    }
 }
 
 
@@ -100,6 +232,12 @@ impl SimpleState {
             _ => false,
         }
     }
+    pub fn is_cancelled(&self) -> bool {
+        match *self {
+            SimpleState::Cancelled => true,
+            _ => false,
+        }
+    }
 }
 
 
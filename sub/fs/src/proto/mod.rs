@@ -1,10 +1,44 @@
 
+pub mod admin;
 pub mod content;
 pub mod auth;
 
-use protocol::workflow::ProtocolVersion;
+use protocol::workflow::{ProtocolVersion, ProtocolVersionRange};
 
 
-const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 1);
+/// The version of the protocol spoken by this side of a connection
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 10);
 
+/// The first version told the accepted versions when rejected for its own
+pub const VERSION_RANGE_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 2);
+
+/// The first version answering heartbeat pings
+pub const HEARTBEAT_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 3);
+
+/// The first version negotiating frame checksums on start
+pub const CHECKSUM_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 4);
+
+/// The first version authenticating with a challenge-response
+pub const AUTH_HASH_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 5);
+
+/// The first version authenticating by the credentials of a Unix socket peer
+pub const AUTH_SCM_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 6);
+
+/// The first version authenticating with tokens minted by the server
+pub const AUTH_TOKEN_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 7);
+
+/// The first version given a ticket to resume its session on another connection
+pub const SESSION_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 8);
+
+/// The first version taking progress updates of long tasks
+pub const PROGRESS_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 9);
+
+/// The first version given the error codes of failed tasks
+pub const TASK_ERROR_VERSION: ProtocolVersion = ProtocolVersion(0, 1, 10);
+
+/// The versions of clients accepted by the server
+pub const SUPPORTED_VERSIONS: ProtocolVersionRange = ProtocolVersionRange {
+    min: ProtocolVersion(0, 1, 1),
+    max: PROTOCOL_VERSION,
+};
 
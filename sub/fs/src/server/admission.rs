@@ -0,0 +1,331 @@
+//! Admission of connections and requests: address lists, connection limits and request rates.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::config::Config;
+use super::control::Peer;
+
+
+/// Rate buckets are pruned once there are so many of them
+const MAX_BUCKETS: usize = 1024;
+
+
+/// A network given by an address and a prefix length, like `10.0.0.0/8`.
+/// An address alone stands for the network of this address only.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+
+/// Limits the requests of each client to `rate` per second, allowing bursts of up to `burst` requests
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+
+/// Decides which connections and requests are served
+#[derive(Debug, Default)]
+pub struct Admission {
+    /// Only the TCP clients from these networks are served, if any are listed
+    allow: Vec<Cidr>,
+    /// The TCP clients from these networks are never served
+    deny: Vec<Cidr>,
+    /// The limit of connections, if any
+    max_connections: Option<usize>,
+    /// The limit of connections from an IP address or a Unix user, if any
+    max_per_peer: Option<usize>,
+    rate: Option<RateLimiter>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (network, ip) = match (self.network, *ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (network.octets().to_vec(), ip.octets().to_vec()),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (network.octets().to_vec(), ip.octets().to_vec()),
+            _ => return false,
+        };
+        let prefix = self.prefix as usize;
+        let (bytes, bits) = (prefix / 8, prefix % 8);
+        if network[..bytes] != ip[..bytes] {
+            return false;
+        }
+        bits == 0 || {
+            let mask = 0xffu8 << (8 - bits);
+            network[bytes] & mask == ip[bytes] & mask
+        }
+    }
+}
+
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let network = parts.next().unwrap_or("").parse::<IpAddr>()
+            .map_err(|_| format!("Invalid network address in {}", s))?;
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().ok().and_then(|prefix| if prefix <= max { Some(prefix) } else { None })
+                .ok_or_else(|| format!("Invalid prefix length in {}", s))?,
+            None => max,
+        };
+        Ok(Cidr { network: network, prefix: prefix })
+    }
+}
+
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            burst: if burst > 0 { burst as f64 } else { rate as f64 },
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a request of the client from its bucket. Returns `false` if the bucket is empty.
+    pub fn take(&self, client: &str, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            self.prune(&mut buckets, now);
+        }
+        let burst = self.burst;
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let tokens = bucket.tokens + secs * self.rate;
+        if tokens < self.burst { tokens } else { self.burst }
+    }
+
+    /// Forgets the clients whose buckets are full again, as if they have never sent a request
+    fn prune(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let full = buckets.iter()
+            .filter(|&(_, bucket)| self.refill(bucket, now) >= self.burst)
+            .map(|(client, _)| client.clone())
+            .collect::<Vec<_>>();
+        for client in full {
+            buckets.remove(&client);
+        }
+    }
+}
+
+
+impl Admission {
+    pub fn from_config(config: &Config) -> io::Result<Admission> {
+        let parse = |hosts: &[String]| hosts.iter()
+            .map(|host| host.parse::<Cidr>().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)))
+            .collect::<io::Result<Vec<_>>>();
+        let limit = |n| if n > 0 { Some(n) } else { None };
+        Ok(Admission {
+            allow: parse(&config.allow_hosts)?,
+            deny: parse(&config.deny_hosts)?,
+            max_connections: limit(config.max_connections),
+            max_per_peer: limit(config.max_connections_per_peer),
+            rate: match config.rate_limit {
+                0 => None,
+                rate => Some(RateLimiter::new(rate, config.rate_burst)),
+            },
+        })
+    }
+
+    /// Checks the address of the peer against the allow and deny lists. Unix socket peers are always allowed.
+    pub fn is_allowed(&self, peer: &Peer) -> bool {
+        let ip = match *peer {
+            Peer::Tcp(addr) => addr.ip(),
+            Peer::Unix(_) => return true,
+            Peer::Unknown => return self.allow.is_empty() && self.deny.is_empty(),
+        };
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(&ip)))
+            && !self.deny.iter().any(|cidr| cidr.contains(&ip))
+    }
+
+    /// Decides whether a new connection from the peer is served, given the peers of the other connections.
+    /// Returns the reason of the rejection otherwise.
+    pub fn admit(&self, peer: &Peer, others: &[Peer]) -> Result<(), String> {
+        if !self.is_allowed(peer) {
+            return Err(format!("Connections from {} are not allowed", peer));
+        }
+        if let Some(max) = self.max_connections {
+            if others.len() >= max {
+                return Err(format!("Too many connections, the limit is {}", max));
+            }
+        }
+        if let (Some(max), Some(key)) = (self.max_per_peer, peer_key(peer)) {
+            if others.iter().filter(|other| peer_key(other).as_ref() == Some(&key)).count() >= max {
+                return Err(format!("Too many connections from {}, the limit is {}", key, max));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a request of the client, known by its identity or else by its peer.
+    /// Returns `false` if the client has exceeded its rate.
+    pub fn take_request(&self, identity: Option<&str>, peer: &Peer) -> bool {
+        let rate = match self.rate {
+            Some(ref rate) => rate,
+            None => return true,
+        };
+        let client = match (identity, peer_key(peer)) {
+            (Some(identity), _) => format!("identity {}", identity),
+            (None, Some(key)) => key,
+            (None, None) => "unknown".to_owned(),
+        };
+        rate.take(&client, Instant::now())
+    }
+}
+
+
+/// The IP address of a TCP peer or the user of a Unix socket peer
+pub fn peer_key(peer: &Peer) -> Option<String> {
+    match *peer {
+        Peer::Tcp(addr) => Some(addr.ip().to_string()),
+        Peer::Unix(Some(ref cred)) => Some(format!("uid {}", cred.uid)),
+        Peer::Unix(None) | Peer::Unknown => None,
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ::server::config::Config;
+    use ::server::control::{Peer, PeerCred};
+
+    use super::{Admission, Cidr, RateLimiter};
+
+    fn tcp(addr: &str) -> Peer {
+        Peer::Tcp(addr.parse().unwrap())
+    }
+
+    fn unix(uid: u32) -> Peer {
+        Peer::Unix(Some(PeerCred { uid: uid, gid: 100, pid: None }))
+    }
+
+    #[test]
+    fn cidr() {
+        let cidr = "10.1.0.0/16".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(!cidr.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let cidr = "192.168.1.128/25".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&"192.168.1.200".parse().unwrap()));
+        assert!(!cidr.contains(&"192.168.1.100".parse().unwrap()));
+
+        let single = "127.0.0.1".parse::<Cidr>().unwrap();
+        assert_eq!(single.to_string(), "127.0.0.1/32");
+        assert!(!single.contains(&"127.0.0.2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&"8.8.8.8".parse().unwrap()));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(&"fd12::1".parse().unwrap()));
+
+        for invalid in &["10.0.0.0/33", "10.0.0.0/", "example.com", "::/129"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let mut config = Config::new();
+        config.allow_hosts = vec!["10.0.0.0/8".to_owned()];
+        config.deny_hosts = vec!["10.6.6.0/24".to_owned()];
+        let admission = Admission::from_config(&config).unwrap();
+        assert!(admission.admit(&tcp("10.1.2.3:4000"), &[]).is_ok());
+        assert!(admission.admit(&tcp("10.6.6.6:4000"), &[]).unwrap_err().contains("not allowed"));
+        assert!(admission.admit(&tcp("192.168.0.1:4000"), &[]).is_err());
+        assert!(admission.admit(&unix(1000), &[]).is_ok());
+
+        config.deny_hosts = vec!["10.6.6.0/99".to_owned()];
+        assert!(Admission::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn connection_limits() {
+        let mut config = Config::new();
+        config.max_connections = 3;
+        config.max_connections_per_peer = 2;
+        let admission = Admission::from_config(&config).unwrap();
+
+        let others = vec![tcp("10.0.0.1:4000"), tcp("10.0.0.1:4001")];
+        assert!(admission.admit(&tcp("10.0.0.1:4002"), &others).unwrap_err().contains("from 10.0.0.1"));
+        assert!(admission.admit(&tcp("10.0.0.2:4000"), &others).is_ok());
+        assert!(admission.admit(&unix(1000), &[unix(1000), unix(1001)]).is_ok());
+        assert!(admission.admit(&unix(1000), &[unix(1000), unix(1000)]).is_err());
+
+        let others = vec![tcp("10.0.0.1:4000"), tcp("10.0.0.2:4000"), unix(1000)];
+        assert!(admission.admit(&tcp("10.0.0.3:4000"), &others).unwrap_err().contains("Too many connections,"));
+
+        assert!(Admission::default().admit(&tcp("10.0.0.1:4000"), &vec![tcp("10.0.0.1:4000"); 100]).is_ok());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let limiter = RateLimiter::new(2, 3);
+        let start = Instant::now();
+        assert!((0..3).all(|_| limiter.take("alice", start)));
+        assert!(!limiter.take("alice", start));
+        assert!(limiter.take("bob", start));
+
+        // Two requests a second come back, up to the burst
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.take("alice", later));
+        assert!(!limiter.take("alice", later));
+        let much_later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.take("alice", much_later)));
+        assert!(!limiter.take("alice", much_later));
+
+        let mut config = Config::new();
+        config.rate_limit = 1;
+        let admission = Admission::from_config(&config).unwrap();
+        assert!(admission.take_request(Some("alice"), &tcp("10.0.0.1:4000")));
+        assert!(!admission.take_request(Some("alice"), &tcp("10.0.0.2:4000")));
+        assert!(admission.take_request(None, &tcp("10.0.0.1:4000")));
+        assert!(Admission::default().take_request(None, &tcp("10.0.0.1:4000")));
+    }
+}
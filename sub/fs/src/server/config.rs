@@ -1,4 +1,19 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use protocol::capture::Capture;
+use protocol::heartbeat::Heartbeat;
+use protocol::tls;
+use protocol::tls::ServerConfig;
+
+use super::control::{Peer, PeerCred};
+use super::permissions::{Permission, Permissions};
+use super::tokens::Claims;
 
 
 #[derive(Debug)]
@@ -6,15 +21,78 @@ pub struct Config {
     pub pidfile: &'static Path,
     pub workdir: &'static Path,
     pub filesdir: &'static Path,
+    /// The directories the clients may import files from with `CopyFrom`; nothing can be imported when empty
+    pub import_roots: Vec<String>,
 
     pub daemonize: bool,
+    /// The frequency of timer checks, like idle connections and task deadlines
     pub hz: u32,
 
     pub bind: Vec<String>,
     pub port: u16,
     pub tcp_keepalive: u32,
     pub tcp_backlog: i32,
+    /// Serve at most N connections at once, 0 for no limit
+    pub max_connections: usize,
+    /// Serve at most N connections at once from an IP address or a Unix user, 0 for no limit
+    pub max_connections_per_peer: usize,
+    /// The networks of TCP clients served, like `10.0.0.0/8`; all are served when empty
+    pub allow_hosts: Vec<String>,
+    /// The networks of TCP clients never served, even if allowed
+    pub deny_hosts: Vec<String>,
+    /// Start at most N tasks a second for each identity, or for each peer of anonymous clients, 0 for no limit
+    pub rate_limit: u32,
+    /// The number of tasks which may be started at once within the rate limit; the rate itself when 0
+    pub rate_burst: u32,
+    /// Close a connection after it has been idle (no requests and no running tasks) for N seconds, 0 to disable
     pub timeout: u64,
+    /// Fail tasks running for more than N seconds, 0 to disable
+    pub task_timeout: u64,
+    /// Keep the tasks of a lost connection for N seconds, so that its client may resume them, 0 to disable
+    pub session_grace: u64,
+    /// Ping clients each N seconds, 0 to disable
+    pub heartbeat_interval: u64,
+    /// Close the connection when so many pings are left without an answer
+    pub heartbeat_misses: u32,
+
+    /// The certificate chain and the private key files. TCP connections are served over TLS when set
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Require clients to present a certificate signed by this CA
+    pub tls_client_ca: Option<String>,
+
+    /// Serve all connections from a single event loop instead of a thread per connection. Can't be used with TLS
+    pub event_loop: bool,
+    /// The number of threads running blocking jobs of tasks in the event loop mode
+    pub workers: usize,
+
+    /// Accept frame checksums requested by clients
+    pub frame_checksum: bool,
+    /// Record the frames of each connection into a capture file in this directory.
+    /// Frames are recorded as is, including the passwords of clients
+    pub capture_dir: Option<String>,
+
+    /// The IP addresses of TCP clients allowed to use the admin subprotocol.
+    /// Clients connected to the Unix socket may use it too; all of them also need the admin permission.
+    pub admin_hosts: Vec<String>,
+    /// On shutdown, wait up to N seconds for the running tasks to finish
+    pub shutdown_timeout: u64,
+
+    /// The file of client logins and password hashes. Clients must authenticate with a password when set,
+    /// which is only accepted over TLS or the Unix socket
+    pub credentials: Option<String>,
+    /// The file of the key signing tokens, in hex. Tokens are only accepted when set, like passwords
+    pub token_key: Option<String>,
+    /// The file keeping the ids of revoked tokens over restarts
+    pub token_revocations: Option<String>,
+    /// The identities of the processes connected to the Unix socket, given by their user or group.
+    /// The first matching rule applies; the processes matching none authenticate as over TCP
+    pub unix_identities: Vec<UnixIdentity>,
+    /// The permissions of the identities
+    pub permissions: HashMap<String, Permissions>,
+    /// The permissions of anonymous clients and of the identities not listed. Never includes admin,
+    /// which is only given to the identities listed
+    pub default_permissions: Permissions,
 
     pub unixsocket: Option<String>,
     pub unixsocketperm: u32,
@@ -24,6 +102,21 @@ pub struct Config {
     pub syslog_facility: String,
 }
 
+/// Gives an identity to the Unix socket peers run by the user and in the group, if set
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixIdentity {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub identity: String,
+}
+
+/// The permissions of the config, granted to the clients once they are authenticated
+#[derive(Debug, Clone)]
+pub struct Grants {
+    permissions: HashMap<String, Permissions>,
+    default_permissions: Permissions,
+}
+
 //#[derive(Debug)]
 //pub enum ConfigError {
 //    InvalidFormat,
@@ -40,6 +133,7 @@ impl Config {
             pidfile: Path::new("/var/run/irbis/ifsd.pid"),
             workdir: Path::new("/srv/irbisfs/content"),
             filesdir: Path::new("/srv/irbisfs/content/files"),
+            import_roots: vec![],
 
             daemonize: false,
             hz: 10,
@@ -50,7 +144,38 @@ impl Config {
             port: port,
             tcp_keepalive: 0,
             tcp_backlog: 511,
+            max_connections: 0,
+            max_connections_per_peer: 0,
+            allow_hosts: vec![],
+            deny_hosts: vec![],
+            rate_limit: 0,
+            rate_burst: 0,
             timeout: 0,
+            task_timeout: 0,
+            session_grace: 30,
+            heartbeat_interval: 0,
+            heartbeat_misses: 3,
+
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+
+            event_loop: false,
+            workers: 4,
+
+            frame_checksum: true,
+            capture_dir: None,
+
+            admin_hosts: vec![],
+            shutdown_timeout: 30,
+
+            credentials: None,
+            token_key: None,
+            token_revocations: None,
+            unix_identities: vec![],
+            permissions: HashMap::new(),
+            // Only the info is given away; anything else needs an identity allowed to do it
+            default_permissions: Permissions::of(&[Permission::Info]),
 
             unixsocket: None,
             unixsocketperm: 0700,
@@ -65,6 +190,95 @@ impl Config {
         Self::default(1313)
     }
 
+    /// The interval of timer checks, if any timeouts are enabled
+    pub fn tick(&self) -> Option<Duration> {
+        match (self.timeout, self.task_timeout, self.heartbeat_interval) {
+            (0, 0, 0) => None,
+            _ => Some(Duration::from_millis(1000 / cmp::max(self.hz, 1) as u64)),
+        }
+    }
+
+    pub fn heartbeat(&self) -> Option<Heartbeat> {
+        match self.heartbeat_interval {
+            0 => None,
+            secs => Some(Heartbeat::new(Duration::from_secs(secs), self.heartbeat_misses)),
+        }
+    }
+
+    /// Checks the settings which can't be served together
+    pub fn check(&self) -> io::Result<()> {
+        if self.event_loop && (self.tls_cert.is_some() || self.tls_key.is_some()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "TLS is not supported by the event loop: unset event_loop or tls_cert and tls_key"));
+        }
+        Ok(())
+    }
+
+    /// Loads the TLS configuration if it is enabled
+    pub fn tls(&self) -> io::Result<Option<Arc<ServerConfig>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (&Some(ref cert), &Some(ref key)) =>
+                tls::server_config(cert, key, self.tls_client_ca.as_ref().map(|s| &s[..])).map(Some),
+            (&None, &None) => Ok(None),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Both tls_cert and tls_key must be set")),
+        }
+    }
+
+    /// Creates the capture file of a connection, if recording is enabled.
+    /// A connection is served without recording if the file can not be created.
+    pub fn capture(&self, id: usize) -> Option<Arc<Capture>> {
+        let dir = match self.capture_dir {
+            Some(ref dir) => Path::new(dir),
+            None => return None,
+        };
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let path = dir.join(format!("{}-{}.ifscap", started, id));
+        match Capture::server(&path) {
+            Ok(capture) => {
+                info!("Recording connection {} into {}", id, path.display());
+                Some(Arc::new(capture))
+            },
+            Err(err) => {
+                warn!("Creating capture file {}: {:?}", path.display(), err);
+                None
+            },
+        }
+    }
+
+    /// Whether the admin subprotocol may be used from the peer, by an identity with the admin permission
+    pub fn is_admin_peer(&self, peer: &Peer) -> bool {
+        match *peer {
+            Peer::Unix(_) => true,
+            Peer::Tcp(addr) => self.admin_hosts.iter().any(|host| host.parse::<IpAddr>().ok() == Some(addr.ip())),
+            Peer::Unknown => false,
+        }
+    }
+
+    /// The identity of the peer by its credentials on the Unix socket, if any rule matches
+    pub fn peer_identity(&self, peer: &Peer) -> Option<String> {
+        match *peer {
+            Peer::Unix(Some(ref cred)) => self.unix_identities.iter()
+                .find(|rule| rule.matches(cred))
+                .map(|rule| rule.identity.clone()),
+            _ => None,
+        }
+    }
+
+    /// The permissions of the client with the identity given by the auth stage, if any
+    pub fn permissions_of(&self, identity: Option<&str>) -> Permissions {
+        permissions_of(&self.permissions, self.default_permissions, identity)
+    }
+
+    /// The permissions of the identity, limited to the scopes of the token it has authenticated with
+    pub fn granted(&self, identity: Option<&str>, token: Option<&Claims>) -> Permissions {
+        limit(self.permissions_of(identity), token)
+    }
+
+    /// A copy of the permissions, to be granted without holding the database
+    pub fn grants(&self) -> Grants {
+        Grants { permissions: self.permissions.clone(), default_permissions: self.default_permissions }
+    }
+
     // FIXME никаких заглушек "на случай если нет", список адресов должен формироваться сразу в конфигурации
     pub fn addresses(&self) -> Vec<(String, u16)> {
         if self.bind.len() == 0 {
@@ -74,3 +288,74 @@ impl Config {
         }
     }
 }
+
+
+impl Grants {
+    /// See `Config::granted`
+    pub fn granted(&self, identity: Option<&str>, token: Option<&Claims>) -> Permissions {
+        limit(permissions_of(&self.permissions, self.default_permissions, identity), token)
+    }
+}
+
+
+impl UnixIdentity {
+    pub fn matches(&self, cred: &PeerCred) -> bool {
+        self.uid.map_or(true, |uid| uid == cred.uid) && self.gid.map_or(true, |gid| gid == cred.gid)
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+fn permissions_of(permissions: &HashMap<String, Permissions>, default_permissions: Permissions,
+                  identity: Option<&str>) -> Permissions
+{
+    match identity.and_then(|identity| permissions.get(identity)) {
+        Some(&permissions) => permissions,
+        None => default_permissions.without(Permission::Admin),
+    }
+}
+
+
+fn limit(permissions: Permissions, token: Option<&Claims>) -> Permissions {
+    token.map_or(permissions, |claims| permissions.intersect(claims.scopes))
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use ::server::permissions::{Permission, Permissions};
+    use super::Config;
+
+    #[test]
+    fn event_loop_refuses_tls() {
+        let mut config = Config::new();
+        config.event_loop = true;
+        assert!(config.check().is_ok());
+        config.tls_cert = Some("server.pem".to_owned());
+        assert!(config.check().is_err());
+        config.event_loop = false;
+        assert!(config.check().is_ok());
+    }
+
+    #[test]
+    fn admin_only_by_identity() {
+        let mut config = Config::new();
+        assert_eq!(config.permissions_of(None), Permissions::of(&[Permission::Info]));
+        config.default_permissions = Permissions::all();
+        config.permissions.insert("root".to_owned(), Permissions::all());
+        assert_eq!(config.permissions_of(Some("root")), Permissions::all());
+        for identity in vec![None, Some("guest")] {
+            let permissions = config.permissions_of(identity);
+            assert!(permissions.contains(Permission::Import));
+            assert!(!permissions.contains(Permission::Admin));
+        }
+        let grants = config.grants();
+        assert_eq!(grants.granted(Some("root"), None), Permissions::all());
+        assert_eq!(grants.granted(Some("guest"), None), config.permissions_of(Some("guest")));
+    }
+}
@@ -1,16 +1,24 @@
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Receiver, channel};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use protocol::capture::Capture;
 use protocol::stream::Stream;
-use protocol::message::{Message, RawMessage, Reader, ReadError, ReadFlow};
-use protocol::workflow::{Protocol, Workflow};
+use protocol::message::{RawMessage, Reader, ReadError, ReadFlow};
+use protocol::heartbeat::Heartbeat;
+use protocol::workflow::{Protocol, ProtocolVersion, Workflow};
 
 use ::connection::{StreamSender, StreamMessage};
-use ::proto::auth::server::AuthProtocol;
-use ::proto::content::server::{ContentProtocol, ContentConfig};
+use ::proto::HEARTBEAT_VERSION;
+use ::proto::auth::message::SessionTicket;
+use ::proto::auth::server::{AuthConfig, AuthProtocol};
 
+use super::control::{Peer, ServerControl};
 use super::database::Database;
+use super::pool::Executor;
+use super::registry::{ConnectionContext, Registry};
+use super::tokens::Claims;
 
 
 /// A client connection
@@ -21,35 +29,44 @@ pub struct Connection {
     db: Arc<Mutex<Database>>,
     /// The client unique identifier
     id: usize,
+    /// Close the connection after being idle for this time
+    idle_timeout: Option<Duration>,
+    /// Pings the client, if it is able to answer
+    heartbeat: Option<Arc<Mutex<Heartbeat>>>,
+    /// Records the frames of the connection
+    capture: Option<Arc<Capture>>,
+    /// The subprotocols served after auth
+    registry: Arc<Registry>,
+    /// The connections of the server
+    control: Arc<ServerControl>,
+    peer: Peer,
 }
 
 
 impl Connection {
     /// Creates a new client
-    pub fn new(stream: Stream, db: Arc<Mutex<Database>>, id: usize) -> Connection {
+    pub fn new(stream: Stream, db: Arc<Mutex<Database>>, id: usize, registry: Arc<Registry>,
+               control: Arc<ServerControl>) -> Connection {
         return Connection {
+            peer: Peer::of(&stream),
             stream: stream,
             db: db,
             id: id,
+            idle_timeout: None,
+            heartbeat: None,
+            capture: None,
+            registry: registry,
+            control: control,
         }
     }
 
     /// Creates a thread that writes into the client stream each response received
-    fn create_writer_thread(&self, rx: Receiver<Option<Message>>) {
+    fn create_writer_thread(&self, rx: Receiver<StreamMessage>, sender: &StreamSender) {
         let mut stream = self.stream.try_clone().unwrap();
+        let (checksum, traffic) = (sender.checksum().clone(), sender.traffic().clone());
+        let capture = self.capture.clone();
         thread::spawn(move || {
-            loop {
-                match rx.recv() {
-                    Ok(m) => match m {
-                        Some(msg) => match stream.write(&*msg.as_bytes()) {
-                            Ok(_) => (),
-                            Err(e) => warn!("Error writing to client: {:?}", e),
-                        },
-                        None => break,
-                    },
-                    Err(_) => break,
-                };
-            }
+            ::connection::write_messages(&mut stream, rx, checksum, traffic, None, capture);
         });
     }
 
@@ -57,25 +74,59 @@ impl Connection {
     /// disconnects.
     pub fn run(&mut self) {
         #![allow(unused_must_use)]
+        let (timeout, tick, heartbeat, capture) = {
+            let db = self.db.lock().unwrap();
+            (db.config.timeout, db.config.tick(), db.config.heartbeat(), db.config.capture(self.id))
+        };
+        self.capture = capture;
+
         let (stream_tx, rx) = channel::<StreamMessage>();
-        self.create_writer_thread(rx);
+        let stream_tx = StreamSender::new(stream_tx);
+        self.create_writer_thread(rx, &stream_tx);
 
-        let stream_tx = Arc::new(Mutex::new(stream_tx));
+        self.idle_timeout = match timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        // Reads time out on each tick to check the timers
+        if let Err(err) = self.stream.set_read_timeout(tick) {
+            error!("Setting read timeout: {:?}", err);
+            return;
+        }
 
-        if self.run_auth(stream_tx.clone()).is_ok() {
-            self.run_content(stream_tx);
+        let stream = self.stream.try_clone().ok();
+        self.control.add_connection(self.id, self.peer.clone(), stream_tx.traffic().clone(), stream);
+        if let Ok((version, code, identity, token, ticket)) = self.run_auth(stream_tx.clone()) {
+            if version >= HEARTBEAT_VERSION {
+                self.heartbeat = heartbeat.map(|heartbeat| Arc::new(Mutex::new(heartbeat)));
+            }
+            self.run_subprotocols(stream_tx, version, code, identity, token, ticket);
         };
+        self.control.remove_connection(self.id);
     }
 
-    fn run_auth(&mut self, stream_tx: StreamSender) -> Result<(), ()> {
+    /// Returns the protocol version of the authenticated client, the code of the requested subprotocol
+    /// and the identity of the client and the claims of its token, if it has authenticated,
+    /// and the ticket given to the client to resume the session
+    fn run_auth(&mut self, stream_tx: StreamSender)
+        -> Result<(ProtocolVersion, usize, Option<String>, Option<Claims>, Option<SessionTicket>), ()>
+    {
 
-        let protocol = AuthProtocol::new(stream_tx, self.id);
+        let config = {
+            let db = self.db.lock().unwrap();
+            AuthConfig::from_config(&db, &self.registry, &self.control, self.id, &self.stream)
+        };
+        let protocol = AuthProtocol::with_config(stream_tx.clone(), self.id, config);
 
-        info!(">>::  New connection. Starting auth");
+        info!(">>::  New connection #{} from {}. Starting auth", self.id, self.peer);
 
         'iter_messages: loop {
-            let message = match self.read() {
+            let message = match self.read(&stream_tx, &|| false) {
                 Ok(message) => message,
+                Err(ref error) if !error.is_fatal() => {
+                    warn!("  ::  Dropped a message of connection #{}: {}", self.id, error);
+                    continue 'iter_messages;
+                },
                 Err(error) => {
                     error!("Auth stage failed: {:?}", error);
                     return Err(());
@@ -88,61 +139,184 @@ impl Connection {
                     info!("Terminated: {}", m);
                     return Err(());
                 },
-                Workflow::SwitchProtocol(_) => {
-                    info!("Switch Protocol to: ContentProtocol");
-                    break 'iter_messages;
+                Workflow::SwitchProtocol(code) => {
+                    return protocol.client_version()
+                        .map(|version| (version, code, protocol.identity(), protocol.token(), protocol.ticket()))
+                        .ok_or(());
                 }
             };
         }
-        Ok(())
     }
 
-    fn run_content(&mut self, stream_tx: StreamSender) {
+    /// Serves the subprotocol requested on start and the ones it switches to, until the connection is closed
+    fn run_subprotocols(&mut self, stream_tx: StreamSender, client_version: ProtocolVersion, code: usize,
+                        identity: Option<String>, token: Option<Claims>, ticket: Option<SessionTicket>)
+    {
+        let permissions = self.db.lock().unwrap().config
+            .granted(identity.as_ref().map(|s| &s[..]), token.as_ref());
+        info!("  ::  Connection #{} is allowed: {}", self.id, permissions);
+        let context = ConnectionContext {
+            id: self.id,
+            sender: stream_tx.clone(),
+            db: self.db.clone(),
+            executor: Executor::Thread,
+            client_version: client_version,
+            peer: self.peer.clone(),
+            control: self.control.clone(),
+            identity: identity,
+            token: token,
+            ticket: ticket,
+            permissions: permissions,
+        };
+        let registry = self.registry.clone();
+        let mut code = code;
 
-        let protocol: ContentProtocol = ContentProtocol::new(stream_tx, self.id, self.db.clone());
+        'switch_protocol: loop {
+            let protocol = match registry.create(code, context.clone()) {
+                Some(protocol) => protocol,
+                None => {
+                    warn!("Switching to unknown subprotocol {}", code);
+                    break 'switch_protocol;
+                },
+            };
 
-        info!("  ::  Auth Ok. Switched to ContentProtocol");
+            info!("  ::  Switched to subprotocol {} ({})", code, registry.name(code).unwrap_or(""));
+            self.control.set_tasks(self.id, protocol.tasks());
 
-        'iter_messages: loop {
-            let message = match self.read() {
-                Ok(message) => message,
-                Err(error) => {
-                    info!("Read error: {:?}", error);
-                    break 'iter_messages;
-                }
-            };
+            'iter_messages: loop {
+                let tick = || {
+                    protocol.on_tick();
+                    protocol.active_tasks() > 0
+                };
+                let message = match self.read(&stream_tx, &tick) {
+                    Ok(message) => message,
+                    Err(ref error) if !error.is_fatal() => {
+                        warn!("  ::  Dropped a message of connection #{}: {}", self.id, error);
+                        continue 'iter_messages;
+                    },
+                    Err(error) => {
+                        info!("Read error: {:?}", error);
+                        protocol.suspend();
+                        break 'switch_protocol;
+                    }
+                };
 
-            match protocol.flow(message) {
-                Workflow::Continue          => (),
-                Workflow::Terminate(m)      => {
-                    info!("Terminated: {}", m);
-                    break 'iter_messages;
-                },
-                Workflow::SwitchProtocol(_) => {
-                    unreachable!();
-                }
-            };
+                match protocol.flow(message) {
+                    Workflow::Continue          => (),
+                    Workflow::Terminate(m)      => {
+                        info!("Terminated: {}", m);
+                        break 'switch_protocol;
+                    },
+                    Workflow::SwitchProtocol(next) => {
+                        code = next;
+                        continue 'switch_protocol;
+                    }
+                };
+            }
         }
 
         info!("<<::  Hangup");
     }
 
+    /// Pings the client if it is due
+    fn check_heartbeat(&self, sender: &StreamSender) -> Result<(), ReadError> {
+        if let Some(ref heartbeat) = self.heartbeat {
+            match heartbeat.lock().unwrap().poll(Instant::now()) {
+                Ok(Some(ping)) => ::connection::send_message(sender, Some(Box::new(ping))),
+                Ok(None) => (),
+                Err(missed) => return Err(ReadError::ConnectionError(format!("Client missed {} pongs", missed))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next message of the subprotocol, answering heartbeat messages on the way.
+    /// While waiting, `tick` is called on each read timeout
+    /// and returns whether the connection has work in progress, so that it is not idle.
     #[cfg_attr(feature = "dev", trace)]
-    pub fn read(&mut self) -> Result<RawMessage, ReadError> {
-        let mut reader = Reader::new();
+    pub fn read(&mut self, sender: &StreamSender, tick: &Fn() -> bool) -> Result<RawMessage, ReadError> {
+        let mut reader = Reader::new(sender.checksum().negotiated());
+        let mut idle_since = Instant::now();
 
         'read_message: loop {
             match reader.read(&mut self.stream) {
                 ReadFlow::Error(err)    => return Err(err),
                 ReadFlow::Incomplete    => continue 'read_message,
+                ReadFlow::WouldBlock    => {
+                    try!(self.check_heartbeat(sender));
+                    if tick() {
+                        idle_since = Instant::now();
+                    } else if self.idle_timeout.map_or(false, |timeout| idle_since.elapsed() >= timeout) {
+                        return Err(ReadError::ConnectionError("Idle timeout".to_owned()));
+                    }
+                    continue 'read_message;
+                },
                 ReadFlow::Complete      => match reader.to_message() {
-                    Ok(message) => return Ok(message),
+                    Ok(message) => {
+                        sender.traffic().add_read(reader.frame_size());
+                        if let Some(ref capture) = self.capture {
+                            capture.received(&reader.frame());
+                        }
+                        let heartbeat = self.heartbeat.as_ref().map(|heartbeat| &**heartbeat);
+                        match try!(::connection::handle_control(sender, heartbeat, message)) {
+                            Some(message) => return Ok(message),
+                            None => {
+                                try!(self.check_heartbeat(sender));
+                                reader = Reader::new(sender.checksum().negotiated());
+                                continue 'read_message;
+                            },
+                        }
+                    },
                     Err(err)    => {
                         info!("Parse error: {:?}", err);
-                        return Err(ReadError::Fatal(format!("Parse error: {:?}", err)))
+                        return Err(ReadError::NonFatal(format!("Parse error: {:?}", err)))
                     }
                 },
             };
         }
     }
 }
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use unix_socket::UnixStream;
+
+    use protocol::stream::Stream;
+
+    use ::server::config::Config;
+    use ::server::control::ServerControl;
+    use ::server::registry::Registry;
+    use ::testing;
+
+    use super::Connection;
+
+    #[test]
+    fn idle_connection_is_closed() {
+        let mut config = Config::new();
+        config.timeout = 1;
+        let control = Arc::new(ServerControl::new());
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut connection = Connection::new(Stream::Unix(server), testing::holder(config), 1,
+            Arc::new(Registry::new()), control.clone());
+
+        let started = Instant::now();
+        let server = thread::spawn(move || connection.run());
+        // The client sends nothing, so the server hangs up once the timeout is over
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).unwrap(), 0);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        server.join().unwrap();
+        assert!(control.connections().is_empty());
+    }
+}
@@ -0,0 +1,588 @@
+//! The live state of a running server: its connections and their tasks.
+//! It is shared by all connections, so that the admin subprotocol can inspect and control them.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc;
+use unix_socket::UnixStream;
+
+use protocol::stream::Stream;
+
+use ::connection::{Notify, Traffic};
+use ::proto::admin::message::{ConnectionInfo, TaskInfo};
+use ::proto::auth::mac;
+use ::types::TaskId;
+
+use super::admission::Admission;
+use super::config::Config;
+use super::credentials::Authenticator;
+
+
+/// The tasks of a connection, as seen from the other connections
+pub trait TaskSet: Send + Sync {
+    fn list(&self) -> Vec<TaskInfo>;
+
+    /// The number of tasks in progress
+    fn active(&self) -> usize;
+
+    /// Fails the task with the reason, if it is in progress
+    fn cancel(&self, task_id: TaskId, reason: &str) -> bool;
+}
+
+
+/// The process on the other side of a Unix socket, as seen by the kernel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not known on some systems
+    pub pid: Option<u32>,
+}
+
+
+/// The remote side of a connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// With the credentials of the peer, if they could be read
+    Unix(Option<PeerCred>),
+    Unknown,
+}
+
+
+struct ConnectionEntry {
+    peer: Peer,
+    started: SystemTime,
+    traffic: Traffic,
+    /// A clone of the stream, shut down to kill the connection
+    stream: Option<Stream>,
+    tasks: Option<Arc<TaskSet>>,
+}
+
+
+/// What is left of a lost connection until its client resumes it
+struct SuspendedSession {
+    identity: Option<String>,
+    secret: Vec<u8>,
+    expires: Instant,
+    /// Owned by the subprotocol which has suspended the session
+    state: Box<Any + Send>,
+}
+
+
+pub struct ServerControl {
+    connections: Mutex<HashMap<usize, ConnectionEntry>>,
+    /// The sessions of lost connections by the ids of the connections
+    sessions: Mutex<HashMap<usize, SuspendedSession>>,
+    /// Stops the listeners, with the wakeups of those which poll rather than block in accept
+    listeners: Mutex<Vec<(Sender<u8>, Option<Notify>)>>,
+    stopping: AtomicBool,
+    /// Checks the credentials of the connecting clients
+    authenticator: Arc<Authenticator>,
+    /// Limits the connections and the requests of clients
+    admission: Admission,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl PeerCred {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn of<S: AsRawFd>(socket: &S) -> io::Result<PeerCred> {
+        let mut cred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                             &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCred { uid: cred.uid, gid: cred.gid, pid: Some(cred.pid as u32) })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn of<S: AsRawFd>(socket: &S) -> io::Result<PeerCred> {
+        let (mut uid, mut gid) = (0, 0);
+        if unsafe { libc::getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCred { uid: uid, gid: gid, pid: None })
+    }
+}
+
+
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uid={} gid={}", self.uid, self.gid)?;
+        match self.pid {
+            Some(pid) => write!(f, " pid={}", pid),
+            None => Ok(()),
+        }
+    }
+}
+
+
+impl Peer {
+    pub fn of(stream: &Stream) -> Peer {
+        let addr = match *stream {
+            Stream::Tcp(ref s) => s.peer_addr(),
+            Stream::Tls(ref s) => s.get_ref().peer_addr(),
+            Stream::Unix(ref s) => return Peer::Unix(PeerCred::of(s)
+                .map_err(|err| warn!("Reading the credentials of a Unix socket peer: {:?}", err))
+                .ok()),
+        };
+        addr.map(Peer::Tcp).unwrap_or(Peer::Unknown)
+    }
+}
+
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Peer::Tcp(ref addr) => write!(f, "{}", addr),
+            Peer::Unix(Some(ref cred)) => write!(f, "unix ({})", cred),
+            Peer::Unix(None) => write!(f, "unix"),
+            Peer::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+
+impl ServerControl {
+    pub fn new() -> ServerControl {
+        Self::with_authenticator(Authenticator::default())
+    }
+
+    pub fn with_authenticator(authenticator: Authenticator) -> ServerControl {
+        ServerControl {
+            connections: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            listeners: Mutex::new(Vec::new()),
+            stopping: AtomicBool::new(false),
+            authenticator: Arc::new(authenticator),
+            admission: Admission::default(),
+        }
+    }
+
+    pub fn with_admission(mut self, admission: Admission) -> ServerControl {
+        self.admission = admission;
+        self
+    }
+
+    pub fn authenticator(&self) -> Arc<Authenticator> {
+        self.authenticator.clone()
+    }
+
+    /// Decides whether the new connection is served. Returns the reason of the rejection otherwise.
+    pub fn admit(&self, id: usize, peer: &Peer) -> Result<(), String> {
+        let others = self.connections.lock().unwrap().iter()
+            .filter(|&(&other, _)| other != id)
+            .map(|(_, entry)| entry.peer.clone())
+            .collect::<Vec<_>>();
+        self.admission.admit(peer, &others)
+    }
+
+    /// Counts a request of the client against its rate limit. Returns `false` if the limit is exceeded.
+    pub fn take_request(&self, identity: Option<&str>, peer: &Peer) -> bool {
+        self.admission.take_request(identity, peer)
+    }
+
+    pub fn add_connection(&self, id: usize, peer: Peer, traffic: Traffic, stream: Option<Stream>) {
+        let entry = ConnectionEntry {
+            peer: peer,
+            started: SystemTime::now(),
+            traffic: traffic,
+            stream: stream,
+            tasks: None,
+        };
+        self.connections.lock().unwrap().insert(id, entry);
+    }
+
+    /// Sets the tasks of the current subprotocol of the connection
+    pub fn set_tasks(&self, id: usize, tasks: Option<Arc<TaskSet>>) {
+        if let Some(entry) = self.connections.lock().unwrap().get_mut(&id) {
+            entry.tasks = tasks;
+        }
+    }
+
+    pub fn remove_connection(&self, id: usize) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    /// Lists the connections ordered by id
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        let mut list = connections.iter()
+            .map(|(&id, entry)| ConnectionInfo {
+                id: id,
+                peer: entry.peer.to_string(),
+                started: entry.started.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                tasks: entry.tasks.as_ref().map_or(0, |tasks| tasks.active()) as u32,
+                bytes_read: entry.traffic.read() as u64,
+                bytes_written: entry.traffic.written() as u64,
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    /// Lists the tasks of all connections
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        let mut list = self.task_sets().iter().flat_map(|tasks| tasks.list()).collect::<Vec<_>>();
+        list.sort_by_key(|info| (info.connection, info.task_id));
+        list
+    }
+
+    /// Counts the tasks in progress on all connections
+    pub fn active_tasks(&self) -> usize {
+        self.task_sets().iter().map(|tasks| tasks.active()).sum()
+    }
+
+    /// The task sets are cloned, so that no task is locked under the lock of the connections
+    fn task_sets(&self) -> Vec<Arc<TaskSet>> {
+        self.connections.lock().unwrap().values().filter_map(|entry| entry.tasks.clone()).collect()
+    }
+
+    /// Fails a task of the connection
+    pub fn cancel_task(&self, id: usize, task_id: TaskId, reason: &str) -> bool {
+        let tasks = self.connections.lock().unwrap().get(&id).and_then(|entry| entry.tasks.clone());
+        tasks.map_or(false, |tasks| tasks.cancel(task_id, reason))
+    }
+
+    /// Shuts down the stream of the connection; the connection is closed on the next read
+    pub fn kill_connection(&self, id: usize) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(&ConnectionEntry { stream: Some(ref stream), .. }) => {
+                if let Err(err) = stream.shutdown() {
+                    warn!("Shutting down connection #{}: {:?}", id, err);
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn kill_all(&self) {
+        let ids = self.connections.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            self.kill_connection(id);
+        }
+    }
+
+    /// Keeps the state of a lost connection for the grace period, so that its client may resume it
+    pub fn suspend_session(&self, id: usize, identity: Option<String>, secret: Vec<u8>, grace: Duration,
+                           state: Box<Any + Send>)
+    {
+        let mut sessions = self.sessions.lock().unwrap();
+        reap_sessions(&mut sessions);
+        let session = SuspendedSession {
+            identity: identity,
+            secret: secret,
+            expires: Instant::now() + grace,
+            state: state,
+        };
+        sessions.insert(id, session);
+    }
+
+    /// Takes the state of a suspended session, if the secret and the identity are the ones it was given.
+    /// A mismatch leaves the session for its rightful client.
+    pub fn resume_session(&self, id: usize, secret: &[u8], identity: Option<&str>) -> Option<Box<Any + Send>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        reap_sessions(&mut sessions);
+        let matches = !secret.is_empty() && sessions.get(&id).map_or(false, |session| {
+            mac::verify(&session.secret, secret) && session.identity.as_ref().map(|s| &s[..]) == identity
+        });
+        match matches {
+            true => sessions.remove(&id).map(|session| session.state),
+            false => None,
+        }
+    }
+
+    /// Counts the sessions which may still be resumed
+    pub fn suspended_sessions(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        reap_sessions(&mut sessions);
+        sessions.len()
+    }
+
+    /// Drops the sessions whose grace period has ended, stopping their tasks
+    pub fn reap_sessions(&self) {
+        reap_sessions(&mut self.sessions.lock().unwrap());
+    }
+
+    /// Registers the stop signal of a listener, and the wakeup of its poll if it has one.
+    /// A listener added once the server is stopping is stopped at once.
+    pub fn add_listener(&self, stop: Sender<u8>, wake: Option<Notify>) {
+        let mut listeners = self.listeners.lock().unwrap();
+        if self.is_stopping() {
+            let _ = stop.send(0);
+            if let Some(ref wake) = wake {
+                wake();
+            }
+        }
+        listeners.push((stop, wake));
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Stops the listeners, wakes up the polling ones and connects to the others to break their accepting loops.
+    /// Returns `false` if the server is already stopping.
+    pub fn shutdown(&self, addresses: Vec<(String, u16)>, unixsocket: Option<String>) -> bool {
+        #![allow(unused_must_use)]
+        {
+            // Locked first, so that a listener is either added before or stopped at once
+            let listeners = self.listeners.lock().unwrap();
+            if self.stopping.swap(true, Ordering::SeqCst) {
+                return false;
+            }
+            info!("Shutting down the server");
+            for &(ref stop, ref wake) in listeners.iter() {
+                stop.send(0);
+                if let Some(ref wake) = *wake {
+                    wake();
+                }
+            }
+        }
+        for (host, port) in addresses {
+            if let Ok(addrs) = (&host[..], port).to_socket_addrs() {
+                for addr in addrs {
+                    TcpStream::connect(addr);
+                }
+            }
+        }
+        if let Some(unixsocket) = unixsocket {
+            UnixStream::connect(unixsocket);
+        }
+        true
+    }
+
+    /// Shuts down with the listening addresses of the config
+    pub fn shutdown_listeners(&self, config: &Config) -> bool {
+        self.shutdown(config.addresses(), config.unixsocket.clone())
+    }
+}
+
+
+/// Drops the sessions whose grace period has ended
+fn reap_sessions(sessions: &mut HashMap<usize, SuspendedSession>) {
+    let now = Instant::now();
+    let expired = sessions.iter()
+        .filter(|&(_, session)| session.expires <= now)
+        .map(|(&id, _)| id)
+        .collect::<Vec<_>>();
+    for id in expired {
+        info!("The session of connection #{} has expired", id);
+        sessions.remove(&id);
+    }
+}
+
+
+impl fmt::Debug for ServerControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServerControl {{ connections: {}, sessions: {}, stopping: {} }}",
+            self.connections.lock().unwrap().len(), self.sessions.lock().unwrap().len(), self.is_stopping())
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use unix_socket::UnixStream;
+
+    use protocol::stream::Stream;
+
+    use ::connection::{Notify, Traffic};
+    use ::proto::admin::message::TaskInfo;
+    use ::types::TaskId;
+
+    use libc;
+
+    use super::{Peer, PeerCred, ServerControl, TaskSet};
+
+    /// Tasks of a connection, cancelled by removal
+    struct Tasks {
+        connection: usize,
+        running: Mutex<Vec<TaskId>>,
+    }
+
+    impl TaskSet for Tasks {
+        fn list(&self) -> Vec<TaskInfo> {
+            self.running.lock().unwrap().iter()
+                .map(|&task_id| TaskInfo {
+                    connection: self.connection,
+                    task_id: task_id,
+                    description: "Test".to_owned(),
+                    running: 0,
+                    finished: false,
+                })
+                .collect()
+        }
+
+        fn active(&self) -> usize {
+            self.running.lock().unwrap().len()
+        }
+
+        fn cancel(&self, task_id: TaskId, _: &str) -> bool {
+            let mut running = self.running.lock().unwrap();
+            let before = running.len();
+            running.retain(|&id| id != task_id);
+            running.len() < before
+        }
+    }
+
+    fn tasks(connection: usize, running: Vec<TaskId>) -> Option<Arc<TaskSet>> {
+        Some(Arc::new(Tasks { connection: connection, running: Mutex::new(running) }))
+    }
+
+    #[test]
+    fn list_connections_and_tasks() {
+        let control = ServerControl::new();
+        let traffic = Traffic::default();
+        traffic.add_read(10);
+        traffic.add_written(25);
+        control.add_connection(2, Peer::Unix(None), traffic, None);
+        control.add_connection(1, Peer::Tcp("127.0.0.1:4000".parse().unwrap()), Traffic::default(), None);
+        control.set_tasks(2, tasks(2, vec![5, 3]));
+        control.set_tasks(1, tasks(1, vec![7]));
+
+        let connections = control.connections();
+        assert_eq!(connections.iter().map(|c| c.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(connections[0].peer, "127.0.0.1:4000");
+        assert_eq!(connections[1].peer, "unix");
+        assert_eq!((connections[1].tasks, connections[1].bytes_read, connections[1].bytes_written), (2, 10, 25));
+
+        let listed = control.tasks().iter().map(|t| (t.connection, t.task_id)).collect::<Vec<_>>();
+        assert_eq!(listed, vec![(1, 7), (2, 3), (2, 5)]);
+        assert_eq!(control.active_tasks(), 3);
+
+        assert!(control.cancel_task(2, 3, "Test"));
+        assert!(!control.cancel_task(2, 3, "Test"));
+        assert!(!control.cancel_task(9, 3, "Test"));
+        assert_eq!(control.active_tasks(), 2);
+
+        control.remove_connection(2);
+        assert_eq!(control.connections().len(), 1);
+        assert_eq!(control.active_tasks(), 1);
+    }
+
+    #[test]
+    fn suspend_and_resume_sessions() {
+        let control = ServerControl::new();
+        let grace = Duration::from_secs(30);
+        control.suspend_session(1, Some("alice".to_owned()), vec![1; 16], grace, Box::new(42u32));
+        control.suspend_session(2, None, vec![2; 16], Duration::from_secs(0), Box::new(43u32));
+        assert_eq!(control.suspended_sessions(), 1);
+
+        // A wrong secret or identity leaves the session for its client
+        assert!(control.resume_session(1, &[2; 16], Some("alice")).is_none());
+        assert!(control.resume_session(1, &[1; 16], Some("bob")).is_none());
+        assert!(control.resume_session(1, &[1; 16], None).is_none());
+        assert!(control.resume_session(2, &[2; 16], None).is_none());
+
+        let state = control.resume_session(1, &[1; 16], Some("alice")).unwrap();
+        assert_eq!(state.downcast::<u32>().ok().map(|state| *state), Some(42));
+        assert!(control.resume_session(1, &[1; 16], Some("alice")).is_none());
+        assert_eq!(control.suspended_sessions(), 0);
+    }
+
+    /// The state of a suspended session, counting its drops
+    struct Session(Arc<AtomicUsize>);
+
+    impl Drop for Session {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn expired_sessions_are_reaped() {
+        let control = ServerControl::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let grace = Duration::from_secs(30);
+        control.suspend_session(1, None, vec![1; 16], grace, Box::new(Session(dropped.clone())));
+        control.suspend_session(2, None, vec![2; 16], Duration::from_secs(0), Box::new(Session(dropped.clone())));
+        assert_eq!(dropped.load(Ordering::SeqCst), 0);
+
+        // The expired session is stopped without waiting for another to be suspended, resumed or counted
+        control.reap_sessions();
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+        assert!(control.resume_session(1, &[1; 16], None).is_some());
+        assert_eq!(dropped.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn peer_credentials() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let cred = PeerCred::of(&a).unwrap();
+        assert_eq!((cred.uid, cred.gid), unsafe { (libc::getuid(), libc::getgid()) });
+        assert!(cred.pid.map_or(true, |pid| pid == unsafe { libc::getpid() } as u32));
+
+        let peer = Peer::of(&Stream::Unix(a));
+        assert_eq!(peer, Peer::Unix(Some(cred)));
+        let cred = PeerCred { uid: 1000, gid: 100, pid: Some(42) };
+        assert_eq!(Peer::Unix(Some(cred)).to_string(), "unix (uid=1000 gid=100 pid=42)");
+        assert_eq!(Peer::Unix(Some(PeerCred { pid: None, ..cred })).to_string(), "unix (uid=1000 gid=100)");
+    }
+
+    #[test]
+    fn kill_connection() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let control = ServerControl::new();
+        control.add_connection(1, Peer::Unix(None), Traffic::default(), Some(Stream::Unix(a)));
+        control.add_connection(2, Peer::Unix(None), Traffic::default(), None);
+
+        assert!(control.kill_connection(1));
+        assert!(!control.kill_connection(2));
+        assert!(!control.kill_connection(3));
+        let mut buf = Vec::new();
+        assert_eq!(b.read_to_end(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn shutdown_once() {
+        let control = ServerControl::new();
+        let (tx, rx) = channel();
+        let woken = Arc::new(AtomicUsize::new(0));
+        let wake: Notify = {
+            let woken = woken.clone();
+            Arc::new(move || { woken.fetch_add(1, Ordering::SeqCst); })
+        };
+        control.add_listener(tx, Some(wake.clone()));
+        assert!(!control.is_stopping());
+        assert!(control.shutdown(vec![], None));
+        assert!(control.is_stopping());
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+        assert!(!control.shutdown(vec![], None));
+        assert!(rx.try_recv().is_err());
+
+        // A listener started meanwhile is stopped at once
+        let (tx, rx) = channel();
+        control.add_listener(tx, Some(wake));
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert_eq!(woken.load(Ordering::SeqCst), 2);
+    }
+}
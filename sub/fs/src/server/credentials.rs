@@ -0,0 +1,539 @@
+//! Client credentials of the server and the bookkeeping of failed authentication attempts.
+//!
+//! The credential file holds a line per login: either `login:blake2b$<rounds>$<salt>$<hash>` for a password,
+//! or `login:key$<key>` for a key shared with the client. Salts, hashes and keys are in hex.
+//! Empty lines and lines starting with `#` are skipped.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::str;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use blake2_rfc::blake2b::{Blake2b, blake2b};
+use rand::{OsRng, Rng};
+
+use super::admission::peer_key;
+use super::config::Config;
+use super::control::Peer;
+use super::tokens::{Claims, TokenAuthority, TokenError};
+
+
+const HASH_SIZE: usize = 64;
+const SALT_SIZE: usize = 16;
+/// The size of generated keys; shorter keys are not accepted
+pub const KEY_SIZE: usize = 32;
+/// The number of rounds of new password hashes
+pub const DEFAULT_ROUNDS: u32 = 10000;
+
+/// The delay after the first failed attempt, doubled on each next one
+const BACKOFF_BASE_MS: u64 = 500;
+const BACKOFF_MAX_MS: u64 = 60000;
+/// Failed logins are pruned once there are so many of them
+const MAX_FAILURES: usize = 1024;
+
+
+/// A salted password hash, iterated to slow down guessing
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordHash {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+
+/// What a client proves to authenticate
+#[derive(Debug, Clone, PartialEq)]
+pub enum Secret {
+    /// A password sent with `AuthPlain`
+    Password(PasswordHash),
+    /// A key proven with `AuthHash`, never sent
+    Key(Vec<u8>),
+}
+
+
+#[derive(Debug, Default)]
+pub struct Credentials {
+    users: HashMap<String, Secret>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthFailure {
+    /// Unknown login or wrong password
+    BadCredentials,
+    /// Too many failed attempts of the login from the peer; retry after the delay
+    Backoff(Duration),
+}
+
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    /// No attempts are checked until then
+    until: Instant,
+}
+
+
+/// Checks the credentials of clients, delaying the attempts after failures
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    credentials: Option<Credentials>,
+    tokens: Option<TokenAuthority>,
+    /// The failed attempts of each login from each peer
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join("")
+}
+
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.as_bytes().chunks(2)
+        .map(|pair| str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+
+/// Compares in a time independent of the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl PasswordHash {
+    /// Hashes the password with a random salt
+    pub fn new(password: &str, rounds: u32) -> io::Result<PasswordHash> {
+        let mut salt = vec![0u8; SALT_SIZE];
+        OsRng::new()?.fill_bytes(&mut salt);
+        Ok(Self::with_salt(password, rounds, salt))
+    }
+
+    pub fn with_salt(password: &str, rounds: u32, salt: Vec<u8>) -> PasswordHash {
+        let hash = Self::compute(password, rounds, &salt);
+        PasswordHash { rounds: rounds, salt: salt, hash: hash }
+    }
+
+    fn compute(password: &str, rounds: u32, salt: &[u8]) -> Vec<u8> {
+        let mut hash = blake2b(HASH_SIZE, salt, password.as_bytes()).as_bytes().to_vec();
+        for _ in 1..rounds {
+            let mut state = Blake2b::with_key(HASH_SIZE, salt);
+            state.update(&hash);
+            state.update(password.as_bytes());
+            hash = state.finalize().as_bytes().to_vec();
+        }
+        hash
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        constant_time_eq(&Self::compute(password, self.rounds, &self.salt), &self.hash)
+    }
+}
+
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "blake2b${}${}${}", self.rounds, to_hex(&self.salt), to_hex(&self.hash))
+    }
+}
+
+
+impl FromStr for PasswordHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PasswordHash, String> {
+        let parts = s.split('$').collect::<Vec<_>>();
+        match parts.len() == 4 && parts[0] == "blake2b" {
+            true => {
+                let rounds = parts[1].parse().ok().and_then(|rounds| if rounds > 0 { Some(rounds) } else { None });
+                match (rounds, from_hex(parts[2]), from_hex(parts[3])) {
+                    (Some(rounds), Some(salt), Some(hash)) =>
+                        if salt.len() <= HASH_SIZE && hash.len() == HASH_SIZE {
+                            Ok(PasswordHash { rounds: rounds, salt: salt, hash: hash })
+                        } else {
+                            Err("Invalid salt or hash size".to_owned())
+                        },
+                    _ => Err("Invalid rounds, salt or hash".to_owned()),
+                }
+            },
+            false => Err("Expected blake2b$<rounds>$<salt>$<hash>".to_owned()),
+        }
+    }
+}
+
+
+impl Secret {
+    /// Generates a random key
+    pub fn new_key() -> io::Result<Secret> {
+        let mut key = vec![0u8; KEY_SIZE];
+        OsRng::new()?.fill_bytes(&mut key);
+        Ok(Secret::Key(key))
+    }
+}
+
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Secret::Password(ref hash) => write!(f, "{}", hash),
+            Secret::Key(ref key) => write!(f, "key${}", to_hex(key)),
+        }
+    }
+}
+
+
+impl FromStr for Secret {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Secret, String> {
+        if !s.starts_with("key$") {
+            return s.parse().map(Secret::Password);
+        }
+        match from_hex(&s[4..]) {
+            Some(ref key) if key.len() < KEY_SIZE / 2 || key.len() > HASH_SIZE => Err("Invalid key size".to_owned()),
+            Some(key) => Ok(Secret::Key(key)),
+            None => Err("Invalid key".to_owned()),
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Credentials {
+    pub fn load(path: &str) -> io::Result<Credentials> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        content.parse().map_err(|err| invalid_data(format!("{}: {}", path, err)))
+    }
+
+    pub fn add(&mut self, login: String, secret: Secret) {
+        self.users.insert(login, secret);
+    }
+
+    pub fn get(&self, login: &str) -> Option<&Secret> {
+        self.users.get(login)
+    }
+
+    pub fn verify(&self, login: &str, password: &str) -> bool {
+        match self.users.get(login) {
+            Some(&Secret::Password(ref hash)) => hash.verify(password),
+            _ => {
+                // Takes as long as a real check, so that the timing doesn't tell which logins exist
+                PasswordHash::compute(password, DEFAULT_ROUNDS, &[0; SALT_SIZE]);
+                false
+            },
+        }
+    }
+}
+
+
+impl FromStr for Credentials {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Credentials, String> {
+        let mut credentials = Credentials::default();
+        for (n, line) in s.lines().enumerate().map(|(n, line)| (n + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(login), Some(secret)) if !login.is_empty() => {
+                    let secret = secret.parse().map_err(|err| format!("line {}: {}", n, err))?;
+                    credentials.add(login.to_owned(), secret);
+                },
+                _ => return Err(format!("line {}: expected login:secret", n)),
+            }
+        }
+        Ok(credentials)
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Authenticator {
+    pub fn new(credentials: Option<Credentials>) -> Authenticator {
+        Authenticator { credentials: credentials, tokens: None, failures: Mutex::new(HashMap::new()) }
+    }
+
+    /// Accepts the tokens of the authority as well
+    pub fn with_tokens(mut self, tokens: TokenAuthority) -> Authenticator {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Loads the credential file and the token key of the config, if any
+    pub fn from_config(config: &Config) -> io::Result<Authenticator> {
+        let credentials = match config.credentials {
+            Some(ref path) => Some(Credentials::load(path)?),
+            None => None,
+        };
+        let authenticator = Self::new(credentials);
+        Ok(match TokenAuthority::from_config(config)? {
+            Some(tokens) => authenticator.with_tokens(tokens),
+            None => authenticator,
+        })
+    }
+
+    /// Clients must authenticate
+    pub fn need_auth(&self) -> bool {
+        self.credentials.is_some() || self.tokens.is_some()
+    }
+
+    /// Mints and revokes tokens, if they are enabled
+    pub fn tokens(&self) -> Option<&TokenAuthority> {
+        self.tokens.as_ref()
+    }
+
+    /// Checks the token. Tokens are unguessable, so failures are not delayed
+    pub fn authenticate_token(&self, token: &str) -> Result<Claims, TokenError> {
+        match self.tokens {
+            Some(ref tokens) => tokens.verify(token),
+            None => Err(TokenError::BadSignature),
+        }
+    }
+
+    /// Checks the password of the login, given by the peer
+    pub fn authenticate(&self, peer: &Peer, login: &str, password: &str) -> Result<(), AuthFailure> {
+        self.authenticate_at(peer, login, password, Instant::now())
+    }
+
+    fn authenticate_at(&self, peer: &Peer, login: &str, password: &str, now: Instant) -> Result<(), AuthFailure> {
+        self.check(&client(peer, login), now, |credentials| credentials.verify(login, password))
+    }
+
+    /// Checks the proof of the key of the login, given by the peer.
+    /// Returns the key, so that the server can prove it in turn
+    pub fn authenticate_key<F>(&self, peer: &Peer, login: &str, verify: F) -> Result<Vec<u8>, AuthFailure>
+        where F: FnOnce(&[u8]) -> bool
+    {
+        let mut key = None;
+        self.check(&client(peer, login), Instant::now(), |credentials| match credentials.get(login) {
+            Some(&Secret::Key(ref k)) if verify(k) => {
+                key = Some(k.clone());
+                true
+            },
+            _ => false,
+        })?;
+        key.ok_or(AuthFailure::BadCredentials)
+    }
+
+    /// Runs the check unless the client is backing off after its failed attempts; a failure makes it back off longer.
+    /// The client is the login from a peer, so that the failures of others can't lock the login out.
+    fn check<F>(&self, client: &str, now: Instant, check: F) -> Result<(), AuthFailure>
+        where F: FnOnce(&Credentials) -> bool
+    {
+        if let Some(previous) = self.failures.lock().unwrap().get(client) {
+            if previous.until > now {
+                return Err(AuthFailure::Backoff(previous.until - now));
+            }
+        }
+
+        if self.credentials.as_ref().map_or(false, check) {
+            self.failures.lock().unwrap().remove(client);
+            return Ok(());
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        let previous = failures.get(client).cloned();
+        if previous.is_none() && failures.len() >= MAX_FAILURES {
+            Self::prune(&mut failures, now);
+        }
+        let count = previous.map_or(0, |previous| previous.count) + 1;
+        let delay = cmp::min(BACKOFF_BASE_MS << cmp::min(count - 1, 16), BACKOFF_MAX_MS);
+        failures.insert(client.to_owned(), Failures { count: count, until: now + Duration::from_millis(delay) });
+        Err(AuthFailure::BadCredentials)
+    }
+
+    /// Forgets the clients that are not backing off anymore, or the ones closest to the end of the delay
+    /// if all of them are, so that guessing many logins can't grow the map without bounds
+    fn prune(failures: &mut HashMap<String, Failures>, now: Instant) {
+        let mut clients = failures.iter()
+            .map(|(client, failures)| (failures.until, client.clone()))
+            .collect::<Vec<_>>();
+        clients.sort();
+        let expired = clients.iter().take_while(|&&(until, _)| until <= now).count();
+        let forget = cmp::max(expired, clients.len() + 1 - MAX_FAILURES);
+        for (_, client) in clients.into_iter().take(forget) {
+            failures.remove(&client);
+        }
+    }
+}
+
+
+/// Names the login attempted from the peer, by the IP address or the Unix user of the peer
+fn client(peer: &Peer, login: &str) -> String {
+    match peer_key(peer) {
+        Some(key) => format!("{} from {}", login, key),
+        None => format!("{} from an unknown peer", login),
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::time::{Duration, Instant};
+
+    use ::server::control::Peer;
+
+    use super::{AuthFailure, Authenticator, Credentials, MAX_FAILURES, PasswordHash, Secret};
+
+    fn credentials() -> Credentials {
+        let mut credentials = Credentials::default();
+        credentials.add("alice".to_owned(), Secret::Password(PasswordHash::with_salt("secret", 3, b"salt".to_vec())));
+        credentials.add("robot".to_owned(), Secret::Key(vec![7; 32]));
+        credentials
+    }
+
+    fn peer(ip: &str) -> Peer {
+        Peer::Tcp(format!("{}:4000", ip).parse().unwrap())
+    }
+
+    #[test]
+    fn password_hash_format() {
+        let hash = PasswordHash::new("secret", 5).unwrap();
+        let line = hash.to_string();
+        assert!(line.starts_with("blake2b$5$"), "{}", line);
+        let parsed: PasswordHash = line.parse().unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("Secret"));
+        assert!(PasswordHash::new("secret", 5).unwrap() != hash);
+
+        assert!("md5$1$00$00".parse::<PasswordHash>().is_err());
+        assert!("blake2b$0$00$00".parse::<PasswordHash>().is_err());
+        assert!("blake2b$1$0$00".parse::<PasswordHash>().is_err());
+    }
+
+    #[test]
+    fn credential_file() {
+        let line = format!("alice:{}", PasswordHash::with_salt("secret", 3, b"salt".to_vec()));
+        let credentials: Credentials = format!("# logins\n\n{}\n", line).parse().unwrap();
+        assert!(credentials.verify("alice", "secret"));
+        assert!(!credentials.verify("alice", "wrong"));
+        assert!(!credentials.verify("bob", "secret"));
+
+        let err = "alice\n".parse::<Credentials>().unwrap_err();
+        assert!(err.starts_with("line 1"), "{}", err);
+        let err = format!("{}\nbob:plain", line).parse::<Credentials>().unwrap_err();
+        assert!(err.starts_with("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn key_lines() {
+        let key = Secret::new_key().unwrap();
+        let credentials: Credentials = format!("robot:{}", key).parse().unwrap();
+        assert_eq!(credentials.get("robot"), Some(&key));
+        // A key is not a password
+        assert!(!credentials.verify("robot", &key.to_string()[4..]));
+
+        assert!("robot:key$0011".parse::<Credentials>().is_err());
+        assert!("robot:key$xyz".parse::<Credentials>().is_err());
+    }
+
+    #[test]
+    fn key_proofs() {
+        let authenticator = Authenticator::new(Some(credentials()));
+        let (robot, other) = (peer("10.0.0.1"), peer("10.0.0.2"));
+        assert_eq!(authenticator.authenticate_key(&robot, "robot", |key| key == &[7; 32][..]), Ok(vec![7; 32]));
+        assert_eq!(authenticator.authenticate_key(&other, "robot", |_| false), Err(AuthFailure::BadCredentials));
+        // The failure of another peer does not hold the robot back
+        assert_eq!(authenticator.authenticate_key(&robot, "robot", |_| true), Ok(vec![7; 32]));
+        match authenticator.authenticate_key(&other, "robot", |_| true) {
+            Err(AuthFailure::Backoff(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // A password login can't authenticate with a key
+        assert_eq!(authenticator.authenticate_key(&robot, "alice", |_| true), Err(AuthFailure::BadCredentials));
+        assert_eq!(authenticator.authenticate(&robot, "robot", "secret"), Err(AuthFailure::BadCredentials));
+    }
+
+    #[test]
+    fn backoff_after_failures() {
+        let authenticator = Authenticator::new(Some(credentials()));
+        let alice = peer("10.0.0.1");
+        let now = Instant::now();
+        assert_eq!(authenticator.authenticate_at(&alice, "alice", "wrong", now), Err(AuthFailure::BadCredentials));
+        // The right password is not checked while backing off
+        match authenticator.authenticate_at(&alice, "alice", "secret", now + Duration::from_millis(100)) {
+            Err(AuthFailure::Backoff(delay)) => assert_eq!(delay, Duration::from_millis(400)),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let later = now + Duration::from_millis(500);
+        assert_eq!(authenticator.authenticate_at(&alice, "alice", "wrong", later), Err(AuthFailure::BadCredentials));
+        match authenticator.authenticate_at(&alice, "alice", "secret", later + Duration::from_millis(500)) {
+            Err(AuthFailure::Backoff(delay)) => assert_eq!(delay, Duration::from_millis(500)),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let later = later + Duration::from_millis(1000);
+        assert_eq!(authenticator.authenticate_at(&alice, "alice", "secret", later), Ok(()));
+        assert_eq!(authenticator.authenticate_at(&alice, "alice", "wrong", later), Err(AuthFailure::BadCredentials));
+        // Unknown logins fail the same way
+        assert_eq!(authenticator.authenticate_at(&alice, "bob", "secret", later), Err(AuthFailure::BadCredentials));
+    }
+
+    #[test]
+    fn failures_of_other_peers_do_not_lock_out() {
+        let authenticator = Authenticator::new(Some(credentials()));
+        let (alice, other) = (peer("10.0.0.1"), peer("10.0.0.2"));
+        let now = Instant::now();
+        assert_eq!(authenticator.authenticate_at(&other, "alice", "wrong", now), Err(AuthFailure::BadCredentials));
+        assert_eq!(authenticator.authenticate_at(&alice, "alice", "secret", now), Ok(()));
+        // Valid logins at the same moment all succeed
+        assert_eq!(authenticator.authenticate_at(&alice, "alice", "secret", now), Ok(()));
+        match authenticator.authenticate_at(&other, "alice", "secret", now) {
+            Err(AuthFailure::Backoff(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn failures_are_bounded() {
+        let authenticator = Authenticator::new(Some(credentials()));
+        let now = Instant::now();
+        for n in 0..MAX_FAILURES + 10 {
+            let at = now + Duration::new(0, n as u32 * 1000);
+            assert_eq!(authenticator.check(&format!("user{}", n), at, |_| false), Err(AuthFailure::BadCredentials));
+        }
+        assert!(authenticator.failures.lock().unwrap().len() <= MAX_FAILURES);
+        // The latest failures are kept
+        match authenticator.check(&format!("user{}", MAX_FAILURES + 9), now + Duration::from_millis(2), |_| true) {
+            Err(AuthFailure::Backoff(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // Expired ones are forgotten first
+        let later = now + Duration::from_millis(1000);
+        assert_eq!(authenticator.check("late", later, |_| false), Err(AuthFailure::BadCredentials));
+        assert!(authenticator.failures.lock().unwrap().len() <= 1);
+    }
+}
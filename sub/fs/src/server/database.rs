@@ -17,6 +17,8 @@ use uuid::Uuid;
 use ::types::ContentId;
 
 use super::config::Config;
+use super::import;
+use super::import::ImportError;
 
 
 pub type DatabaseHolder = Arc<Mutex<Database>>;
@@ -59,6 +61,55 @@ fn checksum_file(f: &mut fs::File) -> io::Result<Vec<u8>> {
     Ok(context.finalize().as_bytes().to_vec())
 }
 
+
+/// Removes the temporary files left by interrupted copies. The files locked by running copies are kept.
+/// Returns the number of removed files.
+fn cleanup_dir(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().map_or(true, |ext| ext != "tmp") {
+            continue;
+        }
+        let file = fs::File::open(&path)?;
+        if flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_ok() {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+
+/// Checks that the checksum of each stored file matches its path.
+/// Returns the number of checked files and the paths of the damaged ones.
+fn verify_dir(filesdir: &Path) -> io::Result<(usize, Vec<PathBuf>)> {
+    let mut checked = 0;
+    let mut damaged = Vec::new();
+    for level1 in fs::read_dir(filesdir)? {
+        let level1 = level1?;
+        if !level1.path().is_dir() { continue; }
+        for level2 in fs::read_dir(level1.path())? {
+            let level2 = level2?;
+            if !level2.path().is_dir() { continue; }
+            for file in fs::read_dir(level2.path())? {
+                let file = file?;
+                let path = file.path();
+                if !path.is_file() { continue; }
+                let expected = format!("{}{}{}", level1.file_name().to_string_lossy(),
+                    level2.file_name().to_string_lossy(), file.file_name().to_string_lossy());
+                let hash = checksum_file(&mut fs::File::open(&path)?)?;
+                checked += 1;
+                if ContentId::from_slice(&hash).to_string() != expected {
+                    warn!("Damaged file {}", path.display());
+                    damaged.push(path);
+                }
+            }
+        }
+    }
+    Ok((checked, damaged))
+}
+
 // --------------------------------------------------------------------------------------------------------------------
 
 
@@ -89,14 +140,20 @@ impl Database {
         (dir_path, file_path)
     }
 
-    pub fn copy_from(db: DatabaseHolder, uri: &str) -> io::Result<ContentId> {
-        let mut options = fs::OpenOptions::new();
-
-        let path = Path::new(uri);
-        let mut input = options.read(true).append(false).open(path).unwrap();
+    /// Imports a file of the server host, if it is beneath the import roots.
+    /// The copy is checked for cancellation before each block; a cancelled copy removes its temporary file.
+    /// After each block `progress` is given the bytes copied and the size of the file.
+    pub fn copy_from(db: DatabaseHolder, uri: &str, cancelled: &Fn() -> bool, progress: &Fn(u64, u64))
+        -> Result<ContentId, ImportError>
+    {
+        let roots = db.lock().unwrap().config.import_roots.clone();
+        let (path, mut input) = import::open(&roots, uri)?;
+        let total = input.metadata()?.len();
+        info!("  ::  Importing {} ({} bytes)", path.display(), total);
         // TODO lock timeout
         flock(input.as_raw_fd(), FlockArg::LockExclusive).unwrap();
 
+        let mut options = fs::OpenOptions::new();
         let tmp_name = format!("{}", Uuid::new_v4().hyphenated());
         let tmp_path = Path::new(".").canonicalize()?.join(tmp_name).with_extension("tmp");
         let mut output = options.create(true).append(true).open(&tmp_path).unwrap();
@@ -105,12 +162,21 @@ impl Database {
         let mut buf = [0u8; READ_BUFFER_SIZE];
         let mut context = Blake2b::new(64);
         context.update(&buf[0..0]);
+        let mut done = 0;
 
         'read_file: loop {
+            if cancelled() {
+                drop(output);
+                fs::remove_file(&tmp_path)?;
+                info!("  ::  Import of {} is cancelled", path.display());
+                return Err(ImportError::Cancelled);
+            }
             let len = input.read(&mut buf).unwrap();
             if len == 0 { break 'read_file; };
             context.update(&buf[0..len]);
             output.write(&buf[0..len]).unwrap();
+            done += len as u64;
+            progress(done, total);
         }
 
         let hash = ContentId::from_slice(context.finalize().as_bytes());
@@ -122,6 +188,18 @@ impl Database {
         fs::rename(tmp_path, file_path).unwrap();
         Ok(hash)
     }
+
+    /// Removes the temporary files of interrupted copies; returns their number
+    pub fn cleanup(db: DatabaseHolder) -> io::Result<usize> {
+        let workdir = db.lock().unwrap().config.workdir;
+        cleanup_dir(workdir)
+    }
+
+    /// Checks the stored files; returns the number of checked files and the paths of the damaged ones
+    pub fn verify(db: DatabaseHolder) -> io::Result<(usize, Vec<PathBuf>)> {
+        let filesdir = db.lock().unwrap().config.filesdir;
+        verify_dir(filesdir)
+    }
 }
 
 
@@ -138,7 +216,13 @@ mod tests {
     use blake2_rfc::blake2b::{blake2b};
     use nix::fcntl::{flock, FlockArg};
 
-    use super::checksum_file;
+    use ::server::config::Config;
+    use ::server::import::ImportError;
+    use ::testing;
+    use ::testing::temp_dir;
+    use ::types::ContentId;
+
+    use super::{Database, checksum_file, cleanup_dir, verify_dir};
 
 
     fn path<'a>() -> &'a Path {
@@ -173,4 +257,59 @@ mod tests {
 
         assert_eq!(a.as_slice(), b.as_bytes());
     }
+
+    #[test]
+    fn cleanup_interrupted_copies() {
+        let dir = temp_dir("cleanup");
+        fs::File::create(dir.join("a.tmp")).unwrap();
+        fs::File::create(dir.join("b.txt")).unwrap();
+        let running = fs::File::create(dir.join("c.tmp")).unwrap();
+        flock(running.as_raw_fd(), FlockArg::LockExclusive).unwrap();
+
+        assert_eq!(cleanup_dir(&dir).unwrap(), 1);
+        assert!(!dir.join("a.tmp").exists());
+        assert!(dir.join("b.txt").exists());
+        assert!(dir.join("c.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verify_stored_files() {
+        let dir = temp_dir("verify");
+        let store = |content: &[u8], name: &str| {
+            let path = dir.join(&name[0..2]).join(&name[2..4]);
+            fs::create_dir_all(&path).unwrap();
+            fs::File::create(path.join(&name[4..])).unwrap().write_all(content).unwrap();
+        };
+        let good = ContentId::from_slice(blake2b(64, &[], b"good").as_bytes()).to_string();
+        let other = ContentId::from_slice(blake2b(64, &[], b"other").as_bytes()).to_string();
+        store(b"good", &good);
+        store(b"damaged", &other);
+
+        let (checked, damaged) = verify_dir(&dir).unwrap();
+        assert_eq!(checked, 2);
+        assert_eq!(damaged, vec![dir.join(&other[0..2]).join(&other[2..4]).join(&other[4..])]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cancelled_copy() {
+        let dir = temp_dir("cancel");
+        fs::File::create(dir.join("input")).unwrap().write_all(b"content").unwrap();
+        let mut config = Config::new();
+        config.import_roots = vec![dir.to_string_lossy().into_owned()];
+        let db = testing::holder(config);
+        let tmp_files = || fs::read_dir(".").unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().map_or(false, |ext| ext == "tmp"))
+            .count();
+        let before = tmp_files();
+
+        let uri = dir.join("input").to_string_lossy().into_owned();
+        match Database::copy_from(db, &uri, &|| true, &|_, _| ()) {
+            Err(ImportError::Cancelled) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(tmp_files(), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
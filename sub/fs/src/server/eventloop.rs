@@ -6,33 +6,47 @@
 use net2::{TcpBuilder, TcpStreamExt};
 #[cfg(unix)] use unix_socket::{UnixListener};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::io;
 use std::io::{Write};
-use std::net::{SocketAddr, ToSocketAddrs, TcpStream};
+use std::net::{SocketAddr, ToSocketAddrs, TcpListener};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Sender, channel};
+use std::sync::mpsc::{TryRecvError, channel};
 use std::thread;
 
 use protocol::stream::Stream;
+use protocol::tls::{ServerConfig, TlsStream};
 
+use ::proto::auth::server::refuse_unadmitted;
 
+
+use super::admission::Admission;
 use super::config::Config;
 use super::connection::Connection;
+use super::control::ServerControl;
+use super::credentials::Authenticator;
 use super::database::Database;
+use super::registry::{ConnectionContext, Registry, ServerProtocol};
+#[cfg(unix)] use super::pool::{Executor, WorkerPool};
+#[cfg(unix)] use super::reactor::{EventLoop, Listener};
+
 
+/// How often the suspended sessions are checked for the end of their grace periods
+const SESSION_REAP_INTERVAL_MS: u64 = 1000;
 
 
 /// The database server
 pub struct Server {
     /// A reference to the database
     pub db: Arc<Mutex<Database>>,
-    /// A list of channels listening for incoming connections
-    listener_channels: Vec<Sender<u8>>,
+    /// The connections of the server and the channels stopping its listeners
+    pub control: Arc<ServerControl>,
     /// A list of threads listening for incoming connections
     listener_threads: Vec<thread::JoinHandle<()>>,
     /// An incremental id for new clients
     pub next_id: Arc<Mutex<usize>>,
+    /// The subprotocols served after auth
+    registry: Arc<Registry>,
 }
 
 
@@ -40,14 +54,27 @@ pub struct Server {
 impl Server {
     /// Creates a new server
     pub fn new(config: Config) -> io::Result<Server> {
+        config.check()?;
+        let authenticator = Authenticator::from_config(&config)?;
+        let admission = Admission::from_config(&config)?;
         Ok(Server {
             db: Arc::new(Mutex::new(Database::new(config)?)),
-            listener_channels: Vec::new(),
+            control: Arc::new(ServerControl::with_authenticator(authenticator).with_admission(admission)),
             listener_threads: Vec::new(),
             next_id: Arc::new(Mutex::new(0)),
+            registry: Arc::new(Registry::new()),
         })
     }
 
+    /// Adds a subprotocol to serve. Subprotocols are registered before the server starts.
+    pub fn register<F>(&mut self, code: u8, name: &'static str, factory: F)
+        where F: Fn(ConnectionContext) -> Box<ServerProtocol> + Send + Sync + 'static
+    {
+        Arc::get_mut(&mut self.registry)
+            .expect("Subprotocols are registered before the server starts")
+            .register(code, name, factory)
+    }
+
     /// Runs the server. If `config.daemonize` is true, it forks and exits.
     #[cfg(unix)]
     pub fn run(&mut self) {
@@ -105,8 +132,9 @@ impl Server {
         Ok(())
     }
 
-    /// Listens to a socket address.
-    fn handle_tcp<T: ToSocketAddrs>(&mut self, t: T, tcp_keepalive: u32, timeout: u64, tcp_backlog: i32) -> io::Result<()> {
+    /// Binds listeners to all addresses of a socket address.
+    fn bind_tcp<T: ToSocketAddrs>(&self, t: T, tcp_backlog: i32) -> io::Result<Vec<TcpListener>> {
+        let mut listeners = Vec::new();
         for addr in try!(t.to_socket_addrs()) {
             let tcp_builder = try!(match addr {
                 SocketAddr::V4(_) => TcpBuilder::new_v4(),
@@ -114,8 +142,17 @@ impl Server {
             });
 
             try!(self.reuse_address(&tcp_builder));
-            let listener = try!(try!(tcp_builder.bind(addr)).listen(tcp_backlog));
+            listeners.push(try!(try!(tcp_builder.bind(addr)).listen(tcp_backlog)));
+        }
+        Ok(listeners)
+    }
+
+    /// Listens to a socket address.
+    fn handle_tcp<T: ToSocketAddrs>(&mut self, t: T, tcp_keepalive: u32, timeout: u64, tcp_backlog: i32,
+                                    tls: Option<Arc<ServerConfig>>) -> io::Result<()> {
+        for listener in try!(self.bind_tcp(t, tcp_backlog)) {
             {
+                let tls = tls.clone();
                 self.handle_listener(move || {
                     let stream = listener.incoming().next().unwrap();
                     match stream {
@@ -125,15 +162,14 @@ impl Server {
                                 if tcp_keepalive > 0 { Some(Duration::from_secs(tcp_keepalive as u64)) }
                                 else { None }
                             ).unwrap();
-                            stream.set_read_timeout(
-                                if timeout > 0 { Some(Duration::new(timeout, 0)) }
-                                else { None }
-                            ).unwrap();
                             stream.set_write_timeout(
                                 if timeout > 0 { Some(Duration::new(timeout, 0)) }
                                 else { None }
                             ).unwrap();
-                            Ok(Stream::Tcp(stream))
+                            Ok(match tls {
+                                Some(ref tls) => Stream::Tls(TlsStream::server(stream, tls)),
+                                None => Stream::Tcp(stream),
+                            })
                         },
                     }
                 });
@@ -155,9 +191,16 @@ impl Server {
                     return;
                 }
             };
+            let timeout = db.config.timeout;
             self.handle_listener(move || {
                 let stream = listener.incoming().next().unwrap();
-                stream.map(|stream| Stream::Unix(stream) )
+                stream.map(|stream| Stream::Unix(stream)).and_then(|stream| {
+                    try!(stream.set_write_timeout(
+                        if timeout > 0 { Some(Duration::new(timeout, 0)) }
+                        else { None }
+                    ));
+                    Ok(stream)
+                })
             });
         }
     }
@@ -172,9 +215,11 @@ impl Server {
 
     fn handle_listener<F>(&mut self, incoming: F) where F: Send + 'static + Fn() -> io::Result<Stream> {
         let (tx, rx) = channel();
-        self.listener_channels.push(tx);
+        self.control.add_listener(tx, None);
         let db = self.db.clone();
         let next_id = self.next_id.clone();
+        let registry = self.registry.clone();
+        let control = self.control.clone();
 
         let th = thread::spawn(move || {
             loop {
@@ -184,14 +229,18 @@ impl Server {
                     break;
                 }
                 match stream {
-                    Ok(stream) => {
+                    Ok(mut stream) => {
                         info!("Accepted connection to {:?}", stream);
                         let id = {
                             let mut nid = next_id.lock().unwrap();
                             *nid += 1;
                             *nid - 1
                         };
-                        let mut connection = Connection::new(stream, db.clone(), id);
+                        if refuse_unadmitted(&control, id, &mut stream) {
+                            continue;
+                        }
+                        let mut connection = Connection::new(stream, db.clone(), id, registry.clone(),
+                                                             control.clone());
                         thread::spawn(move || {
                             connection.run();
                         });
@@ -204,18 +253,116 @@ impl Server {
     }
 
 
+    /// Starts a thread serving all connections from an event loop.
+    #[cfg(unix)]
+    fn start_event_loop(&mut self) -> bool {
+        let (tcp_keepalive, addresses, tcp_backlog, unixsocket, workers) = {
+            let db = self.db.lock().unwrap();
+            (db.config.tcp_keepalive,
+            db.config.addresses(),
+            db.config.tcp_backlog,
+            db.config.unixsocket.clone(),
+            db.config.workers,
+            )
+        };
+
+        let mut listeners = Vec::new();
+        for (host, port) in addresses {
+            match self.bind_tcp((&host[..], port), tcp_backlog) {
+                Ok(tcp_listeners) => {
+                    listeners.extend(tcp_listeners.into_iter().map(|l| Listener::Tcp(l, tcp_keepalive)));
+                    info!("The server is now ready to accept connections on port {}", port);
+                },
+                Err(err) => warn!("Creating Server TCP listening socket {}:{}: {:?}", host, port, err),
+            }
+        }
+        if let Some(ref unixsocket) = unixsocket {
+            match UnixListener::bind(unixsocket) {
+                Ok(l) => listeners.push(Listener::Unix(l)),
+                Err(err) => warn!("Creating Server Unix socket {}: {:?}", unixsocket, err),
+            }
+        }
+
+        let (tx, rx) = channel();
+        let db = self.db.clone();
+        let next_id = self.next_id.clone();
+        let registry = self.registry.clone();
+        let control = self.control.clone();
+        let pool = Arc::new(WorkerPool::new(workers));
+        let executor = Executor::Pool(pool.clone());
+
+        let th = thread::spawn(move || {
+            match EventLoop::new(listeners, db, executor, registry, control.clone(), next_id, rx) {
+                Ok(mut event_loop) => {
+                    // Woken up on shutdown rather than by a connection to one of its listeners
+                    control.add_listener(tx, Some(event_loop.waker()));
+                    event_loop.run();
+                },
+                Err(err) => error!("Starting the event loop: {:?}", err),
+            }
+            // The jobs still queued are run before the workers stop
+            pool.shutdown();
+        });
+        self.listener_threads.push(th);
+        true
+    }
+
+    #[cfg(not(unix))]
+    fn start_event_loop(&mut self) -> bool {
+        warn!("Ignoring event_loop in non unix environment, serving a thread per connection");
+        false
+    }
+
+    /// Starts a thread dropping the suspended sessions once their grace periods end,
+    /// so that their tasks are stopped even if no other session is suspended or resumed.
+    /// It is stopped with the listeners.
+    fn start_session_reaper(&mut self) {
+        let (tx, rx) = channel();
+        self.control.add_listener(tx, None);
+        let control = self.control.clone();
+        let th = thread::spawn(move || {
+            loop {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => (),
+                    Ok(_) | Err(TryRecvError::Disconnected) => break,
+                }
+                control.reap_sessions();
+                thread::sleep(Duration::from_millis(SESSION_REAP_INTERVAL_MS));
+            }
+        });
+        self.listener_threads.push(th);
+    }
+
     /// Starts threads listening to new connections.
     pub fn start(&mut self) {
-        let (tcp_keepalive, timeout, addresses, tcp_backlog) = {
+        let (tcp_keepalive, timeout, addresses, tcp_backlog, event_loop, tls) = {
             let db = self.db.lock().unwrap();
             (db.config.tcp_keepalive.clone(),
             db.config.timeout.clone(),
             db.config.addresses().clone(),
             db.config.tcp_backlog.clone(),
+            db.config.event_loop,
+            db.config.tls(),
             )
         };
+
+        let tls = match tls {
+            Ok(tls) => tls,
+            Err(err) => {
+                error!("Loading TLS configuration: {:?}", err);
+                return;
+            },
+        };
+
+        self.start_session_reaper();
+
+        // TLS with the event loop is refused by `Config::check`
+        if event_loop && tls.is_none() && self.start_event_loop() {
+            return;
+        }
+
         for (host, port) in addresses {
-            match self.handle_tcp((&host[..], port), tcp_keepalive, timeout, tcp_backlog) {
+            match self.handle_tcp((&host[..], port), tcp_keepalive, timeout, tcp_backlog, tls.clone()) {
                 Ok(_) => {
                     //                    let db = self.db.lock().unwrap();
                     info!("The server is now ready to accept connections on port {}", port);
@@ -234,24 +381,37 @@ impl Server {
     /// Sends a kill signal to the listeners and connects to the incoming
     /// connections to break the listening loop.
     pub fn stop(&mut self) {
-        #![allow(unused_must_use)]
-        for sender in self.listener_channels.iter() {
-            sender.send(0);
+        {
             let db = self.db.lock().unwrap();
-            for (host, port) in db.config.addresses() {
-                for addrs in (&host[..], port).to_socket_addrs().unwrap() {
-                    TcpStream::connect(addrs);
-                }
-            }
+            self.control.shutdown_listeners(&db.config);
         }
         self.join();
     }
 
-    /// Join the listener threads.
+    /// Join the listener threads, then waits for the running tasks to finish and closes the connections.
     pub fn join(&mut self) {
         #![allow(unused_must_use)]
         while self.listener_threads.len() > 0 {
             self.listener_threads.pop().unwrap().join();
         }
+        self.drain();
+    }
+
+    /// Gives the running tasks `shutdown_timeout` seconds to finish, then kills the remaining connections
+    fn drain(&self) {
+        let timeout = Duration::from_secs(self.db.lock().unwrap().config.shutdown_timeout);
+        let started = Instant::now();
+        loop {
+            let active = self.control.active_tasks();
+            if active == 0 {
+                break;
+            }
+            if started.elapsed() >= timeout {
+                warn!("Closing connections with {} tasks still running", active);
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.control.kill_all();
     }
 }
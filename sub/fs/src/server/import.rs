@@ -0,0 +1,298 @@
+//! Opening the files of the server host which the clients import with `CopyFrom`.
+//!
+//! Only regular files beneath the configured import roots are opened. A path is refused if it is
+//! relative or has `..` components, and it is resolved to check that no symlink leads out of the roots.
+//! The resolved path is then opened one component at a time without following symlinks, so that
+//! a symlink put in place after the check is not followed either.
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+
+use libc;
+
+
+#[derive(Debug)]
+pub enum ImportError {
+    NotAbsolute,
+    /// The path has `..` components
+    ParentDir,
+    /// The path is not beneath any import root, or leads out of them through a symlink
+    OutsideRoots,
+    /// The path is not a regular file, like a directory, a device or a FIFO
+    NotAFile,
+    /// The task importing the file is cancelled
+    Cancelled,
+    Io(io::Error),
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl ImportError {
+    /// The path is refused by the import rules, as opposed to failing to be read
+    pub fn is_denied(&self) -> bool {
+        match *self {
+            ImportError::Io(_) | ImportError::Cancelled => false,
+            _ => true,
+        }
+    }
+}
+
+
+impl From<io::Error> for ImportError {
+    fn from(err: io::Error) -> Self {
+        ImportError::Io(err)
+    }
+}
+
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::NotAbsolute    => write!(f, "the path is not absolute"),
+            ImportError::ParentDir      => write!(f, "the path has .. components"),
+            ImportError::OutsideRoots   => write!(f, "the path is outside of the import roots"),
+            ImportError::NotAFile       => write!(f, "the path is not a regular file"),
+            ImportError::Cancelled      => write!(f, "the import is cancelled"),
+            ImportError::Io(ref err)    => write!(f, "{}", err),
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+/// Opens the file at `uri` for reading if it is allowed by the import roots.
+/// Returns the resolved path along with the file.
+pub fn open(roots: &[String], uri: &str) -> Result<(PathBuf, fs::File), ImportError> {
+    let path = Path::new(uri);
+    if !path.is_absolute() {
+        return Err(ImportError::NotAbsolute);
+    }
+    if path.components().any(|component| component == Component::ParentDir) {
+        return Err(ImportError::ParentDir);
+    }
+
+    let roots = roots.iter()
+        .filter_map(|root| match Path::new(root).canonicalize() {
+            Ok(canonical) => Some((Path::new(root), canonical)),
+            Err(err) => {
+                warn!("Import root {} is unavailable: {}", root, err);
+                None
+            },
+        })
+        .collect::<Vec<_>>();
+    // Checked before resolving, so that the clients can't learn which files exist elsewhere
+    if !roots.iter().any(|&(root, ref canonical)| path.starts_with(root) || path.starts_with(canonical)) {
+        return Err(ImportError::OutsideRoots);
+    }
+
+    let resolved = path.canonicalize()?;
+    let root = match roots.iter().map(|&(_, ref canonical)| canonical).find(|root| resolved.starts_with(root)) {
+        Some(root) => root,
+        None => return Err(ImportError::OutsideRoots),
+    };
+    let beneath = match resolved.strip_prefix(root) {
+        Ok(beneath) => beneath.to_path_buf(),
+        Err(_) => return Err(ImportError::OutsideRoots),
+    };
+    let file = open_beneath(root, &beneath)?;
+    if !file.metadata()?.is_file() {
+        return Err(ImportError::NotAFile);
+    }
+    Ok((resolved, file))
+}
+
+
+/// Opens `path` relative to the `root` directory, refusing to follow symlinks on the way
+fn open_beneath(root: &Path, path: &Path) -> io::Result<fs::File> {
+    let mut dir = open_at(libc::AT_FDCWD, root.as_os_str().as_bytes(), libc::O_DIRECTORY)?;
+    let components = path.components().collect::<Vec<_>>();
+    for (i, component) in components.iter().enumerate() {
+        let name = match *component {
+            Component::Normal(name) => name,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unexpected path component")),
+        };
+        // Not blocking on a FIFO in place of the file; reads of regular files are not affected
+        let flags = if i + 1 == components.len() { libc::O_NONBLOCK } else { libc::O_DIRECTORY };
+        dir = open_at(dir.as_raw_fd(), name.as_bytes(), flags | libc::O_NOFOLLOW)?;
+    }
+    Ok(dir)
+}
+
+
+fn open_at(dir: RawFd, name: &[u8], flags: libc::c_int) -> io::Result<fs::File> {
+    let name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contains NUL"))?;
+    let fd = unsafe { libc::openat(dir, name.as_ptr(), flags | libc::O_RDONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::ffi::CString;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+
+    use libc;
+
+    use ::testing::temp_dir;
+    use super::{ImportError, open, open_beneath};
+
+    /// A root with files and symlinks, and a directory with a secret next to it
+    struct Sandbox {
+        dir: PathBuf,
+    }
+
+    impl Sandbox {
+        fn new(name: &str) -> Sandbox {
+            let dir = temp_dir(name);
+            for sub in &["root/sub", "root2", "outside"] {
+                fs::create_dir_all(dir.join(sub)).unwrap();
+            }
+            // The paths are compared to the resolved ones
+            let dir = dir.canonicalize().unwrap();
+            let write = |path: &str, content: &[u8]| {
+                fs::File::create(dir.join(path)).unwrap().write_all(content).unwrap()
+            };
+            write("root/a.txt", b"a");
+            write("root/sub/b.txt", b"b");
+            write("root2/c.txt", b"c");
+            write("outside/secret.txt", b"secret");
+            symlink(dir.join("root/a.txt"), dir.join("root/link-in")).unwrap();
+            symlink(dir.join("outside/secret.txt"), dir.join("root/link-out")).unwrap();
+            symlink("../../outside/secret.txt", dir.join("root/sub/relative-out")).unwrap();
+            symlink(dir.join("outside"), dir.join("root/dir-out")).unwrap();
+            symlink(dir.join("root"), dir.join("root-link")).unwrap();
+            let fifo = CString::new(dir.join("root/fifo").as_os_str().as_bytes()).unwrap();
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+            Sandbox { dir: dir }
+        }
+
+        fn path(&self, path: &str) -> String {
+            format!("{}/{}", self.dir.display(), path)
+        }
+
+        fn roots(&self, roots: &[&str]) -> Vec<String> {
+            roots.iter().map(|root| self.path(root)).collect()
+        }
+
+        fn read(&self, roots: &[&str], path: &str) -> Result<(PathBuf, String), ImportError> {
+            let (resolved, mut file) = open(&self.roots(roots), &self.path(path))?;
+            let mut content = String::new();
+            file.read_to_string(&mut content).unwrap();
+            Ok((resolved, content))
+        }
+
+        fn denied(&self, roots: &[&str], path: &str) -> ImportError {
+            match open(&self.roots(roots), path) {
+                Ok(_) => panic!("{} is not denied", path),
+                Err(err) => err,
+            }
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn is(err: ImportError, expected: ImportError) -> bool {
+        format!("{:?}", err) == format!("{:?}", expected)
+    }
+
+    #[test]
+    fn files_beneath_roots() {
+        let sandbox = Sandbox::new("import-allowed");
+        let (resolved, content) = sandbox.read(&["root"], "root/a.txt").unwrap();
+        assert_eq!((resolved, &content[..]), (sandbox.dir.join("root/a.txt"), "a"));
+        assert_eq!(sandbox.read(&["root"], "root/sub/b.txt").unwrap().1, "b");
+        assert_eq!(sandbox.read(&["root", "root2"], "root2/c.txt").unwrap().1, "c");
+        assert_eq!(sandbox.read(&["root/"], "root/./sub//b.txt").unwrap().1, "b");
+
+        // Symlinks are followed as long as they stay inside, and a root may be a symlink itself
+        let (resolved, content) = sandbox.read(&["root"], "root/link-in").unwrap();
+        assert_eq!((resolved, &content[..]), (sandbox.dir.join("root/a.txt"), "a"));
+        assert_eq!(sandbox.read(&["root-link"], "root-link/a.txt").unwrap().1, "a");
+        assert_eq!(sandbox.read(&["root-link"], "root/a.txt").unwrap().1, "a");
+    }
+
+    #[test]
+    fn traversal_is_denied() {
+        let sandbox = Sandbox::new("import-traversal");
+        let roots = ["root"];
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/../outside/secret.txt")), ImportError::ParentDir));
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/sub/../a.txt")), ImportError::ParentDir));
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/sub/../../outside/secret.txt")),
+            ImportError::ParentDir));
+        assert!(is(sandbox.denied(&roots, "root/a.txt"), ImportError::NotAbsolute));
+        assert!(is(sandbox.denied(&roots, "../../etc/passwd"), ImportError::NotAbsolute));
+        assert!(is(sandbox.denied(&roots, "/etc/passwd"), ImportError::OutsideRoots));
+        assert!(is(sandbox.denied(&roots, &sandbox.path("outside/secret.txt")), ImportError::OutsideRoots));
+        // A sibling sharing the prefix of the root is not beneath it
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root2/c.txt")), ImportError::OutsideRoots));
+        // Nothing is allowed without roots
+        assert!(is(sandbox.denied(&[], &sandbox.path("root/a.txt")), ImportError::OutsideRoots));
+    }
+
+    #[test]
+    fn symlink_escapes_are_denied() {
+        let sandbox = Sandbox::new("import-symlinks");
+        let roots = ["root"];
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/link-out")), ImportError::OutsideRoots));
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/sub/relative-out")), ImportError::OutsideRoots));
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/dir-out/secret.txt")), ImportError::OutsideRoots));
+    }
+
+    #[test]
+    fn only_regular_files() {
+        let sandbox = Sandbox::new("import-files");
+        let roots = ["root"];
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root")), ImportError::NotAFile));
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/sub")), ImportError::NotAFile));
+        // Not blocked waiting for a writer
+        assert!(is(sandbox.denied(&roots, &sandbox.path("root/fifo")), ImportError::NotAFile));
+    }
+
+    #[test]
+    fn missing_files() {
+        let sandbox = Sandbox::new("import-missing");
+        match sandbox.denied(&["root"], &sandbox.path("root/missing.txt")) {
+            ImportError::Io(ref err) => assert_eq!(err.kind(), ::std::io::ErrorKind::NotFound),
+            err => panic!("Unexpected error {:?}", err),
+        }
+        // Files outside are refused whether they exist or not
+        assert!(is(sandbox.denied(&["root"], &sandbox.path("outside/missing.txt")), ImportError::OutsideRoots));
+        assert!(!ImportError::Io(::std::io::Error::last_os_error()).is_denied());
+        assert!(ImportError::OutsideRoots.is_denied());
+    }
+
+    #[test]
+    fn symlinks_are_not_followed_when_opening() {
+        let sandbox = Sandbox::new("import-nofollow");
+        let root = sandbox.dir.join("root");
+        assert!(open_beneath(&root, Path::new("sub/b.txt")).is_ok());
+        assert!(open_beneath(&root, Path::new("link-in")).is_err());
+        assert!(open_beneath(&root, Path::new("dir-out/secret.txt")).is_err());
+        assert!(open_beneath(&root, Path::new("../outside/secret.txt")).is_err());
+    }
+}
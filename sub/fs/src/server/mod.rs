@@ -1,6 +1,15 @@
+pub mod admission;
 pub mod config;
 pub mod connection;
+pub mod control;
+pub mod credentials;
 pub mod database;
 mod eventloop;
+pub mod import;
+pub mod permissions;
+pub mod pool;
+#[cfg(unix)] mod reactor;
+pub mod registry;
+pub mod tokens;
 
 pub use self::eventloop::*;
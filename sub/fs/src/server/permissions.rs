@@ -0,0 +1,189 @@
+//! What the clients are allowed to do once they have authenticated.
+//!
+//! Permissions are given to identities (logins and the identities of Unix socket peers) in the config.
+//! They are written as a comma-separated list of names, like `info,read`, or as `all` and `none`.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use protocol::serde::{EncodeTo, Parse, Parser, ParserError};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    /// Getting the info of the server
+    Info,
+    /// Copying files of the server host into the storage
+    Import,
+    /// Reading the stored content
+    Read,
+    /// Removing the stored content
+    Delete,
+    /// Creating and changing the references to content
+    RefWrite,
+    /// Using the admin subprotocol
+    Admin,
+}
+
+
+/// A set of permissions
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Permissions(u8);
+
+
+const ALL: &'static [Permission] = &[
+    Permission::Info,
+    Permission::Import,
+    Permission::Read,
+    Permission::Delete,
+    Permission::RefWrite,
+    Permission::Admin,
+];
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Permission {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Permission::Info        => "info",
+            Permission::Import      => "import",
+            Permission::Read        => "read",
+            Permission::Delete      => "delete",
+            Permission::RefWrite    => "ref-write",
+            Permission::Admin       => "admin",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Permission, String> {
+        ALL.iter().find(|permission| permission.name() == s).cloned()
+            .ok_or_else(|| format!("Unknown permission {}", s))
+    }
+}
+
+
+impl Permissions {
+    pub fn none() -> Permissions {
+        Permissions(0)
+    }
+
+    pub fn all() -> Permissions {
+        Permissions::of(ALL)
+    }
+
+    pub fn of(permissions: &[Permission]) -> Permissions {
+        Permissions(permissions.iter().fold(0, |bits, permission| bits | permission.bit()))
+    }
+
+    pub fn contains(&self, permission: Permission) -> bool {
+        self.0 & permission.bit() != 0
+    }
+
+    /// The permissions of the set but the given one
+    pub fn without(&self, permission: Permission) -> Permissions {
+        Permissions(self.0 & !permission.bit())
+    }
+
+    /// The permissions in both sets
+    pub fn intersect(&self, other: Permissions) -> Permissions {
+        Permissions(self.0 & other.0)
+    }
+
+    pub fn list(&self) -> Vec<Permission> {
+        ALL.iter().cloned().filter(|&permission| self.contains(permission)).collect()
+    }
+}
+
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            p if p == Permissions::none() => write!(f, "none"),
+            p if p == Permissions::all() => write!(f, "all"),
+            p => write!(f, "{}", p.list().iter().map(|permission| permission.name()).collect::<Vec<_>>().join(",")),
+        }
+    }
+}
+
+
+impl EncodeTo for Permissions {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.encode_to(w)
+    }
+}
+
+
+impl Parse for Permissions {
+    fn parse_from(input: &mut Parser) -> Result<Permissions, ParserError> {
+        // Unknown permissions are not granted
+        Ok(Permissions(u8::parse_from(input)? & Permissions::all().0))
+    }
+}
+
+
+impl FromStr for Permissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Permissions, String> {
+        match s.trim() {
+            "all"           => Ok(Permissions::all()),
+            "none" | ""     => Ok(Permissions::none()),
+            s => {
+                let permissions = s.split(',').map(|name| name.trim().parse()).collect::<Result<Vec<_>, _>>()?;
+                Ok(Permissions::of(&permissions))
+            },
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use super::{Permission, Permissions};
+
+    #[test]
+    fn parse_and_format() {
+        let permissions = "info, read,ref-write".parse::<Permissions>().unwrap();
+        assert!(permissions.contains(Permission::Info));
+        assert!(permissions.contains(Permission::RefWrite));
+        assert!(!permissions.contains(Permission::Import));
+        assert!(!permissions.contains(Permission::Admin));
+        assert_eq!(permissions.to_string(), "info,read,ref-write");
+
+        assert_eq!("all".parse::<Permissions>().unwrap(), Permissions::all());
+        assert_eq!("none".parse::<Permissions>().unwrap(), Permissions::none());
+        assert_eq!(Permissions::all().to_string(), "all");
+        assert_eq!(Permissions::default(), Permissions::none());
+        assert_eq!("info,write".parse::<Permissions>().unwrap_err(), "Unknown permission write");
+
+        let import = Permissions::of(&[Permission::Import, Permission::Read]);
+        assert_eq!(permissions.intersect(import), Permissions::of(&[Permission::Read]));
+        assert_eq!(Permissions::all().intersect(import), import);
+        assert_eq!(import.without(Permission::Import), Permissions::of(&[Permission::Read]));
+        assert_eq!(import.without(Permission::Admin), import);
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt;
+use std::mem;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::thread::{self, JoinHandle};
+
+
+/// The number of jobs which may wait in the queue for each worker
+const QUEUE_PER_WORKER: usize = 16;
+
+
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+
+impl <F: FnOnce() + Send> Job for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+
+/// A fixed set of threads running blocking jobs, like storage work of tasks.
+/// The jobs wait in a bounded queue; the workers are stopped once the pool is shut down or dropped.
+pub struct WorkerPool {
+    /// Taken away on shutdown
+    tx: Mutex<Option<SyncSender<Box<Job>>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    size: usize,
+}
+
+
+/// Runs the jobs of tasks either in own threads or in a worker pool
+#[derive(Clone, Debug)]
+pub enum Executor {
+    Thread,
+    Pool(Arc<WorkerPool>),
+}
+
+
+/// The job is refused, since the queue of the pool is full or the pool is shut down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolBusy;
+
+
+/// A handle to wait for a job completion
+#[derive(Debug)]
+pub struct JobHandle {
+    /// Signalled once the job is done; the sender is dropped without a signal if the job panics
+    done: Receiver<()>,
+    /// Whether the job has finished without a panic, once it is known
+    result: Option<bool>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl WorkerPool {
+    pub fn new(size: usize) -> WorkerPool {
+        Self::with_queue(size, size * QUEUE_PER_WORKER)
+    }
+
+    /// Creates the pool with a queue of at most `queue` jobs waiting for the workers
+    pub fn with_queue(size: usize, queue: usize) -> WorkerPool {
+        assert!(size > 0);
+        let (tx, rx) = sync_channel::<Box<Job>>(queue);
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..size).map(|n| {
+            let rx = rx.clone();
+            thread::Builder::new().name(format!("worker-{}", n)).spawn(move || {
+                loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    if catch_unwind(AssertUnwindSafe(move || job.run())).is_err() {
+                        error!("A job has panicked in worker #{}", n);
+                    }
+                }
+            }).unwrap()
+        }).collect();
+        WorkerPool { tx: Mutex::new(Some(tx)), workers: Mutex::new(workers), size: size }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Queues the job, unless the queue is full
+    pub fn execute<F>(&self, f: F) -> Result<JobHandle, PoolBusy> where F: FnOnce() + Send + 'static {
+        let (done_tx, done_rx) = channel();
+        let job = move || {
+            f();
+            let _ = done_tx.send(());
+        };
+        let tx = self.tx.lock().unwrap();
+        match tx.as_ref().map(|tx| tx.try_send(Box::new(job))) {
+            Some(Ok(())) => Ok(JobHandle::new(done_rx)),
+            Some(Err(TrySendError::Full(_))) => {
+                warn!("The job queue of the worker pool is full, a job is refused");
+                Err(PoolBusy)
+            },
+            Some(Err(TrySendError::Disconnected(_))) | None => Err(PoolBusy),
+        }
+    }
+
+    /// Refuses new jobs and waits for the workers to run the queued ones and to stop.
+    /// Must not be called from a job.
+    pub fn shutdown(&self) {
+        self.close();
+        let workers = mem::replace(&mut *self.workers.lock().unwrap(), Vec::new());
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+
+    /// Refuses new jobs; the workers stop once the queued ones are run
+    fn close(&self) {
+        if let Ok(mut tx) = self.tx.lock() {
+            drop(tx.take());
+        }
+    }
+}
+
+
+/// The workers are not waited for, as the last reference may be dropped by a job
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WorkerPool({})", self.size)
+    }
+}
+
+
+impl Executor {
+    /// Starts the job; a pool refuses it when its queue is full
+    pub fn spawn<F>(&self, f: F) -> Result<JobHandle, PoolBusy> where F: FnOnce() + Send + 'static {
+        match *self {
+            Executor::Thread => {
+                let (done_tx, done_rx) = channel();
+                thread::spawn(move || {
+                    f();
+                    let _ = done_tx.send(());
+                });
+                Ok(JobHandle::new(done_rx))
+            },
+            Executor::Pool(ref pool) => pool.execute(f),
+        }
+    }
+}
+
+
+impl JobHandle {
+    fn new(done: Receiver<()>) -> JobHandle {
+        JobHandle { done: done, result: None }
+    }
+
+    /// Waits for the job to finish. Returns `false` if the job has panicked
+    pub fn join(self) -> bool {
+        match self.result {
+            Some(result) => result,
+            None => self.done.recv().is_ok(),
+        }
+    }
+
+    /// Checks whether the job has finished without waiting for it.
+    /// Returns `Some(false)` if the job has panicked, `None` while it is running.
+    pub fn try_join(&mut self) -> Option<bool> {
+        if self.result.is_none() {
+            self.result = match self.done.try_recv() {
+                Ok(()) => Some(true),
+                Err(TryRecvError::Disconnected) => Some(false),
+                Err(TryRecvError::Empty) => None,
+            };
+        }
+        self.result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{WorkerPool, Executor, PoolBusy};
+
+    #[test]
+    fn pool_runs_all_jobs() {
+        let pool = Arc::new(WorkerPool::new(2));
+        let executor = Executor::Pool(pool);
+        let counter = Arc::new(Mutex::new(0));
+        let handles = (0..10).map(|_| {
+            let counter = counter.clone();
+            executor.spawn(move || *counter.lock().unwrap() += 1).unwrap()
+        }).collect::<Vec<_>>();
+        for handle in handles {
+            assert!(handle.join());
+        }
+        assert_eq!(*counter.lock().unwrap(), 10);
+    }
+
+    #[test]
+    fn pool_survives_panic() {
+        let executor = Executor::Pool(Arc::new(WorkerPool::new(1)));
+        assert!(!executor.spawn(|| panic!("Expected panic")).unwrap().join());
+        assert!(executor.spawn(|| ()).unwrap().join());
+    }
+
+    #[test]
+    fn full_queue_refuses_jobs() {
+        let pool = WorkerPool::with_queue(1, 1);
+        let (tx, rx) = channel::<()>();
+        let (started_tx, started_rx) = channel::<()>();
+        let running = pool.execute(move || { started_tx.send(()).unwrap(); let _ = rx.recv(); }).unwrap();
+        started_rx.recv().unwrap();
+        let queued = pool.execute(|| ()).unwrap();
+        assert_eq!(pool.execute(|| ()).err(), Some(PoolBusy));
+
+        tx.send(()).unwrap();
+        assert!(running.join());
+        assert!(queued.join());
+        assert!(pool.execute(|| ()).unwrap().join());
+    }
+
+    #[test]
+    fn shutdown_stops_workers() {
+        let pool = WorkerPool::new(2);
+        let counter = Arc::new(Mutex::new(0));
+        for _ in 0..4 {
+            let counter = counter.clone();
+            pool.execute(move || *counter.lock().unwrap() += 1).unwrap();
+        }
+        pool.shutdown();
+        // The queued jobs are run before the workers stop
+        assert_eq!(*counter.lock().unwrap(), 4);
+        assert_eq!(pool.execute(|| ()).err(), Some(PoolBusy));
+    }
+
+    #[test]
+    fn try_join_does_not_wait() {
+        let (tx, rx) = channel::<()>();
+        let mut handle = Executor::Thread.spawn(move || { let _ = rx.recv(); }).unwrap();
+        assert_eq!(handle.try_join(), None);
+        tx.send(()).unwrap();
+        while handle.try_join().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(handle.try_join(), Some(true));
+        assert!(handle.join());
+
+        let mut handle = Executor::Thread.spawn(|| panic!("Expected panic")).unwrap();
+        while handle.try_join().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!handle.join());
+    }
+}
@@ -0,0 +1,910 @@
+//! The event-driven server mode: all connections are served by a single thread polling the sockets,
+//! while blocking jobs of tasks run in a bounded worker pool.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, TryRecvError, channel};
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::unix::EventedFd;
+use net2::TcpStreamExt;
+use unix_socket::UnixListener;
+
+use protocol::capture::Capture;
+use protocol::heartbeat::Heartbeat;
+use protocol::message::{RawMessage, Reader, ReadFlow};
+use protocol::stream::Stream;
+use protocol::workflow::{Protocol, ProtocolVersion, Workflow};
+
+use ::connection::{Notify, StreamMessage, StreamSender, handle_control, send_message};
+use ::proto::{HEARTBEAT_VERSION, PROTOCOL_VERSION};
+use ::proto::auth::message::SessionTicket;
+use ::proto::auth::server::{AuthConfig, AuthProtocol, CheckedCredentials, refuse_unadmitted};
+
+use super::config::Grants;
+use super::control::{Peer, ServerControl};
+use super::database::DatabaseHolder;
+use super::pool::Executor;
+use super::registry::{ConnectionContext, Registry, ServerProtocol};
+use super::tokens::Claims;
+
+
+const WAKE: Token = Token(0);
+const FIRST_LISTENER: usize = 1;
+const FIRST_CONNECTION: usize = 1 << 16;
+
+const EVENTS_CAPACITY: usize = 1024;
+/// Output buffers grown above this size are released once flushed
+const WRITE_BUFFER_LIMIT: usize = 1 << 20;
+/// The poll interval while waiting for the tasks to finish on shutdown
+const STOPPING_TICK_MS: u64 = 100;
+/// A listener failing to accept, like when out of file descriptors, is paused for this long
+const ACCEPT_BACKOFF_MS: u64 = 500;
+
+
+pub enum Listener {
+    /// A TCP listener with the keepalive timeout for accepted connections
+    Tcp(TcpListener, u32),
+    Unix(UnixListener),
+}
+
+
+enum Stage {
+    Auth(AuthProtocol),
+    /// A subprotocol from the registry, with its code
+    Subprotocol(usize, Box<ServerProtocol>),
+}
+
+
+/// What the switch of a connection to its subprotocol takes from the config, read once on start
+struct SwitchConfig {
+    /// The interval of pings and the number of pongs a client may miss, if heartbeats are enabled
+    heartbeat: Option<(Duration, u32)>,
+    grants: Grants,
+}
+
+
+/// A client connection served by the event loop
+struct PollConnection {
+    id: usize,
+    peer: Peer,
+    stream: Stream,
+    reader: Reader,
+    stage: Stage,
+    sender: StreamSender,
+    rx: Receiver<StreamMessage>,
+    /// Framed messages not yet written to the socket
+    out: Vec<u8>,
+    written: usize,
+    /// The socket is registered for writable events
+    writable: bool,
+    /// The connection should be closed once the output is flushed
+    closing: bool,
+    /// The last time a message was received or a task was running
+    active_at: Instant,
+    /// Pings the client, if it is able to answer
+    heartbeat: Option<Arc<Mutex<Heartbeat>>>,
+    /// Records the frames of the connection
+    capture: Option<Arc<Capture>>,
+    /// The protocol version of the client, once authenticated
+    client_version: ProtocolVersion,
+    /// The identity of the client, if it has authenticated
+    identity: Option<String>,
+    /// The claims of the token the client has authenticated with
+    token: Option<Claims>,
+    /// Given to the client on auth, to resume the session
+    ticket: Option<SessionTicket>,
+    /// The connection is closed by its protocol rather than lost
+    terminated: bool,
+    /// Wakes up the loop to serve the connection
+    notify: Notify,
+    /// The credentials of the client checked by the executor, as the hashing would stall the loop
+    checking: Option<Receiver<CheckedCredentials>>,
+    control: Arc<ServerControl>,
+}
+
+
+pub struct EventLoop {
+    poll: Poll,
+    listeners: Vec<Listener>,
+    /// The listeners deregistered after an accept error, until the time they are registered again
+    paused: Vec<Option<Instant>>,
+    connections: HashMap<usize, PollConnection>,
+    /// Connections with queued messages
+    woken: Arc<Mutex<Vec<usize>>>,
+    // Must live as long as the loop
+    _registration: Registration,
+    set_readiness: SetReadiness,
+    db: DatabaseHolder,
+    executor: Executor,
+    registry: Arc<Registry>,
+    switch: SwitchConfig,
+    control: Arc<ServerControl>,
+    next_id: Arc<Mutex<usize>>,
+    stop_rx: Receiver<u8>,
+    /// The interval of timer checks
+    tick: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    ticked_at: Instant,
+    /// The time the stop signal was received; the running tasks are given this long to finish
+    stopping_since: Option<Instant>,
+    shutdown_timeout: Duration,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Listener {
+    fn accept(&self) -> io::Result<Stream> {
+        match *self {
+            Listener::Tcp(ref listener, keepalive) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_keepalive(
+                    if keepalive > 0 { Some(Duration::from_secs(keepalive as u64)) }
+                    else { None }
+                )?;
+                Ok(Stream::Tcp(stream))
+            },
+            Listener::Unix(ref listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok(Stream::Unix(stream))
+            },
+        }
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener, _) => listener.set_nonblocking(true),
+            Listener::Unix(ref listener) => listener.set_nonblocking(true),
+        }
+    }
+
+    fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        let fd = match *self {
+            Listener::Tcp(ref listener, _) => listener.as_raw_fd(),
+            Listener::Unix(ref listener) => listener.as_raw_fd(),
+        };
+        poll.register(&EventedFd(&fd), token, Ready::readable(), PollOpt::level())
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        let fd = match *self {
+            Listener::Tcp(ref listener, _) => listener.as_raw_fd(),
+            Listener::Unix(ref listener) => listener.as_raw_fd(),
+        };
+        poll.deregister(&EventedFd(&fd))
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl PollConnection {
+    /// Reads all available messages and passes them to the current protocol.
+    /// Returns `false` if the connection should be closed.
+    fn read(&mut self, db: &DatabaseHolder, executor: &Executor, registry: &Registry, switch: &SwitchConfig)
+        -> bool
+    {
+        loop {
+            match self.reader.read(&mut self.stream) {
+                ReadFlow::Incomplete    => continue,
+                ReadFlow::WouldBlock    => return true,
+                ReadFlow::Error(err)    => {
+                    info!("Read error: {:?}", err);
+                    return false;
+                },
+                ReadFlow::Complete      => {
+                    let message = match self.reader.to_message() {
+                        Ok(message) => message,
+                        Err(err)    => {
+                            warn!("  ::  Dropped a message of connection #{}: {:?}", self.id, err);
+                            self.next_frame();
+                            continue;
+                        },
+                    };
+                    self.sender.traffic().add_read(self.reader.frame_size());
+                    if let Some(ref capture) = self.capture {
+                        capture.received(&self.reader.frame());
+                    }
+                    self.next_frame();
+                    let message = {
+                        let heartbeat = self.heartbeat.as_ref().map(|heartbeat| &**heartbeat);
+                        match handle_control(&self.sender, heartbeat, message) {
+                            Ok(Some(message)) => message,
+                            Ok(None) => continue,
+                            Err(ref err) if !err.is_fatal() => {
+                                warn!("  ::  Dropped a message of connection #{}: {}", self.id, err);
+                                continue;
+                            },
+                            Err(err) => {
+                                info!("Read error: {:?}", err);
+                                return false;
+                            },
+                        }
+                    };
+                    self.active_at = Instant::now();
+                    if !self.flow(message, db, executor, registry, switch) {
+                        return false;
+                    }
+                    // The message may have negotiated the checksum of the frames to come
+                    self.next_frame();
+                },
+            }
+        }
+    }
+
+    /// Starts reading the next frame, with the checksum negotiated so far
+    fn next_frame(&mut self) {
+        self.reader = Reader::new(self.sender.checksum().negotiated());
+    }
+
+    fn flow(&mut self, message: RawMessage, db: &DatabaseHolder, executor: &Executor, registry: &Registry,
+            switch: &SwitchConfig) -> bool
+    {
+        let workflow = match self.stage {
+            Stage::Auth(ref protocol) => match protocol.flow(message) {
+                Workflow::Continue => match protocol.take_check() {
+                    Some(check) => {
+                        let (tx, rx) = channel();
+                        let notify = self.notify.clone();
+                        let spawned = executor.spawn(move || {
+                            let _ = tx.send(check.run());
+                            notify();
+                        });
+                        match spawned {
+                            Ok(_) => {
+                                self.checking = Some(rx);
+                                Workflow::Continue
+                            },
+                            Err(_) => protocol.reject(
+                                format!("Server is busy, connection #{} can not be authenticated now", self.id)),
+                        }
+                    },
+                    None => Workflow::Continue,
+                },
+                workflow => workflow,
+            },
+            Stage::Subprotocol(_, ref protocol) => protocol.flow(message),
+        };
+        self.on_workflow(workflow, db, executor, registry, switch)
+    }
+
+    /// Completes the auth once the credentials of the client are checked.
+    /// Returns `false` if the connection should be closed.
+    fn check_credentials(&mut self, db: &DatabaseHolder, executor: &Executor, registry: &Registry,
+                         switch: &SwitchConfig) -> bool
+    {
+        let checked = match self.checking.as_ref().map(|rx| rx.try_recv()) {
+            None | Some(Err(TryRecvError::Empty)) => return true,
+            Some(Ok(checked)) => checked,
+            Some(Err(TryRecvError::Disconnected)) => {
+                warn!("  ::  Checking the credentials of connection #{} has failed", self.id);
+                return false;
+            },
+        };
+        self.checking = None;
+        let workflow = match self.stage {
+            Stage::Auth(ref protocol) => protocol.complete(checked),
+            Stage::Subprotocol(..) => return false,
+        };
+        self.on_workflow(workflow, db, executor, registry, switch)
+    }
+
+    fn on_workflow(&mut self, workflow: Workflow, db: &DatabaseHolder, executor: &Executor, registry: &Registry,
+                   switch: &SwitchConfig) -> bool
+    {
+        match workflow {
+            Workflow::Continue          => true,
+            Workflow::Terminate(m)      => {
+                info!("Terminated: {}", m);
+                self.terminated = true;
+                false
+            },
+            Workflow::SwitchProtocol(code) => {
+                let version = match self.stage {
+                    Stage::Auth(ref protocol) => match protocol.client_version() {
+                        Some(version) => version,
+                        None => return false,
+                    },
+                    Stage::Subprotocol(..) => self.client_version,
+                };
+                if let Stage::Auth(ref protocol) = self.stage {
+                    if version >= HEARTBEAT_VERSION {
+                        self.heartbeat = switch.heartbeat
+                            .map(|(interval, misses)| Arc::new(Mutex::new(Heartbeat::new(interval, misses))));
+                    }
+                    self.identity = protocol.identity();
+                    self.token = protocol.token();
+                    self.ticket = protocol.ticket();
+                }
+                self.client_version = version;
+                let permissions = switch.grants.granted(self.identity.as_ref().map(|s| &s[..]), self.token.as_ref());
+                info!("  ::  Connection #{} is allowed: {}", self.id, permissions);
+
+                let context = ConnectionContext {
+                    id: self.id,
+                    sender: self.sender.clone(),
+                    db: db.clone(),
+                    executor: executor.clone(),
+                    client_version: version,
+                    peer: self.peer.clone(),
+                    control: self.control.clone(),
+                    identity: self.identity.clone(),
+                    token: self.token.clone(),
+                    ticket: self.ticket.clone(),
+                    permissions: permissions,
+                };
+                match registry.create(code, context) {
+                    Some(protocol) => {
+                        info!("  ::  Switched to subprotocol {} ({})", code, registry.name(code).unwrap_or(""));
+                        self.control.set_tasks(self.id, protocol.tasks());
+                        self.stage = Stage::Subprotocol(code, protocol);
+                        true
+                    },
+                    None => {
+                        warn!("Switching to unknown subprotocol {}", code);
+                        false
+                    },
+                }
+            },
+        }
+    }
+
+    /// Checks the timers. Returns `false` if the connection has been idle for too long
+    /// or the client does not answer pings.
+    fn tick(&mut self, now: Instant, idle_timeout: Option<Duration>) -> bool {
+        if let Some(ref heartbeat) = self.heartbeat {
+            match heartbeat.lock().unwrap().poll(now) {
+                Ok(Some(ping)) => send_message(&self.sender, Some(Box::new(ping))),
+                Ok(None) => (),
+                Err(missed) => {
+                    info!("  ::  Connection #{} missed {} pongs", self.id, missed);
+                    return false;
+                },
+            }
+        }
+        if let Stage::Subprotocol(_, ref protocol) = self.stage {
+            protocol.on_tick();
+            if protocol.active_tasks() > 0 {
+                self.active_at = now;
+            }
+        }
+        !idle_timeout.map_or(false, |timeout| now.duration_since(self.active_at) >= timeout)
+    }
+
+    /// Frames the queued messages and writes as much as the socket accepts.
+    /// Returns `Ok(true)` if all output is written.
+    fn flush(&mut self) -> io::Result<bool> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(Some(message)) => {
+                    let start = self.out.len();
+                    match self.sender.checksum().write_frame(&*message, &mut self.out) {
+                        Ok(()) => if let Some(ref capture) = self.capture {
+                            capture.sent(&self.out[start..]);
+                        },
+                        Err(err) => warn!("Error encoding message {:?}: {:?}", message, err),
+                    }
+                },
+                Ok(None) => self.closing = true,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        while self.written < self.out.len() {
+            match self.stream.write(&self.out[self.written..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "Peer does not accept data")),
+                Ok(n) => {
+                    self.written += n;
+                    self.sender.traffic().add_written(n);
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.out.clear();
+        self.written = 0;
+        if self.out.capacity() > WRITE_BUFFER_LIMIT {
+            self.out.shrink_to_fit();
+        }
+        Ok(true)
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl EventLoop {
+    pub fn new(listeners: Vec<Listener>, db: DatabaseHolder, executor: Executor, registry: Arc<Registry>,
+               control: Arc<ServerControl>, next_id: Arc<Mutex<usize>>, stop_rx: Receiver<u8>)
+               -> io::Result<EventLoop>
+    {
+        let poll = Poll::new()?;
+
+        let (registration, set_readiness) = Registration::new2();
+        poll.register(&registration, WAKE, Ready::readable(), PollOpt::edge())?;
+
+        let (tick, idle_timeout, shutdown_timeout, switch) = {
+            let database = db.lock().unwrap();
+            let config = &database.config;
+            (config.tick(), match config.timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            }, Duration::from_secs(config.shutdown_timeout), SwitchConfig {
+                heartbeat: match config.heartbeat_interval {
+                    0 => None,
+                    secs => Some((Duration::from_secs(secs), config.heartbeat_misses)),
+                },
+                grants: config.grants(),
+            })
+        };
+
+        for (n, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking()?;
+            listener.register(&poll, Token(FIRST_LISTENER + n))?;
+        }
+
+        Ok(EventLoop {
+            poll: poll,
+            paused: listeners.iter().map(|_| None).collect(),
+            listeners: listeners,
+            connections: HashMap::new(),
+            woken: Arc::new(Mutex::new(Vec::new())),
+            _registration: registration,
+            set_readiness: set_readiness,
+            db: db,
+            executor: executor,
+            registry: registry,
+            switch: switch,
+            control: control,
+            next_id: next_id,
+            stop_rx: stop_rx,
+            tick: tick,
+            idle_timeout: idle_timeout,
+            ticked_at: Instant::now(),
+            stopping_since: None,
+            shutdown_timeout: shutdown_timeout,
+        })
+    }
+
+    /// Wakes up the loop to check for the stop signal
+    pub fn waker(&self) -> Notify {
+        let set_readiness = self.set_readiness.clone();
+        Arc::new(move || { let _ = set_readiness.set_readiness(Ready::readable()); })
+    }
+
+    /// Serves connections until a stop signal is received and the running tasks are finished
+    pub fn run(&mut self) {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        loop {
+            // Checked before polling, as the signal may come before the wakeup is registered
+            if self.stopping_since.is_none() && self.stop_rx.try_recv().is_ok() {
+                // any new message should stop
+                self.stop_listening();
+            }
+            if let Some(since) = self.stopping_since {
+                let active = self.control.active_tasks();
+                if active == 0 {
+                    break;
+                }
+                if since.elapsed() >= self.shutdown_timeout {
+                    warn!("Closing connections with {} tasks still running", active);
+                    break;
+                }
+            }
+
+            let mut timeout = match self.stopping_since {
+                Some(_) => Some(self.tick.map_or(Duration::from_millis(STOPPING_TICK_MS),
+                    |tick| ::std::cmp::min(tick, Duration::from_millis(STOPPING_TICK_MS)))),
+                None => self.tick,
+            };
+            if let Some(resume) = self.paused.iter().filter_map(|paused| *paused).min() {
+                let now = Instant::now();
+                let wait = if resume > now { resume.duration_since(now) } else { Duration::from_millis(0) };
+                timeout = Some(timeout.map_or(wait, |timeout| ::std::cmp::min(timeout, wait)));
+            }
+            if let Err(err) = self.poll.poll(&mut events, timeout) {
+                if err.kind() == ErrorKind::Interrupted { continue; }
+                error!("Polling failed: {:?}", err);
+                break;
+            }
+
+            for event in events.iter() {
+                let token = event.token().0;
+                let readiness = event.readiness();
+                if event.token() == WAKE {
+                    self.wake();
+                } else if token < FIRST_CONNECTION {
+                    self.accept(token - FIRST_LISTENER);
+                } else {
+                    let id = token - FIRST_CONNECTION;
+                    if readiness.is_readable() {
+                        self.read(id);
+                    }
+                    if readiness.is_writable() {
+                        self.write(id);
+                    }
+                }
+            }
+
+            if let Some(tick) = self.tick {
+                if self.ticked_at.elapsed() >= tick {
+                    self.check_timers();
+                }
+            }
+            self.resume_listeners();
+        }
+
+        let ids = self.connections.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            self.close(id);
+        }
+    }
+
+    /// Stops accepting connections, the connected clients are served until their tasks are finished
+    fn stop_listening(&mut self) {
+        info!("Stopped accepting connections");
+        self.stopping_since = Some(Instant::now());
+        for (listener, paused) in self.listeners.iter().zip(self.paused.iter_mut()) {
+            // The paused ones are deregistered already
+            if paused.take().is_none() {
+                if let Err(err) = listener.deregister(&self.poll) {
+                    warn!("Deregistering listener: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Stops polling a listener which fails to accept, as it would be reported ready over and over
+    fn pause_listener(&mut self, n: usize) {
+        if let Err(err) = self.listeners[n].deregister(&self.poll) {
+            warn!("Deregistering listener: {:?}", err);
+            return;
+        }
+        self.paused[n] = Some(Instant::now() + Duration::from_millis(ACCEPT_BACKOFF_MS));
+    }
+
+    /// Polls the paused listeners again once their backoff is over
+    fn resume_listeners(&mut self) {
+        let now = Instant::now();
+        for n in 0..self.listeners.len() {
+            if self.paused[n].map_or(true, |resume| resume > now) {
+                continue;
+            }
+            self.paused[n] = None;
+            if let Err(err) = self.listeners[n].register(&self.poll, Token(FIRST_LISTENER + n)) {
+                error!("Registering listener again: {:?}", err);
+            }
+        }
+    }
+
+    fn accept(&mut self, n: usize) {
+        if self.stopping_since.is_some() {
+            return;
+        }
+        loop {
+            let stream = match self.listeners[n].accept() {
+                Ok(stream) => stream,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+                // Only that connection is lost
+                Err(ref err) if err.kind() == ErrorKind::ConnectionAborted => continue,
+                Err(err) => {
+                    warn!("Accepting client connection: {:?}, pausing for {} ms", err, ACCEPT_BACKOFF_MS);
+                    self.pause_listener(n);
+                    return;
+                },
+            };
+            info!("Accepted connection to {:?}", stream);
+            if let Err(err) = self.add_connection(stream) {
+                warn!("Registering client connection: {:?}", err);
+            }
+        }
+    }
+
+    fn add_connection(&mut self, mut stream: Stream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+
+        let id = {
+            let mut nid = self.next_id.lock().unwrap();
+            *nid += 1;
+            *nid - 1
+        };
+        if refuse_unadmitted(&self.control, id, &mut stream) {
+            return Ok(());
+        }
+
+        self.poll.register(&EventedFd(&stream.as_raw_fd()), Token(FIRST_CONNECTION + id),
+            Ready::readable(), PollOpt::level())?;
+
+        let notify: Notify = {
+            let woken = self.woken.clone();
+            let set_readiness = self.set_readiness.clone();
+            Arc::new(move || {
+                woken.lock().unwrap().push(id);
+                let _ = set_readiness.set_readiness(Ready::readable());
+            })
+        };
+        let (tx, rx) = channel::<StreamMessage>();
+        let sender = StreamSender::with_notify(tx, notify.clone());
+        let (mut auth, capture) = {
+            let db = self.db.lock().unwrap();
+            (AuthConfig::from_config(&db, &self.registry, &self.control, id, &stream), db.config.capture(id))
+        };
+        auth.deferred = true;
+
+        let peer = Peer::of(&stream);
+        self.control.add_connection(id, peer.clone(), sender.traffic().clone(), stream.try_clone().ok());
+
+        info!(">>::  New connection #{} from {}. Starting auth", id, peer);
+        let connection = PollConnection {
+            id: id,
+            peer: peer,
+            stream: stream,
+            reader: Reader::new(sender.checksum().negotiated()),
+            stage: Stage::Auth(AuthProtocol::with_config(sender.clone(), id, auth)),
+            sender: sender,
+            rx: rx,
+            out: Vec::new(),
+            written: 0,
+            writable: false,
+            closing: false,
+            active_at: Instant::now(),
+            heartbeat: None,
+            capture: capture,
+            client_version: PROTOCOL_VERSION,
+            identity: None,
+            token: None,
+            ticket: None,
+            terminated: false,
+            notify: notify,
+            checking: None,
+            control: self.control.clone(),
+        };
+        self.connections.insert(id, connection);
+        Ok(())
+    }
+
+    fn check_timers(&mut self) {
+        let now = Instant::now();
+        self.ticked_at = now;
+        let idle_timeout = self.idle_timeout;
+        let idle = self.connections.iter_mut()
+            .filter_map(|(&id, connection)| match connection.tick(now, idle_timeout) {
+                true => None,
+                false => Some(id),
+            })
+            .collect::<Vec<_>>();
+        for id in idle {
+            info!("  ::  Closing connection #{} on timeout", id);
+            self.close(id);
+        }
+    }
+
+    fn wake(&mut self) {
+        // Reset before draining, so that a later notification produces a new event
+        let _ = self.set_readiness.set_readiness(Ready::empty());
+        let mut ids = mem::replace(&mut *self.woken.lock().unwrap(), Vec::new());
+        ids.sort();
+        ids.dedup();
+        for id in ids {
+            // Woken either by queued messages or by the check of its credentials
+            let keep = match self.connections.get_mut(&id) {
+                Some(connection) =>
+                    connection.check_credentials(&self.db, &self.executor, &self.registry, &self.switch),
+                None => continue,
+            };
+            if keep {
+                self.write(id);
+            } else {
+                self.close(id);
+            }
+        }
+    }
+
+    fn read(&mut self, id: usize) {
+        let keep = match self.connections.get_mut(&id) {
+            Some(connection) => connection.read(&self.db, &self.executor, &self.registry, &self.switch),
+            None => return,
+        };
+        if keep {
+            self.write(id);
+        } else {
+            self.close(id);
+        }
+    }
+
+    fn write(&mut self, id: usize) {
+        let result = match self.connections.get_mut(&id) {
+            Some(connection) => {
+                let result = connection.flush();
+                if let Ok(flushed) = result {
+                    if flushed == connection.writable {
+                        // Listen for writability only while there is pending output
+                        let interest = if flushed { Ready::readable() } else { Ready::readable() | Ready::writable() };
+                        connection.writable = !flushed;
+                        let fd = connection.stream.as_raw_fd();
+                        if let Err(err) = self.poll.reregister(&EventedFd(&fd), Token(FIRST_CONNECTION + id),
+                                                               interest, PollOpt::level()) {
+                            warn!("Registering client connection: {:?}", err);
+                        }
+                    }
+                }
+                result.map(|flushed| !(flushed && connection.closing))
+            },
+            None => return,
+        };
+        match result {
+            Ok(true) => (),
+            Ok(false) => self.close(id),
+            Err(err) => {
+                warn!("Error writing to client: {:?}", err);
+                self.close(id);
+            },
+        }
+    }
+
+    fn close(&mut self, id: usize) {
+        if let Some(mut connection) = self.connections.remove(&id) {
+            // The answers queued before closing are still delivered, as far as the socket accepts them
+            let _ = connection.flush();
+            if let Stage::Subprotocol(_, ref protocol) = connection.stage {
+                if !connection.terminated {
+                    protocol.suspend();
+                }
+            }
+            self.control.remove_connection(id);
+            let _ = self.poll.deregister(&EventedFd(&connection.stream.as_raw_fd()));
+            let _ = connection.stream.shutdown();
+            info!("<<::  Hangup #{}", connection.id);
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use unix_socket::{UnixListener, UnixStream};
+    use uuid::Uuid;
+
+    use protocol::checksum::Negotiated;
+    use protocol::message::decode_frame;
+    use protocol::stream::Stream;
+    use protocol::workflow::WorkflowError;
+
+    use ::client::connection::Connection;
+    use ::proto::auth::client::{AuthConfig, AuthError, AuthProtocol};
+    use ::proto::auth::message::ServerMessage;
+    use ::server::admission::Admission;
+    use ::server::config::Config;
+    use ::server::control::ServerControl;
+    use ::server::credentials::{Authenticator, Credentials, PasswordHash, Secret};
+    use ::server::pool::Executor;
+    use ::server::registry::Registry;
+    use ::testing;
+
+    use super::{EventLoop, Listener};
+
+    /// An event loop serving a Unix socket, with the path of the socket
+    fn event_loop(config: Config, control: Arc<ServerControl>) -> (EventLoop, PathBuf) {
+        let path = env::temp_dir().join(format!("ifs-reactor-{}.sock", Uuid::new_v4()));
+        let listener = Listener::Unix(UnixListener::bind(&path).unwrap());
+        let (tx, rx) = channel();
+        let event_loop = EventLoop::new(vec![listener], testing::holder(config), Executor::Thread,
+            Arc::new(Registry::new()), control.clone(), Arc::new(Mutex::new(0)), rx).unwrap();
+        control.add_listener(tx, Some(event_loop.waker()));
+        (event_loop, path)
+    }
+
+    #[test]
+    fn shutdown_wakes_up_loop() {
+        let control = Arc::new(ServerControl::new());
+        let (mut event_loop, path) = event_loop(Config::new(), control.clone());
+
+        // No timers are enabled and nobody connects, so only the wakeup ends the poll
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            control.shutdown(vec![], None)
+        });
+        event_loop.run();
+        assert!(stopper.join().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn idle_connection_is_closed() {
+        let mut config = Config::new();
+        config.timeout = 1;
+        let control = Arc::new(ServerControl::new());
+        let (mut event_loop, path) = event_loop(config, control.clone());
+        let server = thread::spawn(move || event_loop.run());
+
+        let started = Instant::now();
+        let mut client = UnixStream::connect(&path).unwrap();
+        // The client sends nothing, so the server hangs up once the timeout is over
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).unwrap(), 0);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(control.connections().is_empty());
+
+        assert!(control.shutdown(vec![], None));
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn password_checked_by_executor() {
+        let mut credentials = Credentials::default();
+        credentials.add("alice".to_owned(), Secret::Password(PasswordHash::with_salt("secret", 2, b"salt".to_vec())));
+        let control = Arc::new(ServerControl::with_authenticator(Authenticator::new(Some(credentials))));
+        let (mut event_loop, path) = event_loop(Config::new(), control.clone());
+        let server = thread::spawn(move || event_loop.run());
+
+        let login = |password: &str| {
+            let mut config = AuthConfig::new();
+            config.login = Some(("alice".to_owned(), password.to_owned()));
+            let connection = Connection::new(Stream::Unix(UnixStream::connect(&path).unwrap()));
+            AuthProtocol::with_config(connection, config).auth()
+        };
+        assert!(login("secret").is_ok());
+        match login("wrong") {
+            Err(AuthError::WorkflowError(WorkflowError::ProtocolError(reason))) =>
+                assert!(reason.contains("Invalid login or password"), "{}", reason),
+            r => panic!("Unexpected auth result {:?}", r),
+        }
+
+        assert!(control.shutdown(vec![], None));
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connection_over_limit_is_refused_on_accept() {
+        let mut config = Config::new();
+        config.max_connections = 1;
+        let control = Arc::new(ServerControl::new().with_admission(Admission::from_config(&config).unwrap()));
+        let (mut event_loop, path) = event_loop(config, control.clone());
+        let server = thread::spawn(move || event_loop.run());
+
+        let _first = UnixStream::connect(&path).unwrap();
+        while control.connections().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The second client is refused without sending anything
+        let mut second = UnixStream::connect(&path).unwrap();
+        let mut buf = Vec::new();
+        second.read_to_end(&mut buf).unwrap();
+        match ServerMessage::parse(decode_frame(&buf, Negotiated::Agreed(None)).unwrap()).unwrap() {
+            ServerMessage::Reject(m) => assert!(m.reason.contains("Too many connections"), "{}", m.reason),
+            m => panic!("Unexpected message {:?}", m),
+        }
+        assert_eq!(control.connections().len(), 1);
+
+        assert!(control.shutdown(vec![], None));
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! Subprotocols served after the auth stage.
+//!
+//! The client requests a subprotocol by its code on start, and a subprotocol may switch
+//! the connection to another one with `Workflow::SwitchProtocol(code)`.
+//! Each time, the registry builds the requested protocol from the connection context.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use protocol::workflow::{Protocol, ProtocolVersion};
+
+use ::connection::StreamSender;
+use ::proto::admin;
+use ::proto::admin::server::AdminProtocol;
+use ::proto::auth::message::SessionTicket;
+use ::proto::content;
+use ::proto::content::server::ContentProtocol;
+
+use super::control::{Peer, ServerControl, TaskSet};
+use super::database::DatabaseHolder;
+use super::permissions::Permissions;
+use super::pool::Executor;
+use super::tokens::Claims;
+
+
+/// A subprotocol on the server side of a connection
+pub trait ServerProtocol: Protocol {
+    /// The number of tasks in progress. A connection with running tasks is not idle.
+    fn active_tasks(&self) -> usize {
+        0
+    }
+
+    /// Called on each timer tick, if timers are enabled
+    fn on_tick(&self) {}
+
+    /// The tasks to list and cancel from the admin subprotocol
+    fn tasks(&self) -> Option<Arc<TaskSet>> {
+        None
+    }
+
+    /// Called when the connection is lost rather than closed, so that the client may resume the session
+    fn suspend(&self) {}
+}
+
+
+/// What a subprotocol may know about its connection
+#[derive(Clone, Debug)]
+pub struct ConnectionContext {
+    pub id: usize,
+    pub sender: StreamSender,
+    pub db: DatabaseHolder,
+    /// Runs the blocking jobs of tasks
+    pub executor: Executor,
+    pub client_version: ProtocolVersion,
+    pub peer: Peer,
+    pub control: Arc<ServerControl>,
+    /// Given by the auth stage, if the client has authenticated
+    pub identity: Option<String>,
+    /// The claims of the token the client has authenticated with, if any
+    pub token: Option<Claims>,
+    /// Given to the client by the auth stage, to resume the session on another connection
+    pub ticket: Option<SessionTicket>,
+    pub permissions: Permissions,
+}
+
+
+pub type Factory = Box<Fn(ConnectionContext) -> Box<ServerProtocol> + Send + Sync>;
+
+
+/// Subprotocols by their codes
+pub struct Registry {
+    factories: HashMap<usize, (&'static str, Factory)>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Registry {
+    /// Creates a registry with the built-in subprotocols
+    pub fn new() -> Registry {
+        let mut registry = Registry::empty();
+        registry.register(content::message::SUBPROTOCOL_CODE, "content", |context| {
+            Box::new(ContentProtocol::new(context))
+        });
+        registry.register(admin::message::SUBPROTOCOL_CODE, "admin", |context| {
+            Box::new(AdminProtocol::new(context))
+        });
+        registry
+    }
+
+    pub fn empty() -> Registry {
+        Registry { factories: HashMap::new() }
+    }
+
+    /// Registers a subprotocol, replacing the one registered with the same code
+    pub fn register<F>(&mut self, code: u8, name: &'static str, factory: F)
+        where F: Fn(ConnectionContext) -> Box<ServerProtocol> + Send + Sync + 'static
+    {
+        if self.factories.insert(code as usize, (name, Box::new(factory))).is_some() {
+            warn!("Subprotocol {} replaces a previously registered one with code {}", name, code);
+        }
+    }
+
+    pub fn contains(&self, code: usize) -> bool {
+        self.factories.contains_key(&code)
+    }
+
+    /// The codes of all registered subprotocols
+    pub fn codes(&self) -> Vec<u8> {
+        let mut codes = self.factories.keys().map(|&code| code as u8).collect::<Vec<_>>();
+        codes.sort();
+        codes
+    }
+
+    pub fn name(&self, code: usize) -> Option<&'static str> {
+        self.factories.get(&code).map(|&(name, _)| name)
+    }
+
+    /// Builds the subprotocol for the connection, if it is registered
+    pub fn create(&self, code: usize, context: ConnectionContext) -> Option<Box<ServerProtocol>> {
+        self.factories.get(&code).map(|&(_, ref factory)| factory(context))
+    }
+}
+
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = self.codes().into_iter()
+            .map(|code| format!("{}: {}", code, self.factories[&(code as usize)].0))
+            .collect::<Vec<_>>();
+        write!(f, "Registry {{ {} }}", names.join(", "))
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use protocol::message::{RawMessage, RawMessageBody};
+    use protocol::workflow::{Protocol, Workflow};
+
+    use ::server::config::Config;
+    use ::testing;
+
+    use super::{ConnectionContext, Registry, ServerProtocol};
+
+    /// Counts the messages and switches to the next subprotocol on the third one
+    struct Counter {
+        seen: Cell<usize>,
+        next: usize,
+    }
+
+    impl Protocol for Counter {
+        fn flow(&self, _: RawMessage) -> Workflow {
+            self.seen.set(self.seen.get() + 1);
+            match self.seen.get() {
+                3 => Workflow::SwitchProtocol(self.next),
+                _ => Workflow::Continue,
+            }
+        }
+    }
+
+    impl ServerProtocol for Counter {
+        fn active_tasks(&self) -> usize {
+            self.seen.get()
+        }
+    }
+
+    fn context(id: usize) -> ConnectionContext {
+        testing::context(id, testing::holder(Config::new())).0
+    }
+
+    #[test]
+    fn register_and_create() {
+        let mut registry = Registry::empty();
+        assert!(registry.create(7, context(1)).is_none());
+
+        registry.register(7, "counter", |context| Box::new(Counter { seen: Cell::new(0), next: context.id }));
+        assert!(registry.contains(7));
+        assert!(!registry.contains(7 + 256));
+        assert_eq!(registry.codes(), vec![7]);
+        assert_eq!(registry.name(7), Some("counter"));
+
+        let protocol = registry.create(7, context(5)).unwrap();
+        let message = || RawMessage::new(1, RawMessageBody::Binary(vec![]));
+        assert!(match protocol.flow(message()) { Workflow::Continue => true, _ => false });
+        assert!(match protocol.flow(message()) { Workflow::Continue => true, _ => false });
+        assert!(match protocol.flow(message()) { Workflow::SwitchProtocol(5) => true, _ => false });
+        assert_eq!(protocol.active_tasks(), 3);
+    }
+
+    #[test]
+    fn builtin_subprotocols() {
+        let registry = Registry::new();
+        assert!(registry.contains(::proto::content::message::SUBPROTOCOL_CODE as usize));
+        assert!(registry.contains(::proto::admin::message::SUBPROTOCOL_CODE as usize));
+        assert!(!registry.contains(::proto::auth::message::SUBPROTOCOL_CODE as usize));
+    }
+}
@@ -0,0 +1,282 @@
+//! Tokens signed by the server, which grant an identity some of its permissions until they expire.
+//!
+//! A token is `<claims>.<mac>` in hex, the claims being encoded like the fields of a message and the MAC
+//! being a keyed BLAKE2b of them with the token key of the server. The token key file holds the key in hex.
+//! Tokens are bearer secrets, so they are only accepted over TLS or the Unix socket. A token may be revoked
+//! by its id before it expires; the revoked ids are kept in a file with a line per id, if it is configured.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use blake2_rfc::blake2b::Blake2b;
+use rand::{OsRng, Rng};
+
+use protocol::serde::{EncodeTo, Parse, Parser};
+
+use ::proto::auth::mac;
+use super::config::Config;
+use super::credentials::{from_hex, to_hex};
+use super::permissions::Permissions;
+
+
+const ID_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+const MIN_KEY_SIZE: usize = 16;
+const MAX_KEY_SIZE: usize = 64;
+
+const LABEL: &'static [u8] = b"irbis-fs token";
+
+
+/// What a token grants
+#[derive(Debug, Clone, PartialEq, Encode, Parse)]
+pub struct Claims {
+    /// Random, names the token to revoke it
+    pub id: String,
+    pub identity: String,
+    /// Only these of the permissions of the identity are granted
+    pub scopes: Permissions,
+    /// The time since the UNIX epoch, in seconds
+    pub expires: u64,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    /// Not signed with the key of the server, or changed since
+    BadSignature,
+    Expired,
+    Revoked,
+}
+
+
+/// Mints and checks tokens
+#[derive(Debug)]
+pub struct TokenAuthority {
+    key: Vec<u8>,
+    revoked: Mutex<HashSet<String>>,
+    /// The file of the revoked ids, if they are kept over restarts
+    revocations: Option<String>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Claims {
+    /// Claims with a random id, expiring in `ttl`
+    pub fn new(identity: String, scopes: Permissions, ttl: Duration) -> io::Result<Claims> {
+        let mut id = vec![0u8; ID_SIZE];
+        OsRng::new()?.fill_bytes(&mut id);
+        Ok(Claims {
+            id: to_hex(&id),
+            identity: identity,
+            scopes: scopes,
+            expires: now() + ttl.as_secs(),
+        })
+    }
+}
+
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenError::Malformed       => write!(f, "Malformed token"),
+            TokenError::BadSignature    => write!(f, "Invalid token signature"),
+            TokenError::Expired         => write!(f, "Token has expired"),
+            TokenError::Revoked         => write!(f, "Token has been revoked"),
+        }
+    }
+}
+
+
+impl TokenAuthority {
+    pub fn new(key: Vec<u8>) -> TokenAuthority {
+        TokenAuthority { key: key, revoked: Mutex::new(HashSet::new()), revocations: None }
+    }
+
+    /// Loads the token key and the revoked ids of the config, if tokens are enabled
+    pub fn from_config(config: &Config) -> io::Result<Option<TokenAuthority>> {
+        let path = match config.token_key {
+            Some(ref path) => path,
+            None => return Ok(None),
+        };
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        let key = match from_hex(content.trim()) {
+            Some(key) if key.len() >= MIN_KEY_SIZE && key.len() <= MAX_KEY_SIZE => key,
+            _ => return Err(invalid_data(format!("{}: expected a key of {} to {} bytes in hex",
+                path, MIN_KEY_SIZE, MAX_KEY_SIZE))),
+        };
+        let mut tokens = TokenAuthority::new(key);
+        if let Some(ref path) = config.token_revocations {
+            tokens = tokens.with_revocations(path)?;
+        }
+        Ok(Some(tokens))
+    }
+
+    /// Keeps the revoked ids in the file, loading the ones revoked before
+    pub fn with_revocations(mut self, path: &str) -> io::Result<TokenAuthority> {
+        let mut content = String::new();
+        match File::open(path) {
+            Ok(mut file) => { file.read_to_string(&mut content)?; },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        {
+            let mut revoked = self.revoked.lock().unwrap();
+            revoked.extend(content.lines().map(|line| line.trim()).filter(|id| !id.is_empty()).map(String::from));
+        }
+        self.revocations = Some(path.to_owned());
+        Ok(self)
+    }
+
+    pub fn mint(&self, claims: &Claims) -> String {
+        let mut data = Vec::new();
+        claims.encode_to(&mut data).unwrap();
+        format!("{}.{}", to_hex(&data), to_hex(&self.mac(&data)))
+    }
+
+    /// Returns the claims of a valid token
+    pub fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        self.verify_at(token, now())
+    }
+
+    fn verify_at(&self, token: &str, now: u64) -> Result<Claims, TokenError> {
+        let mut parts = token.trim().splitn(2, '.');
+        let (data, token_mac) = match (parts.next().and_then(from_hex), parts.next().and_then(from_hex)) {
+            (Some(data), Some(token_mac)) => (data, token_mac),
+            _ => return Err(TokenError::Malformed),
+        };
+        if !mac::verify(&self.mac(&data), &token_mac) {
+            return Err(TokenError::BadSignature);
+        }
+        // Only the server encodes the claims, so they are parsed once the signature is checked
+        let mut parser = Parser::new(data);
+        let claims = Claims::parse_from(&mut parser).map_err(|_| TokenError::Malformed)?;
+        parser.complete().map_err(|_| TokenError::Malformed)?;
+        if claims.expires <= now {
+            return Err(TokenError::Expired);
+        }
+        if self.revoked.lock().unwrap().contains(&claims.id) {
+            return Err(TokenError::Revoked);
+        }
+        Ok(claims)
+    }
+
+    /// Revokes the token with the id; returns `false` if it has been revoked already
+    pub fn revoke(&self, id: &str) -> io::Result<bool> {
+        let mut revoked = self.revoked.lock().unwrap();
+        if revoked.contains(id) {
+            return Ok(false);
+        }
+        if let Some(ref path) = self.revocations {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", id)?;
+        }
+        revoked.insert(id.to_owned());
+        Ok(true)
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let mut state = Blake2b::with_key(MAC_SIZE, &self.key);
+        state.update(LABEL);
+        state.update(data);
+        state.finalize().as_bytes().to_vec()
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+    use std::fs;
+    use std::time::Duration;
+
+    use ::server::permissions::{Permission, Permissions};
+    use ::testing::temp_dir;
+    use super::{Claims, TokenAuthority, TokenError};
+
+    fn claims() -> Claims {
+        Claims::new("ci".to_owned(), Permissions::of(&[Permission::Import]), Duration::from_secs(60)).unwrap()
+    }
+
+    #[test]
+    fn mint_and_verify() {
+        let tokens = TokenAuthority::new(vec![1; 32]);
+        let claims = claims();
+        let token = tokens.mint(&claims);
+        assert_eq!(tokens.verify(&token), Ok(claims.clone()));
+
+        // Each token has its own id
+        assert!(Claims::new("ci".to_owned(), Permissions::none(), Duration::from_secs(1)).unwrap().id != claims.id);
+    }
+
+    #[test]
+    fn forged_tokens() {
+        let tokens = TokenAuthority::new(vec![1; 32]);
+        let token = tokens.mint(&claims());
+        assert_eq!(TokenAuthority::new(vec![2; 32]).verify(&token), Err(TokenError::BadSignature));
+
+        // Claims changed to grant everything
+        let mut forged = claims();
+        forged.scopes = Permissions::all();
+        let forged = TokenAuthority::new(vec![2; 32]).mint(&forged);
+        let (data, _) = forged.split_at(forged.find('.').unwrap());
+        let (_, mac) = token.split_at(token.find('.').unwrap());
+        assert_eq!(tokens.verify(&format!("{}{}", data, mac)), Err(TokenError::BadSignature));
+
+        assert_eq!(tokens.verify("garbage"), Err(TokenError::Malformed));
+        assert_eq!(tokens.verify("00.zz"), Err(TokenError::Malformed));
+        assert_eq!(tokens.verify(""), Err(TokenError::Malformed));
+    }
+
+    #[test]
+    fn expired_token() {
+        let tokens = TokenAuthority::new(vec![1; 32]);
+        let claims = claims();
+        let token = tokens.mint(&claims);
+        assert!(tokens.verify_at(&token, claims.expires - 1).is_ok());
+        assert_eq!(tokens.verify_at(&token, claims.expires), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn revoked_token() {
+        let dir = temp_dir("revoked");
+        let path = dir.join("revoked").to_string_lossy().into_owned();
+        let tokens = TokenAuthority::new(vec![1; 32]).with_revocations(&path).unwrap();
+        let (first, second) = (claims(), claims());
+        let token = tokens.mint(&first);
+        assert!(tokens.revoke(&first.id).unwrap());
+        assert!(!tokens.revoke(&first.id).unwrap());
+        assert_eq!(tokens.verify(&token), Err(TokenError::Revoked));
+        assert!(tokens.verify(&tokens.mint(&second)).is_ok());
+
+        // Kept over restarts
+        let tokens = TokenAuthority::new(vec![1; 32]).with_revocations(&path).unwrap();
+        assert_eq!(tokens.verify(&token), Err(TokenError::Revoked));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+//! Fixtures shared by the tests of the server and of the subprotocols.
+
+#![cfg_attr(feature = "clippy", allow(result_unwrap_used))]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use std::sync::mpsc::{Receiver, channel};
+
+use libc;
+use uuid::Uuid;
+
+use protocol::checksum::Negotiated;
+use protocol::message::{RawMessage, WriteFrame, decode_frame};
+
+use ::connection::{StreamMessage, StreamSender};
+use ::proto::PROTOCOL_VERSION;
+use ::server::config::Config;
+use ::server::control::{Peer, ServerControl};
+use ::server::database::{Database, DatabaseHolder};
+use ::server::permissions::Permissions;
+use ::server::pool::Executor;
+use ::server::registry::ConnectionContext;
+
+
+/// A database serving the config, without checking its directories
+pub fn database(config: Config) -> Database {
+    Database { config: config, version: "0.0.0", rustc_version: "", run_id: Uuid::new_v4() }
+}
+
+
+pub fn holder(config: Config) -> DatabaseHolder {
+    Arc::new(Mutex::new(database(config)))
+}
+
+
+/// The context of a current client connected to the Unix socket and allowed everything,
+/// with the receiver of the messages sent to it
+pub fn context(id: usize, db: DatabaseHolder) -> (ConnectionContext, Receiver<StreamMessage>) {
+    let (tx, rx) = channel();
+    let context = ConnectionContext {
+        id: id,
+        sender: StreamSender::new(tx),
+        db: db,
+        executor: Executor::Thread,
+        client_version: PROTOCOL_VERSION,
+        peer: Peer::Unix(None),
+        control: Arc::new(ServerControl::new()),
+        identity: None,
+        token: None,
+        ticket: None,
+        permissions: Permissions::all(),
+    };
+    (context, rx)
+}
+
+
+/// Decodes a message queued for a client without a frame checksum
+pub fn decode(message: StreamMessage) -> RawMessage {
+    let mut buf = Vec::new();
+    message.expect("A message, not the end of the queue").write_frame(&mut buf).unwrap();
+    decode_frame(&buf, Negotiated::Agreed(None)).unwrap()
+}
+
+
+/// Waits for the next message queued for the client
+pub fn answer(rx: &Receiver<StreamMessage>) -> RawMessage {
+    decode(rx.recv().unwrap())
+}
+
+
+/// Creates a new directory for the files of a test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ifs-{}-{}", name, Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+
+/// The path of a TLS certificate or key, generated once for each test run by `testdata/tls/gen.sh` of the protocol
+pub fn tls_data(name: &str) -> String {
+    static GENERATE: Once = ONCE_INIT;
+    let dir = env::temp_dir().join(format!("ifs-fs-tls-{}", unsafe { libc::getpid() }));
+    GENERATE.call_once(|| {
+        fs::create_dir_all(&dir).unwrap();
+        let script = format!("{}/../protocol/testdata/tls/gen.sh", env!("CARGO_MANIFEST_DIR"));
+        let status = Command::new("sh").arg(&script).arg(&dir)
+            .stdout(Stdio::null()).stderr(Stdio::null())
+            .status().unwrap();
+        assert!(status.success(), "Generating the TLS test data with {} has failed", script);
+    });
+    dir.join(name).to_string_lossy().into_owned()
+}
@@ -1,6 +1,8 @@
 use std::fmt;
+use std::io;
+use std::io::Write;
 
-use protocol::serde::{Encode, Parse, Parser, ParserError};
+use protocol::serde::{EncodeTo, Parse, Parser, ParserError};
 
 
 pub type TaskId = u64;
@@ -19,15 +21,15 @@ impl ContentId {
     }
 }
 
-impl Encode for ContentId {
-    fn encode(self) -> Vec<u8> {
-        self.0.to_vec()
+impl EncodeTo for ContentId {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.0)
     }
 }
 
 impl Parse for ContentId {
     fn parse_from(parser: &mut Parser) -> Result<ContentId, ParserError> {
-        Ok(ContentId::from_slice(parser.next(64)))
+        Ok(ContentId::from_slice(try!(parser.next(64))))
     }
 }
 
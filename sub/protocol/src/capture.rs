@@ -0,0 +1,250 @@
+//! Wire captures: the frames of a connection recorded to a file for debugging.
+//!
+//! A capture starts with `CAPTURE_MAGIC` and holds a record for each frame:
+//! the direction, the time since the UNIX epoch as seconds (u64) and nanoseconds (u32),
+//! the length of the frame (u32) and the frame itself, exactly as it was written to the stream.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ::checksum::Negotiated;
+use ::message::{RawMessage, ReadError, decode_frame};
+use ::serde::{decode_u32, decode_u64, encode_u32, encode_u64};
+
+
+pub const CAPTURE_MAGIC: &'static [u8; 8] = b"IFSCAP\x00\x01";
+
+const RECORD_HEADER_SIZE: usize = 17;
+
+
+/// Which side of the connection has sent a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    FromClient,
+    FromServer,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+    /// The time since the UNIX epoch
+    pub time: Duration,
+    pub frame: Vec<u8>,
+}
+
+
+/// Records the frames of one side of a connection. It is shared by the reader and the writer.
+#[derive(Debug)]
+pub struct Capture {
+    /// The direction of the frames sent by the recording side
+    outgoing: Direction,
+    file: Mutex<BufWriter<File>>,
+}
+
+
+/// Reads the records of a capture
+pub struct CaptureReader<R: Read> {
+    input: R,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Direction {
+    fn code(&self) -> u8 {
+        match *self {
+            Direction::FromClient => 0,
+            Direction::FromServer => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Direction> {
+        match code {
+            0 => Some(Direction::FromClient),
+            1 => Some(Direction::FromServer),
+            _ => None,
+        }
+    }
+}
+
+
+impl Record {
+    /// Decodes the recorded frame. The checksum is verified, whichever the frame carries,
+    /// as the frames are recorded from before the negotiation on
+    pub fn to_message(&self) -> Result<RawMessage, ReadError> {
+        decode_frame(&self.frame, Negotiated::Any)
+    }
+}
+
+
+impl Capture {
+    /// Creates the capture file of the server side of a connection
+    pub fn server<P: AsRef<Path>>(path: P) -> io::Result<Capture> {
+        Self::create(path, Direction::FromServer)
+    }
+
+    /// Creates the capture file of the client side of a connection
+    pub fn client<P: AsRef<Path>>(path: P) -> io::Result<Capture> {
+        Self::create(path, Direction::FromClient)
+    }
+
+    fn create<P: AsRef<Path>>(path: P, outgoing: Direction) -> io::Result<Capture> {
+        let mut file = BufWriter::new(try!(File::create(path)));
+        try!(file.write_all(CAPTURE_MAGIC));
+        try!(file.flush());
+        Ok(Capture { outgoing: outgoing, file: Mutex::new(file) })
+    }
+
+    /// Records a frame written to the stream
+    pub fn sent(&self, frame: &[u8]) {
+        self.record(self.outgoing, frame)
+    }
+
+    /// Records a frame read from the stream
+    pub fn received(&self, frame: &[u8]) {
+        let direction = match self.outgoing {
+            Direction::FromClient => Direction::FromServer,
+            Direction::FromServer => Direction::FromClient,
+        };
+        self.record(direction, frame)
+    }
+
+    /// Appends a record. The capture is flushed after each record, so that it survives a crash.
+    /// Failures are only logged: a capture must not break the connection.
+    fn record(&self, direction: Direction, frame: &[u8]) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::new(0, 0));
+        let mut header = Vec::with_capacity(RECORD_HEADER_SIZE);
+        header.push(direction.code());
+        header.extend_from_slice(&encode_u64(time.as_secs()));
+        header.extend_from_slice(&encode_u32(time.subsec_nanos()));
+        header.extend_from_slice(&encode_u32(frame.len() as u32));
+
+        if let Err(err) = Self::write(&mut *self.file.lock().unwrap(), &header, frame) {
+            warn!("Writing capture: {:?}", err);
+        }
+    }
+
+    fn write(file: &mut BufWriter<File>, header: &[u8], frame: &[u8]) -> io::Result<()> {
+        try!(file.write_all(header));
+        try!(file.write_all(frame));
+        file.flush()
+    }
+}
+
+
+impl<R: Read> CaptureReader<R> {
+    /// Checks the capture signature
+    pub fn new(mut input: R) -> io::Result<CaptureReader<R>> {
+        let mut magic = [0u8; 8];
+        try!(input.read_exact(&mut magic));
+        if &magic != CAPTURE_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not a capture file"));
+        }
+        Ok(CaptureReader { input: input })
+    }
+
+    /// Reads the next record, `None` at the end of the capture
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        let mut position = 0;
+        while position < RECORD_HEADER_SIZE {
+            match try!(self.input.read(&mut header[position..])) {
+                0 if position == 0 => return Ok(None),
+                0 => return Err(io::Error::new(ErrorKind::UnexpectedEof, "Truncated capture record")),
+                n => position += n,
+            }
+        }
+
+        let direction = match Direction::from_code(header[0]) {
+            Some(direction) => direction,
+            None => return Err(io::Error::new(ErrorKind::InvalidData, "Invalid capture record direction")),
+        };
+        let time = Duration::new(decode_u64(&header[1..9]), decode_u32(&header[9..13]));
+        let mut frame = vec![0u8; decode_u32(&header[13..17]) as usize];
+        try!(self.input.read_exact(&mut frame));
+        Ok(Some(Record { direction: direction, time: time, frame: frame }))
+    }
+}
+
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        match self.next_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Cursor;
+
+    use libc;
+
+    use ::checksum::{Checksum, seal_frame};
+    use ::message::{RawMessageBody, frame_into};
+
+    use super::{CAPTURE_MAGIC, Capture, CaptureReader, Direction};
+
+    #[test]
+    fn record_and_read() {
+        let dir = env::temp_dir().join(format!("ifs-capture-{}", unsafe { libc::getpid() }));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.ifscap");
+        let mut ping = Vec::new();
+        frame_into(&mut ping, 1, &7u64).unwrap();
+        let mut pong = Vec::new();
+        frame_into(&mut pong, 2, &7u64).unwrap();
+        seal_frame(&mut pong, 0, Checksum::Crc32c);
+        {
+            let capture = Capture::server(&path).unwrap();
+            capture.received(&ping);
+            capture.sent(&pong);
+        }
+
+        let records = CaptureReader::new(File::open(&path).unwrap()).unwrap()
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        let _ = ::std::fs::remove_dir_all(&dir);
+
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].direction, &records[0].frame), (Direction::FromClient, &ping));
+        assert_eq!((records[1].direction, &records[1].frame), (Direction::FromServer, &pong));
+        assert!(records[0].time <= records[1].time);
+        let message = records[1].to_message().unwrap();
+        assert_eq!(message.mtype, 2);
+        match message.body {
+            RawMessageBody::Binary(body) => assert_eq!(body, vec![0, 0, 0, 0, 0, 0, 0, 7]),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+
+    #[test]
+    fn invalid_captures() {
+        assert!(CaptureReader::new(Cursor::new(b"IFSCAP".to_vec())).is_err());
+        assert!(CaptureReader::new(Cursor::new(b"NOTACAPT".to_vec())).is_err());
+
+        let mut empty = CaptureReader::new(Cursor::new(CAPTURE_MAGIC.to_vec())).unwrap();
+        assert!(empty.next().is_none());
+
+        let mut truncated = CAPTURE_MAGIC.to_vec();
+        truncated.extend_from_slice(&[0, 0, 0]);
+        let mut reader = CaptureReader::new(Cursor::new(truncated)).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Liveness checks with ping and pong messages.
+//!
+//! The message types are reserved in every subprotocol, so the messages may be exchanged at any stage
+//! and are handled by connections before the messages reach a `Protocol`.
+//! A peer answers each ping with a pong carrying the same sequence number.
+
+use std::time::{Duration, Instant};
+
+use ::message::{EncodeError, RawMessage, RawMessageBody, WriteFrame, frame_into};
+use ::serde::{ParserError, decode_u64};
+
+
+pub const MT_PING: u8 = 250;
+pub const MT_PONG: u8 = 251;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Ping(u64),
+    Pong(u64),
+}
+
+
+/// Tracks the pings sent to a peer and the pongs received
+#[derive(Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    max_missed: u64,
+    next_ping: Instant,
+    sent: u64,
+    answered: u64,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl Control {
+    /// Recognizes a heartbeat message. Returns `None` for messages of the subprotocol.
+    pub fn parse(message: &RawMessage) -> Option<Result<Control, ParserError>> {
+        let control: fn(u64) -> Control = match message.mtype {
+            MT_PING => Control::Ping,
+            MT_PONG => Control::Pong,
+            _ => return None,
+        };
+        Some(match message.body {
+            RawMessageBody::Binary(ref v) if v.len() == 8 => Ok(control(decode_u64(v))),
+            RawMessageBody::Binary(ref v) if v.len() < 8 => Err(ParserError::Overflow),
+            RawMessageBody::Binary(_) => Err(ParserError::Incomplete),
+            _ => Err(ParserError::InvalidValue),
+        })
+    }
+
+    /// The answer to the message, if any
+    pub fn reply(&self) -> Option<Control> {
+        match *self {
+            Control::Ping(seq) => Some(Control::Pong(seq)),
+            Control::Pong(_) => None,
+        }
+    }
+}
+
+
+impl WriteFrame for Control {
+    fn write_frame(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match *self {
+            Control::Ping(ref seq) => frame_into(buf, MT_PING, seq),
+            Control::Pong(ref seq) => frame_into(buf, MT_PONG, seq),
+        }
+    }
+}
+
+
+impl Heartbeat {
+    /// Pings the peer each `interval`; the peer is considered dead
+    /// when `max_missed` pings are left without an answer by the time of the next one.
+    pub fn new(interval: Duration, max_missed: u32) -> Heartbeat {
+        Heartbeat {
+            interval: interval,
+            max_missed: max_missed as u64,
+            next_ping: Instant::now() + interval,
+            sent: 0,
+            answered: 0,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The time left until the next ping is due
+    pub fn timeout(&self, now: Instant) -> Duration {
+        if self.next_ping > now { self.next_ping - now } else { Duration::new(0, 0) }
+    }
+
+    /// The number of pings without an answer
+    pub fn missed(&self) -> u64 {
+        self.sent - self.answered
+    }
+
+    /// Returns a ping to send if it is due, or the number of missed pongs if the peer is dead
+    pub fn poll(&mut self, now: Instant) -> Result<Option<Control>, u64> {
+        if now < self.next_ping {
+            return Ok(None);
+        }
+        if self.missed() >= self.max_missed {
+            return Err(self.missed());
+        }
+        self.sent += 1;
+        self.next_ping = now + self.interval;
+        Ok(Some(Control::Ping(self.sent)))
+    }
+
+    /// Registers an answer. Pongs for unknown or already answered pings are ignored.
+    pub fn on_pong(&mut self, seq: u64) {
+        if seq > self.answered && seq <= self.sent {
+            self.answered = seq;
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ::message::{RawMessage, RawMessageBody, WriteFrame};
+    use ::serde::ParserError;
+
+    use super::{Control, Heartbeat, MT_PING};
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn ping_when_due() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(secs(10), 3);
+        assert_eq!(heartbeat.poll(start), Ok(None));
+        assert_eq!(heartbeat.poll(start + secs(11)), Ok(Some(Control::Ping(1))));
+        assert_eq!(heartbeat.poll(start + secs(12)), Ok(None));
+        assert_eq!(heartbeat.poll(start + secs(21)), Ok(Some(Control::Ping(2))));
+        assert_eq!(heartbeat.timeout(start + secs(25)), secs(6));
+    }
+
+    #[test]
+    fn pong_keeps_alive() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(secs(1), 2);
+        for n in 1..10 {
+            match heartbeat.poll(start + secs(n + 1)) {
+                Ok(Some(Control::Ping(seq))) => heartbeat.on_pong(seq),
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+        assert_eq!(heartbeat.missed(), 0);
+    }
+
+    #[test]
+    fn dead_after_missed_pongs() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(secs(1), 2);
+        assert!(heartbeat.poll(start + secs(2)).unwrap().is_some());
+        assert!(heartbeat.poll(start + secs(4)).unwrap().is_some());
+        assert_eq!(heartbeat.poll(start + secs(6)), Err(2));
+        // A late answer revives the peer
+        heartbeat.on_pong(2);
+        assert_eq!(heartbeat.poll(start + secs(6)), Ok(Some(Control::Ping(3))));
+    }
+
+    #[test]
+    fn unknown_pong_ignored() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(secs(1), 1);
+        heartbeat.on_pong(5);
+        assert!(heartbeat.poll(start + secs(2)).unwrap().is_some());
+        heartbeat.on_pong(7);
+        assert_eq!(heartbeat.missed(), 1);
+    }
+
+    #[test]
+    fn roundtrip() {
+        for control in &[Control::Ping(1), Control::Pong(u64::max_value())] {
+            let mut buf = Vec::new();
+            control.write_frame(&mut buf).unwrap();
+            let message = RawMessage::new(buf[2], RawMessageBody::Binary(buf[8..].to_vec()));
+            assert_eq!(Control::parse(&message).unwrap().unwrap(), *control);
+        }
+        assert_eq!(Control::Ping(3).reply(), Some(Control::Pong(3)));
+        assert_eq!(Control::Pong(3).reply(), None);
+    }
+
+    #[test]
+    fn not_a_heartbeat() {
+        assert!(Control::parse(&RawMessage::new(1, RawMessageBody::Binary(vec![0; 8]))).is_none());
+        match Control::parse(&RawMessage::new(MT_PING, RawMessageBody::Binary(vec![0; 3]))) {
+            Some(Err(ParserError::Overflow)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
@@ -5,12 +5,21 @@
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 #![cfg_attr(feature = "clippy", allow(items_after_statements))]
 
+extern crate blake2_rfc;
+#[cfg(unix)] extern crate libc;
 #[macro_use] extern crate log;
 extern crate net2;
+extern crate rustls;
 #[cfg(unix)] extern crate unix_socket;
 
+#[cfg(test)] #[macro_use] extern crate quickcheck;
 
+
+pub mod capture;
+pub mod checksum;
+pub mod heartbeat;
 pub mod message;
 pub mod serde;
 pub mod stream;
+pub mod tls;
 pub mod workflow;
@@ -1,10 +1,11 @@
 use std::cmp::{max, min};
 use std::error::Error;
 use std::fmt;
-use std::io::Read;
-use std::mem::transmute;
+use std::io::{ErrorKind, Read};
 use std::str::{from_utf8, Utf8Error};
 
+use ::checksum::{Checksum, Negotiated, verify_frame};
+use ::serde::{EncodeTo, decode_u32, encode_u32};
 use ::stream::Stream;
 
 
@@ -40,6 +41,8 @@ pub enum ReadFlow {
     Complete,
     /// The received buffer is valid but needs more data
     Incomplete,
+    /// No data is available now: the stream is non-blocking or a read timeout has expired
+    WouldBlock,
     Error(ReadError),
 }
 
@@ -47,12 +50,14 @@ pub enum ReadFlow {
 /// Error parsing
 #[derive(Debug, PartialEq)]
 pub enum ReadError {
-//    /// Nonfatal error, drop task if started
-//    NonFatal(String),
+    /// Nonfatal error: the frame is read, but its message is invalid and is dropped
+    NonFatal(String),
     /// Fatal error, drop connection and all started tasks
     Fatal(String),
     /// Lost connection, drop all started tasks
     ConnectionError(String),
+    /// The frame does not match its checksum, or does not carry the negotiated one; the connection is not reliable
+    ChecksumMismatch(Checksum),
 }
 
 
@@ -75,22 +80,30 @@ pub struct Message {
 }
 
 
+/// An outgoing message, which frames itself into a writer's buffer
+pub trait WriteFrame: Send + fmt::Debug {
+    /// Appends the header and the body of the message to the buffer
+    fn write_frame(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError>;
+}
+
+
 // --------------------------------------------------------------------------------------------------------------------
 
 
 impl ReadError {
-//    pub fn is_fatal(&self) -> bool {
-//        match *self {
-//            ReadError::NonFatal => false,
-//            _ => true,
-//        }
-//    }
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            ReadError::NonFatal(_) => false,
+            _ => true,
+        }
+    }
 
     fn response_string(&self) -> String {
         match *self {
-//            ReadError::NonFatal(ref s) => format!("NonFatal protocol error: {}", s),
+            ReadError::NonFatal(ref s) => format!("NonFatal protocol error: {}", s),
             ReadError::Fatal(ref s) => format!("Fatal protocol error: {}", s),
             ReadError::ConnectionError(ref s) => format!("Connection error: {}", s),
+            ReadError::ChecksumMismatch(checksum) => format!("Frame checksum mismatch ({:?})", checksum),
         }
     }
 }
@@ -106,9 +119,10 @@ impl fmt::Display for ReadError {
 impl Error for ReadError {
     fn description(&self) -> &str {
         match *self {
-//            ReadError::NonFatal(_) => "Nonfatal protocol error",
+            ReadError::NonFatal(_) => "Nonfatal protocol error",
             ReadError::Fatal(_) => "Protocol error",
             ReadError::ConnectionError(_) => "Connection error",
+            ReadError::ChecksumMismatch(_) => "Frame checksum mismatch",
         }
     }
 
@@ -122,18 +136,24 @@ impl Error for ReadError {
 /// A stream reader
 pub struct Reader {
     header: Vec<u8>,
+    /// The body followed by the checksum trailer, if any
     body: Vec<u8>,
     position: usize,
     size: Option<usize>,
+    /// The checksums accepted on the frame
+    negotiated: Negotiated,
+    checksum: Option<Checksum>,
 }
 
 impl Reader {
-    pub fn new() -> Reader {
+    pub fn new(negotiated: Negotiated) -> Reader {
         Reader {
             header: vec![0; HEADER_SIZE],
             body: [0; 0].to_vec(),
             position: 0,
-            size: None
+            size: None,
+            negotiated: negotiated,
+            checksum: None,
         }
     }
 
@@ -144,6 +164,9 @@ impl Reader {
             Some(size)  => &mut self.body[self.position .. size],
         }) {
             Ok(r) => r,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
+                return ReadFlow::WouldBlock;
+            },
             Err(err) => {
                 return ReadFlow::Error(ReadError::ConnectionError(format!("Reading from client: {:?}", err)));
             },
@@ -152,7 +175,7 @@ impl Reader {
 
         if len == 0 {
             return match self.is_complete() {
-                true    => ReadFlow::Complete,
+                true    => self.verify(),
                 false   => ReadFlow::Error(ReadError::ConnectionError("Peer closed connection".to_owned())),
             };
         }
@@ -163,17 +186,41 @@ impl Reader {
             if size > BODY_SIZE_LIMIT {
                 return ReadFlow::Error(ReadError::Fatal(format!("message size ({}) exceed limit 16 MB", size)))
             }
+            self.checksum = match frame_checksum(self.header[1], self.negotiated) {
+                Ok(checksum) => checksum,
+                Err(err) => return ReadFlow::Error(err),
+            };
+            let size = size + self.checksum.map_or(0, |checksum| checksum.size());
             self.size = Some(size);
             self.position = 0;
             self.body = vec![0; size];
         }
 
         match self.is_complete() {
-            true    => ReadFlow::Complete,
+            true    => self.verify(),
             false   => ReadFlow::Incomplete,
         }
     }
 
+    /// Checks the trailer of the complete frame
+    fn verify(&self) -> ReadFlow {
+        match self.checksum {
+            Some(checksum) => {
+                let (body, trailer) = self.body.split_at(self.body_size());
+                match verify_frame(checksum, &self.header, body, trailer) {
+                    true    => ReadFlow::Complete,
+                    false   => ReadFlow::Error(ReadError::ChecksumMismatch(checksum)),
+                }
+            },
+            None => ReadFlow::Complete,
+        }
+    }
+
+    /// The size of the body without the trailer
+    fn body_size(&self) -> usize {
+        self.body.len() - self.checksum.map_or(0, |checksum| checksum.size())
+    }
+
     pub fn is_complete(&self) -> bool {
         match self.size {
             None        => false,
@@ -182,29 +229,86 @@ impl Reader {
     }
 
     pub fn to_message(&self) -> Result<RawMessage, ParseError> {
-        let (mtype, body_type) = (self.header[2], self.header[3]);
-
-        let body: RawMessageBody = match body_type {
-            BT_BINARY => RawMessageBody::Binary(self.body.clone()),
-            BT_TEXT => RawMessageBody::Text(
-                match from_utf8(&self.body[..]) {
-                    Ok(r) => r.to_owned(),
-                    Err(err) => return Err(ParseError::Utf8(err)),
-                }
-            ),
-            BT_JSON => RawMessageBody::JSON(
-                match from_utf8(&self.body[..]) {
-                    Ok(r) => r.to_owned(),
-                    Err(err) => return Err(ParseError::Utf8(err)),
-                }
-            ),
-            _ => return Err(ParseError::UnknownContentType),
-        };
-        Ok(RawMessage::new(mtype, body))
+        raw_message(self.header[2], self.header[3], &self.body[..self.body_size()])
+    }
+
+    /// The size of the frame read so far, including the header and the checksum trailer
+    pub fn frame_size(&self) -> usize {
+        match self.size {
+            None        => self.position,
+            Some(_)     => HEADER_SIZE + self.position,
+        }
+    }
+
+    /// The complete frame as it was received, including the checksum trailer
+    pub fn frame(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_SIZE + self.body.len());
+        frame.extend_from_slice(&self.header);
+        frame.extend_from_slice(&self.body);
+        frame
     }
 }
 
 
+fn raw_message(mtype: u8, body_type: u8, body: &[u8]) -> Result<RawMessage, ParseError> {
+    let body: RawMessageBody = match body_type {
+        BT_BINARY => RawMessageBody::Binary(body.to_vec()),
+        BT_TEXT => RawMessageBody::Text(
+            match from_utf8(body) {
+                Ok(r) => r.to_owned(),
+                Err(err) => return Err(ParseError::Utf8(err)),
+            }
+        ),
+        BT_JSON => RawMessageBody::JSON(
+            match from_utf8(body) {
+                Ok(r) => r.to_owned(),
+                Err(err) => return Err(ParseError::Utf8(err)),
+            }
+        ),
+        _ => return Err(ParseError::UnknownContentType),
+    };
+    Ok(RawMessage::new(mtype, body))
+}
+
+
+/// Parses the checksum code of a frame header and checks it against the negotiated checksum.
+/// A mismatch reports the expected checksum, or the one of the frame if none is expected.
+fn frame_checksum(code: u8, negotiated: Negotiated) -> Result<Option<Checksum>, ReadError> {
+    let checksum = match code {
+        0 => None,
+        code => match Checksum::from_code(code) {
+            Some(checksum) => Some(checksum),
+            None => return Err(ReadError::Fatal(format!("Unknown frame checksum {}", code))),
+        },
+    };
+    match (negotiated.accepts(checksum), negotiated.expected().or(checksum)) {
+        (false, Some(reported)) => Err(ReadError::ChecksumMismatch(reported)),
+        _ => Ok(checksum),
+    }
+}
+
+
+/// Decodes a complete frame, as written to a stream, verifying its checksum against the negotiated one
+pub fn decode_frame(frame: &[u8], negotiated: Negotiated) -> Result<RawMessage, ReadError> {
+    if frame.len() < HEADER_SIZE {
+        return Err(ReadError::Fatal(format!("Truncated frame header ({} bytes)", frame.len())));
+    }
+    let (header, rest) = frame.split_at(HEADER_SIZE);
+    let size = decode_u32(&header[4..8]) as usize;
+    let checksum = try!(frame_checksum(header[1], negotiated));
+    if rest.len() != size + checksum.map_or(0, |checksum| checksum.size()) {
+        return Err(ReadError::Fatal(format!("Frame of {} bytes does not match its size {}", frame.len(), size)));
+    }
+    let (body, trailer) = rest.split_at(size);
+    if let Some(checksum) = checksum {
+        if !verify_frame(checksum, header, body, trailer) {
+            return Err(ReadError::ChecksumMismatch(checksum));
+        }
+    }
+    raw_message(header[2], header[3], body).map_err(|err| ReadError::Fatal(format!("Parse error: {:?}", err)))
+}
+
+
 impl fmt::Debug for Reader {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         try!(f.write_str(&format!("Reader({} => {}); header: {:?}; body last 16 bytes: {:?}",
@@ -237,20 +341,44 @@ impl RawMessage {
 // --------------------------------------------------------------------------------------------------------------------
 
 
+/// Writes a message header for a body of `size` bytes
+fn write_header(buf: &mut Vec<u8>, mtype: u8, body_type: u8, size: usize) -> Result<(), EncodeError> {
+    if size > BODY_SIZE_LIMIT {
+        return Err(EncodeError::TooLong);
+    };
+    buf.extend_from_slice(&[0, 0, mtype, body_type]);
+    buf.extend_from_slice(&encode_u32(size as u32));
+    Ok(())
+}
+
+
+/// Frames a binary message into the buffer: the body is encoded in place after a reserved header,
+/// and the header is filled once the size of the body is known.
+pub fn frame_into<E: EncodeTo>(buf: &mut Vec<u8>, mtype: u8, body: &E) -> Result<(), EncodeError> {
+    let start = buf.len();
+    buf.extend_from_slice(&[0u8; HEADER_SIZE]);
+    // Writing into a vector never fails
+    body.encode_to(buf).unwrap();
+
+    let size = buf.len() - start - HEADER_SIZE;
+    if size > BODY_SIZE_LIMIT {
+        buf.truncate(start);
+        return Err(EncodeError::TooLong);
+    };
+    buf[start + 2] = mtype;
+    buf[start + 3] = BT_BINARY;
+    buf[start + 4 .. start + HEADER_SIZE].copy_from_slice(&encode_u32(size as u32));
+    Ok(())
+}
+
+
 impl Message {
     pub fn from_raw(raw_message: RawMessage) -> Result<Message, EncodeError> {
         match raw_message.body {
             RawMessageBody::Binary(v) => {
-                if v.len() > BODY_SIZE_LIMIT {
-                    return Err(EncodeError::TooLong);
-                };
-                let size: [u8; 4] = unsafe { transmute((v.len() as u32).to_be()) };
-                let data: Vec<u8> = [
-                    // header
-                    &[0, 0, raw_message.mtype, BT_BINARY][..], &size[..],
-                    // body
-                    &v[..]
-                ].concat();
+                let mut data = Vec::with_capacity(HEADER_SIZE + v.len());
+                try!(write_header(&mut data, raw_message.mtype, BT_BINARY, v.len()));
+                data.extend_from_slice(&v);
                 Ok(Message{data: data})
             },
             RawMessageBody::Text(_) => { unimplemented!() },
@@ -262,4 +390,150 @@ impl Message {
     pub fn as_bytes(&self) -> &Vec<u8> {
         &self.data
     }
+
+    /// Restores the raw message from the framed data
+    pub fn to_raw(&self) -> Result<RawMessage, ParseError> {
+        let body = &self.data[HEADER_SIZE..];
+        let body = match self.data[3] {
+            BT_BINARY => RawMessageBody::Binary(body.to_vec()),
+            BT_TEXT => RawMessageBody::Text(try!(from_utf8(body).map_err(ParseError::Utf8)).to_owned()),
+            BT_JSON => RawMessageBody::JSON(try!(from_utf8(body).map_err(ParseError::Utf8)).to_owned()),
+            _ => return Err(ParseError::UnknownContentType),
+        };
+        Ok(RawMessage::new(self.data[2], body))
+    }
+}
+
+
+impl WriteFrame for Message {
+    fn write_frame(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        buf.extend_from_slice(&self.data);
+        Ok(())
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::Write;
+
+    use unix_socket::UnixStream;
+
+    use ::checksum::{Checksum, Negotiated, seal_frame};
+    use ::stream::Stream;
+
+    use super::{RawMessageBody, ReadError, ReadFlow, Reader, decode_frame, frame_into};
+
+    /// Sends the frame and reads it back
+    fn transfer(frame: &[u8], negotiated: Negotiated) -> Result<Vec<u8>, ReadError> {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        a.write_all(frame).unwrap();
+        drop(a);
+
+        let mut stream = Stream::Unix(b);
+        let mut reader = Reader::new(negotiated);
+        loop {
+            match reader.read(&mut stream) {
+                ReadFlow::Complete => match reader.to_message().unwrap().body {
+                    RawMessageBody::Binary(body) => {
+                        assert_eq!(reader.frame(), frame);
+                        return Ok(body)
+                    },
+                    body => panic!("Unexpected body {:?}", body),
+                },
+                ReadFlow::Incomplete => continue,
+                ReadFlow::WouldBlock => panic!("Blocking socket would block"),
+                ReadFlow::Error(err) => return Err(err),
+            }
+        }
+    }
+
+    fn frame(checksum: Option<Checksum>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame_into(&mut buf, 7, &0x0102030405060708u64).unwrap();
+        if let Some(checksum) = checksum {
+            seal_frame(&mut buf, 0, checksum);
+        }
+        buf
+    }
+
+    #[test]
+    fn without_checksum() {
+        assert_eq!(transfer(&frame(None), Negotiated::Agreed(None)), Ok(vec![1, 2, 3, 4, 5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn trailer_stripped() {
+        for &checksum in &[Checksum::Crc32c, Checksum::Blake2b] {
+            let negotiated = Negotiated::Agreed(Some(checksum));
+            assert_eq!(transfer(&frame(Some(checksum)), negotiated), Ok(vec![1, 2, 3, 4, 5, 6, 7, 8]));
+        }
+    }
+
+    #[test]
+    fn corrupted_frame() {
+        for &checksum in &[Checksum::Crc32c, Checksum::Blake2b] {
+            let negotiated = Negotiated::Agreed(Some(checksum));
+            let mut buf = frame(Some(checksum));
+            buf[10] ^= 0x80;
+            assert_eq!(transfer(&buf, negotiated), Err(ReadError::ChecksumMismatch(checksum)));
+
+            let mut buf = frame(Some(checksum));
+            let last = buf.len() - 1;
+            buf[last] ^= 0x01;
+            assert_eq!(transfer(&buf, negotiated), Err(ReadError::ChecksumMismatch(checksum)));
+        }
+    }
+
+    #[test]
+    fn unnegotiated_checksum() {
+        let crc = Negotiated::Agreed(Some(Checksum::Crc32c));
+        // A frame without the agreed checksum, or with another one, is not trusted
+        assert_eq!(transfer(&frame(None), crc), Err(ReadError::ChecksumMismatch(Checksum::Crc32c)));
+        assert_eq!(transfer(&frame(Some(Checksum::Blake2b)), crc), Err(ReadError::ChecksumMismatch(Checksum::Crc32c)));
+        // Nor is a checksum nobody has agreed on
+        let blake = frame(Some(Checksum::Blake2b));
+        assert_eq!(transfer(&blake, Negotiated::Agreed(None)), Err(ReadError::ChecksumMismatch(Checksum::Blake2b)));
+
+        // The answer to a request may come either way, but with the requested checksum only
+        let requested = Negotiated::Requested(Checksum::Crc32c);
+        assert!(transfer(&frame(None), requested).is_ok());
+        assert!(transfer(&frame(Some(Checksum::Crc32c)), requested).is_ok());
+        assert!(transfer(&frame(Some(Checksum::Blake2b)), requested).is_err());
+    }
+
+    #[test]
+    fn unknown_checksum() {
+        let mut buf = frame(None);
+        buf[1] = 0x7f;
+        match transfer(&buf, Negotiated::Any) {
+            Err(ReadError::Fatal(_)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_frames() {
+        for checksum in &[None, Some(Checksum::Crc32c), Some(Checksum::Blake2b)] {
+            let buf = frame(*checksum);
+            let negotiated = Negotiated::Agreed(*checksum);
+            let message = decode_frame(&buf, negotiated).unwrap();
+            assert_eq!(message.mtype, 7);
+            match message.body {
+                RawMessageBody::Binary(body) => assert_eq!(body, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+                body => panic!("Unexpected body {:?}", body),
+            }
+            assert!(decode_frame(&buf, Negotiated::Any).is_ok());
+            assert!(decode_frame(&buf[..buf.len() - 1], negotiated).is_err());
+            assert!(decode_frame(&buf[..5], negotiated).is_err());
+        }
+        let negotiated = Negotiated::Agreed(Some(Checksum::Crc32c));
+        let mut buf = frame(Some(Checksum::Crc32c));
+        buf[9] ^= 0x01;
+        assert_eq!(decode_frame(&buf, negotiated).unwrap_err(), ReadError::ChecksumMismatch(Checksum::Crc32c));
+        assert_eq!(decode_frame(&frame(None), negotiated).unwrap_err(), ReadError::ChecksumMismatch(Checksum::Crc32c));
+    }
 }
@@ -1,5 +1,9 @@
 
 use std::cell::Cell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::io::Write;
 use std::mem::transmute;
 use std::mem::size_of_val;
 use std::ops::AddAssign;
@@ -17,9 +21,18 @@ pub enum ParserError {
     // Not all data was parsed
     Incomplete,
     Overflow,
+    /// Unknown tag of an enum variant
+    UnknownTag(u8),
+    /// The value is out of the domain of the type
+    InvalidValue,
     Utf8Error(Utf8Error),
 }
 
+/// A raw byte blob, encoded as a length-prefixed sequence of bytes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
+
 #[derive(Debug)]
 pub struct Parser {
     data: Vec<u8>,
@@ -52,19 +65,100 @@ pub trait Encode {
 }
 
 
-impl <E: Encode> AddAssign<E> for Encoder {
+/// Encoding by reference straight into a writer, without intermediate buffers
+pub trait EncodeTo {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+}
+
+
+impl <T: EncodeTo> Encode for T {
+    fn encode(self) -> Vec<u8> {
+        let mut data = Vec::new();
+        // Writing into a vector never fails
+        self.encode_to(&mut data).unwrap();
+        data
+    }
+}
+
+
+impl <E: EncodeTo> AddAssign<E> for Encoder {
     fn add_assign(&mut self, v: E) {
-        self.extend_from_vec(v.encode());
+        v.encode_to(&mut self.data).unwrap();
+    }
+}
+
+
+impl EncodeTo for u8 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u8(*self)) } }
+impl EncodeTo for u16 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u16(*self)) } }
+impl EncodeTo for u32 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u32(*self)) } }
+impl EncodeTo for u64 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u64(*self)) } }
+impl EncodeTo for usize { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_usize(*self)) } }
+
+impl EncodeTo for i8 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u8(*self as u8)) } }
+impl EncodeTo for i16 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u16(*self as u16)) } }
+impl EncodeTo for i32 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u32(*self as u32)) } }
+impl EncodeTo for i64 { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&encode_u64(*self as u64)) } }
+
+impl EncodeTo for bool { fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> { w.write_all(&[*self as u8]) } }
+
+
+impl EncodeTo for str {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write_len(w, self.len()));
+        w.write_all(self.as_bytes())
+    }
+}
+
+
+impl EncodeTo for String {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.as_str().encode_to(w)
     }
 }
 
 
-impl Encode for u8 { fn encode(self) -> Vec<u8> { encode_u8(self.clone()).to_vec() } }
-impl Encode for u16 { fn encode(self) -> Vec<u8> { encode_u16(self.clone()).to_vec() } }
-impl Encode for u32 { fn encode(self) -> Vec<u8> { encode_u32(self.clone()).to_vec() } }
-impl Encode for u64 { fn encode(self) -> Vec<u8> { encode_u64(self.clone()).to_vec() } }
-impl Encode for usize { fn encode(self) -> Vec<u8> { encode_usize(self.clone()).to_vec() } }
-impl Encode for String { fn encode(self) -> Vec<u8> { encode_str(&self) } }
+impl EncodeTo for Bytes {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write_len(w, self.0.len()));
+        w.write_all(&self.0)
+    }
+}
+
+
+impl <T: EncodeTo> EncodeTo for Option<T> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            None => w.write_all(&[0]),
+            Some(ref v) => {
+                try!(w.write_all(&[1]));
+                v.encode_to(w)
+            },
+        }
+    }
+}
+
+
+impl <T: EncodeTo> EncodeTo for Vec<T> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write_len(w, self.len()));
+        for v in self {
+            try!(v.encode_to(w));
+        }
+        Ok(())
+    }
+}
+
+
+impl <K: EncodeTo + Eq + Hash, V: EncodeTo> EncodeTo for HashMap<K, V> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write_len(w, self.len()));
+        for (k, v) in self {
+            try!(k.encode_to(w));
+            try!(v.encode_to(w));
+        }
+        Ok(())
+    }
+}
 
 
 // --------------------------------------------------------------------------------------------------------------------
@@ -102,9 +196,34 @@ impl Parser {
         }
     }
 
-    pub fn next(&mut self, n: usize) -> &[u8] {
+    /// Takes the next `n` bytes; fails if the data is shorter
+    pub fn next(&mut self, n: usize) -> Result<&[u8], ParserError> {
+        if n > self.remaining() {
+            return Err(ParserError::Overflow);
+        }
         self.position += n;
-        &self.data[(self.position - n) .. self.position]
+        Ok(&self.data[(self.position - n) .. self.position])
+    }
+
+    /// Returns the number of bytes left to parse
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.position)
+    }
+
+    /// Parses a length prefix encoded with `encode_len`
+    pub fn parse_len(&mut self) -> Result<usize, ParserError> {
+        let remaining = self.remaining();
+        if remaining == 0 || (self.data[self.position] & 0b_1000_0000 != 0 && remaining < 4) {
+            return Err(ParserError::Overflow);
+        }
+        let (size, len) = decode_len(&self.data[self.position..]);
+        self.position += size;
+        Ok(len)
+    }
+
+    /// Returns `true` if there is no data left to parse
+    pub fn is_complete(&self) -> bool {
+        self.position >= self.data.len()
     }
 
     pub fn complete(self) -> Result<(), ParserError> {
@@ -167,21 +286,106 @@ pub trait Parse: Sized {
 }
 
 
-impl Parse for u8 { fn parse_from(parser: &mut Parser) -> Result<u8, ParserError> {Ok(decode_u8(parser.next(1)))} }
-impl Parse for u16 { fn parse_from(parser: &mut Parser) -> Result<u16, ParserError> {Ok(decode_u16(parser.next(2)))} }
-impl Parse for u32 { fn parse_from(parser: &mut Parser) -> Result<u32, ParserError> {Ok(decode_u32(parser.next(4)))} }
-impl Parse for u64 { fn parse_from(parser: &mut Parser) -> Result<u64, ParserError> {Ok(decode_u64(parser.next(8)))} }
-impl Parse for usize { fn parse_from(parser: &mut Parser) -> Result<usize, ParserError> {Ok(decode_usize(parser.next(8)))} }
+impl Parse for u8 {
+    fn parse_from(parser: &mut Parser) -> Result<u8, ParserError> { Ok(decode_u8(try!(parser.next(1)))) }
+}
+impl Parse for u16 {
+    fn parse_from(parser: &mut Parser) -> Result<u16, ParserError> { Ok(decode_u16(try!(parser.next(2)))) }
+}
+impl Parse for u32 {
+    fn parse_from(parser: &mut Parser) -> Result<u32, ParserError> { Ok(decode_u32(try!(parser.next(4)))) }
+}
+impl Parse for u64 {
+    fn parse_from(parser: &mut Parser) -> Result<u64, ParserError> { Ok(decode_u64(try!(parser.next(8)))) }
+}
+impl Parse for usize {
+    fn parse_from(parser: &mut Parser) -> Result<usize, ParserError> { Ok(decode_usize(try!(parser.next(8)))) }
+}
 
 impl Parse for String {
     fn parse_from(parser: &mut Parser) -> Result<String, ParserError> {
-        let (size, v) = try!(decode_str(&parser.data[parser.position..]));
-        parser.position += size;
+        let len = try!(parser.parse_len());
+        let v = try!(parser.next(len));
+        Ok(try!(from_utf8(v)).to_owned())
+    }
+}
+
+impl Parse for i8 {
+    fn parse_from(parser: &mut Parser) -> Result<i8, ParserError> { Ok(decode_u8(try!(parser.next(1))) as i8) }
+}
+impl Parse for i16 {
+    fn parse_from(parser: &mut Parser) -> Result<i16, ParserError> { Ok(decode_u16(try!(parser.next(2))) as i16) }
+}
+impl Parse for i32 {
+    fn parse_from(parser: &mut Parser) -> Result<i32, ParserError> { Ok(decode_u32(try!(parser.next(4))) as i32) }
+}
+impl Parse for i64 {
+    fn parse_from(parser: &mut Parser) -> Result<i64, ParserError> { Ok(decode_u64(try!(parser.next(8))) as i64) }
+}
+
+impl Parse for bool {
+    fn parse_from(parser: &mut Parser) -> Result<bool, ParserError> {
+        match decode_u8(try!(parser.next(1))) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ParserError::InvalidValue),
+        }
+    }
+}
+
+
+impl Parse for Bytes {
+    fn parse_from(parser: &mut Parser) -> Result<Bytes, ParserError> {
+        let len = try!(parser.parse_len());
+        Ok(Bytes(try!(parser.next(len)).to_vec()))
+    }
+}
+
+
+impl <T: Parse> Parse for Option<T> {
+    fn parse_from(parser: &mut Parser) -> Result<Option<T>, ParserError> {
+        match try!(u8::parse_from(parser)) {
+            0 => Ok(None),
+            1 => Ok(Some(try!(T::parse_from(parser)))),
+            tag => Err(ParserError::UnknownTag(tag)),
+        }
+    }
+}
+
+
+impl <T: Parse> Parse for Vec<T> {
+    fn parse_from(parser: &mut Parser) -> Result<Vec<T>, ParserError> {
+        let len = try!(parser.parse_len());
+        // Every item takes at least one byte, so a longer length is definitely broken
+        if len > parser.remaining() {
+            return Err(ParserError::Overflow);
+        }
+        let mut v = Vec::with_capacity(len);
+        for _ in 0..len {
+            v.push(try!(T::parse_from(parser)));
+        }
         Ok(v)
     }
 }
 
 
+impl <K: Parse + Eq + Hash, V: Parse> Parse for HashMap<K, V> {
+    fn parse_from(parser: &mut Parser) -> Result<HashMap<K, V>, ParserError> {
+        let len = try!(parser.parse_len());
+        if len > parser.remaining() {
+            return Err(ParserError::Overflow);
+        }
+        let mut map = HashMap::with_capacity(len);
+        for _ in 0..len {
+            let k = try!(K::parse_from(parser));
+            let v = try!(V::parse_from(parser));
+            map.insert(k, v);
+        }
+        Ok(map)
+    }
+}
+
+
 
 
 
@@ -264,12 +468,23 @@ pub fn encode_len(len: usize) -> Vec<u8> {
     data
 }
 
+/// Writes the length prefix like `encode_len` does, without allocation
+pub fn write_len<W: Write>(w: &mut W, len: usize) -> io::Result<()> {
+    if len < 128 {
+        w.write_all(&encode_u8(len as u8))
+    } else {
+        let mut data = encode_u32(len as u32);
+        data[0] |= 0b_1000_0000;
+        w.write_all(&data)
+    }
+}
+
 pub fn decode_len(v: &[u8]) -> (usize, usize) {
     if v[0] & 0b_1000_0000 == 0 {
         (1 as usize, decode_u8(v) as usize)
     } else {
         let mut data = [0u8; 4];
-        data.copy_from_slice(v);
+        data.copy_from_slice(&v[..4]);
         data[0] &= 0b_0111_1111;
         (4 as usize, decode_u32(&data[..]) as usize)
     }
@@ -288,3 +503,92 @@ pub fn decode_str(v: &[u8]) -> Result<(usize, String), Utf8Error> {
     let s = try!(from_utf8(&v[size .. size + len])).to_owned();
     Ok((size + len, s))
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+
+    use super::*;
+
+
+    fn roundtrip<T: Encode + Parse + Clone + PartialEq + Debug>(v: T) -> bool {
+        let mut parser = Parser::new(v.clone().encode());
+        match T::parse_from(&mut parser) {
+            Ok(r) => r == v && parser.complete().is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    quickcheck! {
+        fn prop_u8(v: u8) -> bool { roundtrip(v) }
+        fn prop_u16(v: u16) -> bool { roundtrip(v) }
+        fn prop_u32(v: u32) -> bool { roundtrip(v) }
+        fn prop_u64(v: u64) -> bool { roundtrip(v) }
+        fn prop_usize(v: usize) -> bool { roundtrip(v) }
+        fn prop_i8(v: i8) -> bool { roundtrip(v) }
+        fn prop_i16(v: i16) -> bool { roundtrip(v) }
+        fn prop_i32(v: i32) -> bool { roundtrip(v) }
+        fn prop_i64(v: i64) -> bool { roundtrip(v) }
+        fn prop_bool(v: bool) -> bool { roundtrip(v) }
+        fn prop_string(v: String) -> bool { roundtrip(v) }
+        fn prop_bytes(v: Vec<u8>) -> bool { roundtrip(Bytes(v)) }
+        fn prop_option(v: Option<i64>) -> bool { roundtrip(v) }
+        fn prop_vec(v: Vec<u32>) -> bool { roundtrip(v) }
+        fn prop_vec_string(v: Vec<String>) -> bool { roundtrip(v) }
+        fn prop_vec_option(v: Vec<Option<String>>) -> bool { roundtrip(v) }
+        fn prop_map(v: HashMap<String, u64>) -> bool { roundtrip(v) }
+        fn prop_map_vec(v: HashMap<u16, Vec<bool>>) -> bool { roundtrip(v) }
+
+        fn prop_len(v: u32) -> bool {
+            let len = (v & 0x7fff_ffff) as usize;
+            let data = encode_len(len);
+            decode_len(&data) == (data.len(), len)
+        }
+    }
+
+    quickcheck! {
+        fn prop_encode_to(v: HashMap<String, Vec<Option<i32>>>) -> bool {
+            let mut data = Vec::new();
+            v.encode_to(&mut data).unwrap();
+            let mut encoder = Encoder::new();
+            encoder += v.clone();
+            data == v.encode() && data == encoder.complete()
+        }
+
+        fn prop_write_len(v: u32) -> bool {
+            let len = (v & 0x7fff_ffff) as usize;
+            let mut data = Vec::new();
+            write_len(&mut data, len).unwrap();
+            data == encode_len(len)
+        }
+    }
+
+    #[test]
+    fn long_bytes() {
+        assert!(roundtrip(Bytes(vec![0x5a; 70_000])));
+        assert!(roundtrip(vec![0xa5u8; 300]));
+    }
+
+    #[test]
+    fn invalid_bool() {
+        let mut parser = Parser::new(vec![2]);
+        assert!(bool::parse_from(&mut parser).is_err());
+    }
+
+    #[test]
+    fn bad_length() {
+        let mut parser = Parser::new(encode_len(1000));
+        assert!(Vec::<u8>::parse_from(&mut parser).is_err());
+        let mut parser = Parser::new(encode_len(10));
+        assert!(Bytes::parse_from(&mut parser).is_err());
+    }
+
+    #[test]
+    fn truncated_data() {
+        assert!(u64::parse_from(&mut Parser::new(vec![0, 0, 1])).is_err());
+        assert!(String::parse_from(&mut Parser::new(vec![10, b'a', b'b'])).is_err());
+        assert!(String::parse_from(&mut Parser::new(vec![0x80, 0])).is_err());
+    }
+}
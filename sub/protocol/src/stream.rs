@@ -1,9 +1,14 @@
 use std::io;
 use std::io::prelude::*;
+#[cfg(unix)] use std::mem;
 use std::net::{TcpStream, Shutdown};
 use std::time::Duration;
 
 use net2::TcpStreamExt;
+
+use ::tls::TlsStream;
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)] use libc;
 #[cfg(unix)] use unix_socket::UnixStream;
 
 
@@ -12,6 +17,7 @@ use net2::TcpStreamExt;
 #[derive(Debug)]
 pub enum Stream {
     Tcp(TcpStream),
+    Tls(TlsStream),
     Unix(UnixStream),
 }
 
@@ -20,6 +26,7 @@ pub enum Stream {
 #[derive(Debug)]
 pub enum Stream {
     Tcp(TcpStream),
+    Tls(TlsStream),
 }
 
 
@@ -32,6 +39,7 @@ impl Stream {
     pub fn try_clone(&self) -> io::Result<Stream> {
         match *self {
             Stream::Tcp(ref s) => Ok(Stream::Tcp(try!(s.try_clone()))),
+            Stream::Tls(ref s) => Ok(Stream::Tls(try!(s.try_clone()))),
             Stream::Unix(ref s) => Ok(Stream::Unix(try!(s.try_clone()))),
         }
     }
@@ -40,6 +48,7 @@ impl Stream {
     pub fn shutdown(&self) -> io::Result<()> {
         match *self {
             Stream::Tcp(ref s) => s.shutdown(Shutdown::Both),
+            Stream::Tls(ref s) => s.shutdown(),
             Stream::Unix(ref s) => s.shutdown(Shutdown::Both),
         }
     }
@@ -48,36 +57,95 @@ impl Stream {
     pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match *self {
             Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
             Stream::Unix(ref mut s) => s.write(buf),
         }
     }
 
+    /// Moves the socket into or out of the non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.set_nonblocking(nonblocking),
+            Stream::Tls(ref s) => s.get_ref().set_nonblocking(nonblocking),
+            Stream::Unix(ref s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
     /// Sets the keepalive timeout to the timeout specified.
     /// It fails silently for UNIX sockets.
     pub fn set_keepalive(&self, duration: Option<Duration>) -> io::Result<()> {
         match *self {
             Stream::Tcp(ref s) => TcpStreamExt::set_keepalive(s, duration),
+            Stream::Tls(ref s) => TcpStreamExt::set_keepalive(s.get_ref(), duration),
             Stream::Unix(_) => Ok(()),
         }
     }
 
     /// Sets the write timeout to the timeout specified.
-    /// It fails silently for UNIX sockets.
     pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         match *self {
             Stream::Tcp(ref s) => s.set_write_timeout(dur),
-            // TODO: couldn't figure out how to enable this in unix_socket
-            Stream::Unix(_) => Ok(()),
+            Stream::Tls(ref s) => s.get_ref().set_write_timeout(dur),
+            Stream::Unix(ref s) => set_socket_timeout(s.as_raw_fd(), libc::SO_SNDTIMEO, dur),
         }
     }
 
     /// Sets the read timeout to the timeout specified.
-    /// It fails silently for UNIX sockets.
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         match *self {
             Stream::Tcp(ref s) => s.set_read_timeout(dur),
-            // TODO: couldn't figure out how to enable this in unix_socket
-            Stream::Unix(_) => Ok(()),
+            Stream::Tls(ref s) => s.get_ref().set_read_timeout(dur),
+            Stream::Unix(ref s) => set_socket_timeout(s.as_raw_fd(), libc::SO_RCVTIMEO, dur),
+        }
+    }
+}
+
+
+/// Sets `SO_RCVTIMEO` or `SO_SNDTIMEO` of a socket, like std does for TCP streams.
+/// A zero duration is rejected, as it would disable the timeout.
+#[cfg(unix)]
+fn set_socket_timeout(fd: RawFd, option: libc::c_int, dur: Option<Duration>) -> io::Result<()> {
+    let timeout = match dur {
+        Some(dur) => {
+            if dur.as_secs() == 0 && dur.subsec_nanos() == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot set a zero duration timeout"));
+            }
+            let secs = if dur.as_secs() > libc::time_t::max_value() as u64 {
+                libc::time_t::max_value()
+            } else {
+                dur.as_secs() as libc::time_t
+            };
+            let mut timeout = libc::timeval {
+                tv_sec: secs,
+                tv_usec: (dur.subsec_nanos() / 1000) as libc::suseconds_t,
+            };
+            if timeout.tv_sec == 0 && timeout.tv_usec == 0 {
+                // Less than a microsecond
+                timeout.tv_usec = 1;
+            }
+            timeout
+        },
+        None => libc::timeval { tv_sec: 0, tv_usec: 0 },
+    };
+    let ret = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, option,
+                         &timeout as *const libc::timeval as *const libc::c_void,
+                         mem::size_of::<libc::timeval>() as libc::socklen_t)
+    };
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(unix)]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref s) => s.as_raw_fd(),
+            Stream::Tls(ref s) => s.get_ref().as_raw_fd(),
+            Stream::Unix(ref s) => s.as_raw_fd(),
         }
     }
 }
@@ -90,7 +158,84 @@ impl Read for Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
             Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
             Stream::Unix(ref mut s) => s.read(buf),
         }
     }
 }
+
+
+#[cfg(unix)]
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Stream::write(self, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{ErrorKind, Read};
+    use std::time::{Duration, Instant};
+
+    use unix_socket::UnixStream;
+
+    use super::Stream;
+
+    #[test]
+    fn unix_read_timeout() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let mut stream = Stream::Unix(a);
+        stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        let start = Instant::now();
+        let err = stream.read(&mut [0u8; 1]).unwrap_err();
+        assert!(err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn unix_write_timeout() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let mut stream = Stream::Unix(a);
+        stream.set_write_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        // Nobody reads the other end, so the socket buffer fills up
+        let chunk = [0u8; 64 * 1024];
+        let mut result = Ok(0);
+        while result.is_ok() {
+            result = stream.write(&chunk);
+        }
+        let err = result.unwrap_err();
+        assert!(err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn unix_timeout_reset() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut stream = Stream::Unix(a);
+        stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        stream.set_read_timeout(None).unwrap();
+        drop(b);
+        // Blocks until the peer is closed instead of timing out
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn unix_zero_timeout() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let stream = Stream::Unix(a);
+        assert_eq!(stream.set_read_timeout(Some(Duration::new(0, 0))).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,307 @@
+//! TLS transport over TCP connections.
+//!
+//! A `TlsStream` may be cloned like a socket to read and write from different threads:
+//! the clones share the TLS session, which is locked only to pass data through it,
+//! so a reader blocked on the socket does not stop a writer.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use rustls;
+use rustls::{Certificate, ClientSession, PrivateKey, ServerSession, Session};
+use rustls::internal::pemfile;
+
+pub use rustls::{ClientConfig, ServerConfig};
+
+
+const TLS_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+
+pub struct TlsStream {
+    sock: TcpStream,
+    session: Arc<Mutex<Box<Session + Send>>>,
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+fn tls_error(err: rustls::TLSError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("TLS error: {:?}", err))
+}
+
+
+/// Loads all certificates from a PEM file
+pub fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(try!(File::open(path)));
+    match pemfile::certs(&mut reader) {
+        Ok(ref certs) if certs.is_empty() =>
+            Err(io::Error::new(ErrorKind::InvalidData, format!("No certificates in {}", path))),
+        Ok(certs) => Ok(certs),
+        Err(_) => Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid certificates in {}", path))),
+    }
+}
+
+
+/// Loads the first RSA private key from a PEM file
+pub fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(try!(File::open(path)));
+    match pemfile::rsa_private_keys(&mut reader) {
+        Ok(mut keys) => match keys.len() {
+            0 => Err(io::Error::new(ErrorKind::InvalidData, format!("No RSA private key in {}", path))),
+            _ => Ok(keys.remove(0)),
+        },
+        Err(_) => Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid private key in {}", path))),
+    }
+}
+
+
+/// Builds the server side configuration.
+/// With `client_ca` set, clients must present a certificate signed by it.
+pub fn server_config(cert: &str, key: &str, client_ca: Option<&str>) -> io::Result<Arc<ServerConfig>> {
+    let mut config = ServerConfig::new();
+    config.set_single_cert(try!(load_certs(cert)), try!(load_private_key(key)));
+    if let Some(client_ca) = client_ca {
+        config.set_client_auth_roots(try!(load_certs(client_ca)), true);
+    }
+    Ok(Arc::new(config))
+}
+
+
+/// Builds the client side configuration trusting only the certificates from `ca`.
+/// `cert_key` is a pair of the certificate and the key files for the mutual TLS.
+pub fn client_config(ca: &str, cert_key: Option<(&str, &str)>) -> io::Result<Arc<ClientConfig>> {
+    let mut config = ClientConfig::new();
+    for cert in try!(load_certs(ca)) {
+        if config.root_store.add(&cert).is_err() {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid CA certificate in {}", ca)));
+        }
+    }
+    if let Some((cert, key)) = cert_key {
+        config.set_single_client_cert(try!(load_certs(cert)), try!(load_private_key(key)));
+    }
+    Ok(Arc::new(config))
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl TlsStream {
+    /// Wraps an accepted connection. The handshake is performed along with the first reads.
+    pub fn server(sock: TcpStream, config: &Arc<ServerConfig>) -> TlsStream {
+        TlsStream::new(sock, Box::new(ServerSession::new(config)))
+    }
+
+    /// Wraps a connection to the server, which must present a certificate for `hostname`.
+    pub fn client(sock: TcpStream, config: &Arc<ClientConfig>, hostname: &str) -> TlsStream {
+        TlsStream::new(sock, Box::new(ClientSession::new(config, hostname)))
+    }
+
+    fn new(sock: TcpStream, session: Box<Session + Send>) -> TlsStream {
+        TlsStream {
+            sock: sock,
+            session: Arc::new(Mutex::new(session)),
+        }
+    }
+
+    /// Creates a new handle to the underlying socket, sharing the TLS session.
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            sock: try!(self.sock.try_clone()),
+            session: self.session.clone(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    /// Sends a close notification to the peer and shuts down the socket.
+    pub fn shutdown(&self) -> io::Result<()> {
+        {
+            let mut session = self.session.lock().unwrap();
+            session.send_close_notify();
+            // The peer may be gone already
+            let _ = write_tls(&mut **session, &self.sock);
+        }
+        self.sock.shutdown(Shutdown::Both)
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.session.lock().unwrap().is_handshaking()
+    }
+}
+
+
+/// Writes all pending TLS records of the session to the socket
+fn write_tls(session: &mut Session, mut sock: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        try!(session.write_tls(&mut sock));
+    }
+    Ok(())
+}
+
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut tls_buf = [0u8; TLS_READ_BUFFER_SIZE];
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                let n = try!(session.read(buf));
+                if n > 0 {
+                    return Ok(n);
+                }
+                try!(write_tls(&mut **session, &self.sock));
+            }
+
+            // Wait for the data without holding the session
+            let n = try!(self.sock.read(&mut tls_buf));
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut session = self.session.lock().unwrap();
+            let mut data = &tls_buf[..n];
+            while !data.is_empty() {
+                try!(session.read_tls(&mut data));
+                if let Err(err) = session.process_new_packets() {
+                    // Let the peer know about the failure
+                    let _ = write_tls(&mut **session, &self.sock);
+                    return Err(tls_error(err));
+                }
+            }
+            // Handshake responses and plaintext queued during the handshake
+            try!(write_tls(&mut **session, &self.sock));
+        }
+    }
+}
+
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        // Plaintext is buffered until the handshake is complete
+        let n = try!(session.write(buf));
+        try!(write_tls(&mut **session, &self.sock));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        try!(session.flush());
+        try!(write_tls(&mut **session, &self.sock));
+        (&self.sock).flush()
+    }
+}
+
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsStream({:?})", self.sock)
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::process::{Command, Stdio};
+    use std::sync::{Arc, Once, ONCE_INIT};
+    use std::thread;
+
+    use libc;
+    use rustls::{ClientConfig, ServerConfig};
+
+    use super::{TlsStream, client_config, server_config};
+
+    /// The certificates and keys are generated once for each test run by `testdata/tls/gen.sh`
+    fn data(name: &str) -> String {
+        static GENERATE: Once = ONCE_INIT;
+        let dir = env::temp_dir().join(format!("ifs-tls-{}", unsafe { libc::getpid() }));
+        GENERATE.call_once(|| {
+            fs::create_dir_all(&dir).unwrap();
+            let script = format!("{}/testdata/tls/gen.sh", env!("CARGO_MANIFEST_DIR"));
+            let status = Command::new("sh").arg(&script).arg(&dir)
+                .stdout(Stdio::null()).stderr(Stdio::null())
+                .status().unwrap();
+            assert!(status.success(), "Generating the TLS test data with {} has failed", script);
+        });
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    fn server(client_ca: bool) -> Arc<ServerConfig> {
+        let client_ca = if client_ca { Some(data("ca.pem")) } else { None };
+        server_config(&data("server.pem"), &data("server.key"), client_ca.as_ref().map(|s| &s[..])).unwrap()
+    }
+
+    fn client(ca: &str, with_cert: bool) -> Arc<ClientConfig> {
+        let (cert, key) = (data("client.pem"), data("client.key"));
+        client_config(&data(ca), if with_cert { Some((&cert[..], &key[..])) } else { None }).unwrap()
+    }
+
+    /// Runs an echo server for one connection and sends `ping` through it
+    fn echo(server_config: Arc<ServerConfig>, client_config: Arc<ClientConfig>) -> Result<Vec<u8>, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut reader = TlsStream::server(sock, &server_config);
+            let mut writer = reader.try_clone().unwrap();
+            let mut buf = [0u8; 4];
+            if reader.read_exact(&mut buf).is_ok() {
+                let _ = writer.write_all(&buf);
+            }
+        });
+
+        let mut stream = TlsStream::client(TcpStream::connect(addr).unwrap(), &client_config, "localhost");
+        let result = stream.write_all(b"ping")
+            .and_then(|_| {
+                let mut buf = vec![0u8; 4];
+                stream.read_exact(&mut buf).map(|_| buf)
+            })
+            .map_err(|err| format!("{:?}", err));
+        drop(stream);
+        server.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn echo_through_tls() {
+        assert_eq!(echo(server(false), client("ca.pem", false)), Ok(b"ping".to_vec()));
+    }
+
+    #[test]
+    fn reject_unpinned_server() {
+        assert!(echo(server(false), client("other-ca.pem", false)).is_err());
+    }
+
+    #[test]
+    fn mutual_tls() {
+        assert_eq!(echo(server(true), client("ca.pem", true)), Ok(b"ping".to_vec()));
+    }
+
+    #[test]
+    fn mutual_tls_requires_client_cert() {
+        assert!(echo(server(true), client("ca.pem", false)).is_err());
+    }
+
+    #[test]
+    fn missing_files() {
+        assert!(server_config(&data("nonexistent.pem"), &data("server.key"), None).is_err());
+        assert!(server_config(&data("server.pem"), &data("server.pem"), None).is_err());
+        assert!(client_config(&data("server.key"), None).is_err());
+    }
+}
@@ -1,8 +1,13 @@
 use std::fmt;
+use std::io;
+use std::io::Write;
 use std::mem::transmute;
+use std::num::ParseIntError;
+use std::str::FromStr;
 use std::sync::PoisonError;
 
 use ::message::RawMessage;
+use ::serde::{EncodeTo, Parse, Parser, ParserError};
 
 
 #[derive(Debug)]
@@ -23,10 +28,26 @@ pub enum Workflow {
 }
 
 
-#[derive(PartialEq, PartialOrd, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct ProtocolVersion(pub u8, pub u8, pub u16);
 
 
+/// An inclusive range of protocol versions
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ProtocolVersionRange {
+    pub min: ProtocolVersion,
+    pub max: ProtocolVersion,
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolVersionError {
+    /// The string is not in the `major.minor.patch` form
+    Format,
+    Number(ParseIntError),
+}
+
+
 pub trait Protocol {
     fn flow(&self, raw_message: RawMessage) -> Workflow;
 }
@@ -59,3 +80,142 @@ impl ProtocolVersion {
         ].concat()
     }
 }
+
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+
+impl FromStr for ProtocolVersion {
+    type Err = ProtocolVersionError;
+
+    fn from_str(s: &str) -> Result<ProtocolVersion, ProtocolVersionError> {
+        let parts = s.trim().split('.').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Err(ProtocolVersionError::Format);
+        }
+        Ok(ProtocolVersion(
+            try!(parts[0].parse::<u8>()),
+            try!(parts[1].parse::<u8>()),
+            try!(parts[2].parse::<u16>()),
+        ))
+    }
+}
+
+
+impl From<ParseIntError> for ProtocolVersionError {
+    fn from(err: ParseIntError) -> Self {
+        ProtocolVersionError::Number(err)
+    }
+}
+
+
+impl EncodeTo for ProtocolVersion {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.export())
+    }
+}
+
+
+impl Parse for ProtocolVersion {
+    fn parse_from(parser: &mut Parser) -> Result<ProtocolVersion, ParserError> {
+        Ok(ProtocolVersion(
+            try!(u8::parse_from(parser)),
+            try!(u8::parse_from(parser)),
+            try!(u16::parse_from(parser)),
+        ))
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+impl ProtocolVersionRange {
+    pub fn new(min: ProtocolVersion, max: ProtocolVersion) -> ProtocolVersionRange {
+        ProtocolVersionRange { min: min, max: max }
+    }
+
+    pub fn contains(&self, version: &ProtocolVersion) -> bool {
+        self.min <= *version && *version <= self.max
+    }
+}
+
+
+impl fmt::Display for ProtocolVersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} - {}", self.min, self.max)
+    }
+}
+
+
+impl EncodeTo for ProtocolVersionRange {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.min.encode_to(w));
+        self.max.encode_to(w)
+    }
+}
+
+
+impl Parse for ProtocolVersionRange {
+    fn parse_from(parser: &mut Parser) -> Result<ProtocolVersionRange, ParserError> {
+        Ok(ProtocolVersionRange::new(
+            try!(ProtocolVersion::parse_from(parser)),
+            try!(ProtocolVersion::parse_from(parser)),
+        ))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::serde::{Encode, Parse, Parser};
+
+    use super::{ProtocolVersion, ProtocolVersionRange, ProtocolVersionError};
+
+    #[test]
+    fn version_display() {
+        assert_eq!(format!("{}", ProtocolVersion(0, 1, 12)), "0.1.12");
+    }
+
+    #[test]
+    fn version_from_str() {
+        assert_eq!("0.1.12".parse::<ProtocolVersion>(), Ok(ProtocolVersion(0, 1, 12)));
+        assert_eq!("0.1".parse::<ProtocolVersion>(), Err(ProtocolVersionError::Format));
+        assert!("0.1.x".parse::<ProtocolVersion>().is_err());
+        assert!("256.0.0".parse::<ProtocolVersion>().is_err());
+    }
+
+    #[test]
+    fn version_order() {
+        assert!(ProtocolVersion(0, 1, 2) > ProtocolVersion(0, 1, 1));
+        assert!(ProtocolVersion(0, 2, 0) > ProtocolVersion(0, 1, 300));
+        assert!(ProtocolVersion(1, 0, 0) > ProtocolVersion(0, 255, 0));
+    }
+
+    #[test]
+    fn version_codec() {
+        let version = ProtocolVersion(1, 2, 772);
+        let data = version.encode();
+        assert_eq!(data, vec![1, 2, 3, 4]);
+        let mut parser = Parser::new(data);
+        assert_eq!(ProtocolVersion::parse_from(&mut parser).unwrap(), version);
+        parser.complete().unwrap();
+    }
+
+    #[test]
+    fn range() {
+        let range = ProtocolVersionRange::new(ProtocolVersion(0, 1, 1), ProtocolVersion(0, 1, 3));
+        assert!(!range.contains(&ProtocolVersion(0, 1, 0)));
+        assert!(range.contains(&ProtocolVersion(0, 1, 1)));
+        assert!(range.contains(&ProtocolVersion(0, 1, 3)));
+        assert!(!range.contains(&ProtocolVersion(0, 2, 0)));
+
+        let mut parser = Parser::new(range.encode());
+        assert_eq!(ProtocolVersionRange::parse_from(&mut parser).unwrap(), range);
+        parser.complete().unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+#!/bin/sh
+# Generates the self-signed certificates and keys used by the TLS tests into the given directory,
+# or next to this script. The tests run it on their own, so no private key is kept in the repository.
+# Keys are written in the PKCS#1 format expected by `tls::load_private_key`.
+set -e
+cd "${1:-$(dirname "$0")}"
+
+ca() {
+    openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -sha256 \
+        -keyout "$1.key" -out "$1.pem" -subj "/CN=$2" \
+        -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+    openssl rsa -in "$1.key" -out "$1.key" -traditional
+}
+
+leaf() {
+    openssl req -newkey rsa:2048 -nodes -keyout "$1.key" -out "$1.csr" -subj "/CN=$2"
+    printf "basicConstraints=critical,CA:FALSE\nsubjectAltName=DNS:$2\nextendedKeyUsage=$3\n" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 -sha256 \
+        -extfile "$1.ext" -out "$1.pem"
+    openssl rsa -in "$1.key" -out "$1.key" -traditional
+    rm "$1.csr" "$1.ext"
+}
+
+ca ca "ifs test ca"
+ca other-ca "ifs other ca"
+leaf server localhost serverAuth
+leaf client client.localhost clientAuth
+rm -f ca.srl ca.key other-ca.key
@@ -0,0 +1,253 @@
+//! Custom derives for the `protocol::serde` traits.
+//!
+//! * `#[derive(Encode, Parse)]` on a struct encodes the fields in the order of declaration.
+//!   `Encode` is provided through an `EncodeTo` implementation.
+//! * `#[derive(Encode, Parse)]` on an enum prefixes the variant fields with a `u8` tag,
+//!   given for each variant as `#[tag = "0"]`.
+//! * `#[derive(Dispatch)]` on an enum of messages generates `encode(self) -> RawMessage`,
+//!   `parse(RawMessage) -> Result<Self, ParseError>` and a `WriteFrame` implementation, where each variant wraps a single message
+//!   and is annotated with the name of its code constant, like `#[code = "MC_START"]`.
+//!   `ParseError` of the calling module must have `UnknownCode` and `BadProtocol` variants
+//!   and must be convertible from `ParserError`.
+
+extern crate proc_macro;
+extern crate syn;
+#[macro_use] extern crate quote;
+
+use proc_macro::TokenStream;
+use quote::Tokens;
+use syn::{Body, Ident, Lit, MacroInput, MetaItem, Variant, VariantData};
+
+
+#[proc_macro_derive(Encode, attributes(tag))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input(&input.to_string()).unwrap();
+    impl_encode(&ast).parse().unwrap()
+}
+
+
+#[proc_macro_derive(Parse, attributes(tag))]
+pub fn derive_parse(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input(&input.to_string()).unwrap();
+    impl_parse(&ast).parse().unwrap()
+}
+
+
+#[proc_macro_derive(Dispatch, attributes(code))]
+pub fn derive_dispatch(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input(&input.to_string()).unwrap();
+    impl_dispatch(&ast).parse().unwrap()
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+/// Finds the string value of the attribute `#[name = "value"]`
+fn attr_value(attrs: &[syn::Attribute], name: &str) -> Option<String> {
+    for attr in attrs {
+        if let MetaItem::NameValue(ref ident, Lit::Str(ref value, _)) = attr.value {
+            if ident == name {
+                return Some(value.clone());
+            }
+        }
+    }
+    None
+}
+
+
+fn variant_tag(variant: &Variant) -> u8 {
+    match attr_value(&variant.attrs, "tag") {
+        Some(tag) => match tag.parse::<u8>() {
+            Ok(tag) => tag,
+            Err(_) => panic!("Tag of variant {} must be an u8, got {:?}", variant.ident, tag),
+        },
+        None => panic!("Variant {} has no #[tag = \"..\"] attribute", variant.ident),
+    }
+}
+
+
+/// Bindings for the fields of a variant, named `f0`, `f1`, ...
+fn bindings(data: &VariantData) -> Vec<Ident> {
+    data.fields().iter().enumerate().map(|(i, _)| Ident::new(format!("f{}", i))).collect()
+}
+
+
+/// A pattern matching the variant and binding its fields by reference
+fn variant_pattern(name: &Ident, variant: &Variant) -> Tokens {
+    let ident = &variant.ident;
+    let bindings = bindings(&variant.data);
+    match variant.data {
+        VariantData::Struct(ref fields) => {
+            let names = fields.iter().map(|f| f.ident.clone().unwrap()).collect::<Vec<_>>();
+            quote! { #name::#ident { #(#names: ref #bindings),* } }
+        },
+        VariantData::Tuple(_) => quote! { #name::#ident(#(ref #bindings),*) },
+        VariantData::Unit => quote! { #name::#ident },
+    }
+}
+
+
+/// An expression constructing the struct or the variant at `path`, parsing the fields in order
+fn parse_fields(path: Tokens, data: &VariantData) -> Tokens {
+    match *data {
+        VariantData::Struct(ref fields) => {
+            let names = fields.iter().map(|f| f.ident.clone().unwrap()).collect::<Vec<_>>();
+            quote! { #path { #(#names: try!(::protocol::serde::Parse::parse_from(parser))),* } }
+        },
+        VariantData::Tuple(ref fields) => {
+            let parsers = fields.iter().map(|_| quote! { try!(::protocol::serde::Parse::parse_from(parser)) });
+            quote! { #path(#(#parsers),*) }
+        },
+        VariantData::Unit => path,
+    }
+}
+
+
+// --------------------------------------------------------------------------------------------------------------------
+
+
+fn impl_encode(ast: &MacroInput) -> Tokens {
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let body = match ast.body {
+        Body::Struct(ref data) => {
+            let fields = data.fields().iter().enumerate().map(|(i, f)| match f.ident {
+                Some(ref ident) => ident.clone(),
+                None => Ident::new(i),
+            });
+            quote! { #(try!(::protocol::serde::EncodeTo::encode_to(&self.#fields, w));)* }
+        },
+        Body::Enum(ref variants) => {
+            let arms = variants.iter().map(|variant| {
+                let tag = variant_tag(variant);
+                let pattern = variant_pattern(name, variant);
+                let bindings = bindings(&variant.data);
+                quote! {
+                    #pattern => {
+                        try!(::protocol::serde::EncodeTo::encode_to(&#tag, w));
+                        #(try!(::protocol::serde::EncodeTo::encode_to(#bindings, w));)*
+                    }
+                }
+            });
+            quote! { match *self { #(#arms),* } }
+        },
+    };
+
+    quote! {
+        impl #impl_generics ::protocol::serde::EncodeTo for #name #ty_generics #where_clause {
+            fn encode_to<W: ::std::io::Write>(&self, w: &mut W) -> ::std::io::Result<()> {
+                #body
+                Ok(())
+            }
+        }
+    }
+}
+
+
+fn impl_parse(ast: &MacroInput) -> Tokens {
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let body = match ast.body {
+        Body::Struct(ref data) => {
+            let value = parse_fields(quote! { #name }, data);
+            quote! { Ok(#value) }
+        },
+        Body::Enum(ref variants) => {
+            let arms = variants.iter().map(|variant| {
+                let tag = variant_tag(variant);
+                let ident = &variant.ident;
+                let value = parse_fields(quote! { #name::#ident }, &variant.data);
+                quote! { #tag => #value }
+            });
+            quote! {
+                Ok(match try!(<u8 as ::protocol::serde::Parse>::parse_from(parser)) {
+                    #(#arms,)*
+                    tag => return Err(::protocol::serde::ParserError::UnknownTag(tag)),
+                })
+            }
+        },
+    };
+
+    quote! {
+        impl #impl_generics ::protocol::serde::Parse for #name #ty_generics #where_clause {
+            fn parse_from(parser: &mut ::protocol::serde::Parser)
+                -> Result<#name #ty_generics, ::protocol::serde::ParserError>
+            {
+                #body
+            }
+        }
+    }
+}
+
+
+fn impl_dispatch(ast: &MacroInput) -> Tokens {
+    let name = &ast.ident;
+
+    let variants = match ast.body {
+        Body::Enum(ref variants) => variants,
+        Body::Struct(_) => panic!("#[derive(Dispatch)] is only defined for enums"),
+    };
+
+    let mut idents = Vec::new();
+    let mut codes = Vec::new();
+    for variant in variants {
+        match variant.data {
+            VariantData::Tuple(ref fields) if fields.len() == 1 => (),
+            _ => panic!("Variant {} must wrap exactly one message", variant.ident),
+        }
+        let code = match attr_value(&variant.attrs, "code") {
+            Some(code) => Ident::new(code),
+            None => panic!("Variant {} has no #[code = \"..\"] attribute", variant.ident),
+        };
+        idents.push(variant.ident.clone());
+        codes.push(code);
+    }
+
+    // Interpolations inside a repetition must all be iterable
+    let paths = &idents.iter().map(|ident| quote! { #name::#ident }).collect::<Vec<_>>();
+    let codes = &codes;
+
+    quote! {
+        impl #name {
+            pub fn encode(self) -> ::protocol::message::RawMessage {
+                match self {
+                    #(
+                        #paths(m) => ::protocol::message::RawMessage::new(
+                            #codes, ::protocol::message::RawMessageBody::Binary(::protocol::serde::Encode::encode(m))),
+                    )*
+                }
+            }
+
+            pub fn parse(raw_message: ::protocol::message::RawMessage) -> Result<#name, ParseError> {
+                match raw_message.mtype {
+                    #( #codes => (), )*
+                    _ => return Err(ParseError::UnknownCode),
+                };
+                let mut input = match raw_message.body {
+                    ::protocol::message::RawMessageBody::Binary(v) => ::protocol::serde::Parser::new(v),
+                    _ => return Err(ParseError::BadProtocol),
+                };
+                let message = {
+                    let parser = &mut input;
+                    match raw_message.mtype {
+                        #( #codes => #paths(try!(::protocol::serde::Parse::parse_from(parser))), )*
+                        _ => unreachable!(),
+                    }
+                };
+                try!(input.complete());
+                Ok(message)
+            }
+        }
+
+        impl ::protocol::message::WriteFrame for #name {
+            fn write_frame(&self, buf: &mut Vec<u8>) -> Result<(), ::protocol::message::EncodeError> {
+                match *self {
+                    #( #paths(ref m) => ::protocol::message::frame_into(buf, #codes, m), )*
+                }
+            }
+        }
+    }
+}